@@ -0,0 +1,221 @@
+//! Procedural macros for `rasengan`
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse::{ParseStream, Parser},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Data, DeriveInput, Field, Fields, FieldsNamed, FieldsUnnamed, MacroDelimiter, Meta, Token,
+};
+
+/// Turns an enum of requests into a complete Maelstrom payload type
+///
+/// Every variant marked `#[reply]` gets a matching `<Variant>Ok` variant generated next to it.
+/// A bare `#[reply]` generates a unit reply, while `#[reply { field: Type }]` or
+/// `#[reply(Type)]` gives the reply fields of its own. The enum is made (de)serializable using
+/// Maelstrom's `type` tag with snake_case names, and implements `rasengan::Workload` so
+/// generated replies can be told apart from requests.
+///
+/// A `<Name>Request` enum holding just the requests is generated alongside it, along with
+/// `Workload::into_request` to convert into it. Replies come back as they are, so a node ignores
+/// them without a match arm of their own:
+///
+/// ```ignore
+/// #[workload]
+/// #[derive(Debug, Clone)]
+/// enum Payload {
+///     #[reply { echo: String }]
+///     Echo { echo: String },
+/// }
+/// ...
+/// let Ok(request) = reply.body.payload.into_request() else {
+///     return Ok(());
+/// };
+/// match request {
+///     PayloadRequest::Echo { echo } => ...,
+/// }
+/// ```
+///
+/// The expanded code refers to `::serde` and `::rasengan`, so both must be dependencies of the
+/// crate using it.
+#[proc_macro_attribute]
+pub fn workload(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(Span::call_site(), "#[workload] doesn't take any arguments")
+            .to_compile_error()
+            .into();
+    }
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "#[workload] can only be applied to enums",
+        ));
+    };
+
+    let mut variants = Vec::new();
+    let mut replies = Vec::new();
+    let mut requests = Vec::new();
+    for variant in &data.variants {
+        let mut variant = variant.clone();
+        let mut reply = None;
+        let mut attrs = Vec::new();
+        for attr in variant.attrs.drain(..) {
+            if !attr.path().is_ident("reply") {
+                attrs.push(attr);
+                continue;
+            }
+            if reply.is_some() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "duplicate #[reply] attribute",
+                ));
+            }
+            let fields = match &attr.meta {
+                Meta::Path(_) => Fields::Unit,
+                Meta::List(list) => match list.delimiter {
+                    MacroDelimiter::Brace(brace_token) => Fields::Named(FieldsNamed {
+                        brace_token,
+                        named: (|input: ParseStream| {
+                            Punctuated::<Field, Token![,]>::parse_terminated_with(
+                                input,
+                                Field::parse_named,
+                            )
+                        })
+                        .parse2(list.tokens.clone())?,
+                    }),
+                    MacroDelimiter::Paren(paren_token) => Fields::Unnamed(FieldsUnnamed {
+                        paren_token,
+                        unnamed: (|input: ParseStream| {
+                            Punctuated::<Field, Token![,]>::parse_terminated_with(
+                                input,
+                                Field::parse_unnamed,
+                            )
+                        })
+                        .parse2(list.tokens.clone())?,
+                    }),
+                    MacroDelimiter::Bracket(_) => {
+                        return Err(syn::Error::new_spanned(
+                            attr,
+                            "use #[reply], #[reply { .. }], or #[reply(..)]",
+                        ))
+                    }
+                },
+                Meta::NameValue(_) => {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "use #[reply], #[reply { .. }], or #[reply(..)]",
+                    ))
+                }
+            };
+            reply = Some(fields);
+        }
+        variant.attrs = attrs;
+        requests.push(variant.clone());
+
+        if let Some(fields) = reply {
+            let ident = format_ident!("{}Ok", variant.ident);
+            replies.push(ident.clone());
+            variants.push(variant);
+            variants.push(syn::Variant {
+                attrs: Vec::new(),
+                ident,
+                fields,
+                discriminant: None,
+            });
+        } else {
+            variants.push(variant);
+        }
+    }
+
+    let DeriveInput {
+        attrs,
+        vis,
+        ident,
+        generics,
+        ..
+    } = &input;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let is_reply = if replies.is_empty() {
+        quote!(false)
+    } else {
+        quote!(matches!(self, #(Self::#replies { .. })|*))
+    };
+
+    let request = format_ident!("{}Request", ident);
+    let request_doc = format!("The requests of [`{ident}`], without its replies");
+    let conversions: Vec<_> = requests
+        .iter()
+        .map(|variant| {
+            let name = &variant.ident;
+            let bindings: Vec<_> = variant
+                .fields
+                .iter()
+                .enumerate()
+                .map(|(i, field)| field.ident.clone().unwrap_or(format_ident!("field{i}")))
+                .collect();
+            match &variant.fields {
+                Fields::Named(_) => {
+                    quote!(Self::#name { #(#bindings),* } => Ok(#request::#name { #(#bindings),* }))
+                }
+                Fields::Unnamed(_) => {
+                    quote!(Self::#name(#(#bindings),*) => Ok(#request::#name(#(#bindings),*)))
+                }
+                Fields::Unit => quote!(Self::#name => Ok(#request::#name)),
+            }
+        })
+        .collect();
+    // Serde's attributes mean nothing without its derives
+    let not_serde = |attr: &syn::Attribute| !attr.path().is_ident("serde");
+    let request_attrs: Vec<_> = attrs
+        .iter()
+        .filter(|attr| not_serde(attr) && !attr.path().is_ident("doc"))
+        .collect();
+    for variant in &mut requests {
+        variant.attrs.retain(not_serde);
+        for field in &mut variant.fields {
+            field.attrs.retain(not_serde);
+        }
+    }
+
+    Ok(quote! {
+        #[derive(::serde::Serialize, ::serde::Deserialize)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        #(#attrs)*
+        #vis enum #ident #generics #where_clause {
+            #(#variants,)*
+        }
+
+        #[doc = #request_doc]
+        // Nodes that match on the full payload have no use for it
+        #[allow(dead_code)]
+        #(#request_attrs)*
+        #vis enum #request #generics #where_clause {
+            #(#requests,)*
+        }
+
+        impl #impl_generics ::rasengan::Workload for #ident #ty_generics #where_clause {
+            type Request = #request #ty_generics;
+
+            fn is_reply(&self) -> bool {
+                #is_reply
+            }
+
+            fn into_request(self) -> ::std::result::Result<Self::Request, Self> {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#conversions,)*
+                    reply => Err(reply),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,4 @@
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    rasengan::workloads::counter::run(&args)
+}
@@ -1,47 +1,4 @@
-use rasengan::*;
-
-
-use serde::{Deserialize, Serialize};
-use std::io::{StdoutLock};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Echo { echo: String },
-    EchoOk { echo: String },
-}
-
-struct EchoNode {
-    id: usize,
-}
-
-impl Node<(), Payload> for EchoNode {
-    fn from_init(
-        _state: (),
-        _init: Init,
-        _tx: std::sync::mpsc::Sender<Event<Payload>>,
-    ) -> anyhow::Result<Self> {
-        Ok(Self { id: 1 })
-    }
-
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
-        };
-
-        let mut reply = input.into_reply(Some(&mut self.id));
-        match reply.body.payload {
-            Payload::Echo { echo } => {
-                reply.body.payload = Payload::EchoOk { echo };
-                reply.send(output)?;
-            }
-            Payload::EchoOk { .. } => {}
-        }
-        Ok(())
-    }
-}
-
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, EchoNode, _, _>(())
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    rasengan::workloads::echo::run(&args)
 }
@@ -0,0 +1,270 @@
+use rasengan::{
+    session::{Admission, Sessions},
+    sharding::Shards,
+    *,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    time::Duration,
+};
+
+/// How often unacknowledged updates to other nodes are resent
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// How long a `send` forwarded to its key's leader waits for an answer
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[workload]
+#[derive(Debug, Clone)]
+enum Payload {
+    #[reply { offset: u64 }]
+    Send { key: String, msg: Value },
+    #[reply { msgs: HashMap<String, Vec<(u64, Value)>> }]
+    Poll { offsets: HashMap<String, u64> },
+    #[reply]
+    CommitOffsets { offsets: HashMap<String, u64> },
+    #[reply { offsets: HashMap<String, u64> }]
+    ListCommittedOffsets { keys: Vec<String> },
+    /// Copies a freshly appended entry from a key's leader to every other node
+    #[reply]
+    Replicate {
+        key: String,
+        offset: u64,
+        msg: Value,
+    },
+    /// Spreads committed offsets to every other node
+    #[reply]
+    Committed { offsets: HashMap<String, u64> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Wire {
+    Client(Payload),
+    Error(ErrorPayload),
+}
+
+#[derive(Debug, Clone)]
+enum InjectedPayload {
+    Retry,
+}
+
+struct KafkaNode {
+    node: NodeID,
+    id: usize,
+    nodes: Vec<NodeID>,
+    /// Decides which node leads each key
+    shards: Shards,
+    /// Every entry seen so far, whether this node leads the key or is replicating it
+    logs: HashMap<String, BTreeMap<u64, Value>>,
+    committed: HashMap<String, u64>,
+    /// Keeps a retried `send` from appending its message twice
+    sessions: Sessions,
+    /// Replications and commits other nodes haven't acknowledged, by the ID they went out under
+    unacked: HashMap<MessageID, (NodeID, Payload)>,
+}
+
+impl KafkaNode {
+    fn peers(&self) -> impl Iterator<Item = &NodeID> {
+        self.nodes.iter().filter(move |&id| *id != self.node)
+    }
+
+    /// Sends an update to every other node, resending it until each acknowledges it
+    fn spread(&mut self, payload: Payload, output: &mut impl Write) -> anyhow::Result<()> {
+        let peers: Vec<NodeID> = self.peers().cloned().collect();
+        for peer in peers {
+            self.push(peer, payload.clone(), output)?;
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        peer: NodeID,
+        payload: Payload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let message = Message::new(self.node.clone(), peer.clone())
+            .with_id(&mut self.id)
+            .payload(Wire::Client(payload));
+        message.send(output)?;
+        let Wire::Client(payload) = message.body.payload else {
+            unreachable!("just wrapped");
+        };
+        let id = message.body.id.expect("updates are sent with an ID");
+        self.unacked.insert(id, (peer, payload));
+        Ok(())
+    }
+
+    /// Resends every unacknowledged update under a fresh ID, and gives up on forwarded requests
+    /// that have gone unanswered
+    fn retry(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        self.shards.expire(FORWARD_TIMEOUT);
+        for (_, (peer, payload)) in std::mem::take(&mut self.unacked) {
+            self.push(peer, payload, output)?;
+        }
+        Ok(())
+    }
+
+    /// Entries from `offset` on, stopping at the first one that hasn't been replicated here yet
+    ///
+    /// Polls are answered locally rather than by each key's leader, so a poll may not yet see
+    /// entries the leader has acknowledged; it never skips over one, and retried replication
+    /// fills in what's missing for the client's next poll.
+    fn poll(&self, key: &str, offset: u64) -> Vec<(u64, Value)> {
+        let Some(log) = self.logs.get(key) else {
+            return Vec::new();
+        };
+        log.range(offset..)
+            .zip(offset..)
+            .take_while(|((&have, _), want)| have == *want)
+            .map(|((&offset, msg), _)| (offset, msg.clone()))
+            .collect()
+    }
+
+    fn commit(&mut self, offsets: HashMap<String, u64>) {
+        for (key, offset) in offsets {
+            let committed = self.committed.entry(key).or_default();
+            *committed = (*committed).max(offset);
+        }
+    }
+
+    fn handle(&mut self, input: Message<Wire>, output: &mut impl Write) -> anyhow::Result<()> {
+        if let Some(relayed) = self.shards.relayed(&input) {
+            return self.sessions.reply(&relayed, output);
+        }
+        let input = match &input.body.payload {
+            // Offsets for a key are assigned by its leader alone
+            Wire::Client(Payload::Send { key, .. }) => {
+                let key = key.clone();
+                match self.shards.route(&key, input, &mut self.id, output)? {
+                    Some(input) => input,
+                    None => return Ok(()),
+                }
+            }
+            _ => input,
+        };
+
+        let acked = input.body.in_reply_to;
+        let mut reply = input.into_reply(Some(&mut self.id));
+        let Wire::Client(payload) = reply.body.payload else {
+            // Errors only ever answer forwarded requests, which were relayed above
+            return Ok(());
+        };
+        let request = match payload.into_request() {
+            Ok(request) => request,
+            // Only replicated entries and commits are ever acknowledged to this node
+            Err(_) => {
+                if let Some(id) = acked {
+                    self.unacked.remove(&id);
+                }
+                return Ok(());
+            }
+        };
+        match request {
+            PayloadRequest::Send { key, msg } => {
+                let log = self.logs.entry(key.clone()).or_default();
+                let offset = log.last_key_value().map_or(0, |(&last, _)| last + 1);
+                log.insert(offset, msg.clone());
+                self.spread(Payload::Replicate { key, offset, msg }, output)?;
+                reply.body.payload = Wire::Client(Payload::SendOk { offset });
+                self.sessions.reply(&reply, output)?;
+            }
+            PayloadRequest::Poll { offsets } => {
+                let msgs = offsets
+                    .into_iter()
+                    .map(|(key, offset)| {
+                        let msgs = self.poll(&key, offset);
+                        (key, msgs)
+                    })
+                    .collect();
+                reply.body.payload = Wire::Client(Payload::PollOk { msgs });
+                self.sessions.reply(&reply, output)?;
+            }
+            PayloadRequest::CommitOffsets { offsets } => {
+                self.spread(
+                    Payload::Committed {
+                        offsets: offsets.clone(),
+                    },
+                    output,
+                )?;
+                self.commit(offsets);
+                reply.body.payload = Wire::Client(Payload::CommitOffsetsOk);
+                self.sessions.reply(&reply, output)?;
+            }
+            PayloadRequest::ListCommittedOffsets { keys } => {
+                let offsets = keys
+                    .into_iter()
+                    .filter_map(|key| {
+                        let offset = *self.committed.get(&key)?;
+                        Some((key, offset))
+                    })
+                    .collect();
+                reply.body.payload = Wire::Client(Payload::ListCommittedOffsetsOk { offsets });
+                self.sessions.reply(&reply, output)?;
+            }
+            // Both are safe to apply twice, so resends are simply acknowledged again
+            PayloadRequest::Replicate { key, offset, msg } => {
+                self.logs.entry(key).or_default().insert(offset, msg);
+                reply.body.payload = Wire::Client(Payload::ReplicateOk);
+                reply.send(output)?;
+            }
+            PayloadRequest::Committed { offsets } => {
+                self.commit(offsets);
+                reply.body.payload = Wire::Client(Payload::CommittedOk);
+                reply.send(output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node<(), Wire, InjectedPayload> for KafkaNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        runtime: Runtime<Wire, InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        runtime.every(RETRY_INTERVAL, || InjectedPayload::Retry);
+        Ok(Self {
+            shards: Shards::new(&init),
+            sessions: Sessions::new(&init),
+            node: init.node_id,
+            id: 0,
+            nodes: init.node_ids,
+            logs: HashMap::new(),
+            committed: HashMap::new(),
+            unacked: HashMap::new(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Wire, InjectedPayload>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Retry) => return self.retry(output),
+            _ => return Ok(()),
+        };
+        match self.sessions.admit(&input) {
+            Admission::New => {}
+            Admission::Answered(reply) => return reply.send(output),
+            Admission::Duplicate => return Ok(()),
+        }
+        let (client, request) = (input.src.clone(), input.body.id);
+        let result = self.handle(input, output);
+        if result.is_err() {
+            self.sessions.abandon(&client, request);
+        }
+        result
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, KafkaNode, _, _>(())
+}
@@ -0,0 +1,126 @@
+use rasengan::{
+    kv::{Kv, KvPayload, LIN_KV},
+    rpc::ServiceReply,
+    sharding::Shards,
+    value::JsonValue,
+    *,
+};
+use serde_json::Value;
+use std::time::Duration;
+
+/// How long a forwarded request's client is remembered for relaying the owner's reply
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often forwarded requests past their timeout are forgotten
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Client requests, the service's replies, and errors from either all share one protocol
+type Wire = ServiceReply<KvPayload<JsonValue, Value>>;
+
+/// The client a call to lin-kv is being made on behalf of
+type Origin = (NodeID, Option<MessageID>);
+
+/// Forgets forwarded requests whose owner never answered
+struct Expire;
+
+/// Each key's operations are funneled through the node owning it (by consistent hashing), which
+/// runs them against Maelstrom's linearizable lin-kv service and relays the outcome
+struct LinKvNode {
+    node: NodeID,
+    id: usize,
+    shards: Shards,
+    kv: Kv<Origin>,
+}
+
+impl Node<(), Wire, Expire> for LinKvNode {
+    fn from_init(_state: (), init: Init, runtime: Runtime<Wire, Expire>) -> anyhow::Result<Self> {
+        runtime.every(EXPIRE_INTERVAL, || Expire);
+        Ok(Self {
+            shards: Shards::new(&init),
+            kv: Kv::new(init.node_id.clone(), LIN_KV),
+            node: init.node_id,
+            id: 0,
+        })
+    }
+
+    fn step(&mut self, input: Event<Wire, Expire>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(Expire) => {
+                self.shards.expire(FORWARD_TIMEOUT);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if self.shards.relay(&input, output)? {
+            return Ok(());
+        }
+        if input.body.in_reply_to.is_some() {
+            let Some(((client, id), result)) = self.kv.complete(input.body) else {
+                return Ok(());
+            };
+            let reply = Message::new(self.node.clone(), client);
+            let reply = match id {
+                Some(id) => reply.in_reply_to(id),
+                None => reply,
+            };
+            return match result {
+                Ok(payload) => reply.payload(payload).send(output),
+                Err(Error { code, text }) => reply
+                    .payload(ErrorPayload::Error { code, text })
+                    .send(output),
+            };
+        }
+
+        let key = match &input.body.payload {
+            ServiceReply::Ok(
+                KvPayload::Read { key } | KvPayload::Write { key, .. } | KvPayload::Cas { key, .. },
+            ) => key.clone(),
+            _ => {
+                let reply = input.into_reply(Some(&mut self.id));
+                return reply
+                    .with_payload(ErrorPayload::Error {
+                        code: ErrorCode::NotSupported,
+                        text: "only read, write and cas requests are supported".to_string(),
+                    })
+                    .send(output);
+            }
+        };
+        let Some(input) = self.shards.route(&key, input, &mut self.id, output)? else {
+            return Ok(());
+        };
+        let origin = (input.src, input.body.id);
+        let wrap = ServiceReply::Ok;
+        match input.body.payload {
+            ServiceReply::Ok(KvPayload::Read { key }) => {
+                self.kv.read(key, origin, &mut self.id, output, wrap)?;
+            }
+            ServiceReply::Ok(KvPayload::Write { key, value }) => {
+                self.kv
+                    .write(key, value, origin, &mut self.id, output, wrap)?;
+            }
+            ServiceReply::Ok(KvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            }) => {
+                self.kv.cas(
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    origin,
+                    &mut self.id,
+                    output,
+                    wrap,
+                )?;
+            }
+            _ => unreachable!("only requests get routed"),
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, LinKvNode, _, _>(())
+}
@@ -0,0 +1,544 @@
+//! Drives a cluster of node binaries with synthetic client load, without Maelstrom
+//!
+//! ```text
+//! cargo build --release && target/release/loadgen broadcast --nodes 5 --rate 500 --time-limit 10
+//! ```
+//!
+//! Each node runs as a child process, and loadgen plays both the network, passing messages between
+//! the nodes as soon as they're sent, and the clients, sending requests at a fixed rate (picked
+//! at random by weight, which `--mix read=3,broadcast=1` overrides) and timing their replies.
+//! Once the time limit is up and the stragglers have had `--grace` seconds to come in, it reports
+//! throughput and latency percentiles for every kind of request.
+//!
+//! The node binary is looked for next to loadgen itself, unless given with `--bin`.
+use anyhow::{bail, Context};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+/// Produces a request body for an operation, given how many requests came before it
+type Body = fn(&mut StdRng, u64) -> Value;
+
+/// A kind of request along with its default weight in the mix
+struct Op {
+    name: &'static str,
+    weight: u32,
+    body: Body,
+}
+
+/// A workload along with the binary serving it and the requests it's driven with
+struct Workload {
+    name: &'static str,
+    bin: &'static str,
+    nodes: usize,
+    /// Requests sent to every node before the load starts
+    setup: fn(&[String]) -> Vec<Value>,
+    ops: &'static [Op],
+}
+
+fn no_setup(_nodes: &[String]) -> Vec<Value> {
+    Vec::new()
+}
+
+/// Every node neighbors every other
+fn total_topology(nodes: &[String]) -> Vec<Value> {
+    let topology: BTreeMap<_, Vec<_>> = nodes
+        .iter()
+        .map(|node| (node, nodes.iter().filter(|&peer| peer != node).collect()))
+        .collect();
+    vec![json!({"type": "topology", "topology": topology})]
+}
+
+const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "echo",
+        bin: "echo",
+        nodes: 1,
+        setup: no_setup,
+        ops: &[Op {
+            name: "echo",
+            weight: 1,
+            body: |_, n| json!({"type": "echo", "echo": format!("Please echo {n}")}),
+        }],
+    },
+    Workload {
+        name: "unique-ids",
+        bin: "unique-ids",
+        nodes: 3,
+        setup: no_setup,
+        ops: &[Op {
+            name: "generate",
+            weight: 1,
+            body: |_, _| json!({"type": "generate"}),
+        }],
+    },
+    Workload {
+        name: "broadcast",
+        bin: "broadcast",
+        nodes: 5,
+        setup: total_topology,
+        ops: &[
+            Op {
+                name: "broadcast",
+                weight: 1,
+                body: |_, n| json!({"type": "broadcast", "message": n}),
+            },
+            Op {
+                name: "read",
+                weight: 1,
+                body: |_, _| json!({"type": "read"}),
+            },
+        ],
+    },
+    Workload {
+        name: "g-counter",
+        bin: "counter",
+        nodes: 3,
+        setup: no_setup,
+        ops: &[
+            Op {
+                name: "add",
+                weight: 1,
+                body: |rng, _| json!({"type": "add", "delta": rng.gen_range(1..=10)}),
+            },
+            Op {
+                name: "read",
+                weight: 1,
+                body: |_, _| json!({"type": "read"}),
+            },
+        ],
+    },
+    Workload {
+        name: "kafka",
+        bin: "kafka",
+        nodes: 2,
+        setup: no_setup,
+        ops: &[
+            Op {
+                name: "send",
+                weight: 4,
+                body: |rng, n| json!({"type": "send", "key": rng.gen_range(0..8).to_string(), "msg": n}),
+            },
+            Op {
+                name: "poll",
+                weight: 2,
+                body: |rng, _| json!({"type": "poll", "offsets": {rng.gen_range(0..8).to_string(): 0}}),
+            },
+            Op {
+                name: "commit_offsets",
+                weight: 1,
+                body: |rng, _| json!({"type": "commit_offsets", "offsets": {rng.gen_range(0..8).to_string(): 0}}),
+            },
+            Op {
+                name: "list_committed_offsets",
+                weight: 1,
+                body: |rng, _| json!({"type": "list_committed_offsets", "keys": [rng.gen_range(0..8).to_string()]}),
+            },
+        ],
+    },
+];
+
+struct Args {
+    workload: &'static Workload,
+    bin: Option<PathBuf>,
+    nodes: Option<usize>,
+    rate: f64,
+    time_limit: f64,
+    grace: f64,
+    clients: usize,
+    seed: u64,
+    mix: Vec<(&'static str, u32)>,
+}
+
+fn usage() -> String {
+    let workloads: Vec<_> = WORKLOADS.iter().map(|w| w.name).collect();
+    format!(
+        "usage: loadgen <workload> [--bin <path>] [--nodes <n>] [--rate <per second>] \
+         [--time-limit <s>] [--grace <s>] [--clients <n>] [--seed <n>] [--mix <op>=<weight>,...]\n\
+         workloads: {}",
+        workloads.join(", ")
+    )
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut workload = None;
+    let mut bin = None;
+    let mut nodes = None;
+    let mut rate = 100.0;
+    let mut time_limit = 10.0;
+    let mut grace = 1.0;
+    let mut clients = 10;
+    let mut seed = 0;
+    let mut mix = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bin" => bin = Some(PathBuf::from(args.next().context("--bin requires a path")?)),
+            "--nodes" | "--node-count" => {
+                let value = args.next().context("--nodes requires a count")?;
+                nodes = Some(value.parse().context("--nodes must be an integer")?);
+            }
+            "--rate" => {
+                let value = args.next().context("--rate requires a value")?;
+                rate = value.parse().context("--rate must be a number")?;
+            }
+            "--time-limit" => {
+                let value = args.next().context("--time-limit requires seconds")?;
+                time_limit = value.parse().context("--time-limit must be a number")?;
+            }
+            "--grace" => {
+                let value = args.next().context("--grace requires seconds")?;
+                grace = value.parse().context("--grace must be a number")?;
+            }
+            "--clients" => {
+                let value = args.next().context("--clients requires a count")?;
+                clients = value.parse().context("--clients must be an integer")?;
+            }
+            "--seed" => {
+                let value = args.next().context("--seed requires a value")?;
+                seed = value.parse().context("--seed must be an integer")?;
+            }
+            "--mix" => mix = Some(args.next().context("--mix requires weights")?),
+            "-h" | "--help" => {
+                eprintln!("{}", usage());
+                std::process::exit(0);
+            }
+            name if workload.is_none() && !name.starts_with('-') => {
+                workload = Some(
+                    WORKLOADS
+                        .iter()
+                        .find(|w| w.name == name || w.bin == name)
+                        .with_context(|| format!("unknown workload {name}\n{}", usage()))?,
+                );
+            }
+            _ => bail!("unexpected argument {arg}\n{}", usage()),
+        }
+    }
+    let workload: &'static Workload = workload.with_context(usage)?;
+    if rate <= 0.0 {
+        bail!("--rate must be positive");
+    }
+    if clients == 0 {
+        bail!("--clients must be at least 1");
+    }
+    Ok(Args {
+        workload,
+        bin,
+        nodes,
+        rate,
+        time_limit,
+        grace,
+        clients,
+        seed,
+        mix: parse_mix(workload, mix.as_deref())?,
+    })
+}
+
+/// Reads `op=weight,...`, with ops left out keeping their default weight
+fn parse_mix(workload: &Workload, mix: Option<&str>) -> anyhow::Result<Vec<(&'static str, u32)>> {
+    let mut weights: Vec<_> = workload.ops.iter().map(|op| (op.name, op.weight)).collect();
+    for entry in mix.into_iter().flat_map(|mix| mix.split(',')) {
+        let (name, weight) = entry
+            .split_once('=')
+            .with_context(|| format!("--mix entry {entry} isn't <op>=<weight>"))?;
+        let slot = weights
+            .iter_mut()
+            .find(|(op, _)| *op == name)
+            .with_context(|| format!("{} has no {name} requests", workload.name))?;
+        slot.1 = weight
+            .parse()
+            .with_context(|| format!("weight for {name} must be an integer"))?;
+    }
+    if weights.iter().all(|&(_, weight)| weight == 0) {
+        bail!("--mix leaves nothing to send");
+    }
+    Ok(weights)
+}
+
+/// The node binary: the one asked for, or the workload's binary next to loadgen
+fn locate(args: &Args) -> anyhow::Result<PathBuf> {
+    if let Some(bin) = &args.bin {
+        return Ok(bin.clone());
+    }
+    let exe = std::env::current_exe().context("failed to find loadgen's own path")?;
+    let bin = exe.with_file_name(args.workload.bin);
+    if !bin.exists() {
+        bail!("{} doesn't exist; build it or pass --bin", bin.display());
+    }
+    Ok(bin)
+}
+
+/// Latencies of one kind of request
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Stats {
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let i = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[i]
+    }
+}
+
+/// A request waiting on its reply
+struct Pending {
+    op: &'static str,
+    sent: Instant,
+}
+
+struct Cluster {
+    ids: Vec<String>,
+    children: Vec<Child>,
+    stdins: Vec<Option<ChildStdin>>,
+    lines: mpsc::Receiver<Value>,
+}
+
+impl Cluster {
+    fn spawn(bin: &PathBuf, count: usize) -> anyhow::Result<Self> {
+        let ids: Vec<String> = (1..=count).map(|i| format!("n{i}")).collect();
+        let (tx, lines) = mpsc::channel();
+        let mut children = Vec::new();
+        let mut stdins = Vec::new();
+        for id in &ids {
+            let mut child = Command::new(bin)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .with_context(|| format!("failed to start {} as {id}", bin.display()))?;
+            stdins.push(child.stdin.take());
+            let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+            let tx = tx.clone();
+            let id = id.clone();
+            std::thread::spawn(move || {
+                for line in stdout.lines().map_while(Result::ok) {
+                    match serde_json::from_str(&line) {
+                        Ok(message) => {
+                            if tx.send(message).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => {
+                            eprintln!("loadgen: {id} wrote something other than JSON: {line}")
+                        }
+                    }
+                }
+            });
+            children.push(child);
+        }
+        Ok(Self {
+            ids,
+            children,
+            stdins,
+            lines,
+        })
+    }
+
+    fn send(&mut self, message: &Value) -> anyhow::Result<()> {
+        let dst = message["dest"].as_str().unwrap_or_default();
+        let i = self
+            .ids
+            .iter()
+            .position(|id| id == dst)
+            .with_context(|| format!("no node {dst}"))?;
+        let Some(stdin) = &mut self.stdins[i] else {
+            return Ok(());
+        };
+        writeln!(stdin, "{message}").with_context(|| format!("{dst} stopped reading input"))
+    }
+
+    /// Passes node-to-node messages along until a message for a client shows up
+    fn next_reply(&mut self, until: Instant) -> anyhow::Result<Option<Value>> {
+        loop {
+            let timeout = until.saturating_duration_since(Instant::now());
+            let message = match self.lines.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => bail!("every node exited"),
+            };
+            let dst = message["dest"].as_str().unwrap_or_default();
+            if self.ids.iter().any(|id| id == dst) {
+                self.send(&message)?;
+            } else {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    /// Closes every node's input and waits for them to exit
+    fn shutdown(mut self) {
+        self.stdins.clear();
+        for child in &mut self.children {
+            let _ = child.wait();
+        }
+    }
+}
+
+fn request(client: &str, node: &str, id: u64, mut body: Value) -> Value {
+    body["msg_id"] = id.into();
+    json!({"src": client, "dest": node, "body": body})
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    let workload = args.workload;
+    let bin = locate(&args)?;
+    let mut cluster = Cluster::spawn(&bin, args.nodes.unwrap_or(workload.nodes))?;
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut next_id = 0;
+
+    // Initialize every node, then run the workload's setup, waiting for each step to be answered
+    let ids = cluster.ids.clone();
+    let inits: Vec<_> = ids
+        .iter()
+        .map(|node| json!({"type": "init", "node_id": node, "node_ids": ids}))
+        .collect();
+    for bodies in [inits, (workload.setup)(&ids)] {
+        let mut waiting = 0;
+        for (i, node) in ids.iter().enumerate() {
+            let body = match bodies.len() {
+                0 => continue,
+                // Either one body per node or the same one for all
+                n if n == ids.len() => bodies[i].clone(),
+                _ => bodies[0].clone(),
+            };
+            next_id += 1;
+            cluster.send(&request("c0", node, next_id, body))?;
+            waiting += 1;
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while waiting > 0 {
+            match cluster.next_reply(deadline)? {
+                Some(_) => waiting -= 1,
+                None => bail!("nodes didn't finish setting up within 10 seconds"),
+            }
+        }
+    }
+
+    let total: u32 = args.mix.iter().map(|&(_, weight)| weight).sum();
+    let interval = Duration::from_secs_f64(1.0 / args.rate);
+    let mut stats: BTreeMap<&'static str, Stats> = BTreeMap::new();
+    let mut pending: HashMap<(String, u64), Pending> = HashMap::new();
+    let started = Instant::now();
+    let stop = started + Duration::from_secs_f64(args.time_limit);
+    let mut next_send = started;
+    let mut count = 0;
+
+    eprintln!(
+        "loadgen: {} nodes of {} at {} requests/s for {}s",
+        ids.len(),
+        bin.display(),
+        args.rate,
+        args.time_limit
+    );
+    let record = |reply: Value,
+                  pending: &mut HashMap<(String, u64), Pending>,
+                  stats: &mut BTreeMap<&'static str, Stats>| {
+        let client = reply["dest"].as_str().unwrap_or_default().to_string();
+        let Some(id) = reply["body"]["in_reply_to"].as_u64() else {
+            return;
+        };
+        let Some(request) = pending.remove(&(client, id)) else {
+            return;
+        };
+        let stats = stats.entry(request.op).or_default();
+        if reply["body"]["type"] == "error" {
+            stats.errors += 1;
+        } else {
+            stats.latencies.push(request.sent.elapsed());
+        }
+    };
+    while Instant::now() < stop {
+        while next_send <= Instant::now() && next_send < stop {
+            let mut roll = rng.gen_range(0..total);
+            let (op, _) = *args
+                .mix
+                .iter()
+                .find(|&&(_, weight)| {
+                    let hit = roll < weight;
+                    roll = roll.saturating_sub(weight);
+                    hit
+                })
+                .expect("the roll is below the total weight");
+            let body = (workload
+                .ops
+                .iter()
+                .find(|o| o.name == op)
+                .expect("mixes only name the workload's ops")
+                .body)(&mut rng, count);
+            let client = format!("c{}", 1 + count as usize % args.clients);
+            let node = &ids[rng.gen_range(0..ids.len())];
+            next_id += 1;
+            cluster.send(&request(&client, node, next_id, body))?;
+            pending.insert(
+                (client, next_id),
+                Pending {
+                    op,
+                    sent: Instant::now(),
+                },
+            );
+            stats.entry(op).or_default().sent += 1;
+            count += 1;
+            next_send += interval;
+        }
+        if let Some(reply) = cluster.next_reply(next_send.min(stop))? {
+            record(reply, &mut pending, &mut stats);
+        }
+    }
+    let elapsed = started.elapsed();
+    let grace = Instant::now() + Duration::from_secs_f64(args.grace);
+    while !pending.is_empty() {
+        match cluster.next_reply(grace)? {
+            Some(reply) => record(reply, &mut pending, &mut stats),
+            None => break,
+        }
+    }
+    cluster.shutdown();
+
+    let mut all = Stats::default();
+    for stats in stats.values_mut() {
+        stats.latencies.sort();
+        all.sent += stats.sent;
+        all.errors += stats.errors;
+        all.latencies.extend(&stats.latencies);
+    }
+    all.latencies.sort();
+    println!(
+        "{:<24} {:>8} {:>8} {:>7} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "op", "sent", "ok", "errors", "lost", "ok/s", "p50", "p90", "p99"
+    );
+    for (op, stats) in &stats {
+        print_row(op, stats, elapsed);
+    }
+    print_row("all", &all, elapsed);
+    Ok(())
+}
+
+fn print_row(op: &str, stats: &Stats, elapsed: Duration) {
+    let ok = stats.latencies.len() as u64;
+    println!(
+        "{:<24} {:>8} {:>8} {:>7} {:>8} {:>10.1} {:>10.2?} {:>10.2?} {:>10.2?}",
+        op,
+        stats.sent,
+        ok,
+        stats.errors,
+        stats.sent - ok - stats.errors,
+        ok as f64 / elapsed.as_secs_f64(),
+        stats.percentile(0.5),
+        stats.percentile(0.9),
+        stats.percentile(0.99),
+    );
+}
@@ -0,0 +1,40 @@
+//! Runs a node for any workload in the library's registry
+//!
+//! ```text
+//! rasengan --workload broadcast [<workload args>...]
+//! ```
+//!
+//! Arguments other than `--workload` are passed on to the workload.
+use anyhow::{bail, Context};
+use rasengan::workloads::{self, REGISTRY};
+
+fn usage() -> String {
+    let names: Vec<_> = REGISTRY.iter().map(|workload| workload.name).collect();
+    format!(
+        "usage: rasengan --workload <name> [<workload args>...]\nworkloads: {}",
+        names.join(", ")
+    )
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut name = None;
+    let mut rest = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--workload") {
+            Some("") => name = Some(args.next().context("--workload requires a name")?),
+            Some(value) if value.starts_with('=') => name = Some(value[1..].to_string()),
+            _ if arg == "-h" || arg == "--help" => {
+                eprintln!("{}", usage());
+                return Ok(());
+            }
+            _ => rest.push(arg),
+        }
+    }
+    let Some(name) = name else {
+        bail!("{}", usage());
+    };
+    let workload =
+        workloads::find(&name).with_context(|| format!("unknown workload {name}\n{}", usage()))?;
+    (workload.run)(&rest)
+}
@@ -0,0 +1,171 @@
+//! Replays the messages a node received during a Maelstrom run against a node binary
+//!
+//! Run the test with `RASENGAN_LOG_INPUT=1` so every node records its input on stderr (which
+//! Maelstrom keeps under `store/<test>/<run>/node-logs`), then point this tool at those logs:
+//!
+//! ```text
+//! cargo run --bin replay -- --node n1 --bin broadcast store/latest/node-logs/*.log
+//! ```
+//!
+//! The messages addressed to the node are fed to a fresh instance of the binary in timestamp
+//! order, with the RNG seed from the original run when the log has it. The node's outputs are
+//! printed as they appear, followed by its final state if it implements `Node::snapshot`.
+use anyhow::{bail, Context};
+use rasengan::{Message, INPUT_RECORD_PREFIX};
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+const SEED_MARKER: &str = "running with seed ";
+
+/// A recorded input line along with when it was received, in microseconds since the epoch
+type Record = (u128, String);
+
+struct Args {
+    node: String,
+    bin: PathBuf,
+    seed: Option<u64>,
+    logs: Vec<PathBuf>,
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut node = None;
+    let mut bin = None;
+    let mut seed = None;
+    let mut logs = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--node" => node = Some(args.next().context("--node requires a node ID")?),
+            "--bin" => bin = Some(args.next().context("--bin requires a binary")?),
+            "--seed" => {
+                let value = args.next().context("--seed requires a value")?;
+                seed = Some(value.parse().context("--seed must be an integer")?);
+            }
+            "-h" | "--help" => {
+                eprintln!("usage: replay --node <id> --bin <name or path> [--seed <n>] <log>...");
+                std::process::exit(0);
+            }
+            _ => logs.push(PathBuf::from(arg)),
+        }
+    }
+    let node = node.context("--node is required")?;
+    let bin = bin.context("--bin is required")?;
+    // A bare name refers to a sibling binary from the same cargo build
+    let bin = if bin.contains(std::path::MAIN_SEPARATOR) {
+        PathBuf::from(bin)
+    } else {
+        std::env::current_exe()?
+            .parent()
+            .context("replay binary has no parent directory")?
+            .join(bin)
+    };
+    if logs.is_empty() {
+        bail!("no log files given");
+    }
+    Ok(Args {
+        node,
+        bin,
+        seed,
+        logs,
+    })
+}
+
+/// Collects the recorded input addressed to `node` across all logs, along with the seed its run
+/// used if one was logged
+fn collect(args: &Args) -> anyhow::Result<(Vec<Record>, Option<u64>)> {
+    let mut records = Vec::new();
+    let mut seed = args.seed;
+    for path in &args.logs {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut file_seed = None;
+        let mut relevant = false;
+        for line in contents.lines() {
+            if let Some(rest) = line.split_once(SEED_MARKER).map(|(_, rest)| rest) {
+                file_seed = rest.split_whitespace().next().and_then(|s| s.parse().ok());
+                continue;
+            }
+            let Some(record) = line.strip_prefix(INPUT_RECORD_PREFIX) else {
+                continue;
+            };
+            let (ts, message) = record
+                .split_once(' ')
+                .with_context(|| format!("malformed input record in {}", path.display()))?;
+            let ts: u128 = ts.parse().context("malformed input record timestamp")?;
+            let parsed: Message<serde_json::Value> = serde_json::from_str(message)
+                .with_context(|| format!("recorded input in {} isn't a message", path.display()))?;
+            if parsed.dst == args.node {
+                relevant = true;
+                records.push((ts, message.to_string()));
+            }
+        }
+        if relevant && seed.is_none() {
+            seed = file_seed;
+        }
+    }
+    // Stable, so records sharing a timestamp keep their order within a log
+    records.sort_by_key(|(ts, _)| *ts);
+    Ok((records, seed))
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    let (records, seed) = collect(&args)?;
+    if records.is_empty() {
+        bail!("no recorded input for {} found", args.node);
+    }
+    eprintln!(
+        "replay: feeding {} messages to {} ({})",
+        records.len(),
+        args.node,
+        seed.map_or("seed unknown".to_string(), |seed| format!("seed {seed}")),
+    );
+
+    let snapshot_dir = std::env::temp_dir().join(format!("rasengan-replay-{}", std::process::id()));
+    let mut command = Command::new(&args.bin);
+    command
+        .env_remove("RASENGAN_LOG_INPUT")
+        .env("RASENGAN_SNAPSHOT_DIR", &snapshot_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if let Some(seed) = seed {
+        command.env("RASENGAN_SEED", seed.to_string());
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("failed to start {}", args.bin.display()))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let feeder = std::thread::spawn(move || -> anyhow::Result<()> {
+        for (_, message) in records {
+            writeln!(stdin, "{message}")?;
+        }
+        // Dropping stdin lets the node shut down once it's worked through everything
+        Ok(())
+    });
+
+    let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    for line in stdout.lines() {
+        println!("{}", line.context("failed to read node output")?);
+    }
+    feeder.join().expect("feeder thread panicked")?;
+    let status = child.wait()?;
+    if !status.success() {
+        eprintln!("replay: node exited with {status}");
+    }
+
+    let snapshot = snapshot_dir.join(format!("{}.snapshot.json", args.node));
+    match std::fs::read(&snapshot) {
+        Ok(state) => {
+            let state: serde_json::Value = serde_json::from_slice(&state)?;
+            println!("final state:\n{}", serde_json::to_string_pretty(&state)?);
+        }
+        Err(_) => println!("final state: unavailable (the node doesn't implement Node::snapshot)"),
+    }
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+    Ok(())
+}
@@ -0,0 +1,276 @@
+//! Builds a node binary and runs it through `maelstrom test` with the usual flags for its workload
+//!
+//! ```text
+//! cargo run --bin runner -- broadcast --nodes 25 --nemesis partition
+//! ```
+//!
+//! Maelstrom's jar is looked for at `--maelstrom`, `$MAELSTROM_JAR`, and the `maelstrom` link in
+//! the repository, in that order. If none of them exist, the release is downloaded into the
+//! target directory (needs `curl`, `tar`, and `java` on the path). Anything after `--` is passed
+//! through to `maelstrom test`.
+use anyhow::{bail, Context};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const MAELSTROM_VERSION: &str = "0.2.3";
+
+/// A workload along with the binary serving it and the flags it's usually run with
+struct Workload {
+    name: &'static str,
+    bin: &'static str,
+    nodes: usize,
+    time_limit: u64,
+    rate: u64,
+    extra: &'static [&'static str],
+}
+
+const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "echo",
+        bin: "echo",
+        nodes: 1,
+        time_limit: 10,
+        rate: 5,
+        extra: &[],
+    },
+    Workload {
+        name: "unique-ids",
+        bin: "unique-ids",
+        nodes: 3,
+        time_limit: 30,
+        rate: 1000,
+        extra: &["--availability", "total"],
+    },
+    Workload {
+        name: "broadcast",
+        bin: "broadcast",
+        nodes: 5,
+        time_limit: 20,
+        rate: 10,
+        extra: &[],
+    },
+    Workload {
+        name: "g-counter",
+        bin: "counter",
+        nodes: 3,
+        time_limit: 20,
+        rate: 100,
+        extra: &[],
+    },
+    Workload {
+        name: "kafka",
+        bin: "kafka",
+        nodes: 2,
+        time_limit: 20,
+        rate: 1000,
+        extra: &["--concurrency", "2n"],
+    },
+    Workload {
+        name: "txn-list-append",
+        bin: "txn-list-append",
+        nodes: 2,
+        time_limit: 20,
+        rate: 100,
+        extra: &["--consistency-models", "serializable"],
+    },
+    Workload {
+        name: "lin-kv",
+        bin: "lin-kv",
+        nodes: 3,
+        time_limit: 20,
+        rate: 100,
+        extra: &["--concurrency", "2n"],
+    },
+];
+
+struct Args {
+    workload: &'static Workload,
+    nodes: Option<usize>,
+    time_limit: Option<u64>,
+    rate: Option<u64>,
+    nemesis: Option<String>,
+    release: bool,
+    maelstrom: Option<PathBuf>,
+    passthrough: Vec<String>,
+}
+
+fn usage() -> String {
+    let workloads: Vec<_> = WORKLOADS.iter().map(|w| w.name).collect();
+    format!(
+        "usage: runner <workload> [--nodes <n>] [--time-limit <s>] [--rate <n>] \
+         [--nemesis <kinds>] [--release] [--maelstrom <jar>] [-- <maelstrom args>...]\n\
+         workloads: {}",
+        workloads.join(", ")
+    )
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut workload = None;
+    let mut nodes = None;
+    let mut time_limit = None;
+    let mut rate = None;
+    let mut nemesis = None;
+    let mut release = false;
+    let mut maelstrom = None;
+    let mut passthrough = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--nodes" | "--node-count" => {
+                let value = args.next().context("--nodes requires a count")?;
+                nodes = Some(value.parse().context("--nodes must be an integer")?);
+            }
+            "--time-limit" => {
+                let value = args.next().context("--time-limit requires seconds")?;
+                time_limit = Some(value.parse().context("--time-limit must be an integer")?);
+            }
+            "--rate" => {
+                let value = args.next().context("--rate requires a value")?;
+                rate = Some(value.parse().context("--rate must be an integer")?);
+            }
+            "--nemesis" => nemesis = Some(args.next().context("--nemesis requires a value")?),
+            "--release" => release = true,
+            "--maelstrom" => {
+                maelstrom = Some(PathBuf::from(
+                    args.next().context("--maelstrom requires a path")?,
+                ))
+            }
+            "--" => passthrough.extend(args.by_ref()),
+            "-h" | "--help" => {
+                eprintln!("{}", usage());
+                std::process::exit(0);
+            }
+            name if workload.is_none() && !name.starts_with('-') => {
+                workload = Some(
+                    WORKLOADS
+                        .iter()
+                        .find(|w| w.name == name || w.bin == name)
+                        .with_context(|| format!("unknown workload {name}\n{}", usage()))?,
+                );
+            }
+            _ => bail!("unexpected argument {arg}\n{}", usage()),
+        }
+    }
+    Ok(Args {
+        workload: workload.with_context(usage)?,
+        nodes,
+        time_limit,
+        rate,
+        nemesis,
+        release,
+        maelstrom,
+        passthrough,
+    })
+}
+
+fn target_dir() -> PathBuf {
+    std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("target"))
+}
+
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run {:?}", command.get_program()))?;
+    if !status.success() {
+        bail!("{:?} exited with {status}", command.get_program());
+    }
+    Ok(())
+}
+
+/// Builds the workload's binary, returning its path
+fn build(bin: &str, release: bool) -> anyhow::Result<PathBuf> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["build", "--bin", bin]);
+    if release {
+        command.arg("--release");
+    }
+    run(&mut command)?;
+    let profile = if release { "release" } else { "debug" };
+    Ok(target_dir().join(profile).join(bin))
+}
+
+/// Finds Maelstrom's jar, downloading the release if there isn't one around
+fn locate_maelstrom(explicit: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    if let Some(jar) = explicit {
+        if !jar.exists() {
+            bail!("{} doesn't exist", jar.display());
+        }
+        return Ok(jar);
+    }
+    let candidates = [
+        std::env::var_os("MAELSTROM_JAR").map(PathBuf::from),
+        Some(Path::new(env!("CARGO_MANIFEST_DIR")).join("maelstrom")),
+    ];
+    // `exists` follows links, so a dangling link to someone else's checkout is skipped
+    if let Some(jar) = candidates.into_iter().flatten().find(|jar| jar.exists()) {
+        return Ok(jar);
+    }
+
+    let dir = target_dir().join(format!("maelstrom-{MAELSTROM_VERSION}"));
+    let jar = dir.join("maelstrom").join("lib").join("maelstrom.jar");
+    if jar.exists() {
+        return Ok(jar);
+    }
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let archive = dir.join("maelstrom.tar.bz2");
+    let url = format!(
+        "https://github.com/jepsen-io/maelstrom/releases/download/v{MAELSTROM_VERSION}/maelstrom.tar.bz2"
+    );
+    eprintln!("runner: downloading {url}");
+    run(Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(&archive)
+        .arg(&url))?;
+    run(Command::new("tar")
+        .arg("-xjf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&dir))?;
+    let _ = std::fs::remove_file(&archive);
+    if !jar.exists() {
+        bail!("the Maelstrom release didn't contain {}", jar.display());
+    }
+    Ok(jar)
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    let workload = args.workload;
+    let bin = build(workload.bin, args.release)?;
+    let jar = locate_maelstrom(args.maelstrom)?;
+
+    let mut command = Command::new("java");
+    command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .arg("-Djava.awt.headless=true")
+        .arg("-jar")
+        .arg(&jar)
+        .args(["test", "-w", workload.name, "--bin"])
+        .arg(&bin)
+        .arg("--node-count")
+        .arg(args.nodes.unwrap_or(workload.nodes).to_string())
+        .arg("--time-limit")
+        .arg(args.time_limit.unwrap_or(workload.time_limit).to_string())
+        .arg("--rate")
+        .arg(args.rate.unwrap_or(workload.rate).to_string())
+        .args(workload.extra);
+    if let Some(nemesis) = &args.nemesis {
+        command.args(["--nemesis", nemesis]);
+    }
+    command.args(&args.passthrough);
+    eprintln!("runner: {command:?}");
+    run(&mut command)
+}
@@ -0,0 +1,222 @@
+use rasengan::{
+    kv::{Kv, KvPayload, UpdateError, LIN_KV},
+    rpc::ServiceReply,
+    *,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+/// The lin-kv key holding the whole database
+const ROOT: &str = "root";
+
+/// How many times a transaction reads and compare-and-sets before failing as conflicted
+const MAX_ATTEMPTS: u32 = 20;
+
+/// How long a transaction may take before failing as timed out
+const TXN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often timed out transactions are looked for
+const TICK_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    #[serde(rename = "r")]
+    Read,
+    Append,
+}
+
+/// `[op, key, value]`, where reads carry `null` until they're answered
+type MicroOp = (Op, u64, Value);
+
+type Db = BTreeMap<u64, Vec<Value>>;
+
+#[workload]
+#[derive(Debug, Clone)]
+enum Payload {
+    #[reply { txn: Vec<MicroOp> }]
+    Txn { txn: Vec<MicroOp> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Wire {
+    Client(Payload),
+    Kv(ServiceReply<KvPayload<String, Db>>),
+}
+
+/// Times out transactions stuck waiting on lin-kv
+struct Tick;
+
+/// A transaction being run against lin-kv
+#[derive(Debug)]
+struct Pending {
+    request: Message<()>,
+    txn: Vec<MicroOp>,
+}
+
+/// Runs every transaction as an [update](Kv::update) of the whole database: a read followed by a
+/// compare-and-set of the updated copy, retried from the read whenever another transaction got
+/// there first, up to a limit
+///
+/// Each transaction commits atomically or not at all, which is serializable.
+struct TxnNode {
+    id: usize,
+    next_txn: u64,
+    kv: Kv<u64>,
+    pending: HashMap<u64, Pending>,
+}
+
+impl TxnNode {
+    fn reply<P: Serialize>(
+        &mut self,
+        handle: u64,
+        output: &mut Output,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.remove(&handle) else {
+            return Ok(());
+        };
+        pending
+            .request
+            .into_reply(Some(&mut self.id))
+            .with_payload(payload)
+            .send(output)
+    }
+
+    /// Passes a failed transaction's error on, so the client knows whether it may have committed
+    fn fail(&mut self, handle: u64, output: &mut Output, error: UpdateError) -> anyhow::Result<()> {
+        let Error { code, text } = match error {
+            // None of the attempts took effect
+            UpdateError::Contended { .. } => Error::new(ErrorCode::TxnConflict, error.to_string()),
+            error => error.into(),
+        };
+        self.reply(handle, output, ErrorPayload::Error { code, text })
+    }
+}
+
+fn kv_request(payload: KvPayload<String, Db>) -> Wire {
+    Wire::Kv(ServiceReply::Ok(payload))
+}
+
+/// Runs `txn` against a copy of `db`, returning the completed micro-ops and the updated copy
+fn apply(db: &Db, txn: &[MicroOp]) -> (Vec<MicroOp>, Db) {
+    let mut db = db.clone();
+    let results = txn
+        .iter()
+        .map(|(op, key, value)| match op {
+            Op::Read => {
+                let list = db
+                    .get(key)
+                    .map_or(Value::Null, |list| Value::from(list.clone()));
+                (*op, *key, list)
+            }
+            Op::Append => {
+                db.entry(*key).or_default().push(value.clone());
+                (*op, *key, value.clone())
+            }
+        })
+        .collect();
+    (results, db)
+}
+
+/// Works out what `txn` read from the database it committed, by taking its appends back off
+fn committed(written: &Db, txn: &[MicroOp]) -> Vec<MicroOp> {
+    let mut before = written.clone();
+    for (op, key, _) in txn.iter().rev() {
+        if *op == Op::Append {
+            let list = before.get_mut(key).expect("appended lists exist");
+            list.pop();
+            // Lists only come into being with an append
+            if list.is_empty() {
+                before.remove(key);
+            }
+        }
+    }
+    apply(&before, txn).0
+}
+
+impl Node<(), Wire, Tick> for TxnNode {
+    fn from_init(_state: (), init: Init, runtime: Runtime<Wire, Tick>) -> anyhow::Result<Self> {
+        runtime.every(TICK_INTERVAL, || Tick);
+        Ok(Self {
+            kv: Kv::new(init.node_id, LIN_KV)
+                .max_attempts(MAX_ATTEMPTS)
+                .timeout(TXN_TIMEOUT),
+            id: 0,
+            next_txn: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    fn step(&mut self, input: Event<Wire, Tick>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(Tick) => {
+                for (handle, error) in self.kv.tick(&mut self.id, output, kv_request)? {
+                    self.fail(handle, output, error)?;
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        let (input, payload) = input.split_payload();
+        match payload {
+            Wire::Client(payload) => {
+                let Ok(PayloadRequest::Txn { txn }) = payload.into_request() else {
+                    return Ok(());
+                };
+                let handle = self.next_txn;
+                self.next_txn += 1;
+                let ops = txn.clone();
+                self.kv.update(
+                    ROOT.to_string(),
+                    move |db: Option<Db>| apply(&db.unwrap_or_default(), &ops).1,
+                    handle,
+                    &mut self.id,
+                    output,
+                    kv_request,
+                )?;
+                self.pending.insert(
+                    handle,
+                    Pending {
+                        request: input,
+                        txn,
+                    },
+                );
+            }
+            Wire::Kv(reply) => {
+                let body = Body {
+                    id: input.body.id,
+                    in_reply_to: input.body.in_reply_to,
+                    payload: reply,
+                };
+                let Some((handle, result)) =
+                    self.kv.progress(body, &mut self.id, output, kv_request)?
+                else {
+                    return Ok(());
+                };
+                match result {
+                    Ok(written) => {
+                        let Some(pending) = self.pending.get(&handle) else {
+                            return Ok(());
+                        };
+                        let txn = committed(&written, &pending.txn);
+                        self.reply(handle, output, Wire::Client(Payload::TxnOk { txn }))?;
+                    }
+                    Err(error) => self.fail(handle, output, error)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, TxnNode, _, _>(())
+}
@@ -1,54 +1,4 @@
-use rasengan::*;
-
-use serde::{Deserialize, Serialize};
-use std::io::StdoutLock;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Generate,
-    GenerateOk { id: String },
-}
-
-struct UniqueIDNode {
-    id: usize,
-    node: String,
-    counter: usize,
-}
-
-impl Node<(), Payload> for UniqueIDNode {
-    fn from_init(
-        _state: (),
-        init: Init,
-        _tx: std::sync::mpsc::Sender<Event<Payload>>,
-    ) -> anyhow::Result<Self> {
-        Ok(Self {
-            id: 1,
-            node: init.node_id,
-            counter: 1,
-        })
-    }
-
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
-        };
-
-        let mut reply = input.into_reply(Some(&mut self.id));
-        match reply.body.payload {
-            Payload::Generate => {
-                let id = format!("{}-{}", self.node, self.counter);
-                self.counter += 1;
-                reply.body.payload = Payload::GenerateOk { id };
-                reply.send(output)?;
-            }
-            Payload::GenerateOk { .. } => {}
-        }
-        Ok(())
-    }
-}
-
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, UniqueIDNode, _, _>(())
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    rasengan::workloads::unique_ids::run(&args)
 }
@@ -0,0 +1,212 @@
+//! The replication core shared by nodes that spread a growing set of values to every node
+//!
+//! A [`BroadcastCore`] owns the values a node has seen and the neighbors it passes them on to,
+//! which start out as a [default tree](crate::Init::default_neighbors) and are replaced by
+//! Maelstrom's topology or whatever overlay the node builds. How values actually travel is up to
+//! its [`Disseminate`] strategy, [`Gossip`] by default, so the broadcast workload, g-sets, and
+//! counters built from sets of increments can all embed the same core:
+//!
+//! ```ignore
+//! Event::Injected(InjectedPayload::Gossip) => self.core.tick(output, Wire::Gossip)?,
+//! ...
+//! Wire::Gossip(gossip) => {
+//!     let new = self.core.handle(&reply.dst, gossip, output, Wire::Gossip)?;
+//! }
+//! ```
+use crate::{
+    gossip::{Gossip, GossipPayload, GossipSet},
+    Init, NodeID,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    marker::PhantomData,
+};
+
+/// A way of getting every value in a set to every node
+pub trait Disseminate<S: GossipSet> {
+    /// What the strategy sends between nodes
+    type Payload;
+
+    /// Adds a value to be disseminated, returning whether it was new
+    fn insert(&mut self, value: S::Value) -> bool;
+
+    /// Adds every value in `values` to be disseminated, returning the ones that were new
+    fn merge(&mut self, values: &S) -> S;
+
+    /// Every value seen so far
+    fn values(&self) -> &S;
+
+    /// Runs a round with `neighbors`; meant to be driven by a periodic injected event
+    fn tick<P>(
+        &mut self,
+        neighbors: &[NodeID],
+        output: &mut impl Write,
+        wrap: impl Fn(Self::Payload) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize;
+
+    /// Processes a payload from `src`, returning the values that were new to this node
+    fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: Self::Payload,
+        output: &mut impl Write,
+        wrap: impl Fn(Self::Payload) -> P,
+    ) -> anyhow::Result<S>
+    where
+        P: Serialize;
+}
+
+impl<S> Disseminate<S> for Gossip<S>
+where
+    S: GossipSet + Serialize,
+{
+    type Payload = GossipPayload<S>;
+
+    fn insert(&mut self, value: S::Value) -> bool {
+        Gossip::insert(self, value)
+    }
+
+    fn merge(&mut self, values: &S) -> S {
+        Gossip::merge(self, values)
+    }
+
+    fn values(&self) -> &S {
+        Gossip::values(self)
+    }
+
+    fn tick<P>(
+        &mut self,
+        neighbors: &[NodeID],
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        Gossip::tick(self, neighbors, output, wrap)
+    }
+
+    fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: GossipPayload<S>,
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<S>
+    where
+        P: Serialize,
+    {
+        Gossip::handle(self, src, payload, output, wrap)
+    }
+}
+
+#[derive(Clone)]
+pub struct BroadcastCore<S, D = Gossip<S>> {
+    node: NodeID,
+    /// Every node in the cluster, this one included
+    nodes: HashSet<NodeID>,
+    neighbors: Vec<NodeID>,
+    strategy: D,
+    _values: PhantomData<S>,
+}
+
+impl<S, D> BroadcastCore<S, D>
+where
+    S: GossipSet,
+    D: Disseminate<S>,
+{
+    /// Starts out on the default tree with up to `branching` children per node
+    pub fn new(init: &Init, strategy: D, branching: usize) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            nodes: init.node_ids.iter().cloned().collect(),
+            neighbors: init.default_neighbors(branching),
+            strategy,
+            _values: PhantomData,
+        }
+    }
+
+    pub fn strategy(&self) -> &D {
+        &self.strategy
+    }
+
+    pub fn neighbors(&self) -> &[NodeID] {
+        &self.neighbors
+    }
+
+    pub fn set_neighbors(&mut self, neighbors: Vec<NodeID>) {
+        self.neighbors = neighbors;
+    }
+
+    pub fn add_neighbor(&mut self, neighbor: NodeID) {
+        if !self.neighbors.contains(&neighbor) {
+            self.neighbors.push(neighbor);
+        }
+    }
+
+    pub fn remove_neighbor(&mut self, neighbor: &NodeID) {
+        self.neighbors.retain(|id| id != neighbor);
+    }
+
+    /// Takes this node's neighbors from a topology, keeping the current ones if it leaves this
+    /// node out
+    ///
+    /// The latest topology wins: its neighbors replace whatever came before, default tree and
+    /// earlier topologies alike. IDs that aren't other nodes in the cluster are left out, as are
+    /// repeats.
+    pub fn apply_topology(&mut self, mut topology: HashMap<NodeID, Vec<NodeID>>) {
+        let Some(neighbors) = topology.remove(&self.node) else {
+            return;
+        };
+        let mut seen = HashSet::new();
+        self.neighbors = neighbors
+            .into_iter()
+            .filter(|id| *id != self.node && self.nodes.contains(id) && seen.insert(id.clone()))
+            .collect();
+    }
+
+    /// Adds a value to be disseminated, returning whether it was new
+    pub fn insert(&mut self, value: S::Value) -> bool {
+        self.strategy.insert(value)
+    }
+
+    /// Adds every value in `values`, returning the ones that were new
+    pub fn merge(&mut self, values: &S) -> S {
+        self.strategy.merge(values)
+    }
+
+    /// Every value seen so far
+    pub fn values(&self) -> &S {
+        self.strategy.values()
+    }
+
+    /// Runs a dissemination round with the current neighbors
+    pub fn tick<P>(
+        &mut self,
+        output: &mut impl Write,
+        wrap: impl Fn(D::Payload) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        self.strategy.tick(&self.neighbors, output, wrap)
+    }
+
+    /// Processes a payload from `src`, returning the values that were new to this node
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: D::Payload,
+        output: &mut impl Write,
+        wrap: impl Fn(D::Payload) -> P,
+    ) -> anyhow::Result<S>
+    where
+        P: Serialize,
+    {
+        self.strategy.handle(src, payload, output, wrap)
+    }
+}
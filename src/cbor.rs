@@ -0,0 +1,209 @@
+//! A minimal CBOR (RFC 8949) codec for JSON values
+//!
+//! Covers exactly what JSON can express: integers, floats, strings, arrays, maps with string
+//! keys, booleans, and null, each in its shortest encoding. Byte strings, tags, and
+//! indefinite-length items are rejected on decode, since nothing here produces them.
+use anyhow::{bail, Context};
+use serde_json::{Map, Number, Value};
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const F32: u8 = 0xfa;
+const F64: u8 = 0xfb;
+
+/// Deeper nesting than this is refused rather than risking the stack
+const MAX_DEPTH: usize = 256;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = Vec::new();
+    encode_into(value, &mut output);
+    output
+}
+
+fn encode_into(value: &Value, output: &mut Vec<u8>) {
+    match value {
+        Value::Null => output.push(NULL),
+        Value::Bool(false) => output.push(FALSE),
+        Value::Bool(true) => output.push(TRUE),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                head(UNSIGNED, n, output);
+            } else if let Some(n) = n.as_i64() {
+                // Negative integers are stored as -1 - n
+                head(NEGATIVE, !n as u64, output);
+            } else {
+                output.push(F64);
+                output.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            head(TEXT, s.len() as u64, output);
+            output.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            head(ARRAY, items.len() as u64, output);
+            for item in items {
+                encode_into(item, output);
+            }
+        }
+        Value::Object(map) => {
+            head(MAP, map.len() as u64, output);
+            for (key, value) in map {
+                head(TEXT, key.len() as u64, output);
+                output.extend_from_slice(key.as_bytes());
+                encode_into(value, output);
+            }
+        }
+    }
+}
+
+/// An item's major type along with its argument, in as few bytes as fit it
+fn head(major: u8, n: u64, output: &mut Vec<u8>) {
+    let major = major << 5;
+    match n {
+        0..=23 => output.push(major | n as u8),
+        24..=0xff => output.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            output.push(major | 25);
+            output.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            output.push(major | 26);
+            output.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            output.push(major | 27);
+            output.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+/// Reverses [`encode`], requiring the input to hold exactly one item
+pub fn decode(input: &[u8]) -> anyhow::Result<Value> {
+    let mut decoder = Decoder { input, at: 0 };
+    let value = decoder.item(0)?;
+    if decoder.at != input.len() {
+        bail!(
+            "{} trailing bytes after CBOR item",
+            input.len() - decoder.at
+        );
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    at: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        let end = self
+            .at
+            .checked_add(n)
+            .filter(|&end| end <= self.input.len());
+        let Some(end) = end else {
+            bail!("CBOR input ends in the middle of an item");
+        };
+        let bytes = &self.input[self.at..end];
+        self.at = end;
+        Ok(bytes)
+    }
+
+    /// An item's initial byte and the argument that follows it
+    fn head(&mut self) -> anyhow::Result<(u8, u64)> {
+        let initial = self.take(1)?[0];
+        let n = match initial & 0x1f {
+            n @ 0..=23 => n as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into()?) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into()?) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            _ => bail!("unsupported CBOR item {initial:#04x}"),
+        };
+        Ok((initial, n))
+    }
+
+    fn text(&mut self, len: u64) -> anyhow::Result<String> {
+        let bytes = self.take(usize::try_from(len)?)?;
+        let text = std::str::from_utf8(bytes).context("CBOR text isn't valid UTF-8")?;
+        Ok(text.to_owned())
+    }
+
+    /// How many items to make room for, trusting the declared count only as far as the input
+    /// could possibly hold
+    fn capacity(&self, len: u64) -> usize {
+        (len as usize).min(self.input.len() - self.at)
+    }
+
+    fn item(&mut self, depth: usize) -> anyhow::Result<Value> {
+        if depth > MAX_DEPTH {
+            bail!("CBOR items are nested too deeply");
+        }
+        let initial = self.input.get(self.at).copied();
+        match initial {
+            Some(FALSE) | Some(TRUE) | Some(NULL) => {
+                self.at += 1;
+                return Ok(match initial {
+                    Some(FALSE) => Value::Bool(false),
+                    Some(TRUE) => Value::Bool(true),
+                    _ => Value::Null,
+                });
+            }
+            Some(F32) => {
+                self.at += 1;
+                let f = f32::from_be_bytes(self.take(4)?.try_into()?);
+                return Ok(float(f as f64));
+            }
+            Some(F64) => {
+                self.at += 1;
+                let f = f64::from_be_bytes(self.take(8)?.try_into()?);
+                return Ok(float(f));
+            }
+            _ => {}
+        }
+        let (initial, n) = self.head()?;
+        match initial >> 5 {
+            UNSIGNED => Ok(Value::from(n)),
+            NEGATIVE => match i64::try_from(n) {
+                Ok(n) => Ok(Value::from(-1 - n)),
+                Err(_) => Ok(float(-1.0 - n as f64)),
+            },
+            TEXT => Ok(Value::String(self.text(n)?)),
+            ARRAY => {
+                let mut items = Vec::with_capacity(self.capacity(n));
+                for _ in 0..n {
+                    items.push(self.item(depth + 1)?);
+                }
+                Ok(Value::Array(items))
+            }
+            MAP => {
+                let mut map = Map::new();
+                for _ in 0..n {
+                    let (initial, len) = self.head()?;
+                    if initial >> 5 != TEXT {
+                        bail!("CBOR map keys must be text");
+                    }
+                    let key = self.text(len)?;
+                    map.insert(key, self.item(depth + 1)?);
+                }
+                Ok(Value::Object(map))
+            }
+            SIMPLE => bail!("unsupported CBOR simple value {initial:#04x}"),
+            major => bail!("unsupported CBOR major type {major}"),
+        }
+    }
+}
+
+/// JSON has no NaN or infinities, so those become null, as `serde_json` would make them
+fn float(f: f64) -> Value {
+    Number::from_f64(f).map_or(Value::Null, Value::Number)
+}
@@ -0,0 +1,164 @@
+//! Merging messages bound for the same destination into one
+//!
+//! A [`Coalescer`] stands in for the output a node (or a library module such as
+//! [`Gossip`](crate::gossip::Gossip)) writes to. Fire-and-forget messages, those with neither a
+//! `msg_id` nor an `in_reply_to`, are held per destination for up to a window, and each one is
+//! folded into a held message whose payload [`Coalesce`]s with it. Everything else is passed
+//! through on the next flush. Flush at the end of every step so nothing waits on the window
+//! unless it was meant to:
+//!
+//! ```ignore
+//! self.gossip.tick(&self.neighbors, &mut self.coalescer, Wire::Gossip)?;
+//! ...
+//! self.coalescer.flush_due(output)?;
+//! ```
+use crate::{
+    gossip::{GossipPayload, GossipSet},
+    Message, NodeID,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Payloads that can sometimes travel as one message
+pub trait Coalesce: Sized {
+    /// Folds `other` into `self`, or hands it back if the two can't be merged
+    fn coalesce(&mut self, other: Self) -> Option<Self>;
+}
+
+/// Gossip sets are merged by union; digests are never merged
+impl<S: GossipSet> Coalesce for GossipPayload<S> {
+    fn coalesce(&mut self, other: Self) -> Option<Self> {
+        match (self, other) {
+            (GossipPayload::Gossip { seen }, GossipPayload::Gossip { seen: other }) => {
+                for value in other.values() {
+                    seen.insert(value);
+                }
+                None
+            }
+            (_, other) => Some(other),
+        }
+    }
+}
+
+struct Batch<P> {
+    since: Instant,
+    messages: Vec<Message<P>>,
+}
+
+pub struct Coalescer<P> {
+    window: Duration,
+    /// Bytes written since the last complete line
+    partial: Vec<u8>,
+    /// Lines that go out as is on the next flush
+    immediate: Vec<u8>,
+    pending: HashMap<NodeID, Batch<P>>,
+    merged: u64,
+}
+
+impl<P> Coalescer<P>
+where
+    P: Coalesce + Serialize + DeserializeOwned,
+{
+    /// Holds mergeable messages for up to `window`; with a zero window, only messages written
+    /// between two flushes are merged
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            partial: Vec::new(),
+            immediate: Vec::new(),
+            pending: HashMap::new(),
+            merged: 0,
+        }
+    }
+
+    /// How many messages have been folded into others rather than sent
+    pub fn merged(&self) -> u64 {
+        self.merged
+    }
+
+    /// Writes out the pass-through messages and every batch that has waited out the window
+    pub fn flush_due(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let window = self.window;
+        self.flush_where(output, |batch| now - batch.since >= window)
+    }
+
+    /// Writes out everything, regardless of the window
+    pub fn flush_all(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        self.flush_where(output, |_| true)
+    }
+
+    fn flush_where(
+        &mut self,
+        output: &mut impl Write,
+        due: impl Fn(&Batch<P>) -> bool,
+    ) -> anyhow::Result<()> {
+        if !self.immediate.is_empty() {
+            output.write_all(&std::mem::take(&mut self.immediate))?;
+        }
+        let ready: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, batch)| due(batch))
+            .map(|(dst, _)| dst.clone())
+            .collect();
+        for dst in ready {
+            let batch = self.pending.remove(&dst).expect("batch was just seen");
+            for message in batch.messages {
+                message.send(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_line(&mut self, line: &[u8]) {
+        let message = match serde_json::from_slice::<Message<P>>(line) {
+            Ok(message) if message.body.id.is_none() && message.body.in_reply_to.is_none() => {
+                message
+            }
+            // Requests and replies are answered by ID, so they can't be merged
+            _ => {
+                self.immediate.extend_from_slice(line);
+                return;
+            }
+        };
+        let Message { src, dst, body } = message;
+        let batch = self.pending.entry(dst.clone()).or_insert_with(|| Batch {
+            since: Instant::now(),
+            messages: Vec::new(),
+        });
+        let mut payload = body.payload;
+        for held in batch.messages.iter_mut().rev() {
+            match held.body.payload.coalesce(payload) {
+                None => {
+                    self.merged += 1;
+                    return;
+                }
+                Some(rejected) => payload = rejected,
+            }
+        }
+        batch.messages.push(Message::new(src, dst).payload(payload));
+    }
+}
+
+impl<P> Write for Coalescer<P>
+where
+    P: Coalesce + Serialize + DeserializeOwned,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.push_line(&line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+//! Multi-threaded event processing that preserves ordering per key
+//!
+//! Events are sharded across a pool of workers by [`ConcurrentNode::ordering_key`]: events that
+//! share a key are always stepped by the same worker, in the order they left the runtime's
+//! [priority lanes](crate::runtime::Priority), while events with different keys proceed in
+//! parallel. All workers share one writer thread, which writes each
+//! message in one piece, so messages from different workers never interleave.
+//!
+//! [`Event::Shutdown`] is stepped exactly once, after every worker has finished with the events
+//! queued ahead of it, so shutdown handling never races with other steps. Snapshots are likewise
+//! taken with every worker paused between steps.
+use crate::{
+    runtime::Stamp, serve_with, Event, Init, Options, Output, Runtime, Stepper, Supervisor,
+};
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    marker::PhantomData,
+    sync::{mpsc, Arc, PoisonError, RwLock},
+    thread::JoinHandle,
+};
+
+pub trait ConcurrentNode<State, Payload, InjectedPayload = ()>: Send + Sync {
+    fn from_init(
+        state: State,
+        init: Init,
+        runtime: Runtime<Payload, InjectedPayload>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    /// Events with equal keys are stepped one at a time in arrival order
+    ///
+    /// Defaults to the message's sender, so each client (and each peer) sees its requests
+    /// handled in order, and a peer's liveness changes are ordered with its messages. Injected
+    /// events all share a single key.
+    fn ordering_key(&self, input: &Event<Payload, InjectedPayload>) -> u64 {
+        match input {
+            Event::Message(message) => key(&message.src),
+            Event::PeerDown(peer) | Event::PeerUp(peer) => key(peer),
+            Event::Injected(_) | Event::Shutdown => 0,
+        }
+    }
+
+    fn step(
+        &self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut dyn Write,
+    ) -> anyhow::Result<()>;
+
+    /// Captures the node's state so the runtime can persist it; `None` opts out of snapshots
+    ///
+    /// Taken between steps: every worker waits for it to finish.
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Reinstates state captured by [`ConcurrentNode::snapshot`], called right after `from_init`
+    /// when a persisted snapshot exists
+    fn restore(&mut self, snapshot: serde_json::Value) -> anyhow::Result<()> {
+        let _ = snapshot;
+        Ok(())
+    }
+
+    /// Called once the latest [`ConcurrentNode::snapshot`] has been durably saved
+    fn snapshotted(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The state written to stderr when a debug dump is requested
+    ///
+    /// Taken from the dispatching thread while workers may be mid-step.
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// Hashes any value into an ordering key
+pub fn key(value: &(impl Hash + ?Sized)) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn concurrent_main_loop<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload> + 'static,
+    InjectedPayload: Send + 'static,
+{
+    let options = Options::from_env()?.with_args(std::env::args().skip(1))?;
+    concurrent_main_loop_with::<_, NodeType, _, _>(init_state, options)
+}
+
+pub fn concurrent_main_loop_with<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload> + 'static,
+    InjectedPayload: Send + 'static,
+{
+    serve::<_, NodeType, _, _>(
+        init_state,
+        options,
+        BufReader::new(std::io::stdin()),
+        std::io::stdout(),
+        true,
+    )
+}
+
+/// Like [`run_with_io`](crate::run_with_io), for concurrent nodes
+pub fn concurrent_run_with_io<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write + Send + 'static,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload> + 'static,
+    InjectedPayload: Send + 'static,
+{
+    serve::<_, NodeType, _, _>(init_state, options, reader, writer, false)
+}
+
+/// Runs a node, shutting it down on SIGTERM and SIGINT if `signals` is set
+fn serve<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write + Send + 'static,
+    signals: bool,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload> + 'static,
+    InjectedPayload: Send + 'static,
+{
+    let workers = options.workers.max(1);
+    let per_worker = (options.queue_capacity / workers).max(1);
+    serve_with(
+        init_state,
+        options,
+        reader,
+        writer,
+        signals,
+        |state, init, runtime, snapshot, supervisor, output| {
+            let mut node: NodeType = ConcurrentNode::from_init(state, init, runtime)
+                .context("node initialization failed")?;
+            if let Some(snapshot) = snapshot {
+                node.restore(snapshot)
+                    .context("node could not be restored from snapshot")?;
+            }
+            Ok(Workers::spawn(
+                Arc::new(node),
+                workers,
+                per_worker,
+                supervisor,
+                output,
+            ))
+        },
+    )
+}
+
+/// Steps a [`ConcurrentNode`] on a pool of worker threads
+struct Workers<NodeType, State, Payload, InjectedPayload> {
+    node: Arc<NodeType>,
+    senders: Vec<mpsc::SyncSender<(Event<Payload, InjectedPayload>, Stamp)>>,
+    handles: Vec<JoinHandle<anyhow::Result<()>>>,
+    supervisor: Arc<Supervisor>,
+    /// Held for reading by every step, and for writing while a snapshot is taken
+    stepping: Arc<RwLock<()>>,
+    _state: PhantomData<fn() -> State>,
+}
+
+impl<State, NodeType, Payload, InjectedPayload> Workers<NodeType, State, Payload, InjectedPayload>
+where
+    Payload: Send + 'static,
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload> + 'static,
+    InjectedPayload: Send + 'static,
+{
+    fn spawn(
+        node: Arc<NodeType>,
+        workers: usize,
+        per_worker: usize,
+        supervisor: Arc<Supervisor>,
+        output: &Output,
+    ) -> Self {
+        let stepping = Arc::new(RwLock::new(()));
+        let (senders, handles) = (0..workers)
+            .map(|_| {
+                let (tx, rx) = mpsc::sync_channel(per_worker);
+                let node = Arc::clone(&node);
+                let supervisor = Arc::clone(&supervisor);
+                let stepping = Arc::clone(&stepping);
+                let mut output = output.handle();
+                let handle = std::thread::spawn(move || {
+                    for (input, stamp) in rx {
+                        let _stepping = stepping.read().unwrap_or_else(PoisonError::into_inner);
+                        supervisor.step(input, stamp, &mut output, |input, output| {
+                            node.step(input, output)
+                        })?;
+                    }
+                    Ok(())
+                });
+                (tx, handle)
+            })
+            .unzip();
+        Self {
+            node,
+            senders,
+            handles,
+            supervisor,
+            stepping,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<State, NodeType, Payload, InjectedPayload> Stepper<Payload, InjectedPayload>
+    for Workers<NodeType, State, Payload, InjectedPayload>
+where
+    NodeType: ConcurrentNode<State, Payload, InjectedPayload>,
+{
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+        _output: &mut Output,
+    ) -> anyhow::Result<bool> {
+        let worker = (self.node.ordering_key(&input) % self.senders.len() as u64) as usize;
+        // A worker that bailed out has dropped its receiver; its error is surfaced by finishing
+        Ok(self.senders[worker].send((input, stamp)).is_ok())
+    }
+
+    fn finish(&mut self, shutdown: Option<Stamp>, output: &mut Output) -> anyhow::Result<()> {
+        self.senders.clear();
+        for handle in self.handles.drain(..) {
+            handle.join().expect("worker thread panicked")?;
+        }
+        let Some(stamp) = shutdown else {
+            return Ok(());
+        };
+        // Every worker is done, so the node sees the shutdown after everything queued ahead of it
+        let node = &self.node;
+        self.supervisor
+            .step(Event::Shutdown, stamp, output, |input, output| {
+                node.step(input, output)
+            })
+    }
+
+    fn snapshot(&mut self) -> Option<serde_json::Value> {
+        let _paused = self
+            .stepping
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.node.snapshot()
+    }
+
+    fn snapshotted(&mut self) -> anyhow::Result<()> {
+        self.node.snapshotted()
+    }
+
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        self.node.debug_state()
+    }
+}
@@ -0,0 +1,287 @@
+//! Opt-in compact encodings for large payload fields
+//!
+//! Wrapping a field's type in [`Compressed`] makes it travel as a base64 string holding the
+//! compressed JSON of the value, and decompresses it again on receive, so the rest of the code
+//! keeps working with the plain value. [`Binary`] does the same with the value's
+//! [CBOR](crate::cbor) encoding, which is smaller than JSON and much cheaper to produce than
+//! compressing it, for bulk transfers such as snapshots and Merkle sync. Either is chosen per
+//! message type simply by which fields use it:
+//!
+//! ```ignore
+//! enum Payload {
+//!     Sync { state: Compressed<HashMap<String, usize>> },
+//!     InstallSnapshot { snapshot: Binary<Snapshot> },
+//! }
+//! ```
+//!
+//! The compressor is a small LZ77 variant that does well on the repetitive JSON nodes tend to
+//! exchange; it isn't meant to compete with general-purpose codecs.
+use crate::cbor;
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+
+/// Shortest back-reference worth encoding
+const MIN_MATCH: usize = 4;
+/// Longest back-reference a single token can encode
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+/// Longest run of literals a single token can encode
+const MAX_LITERALS: usize = 0x80;
+/// How far back matches can reach
+const WINDOW: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+/// Most a [`Compressed`] value may expand to when it's received, since a few bytes of matches can
+/// stand for a great deal of output
+pub const MAX_DECOMPRESSED: usize = 64 << 20;
+
+/// A value that's serialized compressed and base64-encoded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compressed<T>(pub T);
+
+impl<T> Compressed<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Compressed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Compressed<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for Compressed<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Serialize> Serialize for Compressed<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let json = serde_json::to_vec(&self.0).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&base64_encode(&compress(&json)))
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Compressed<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let unpack = || -> anyhow::Result<T> {
+            let json = decompress_limited(&base64_decode(&encoded)?, MAX_DECOMPRESSED)?;
+            serde_json::from_slice(&json).context("compressed value isn't valid JSON")
+        };
+        unpack().map(Self).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A value that's serialized as base64-encoded CBOR
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Binary<T>(pub T);
+
+impl<T> Binary<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Binary<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Binary<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for Binary<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Serialize> Serialize for Binary<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = serde_json::to_value(&self.0).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&base64_encode(&cbor::encode(&value)))
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Binary<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let unpack = || -> anyhow::Result<T> {
+            let value = cbor::decode(&base64_decode(&encoded)?)?;
+            serde_json::from_value(value).context("binary value has the wrong shape")
+        };
+        unpack().map(Self).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Compresses `input` into a stream of literal runs and back-references
+///
+/// Each token starts with a tag byte: below `0x80` it's followed by `tag + 1` literal bytes,
+/// otherwise it's a match of `(tag & 0x7f) + 4` bytes at the little-endian `u16` offset that
+/// follows.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literals = 0..0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let slot = hash(&input[i..i + MIN_MATCH]);
+        let candidate = std::mem::replace(&mut table[slot], i);
+        let len = if candidate != usize::MAX && i - candidate <= WINDOW {
+            input[candidate..]
+                .iter()
+                .zip(&input[i..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            0
+        };
+        if len < MIN_MATCH {
+            if literals.is_empty() {
+                literals = i..i;
+            }
+            literals.end += 1;
+            i += 1;
+            continue;
+        }
+        emit_literals(&mut output, &input[literals.clone()]);
+        literals = 0..0;
+        output.push(0x80 | (len - MIN_MATCH) as u8);
+        output.extend_from_slice(&((i - candidate) as u16).to_le_bytes());
+        i += len;
+    }
+    let tail = if literals.is_empty() {
+        i
+    } else {
+        literals.start
+    };
+    emit_literals(&mut output, &input[tail..]);
+    output
+}
+
+/// Reverses [`compress`], expanding to at most [`MAX_DECOMPRESSED`] bytes
+pub fn decompress(input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    decompress_limited(input, MAX_DECOMPRESSED)
+}
+
+/// Reverses [`compress`], failing rather than produce more than `limit` bytes
+pub fn decompress_limited(input: &[u8], limit: usize) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len().saturating_mul(2).min(limit));
+    let mut i = 0;
+    while let Some(&tag) = input.get(i) {
+        i += 1;
+        if tag < 0x80 {
+            let len = tag as usize + 1;
+            let Some(literals) = input.get(i..i + len) else {
+                bail!("compressed data ends in the middle of a literal run");
+            };
+            if output.len() + len > limit {
+                bail!("compressed data expands to more than {limit} bytes");
+            }
+            output.extend_from_slice(literals);
+            i += len;
+        } else {
+            let len = (tag & 0x7f) as usize + MIN_MATCH;
+            let Some(&[lo, hi]) = input.get(i..i + 2) else {
+                bail!("compressed data ends in the middle of a match");
+            };
+            i += 2;
+            let offset = u16::from_le_bytes([lo, hi]) as usize;
+            if offset == 0 || offset > output.len() {
+                bail!("compressed data refers back past its start");
+            }
+            if output.len() + len > limit {
+                bail!("compressed data expands to more than {limit} bytes");
+            }
+            // Byte by byte, since a match may overlap the bytes it produces
+            let start = output.len() - offset;
+            for j in start..start + len {
+                output.push(output[j]);
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn emit_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+/// Each byte's value in [`BASE64`], or `INVALID`
+const BASE64_VALUES: [u8; 256] = {
+    let mut values = [INVALID; 256];
+    let mut i = 0;
+    while i < BASE64.len() {
+        values[BASE64[i] as usize] = i as u8;
+        i += 1;
+    }
+    values
+};
+const INVALID: u8 = 0xff;
+
+/// Standard, padded base64
+pub(crate) fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for k in 0..4 {
+            if k <= chunk.len() {
+                output.push(BASE64[((n >> (18 - 6 * k)) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+pub(crate) fn base64_decode(input: &str) -> anyhow::Result<Vec<u8>> {
+    let input = input.trim_end_matches('=').as_bytes();
+    if input.len() % 4 == 1 {
+        bail!("base64 input has an impossible length");
+    }
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        let mut n = 0u32;
+        for (k, &c) in chunk.iter().enumerate() {
+            let value = BASE64_VALUES[c as usize];
+            if value == INVALID {
+                bail!("invalid base64 character {:?}", c as char);
+            }
+            n |= (value as u32) << (18 - 6 * k);
+        }
+        output.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Ok(output)
+}
@@ -0,0 +1,254 @@
+//! Heartbeat-based failure detection
+//!
+//! Each node periodically sends a [`HeartbeatPayload::Heartbeat`] to every peer and records when
+//! it last heard from each of them (any message counts, not just heartbeats). Peers are suspected
+//! once they've been silent for too long, judged either by a fixed timeout or by a phi-accrual
+//! estimate over recent gaps between heartbeats. Transitions are reported as [`MembershipChange`]s
+//! so the node can react (elect a new leader, repair its topology, shrink quorums, ...).
+//!
+//! Like other library payloads, [`HeartbeatPayload`] is embedded in a node's payload through an
+//! untagged enum. Alternatively, setting `RASENGAN_HEARTBEAT_INTERVAL_MS` has the runtime run a
+//! detector on the node's behalf: heartbeats never reach the node, which instead sees
+//! [`Event::PeerDown`](crate::Event::PeerDown) and [`Event::PeerUp`](crate::Event::PeerUp) and can
+//! ask [`Runtime::alive_peers`](crate::Runtime::alive_peers) at any time.
+use crate::{Init, Message, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// How many inter-arrival samples phi-accrual estimates are based on
+const WINDOW: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatPayload {
+    Heartbeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Suspicion {
+    /// Suspect a peer after a fixed period of silence
+    Timeout(Duration),
+    /// Suspect a peer once phi (the negative log10 probability that a heartbeat is merely late)
+    /// exceeds the threshold; 8 is a common choice
+    PhiAccrual { threshold: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipChange {
+    Up(NodeID),
+    Down(NodeID),
+}
+
+#[derive(Debug)]
+struct Peer {
+    status: PeerStatus,
+    /// When any message last arrived
+    last: Instant,
+    last_heartbeat: Instant,
+    /// Recent gaps between heartbeats, in milliseconds
+    intervals: VecDeque<f64>,
+}
+
+#[derive(Debug)]
+pub struct FailureDetector {
+    node: NodeID,
+    interval: Duration,
+    suspicion: Suspicion,
+    peers: HashMap<NodeID, Peer>,
+}
+
+impl FailureDetector {
+    /// Creates a detector covering every other node, expecting heartbeats every `interval`
+    ///
+    /// All peers start out up.
+    pub fn new(init: &Init, interval: Duration, suspicion: Suspicion) -> Self {
+        let now = Instant::now();
+        Self {
+            node: init.node_id.clone(),
+            interval,
+            suspicion,
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|&id| *id != init.node_id)
+                .map(|id| {
+                    (
+                        id.clone(),
+                        Peer {
+                            status: PeerStatus::Up,
+                            last: now,
+                            last_heartbeat: now,
+                            intervals: VecDeque::new(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Records that a heartbeat from `src` just arrived, reporting if that brought it back up
+    ///
+    /// Only heartbeats, which arrive on schedule, feed the phi-accrual estimate.
+    pub fn heartbeat(&mut self, src: &str) -> Option<MembershipChange> {
+        let peer = self.peers.get_mut(src)?;
+        let now = Instant::now();
+        if peer.intervals.len() == WINDOW {
+            peer.intervals.pop_front();
+        }
+        peer.intervals
+            .push_back((now - peer.last_heartbeat).as_secs_f64() * 1000.0);
+        peer.last_heartbeat = now;
+        self.observe(src)
+    }
+
+    /// Records that some other message from `src` just arrived, reporting if that brought it
+    /// back up
+    pub fn observe(&mut self, src: &str) -> Option<MembershipChange> {
+        let peer = self.peers.get_mut(src)?;
+        peer.last = Instant::now();
+        if peer.status == PeerStatus::Down {
+            peer.status = PeerStatus::Up;
+            return Some(MembershipChange::Up(src.into()));
+        }
+        None
+    }
+
+    /// Sends a heartbeat to every peer and re-evaluates which ones are suspected
+    ///
+    /// Meant to be driven by a periodic injected event firing every `interval`.
+    pub fn tick<P>(
+        &mut self,
+        output: &mut impl Write,
+        wrap: impl Fn(HeartbeatPayload) -> P,
+    ) -> anyhow::Result<Vec<MembershipChange>>
+    where
+        P: Serialize,
+    {
+        for peer in self.peers.keys() {
+            Message::new(self.node.clone(), peer.clone())
+                .payload(wrap(HeartbeatPayload::Heartbeat))
+                .send(output)?;
+        }
+        Ok(self.check())
+    }
+
+    /// Marks peers that have gone quiet as down, returning the transitions
+    pub fn check(&mut self) -> Vec<MembershipChange> {
+        let now = Instant::now();
+        let mut changes = Vec::new();
+        for (id, peer) in &mut self.peers {
+            if peer.status == PeerStatus::Up && suspect(self.suspicion, self.interval, peer, now) {
+                peer.status = PeerStatus::Down;
+                changes.push(MembershipChange::Down(id.clone()));
+            }
+        }
+        changes
+    }
+
+    pub fn status(&self, peer: &str) -> Option<PeerStatus> {
+        self.peers.get(peer).map(|peer| peer.status)
+    }
+
+    /// Peers currently believed to be up
+    pub fn alive_peers(&self) -> impl Iterator<Item = &NodeID> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.status == PeerStatus::Up)
+            .map(|(id, _)| id)
+    }
+
+    /// The current phi-accrual suspicion level of a peer
+    pub fn phi(&self, peer: &str) -> Option<f64> {
+        let peer = self.peers.get(peer)?;
+        Some(phi(self.interval, peer, Instant::now()))
+    }
+}
+
+/// The runtime's own detector, shared by every [`Runtime`](crate::Runtime) handle
+///
+/// Without heartbeats nobody is ever suspected, so every peer counts as alive.
+#[derive(Debug)]
+pub(crate) struct Liveness {
+    detector: Mutex<FailureDetector>,
+    heartbeat: Option<Duration>,
+}
+
+impl Liveness {
+    pub(crate) fn new(init: &Init, heartbeat: Option<Duration>, suspicion: Suspicion) -> Self {
+        Self {
+            detector: Mutex::new(FailureDetector::new(
+                init,
+                heartbeat.unwrap_or(Duration::from_secs(1)),
+                suspicion,
+            )),
+            heartbeat,
+        }
+    }
+
+    pub(crate) fn detector(&self) -> MutexGuard<'_, FailureDetector> {
+        // The detector's updates can't panic halfway through
+        self.detector
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn heartbeat(&self) -> Option<Duration> {
+        self.heartbeat
+    }
+
+    /// The sender of a line that's one of the runtime's heartbeats, which the node never sees
+    pub(crate) fn heartbeat_from(&self, line: &str) -> Option<NodeID> {
+        if self.heartbeat.is_none() || !line.contains("heartbeat") {
+            return None;
+        }
+        let message: Message<serde_json::Value> = serde_json::from_str(line).ok()?;
+        (message.body.payload["type"] == "heartbeat").then_some(message.src)
+    }
+}
+
+fn suspect(suspicion: Suspicion, interval: Duration, peer: &Peer, now: Instant) -> bool {
+    match suspicion {
+        Suspicion::Timeout(timeout) => now - peer.last > timeout,
+        Suspicion::PhiAccrual { threshold } => phi(interval, peer, now) > threshold,
+    }
+}
+
+/// Phi for a peer given the time since it was last heard from, assuming normally distributed
+/// inter-arrival times (Hayashibara et al.)
+fn phi(interval: Duration, peer: &Peer, now: Instant) -> f64 {
+    let expected = interval.as_secs_f64() * 1000.0;
+    let (mean, std_dev) = if peer.intervals.is_empty() {
+        // Nothing observed yet; assume heartbeats arrive on schedule
+        (expected, expected / 4.0)
+    } else {
+        let n = peer.intervals.len() as f64;
+        let mean = peer.intervals.iter().sum::<f64>() / n;
+        let variance = peer
+            .intervals
+            .iter()
+            .map(|x| (x - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        // Keep a floor on the deviation so a perfectly regular peer isn't suspected instantly
+        (mean, variance.sqrt().max(expected / 10.0))
+    };
+    let elapsed = (now - peer.last).as_secs_f64() * 1000.0;
+    let y = (elapsed - mean) / std_dev;
+    // Logistic approximation of the normal CDF's upper tail
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    // e / (1 + e), arranged to stay finite when e overflows
+    let p_later = 1.0 / (1.0 + e.recip());
+    -p_later.max(f64::MIN_POSITIVE).log10()
+}
@@ -0,0 +1,410 @@
+//! Randomized fault injection over a simulated cluster
+//!
+//! Where the [model checker](crate::model) explores every interleaving of a few steps, a
+//! [`FaultInjector`] runs a [`Cluster`] for many steps under randomly generated fault
+//! [schedules](Fault): messages between nodes get dropped, delayed (and so reordered), or
+//! duplicated, and nodes crash and restart with fresh state. Without faults, each step delivers
+//! the oldest message in flight, and every few steps each node's ticks fire. Clients are never
+//! faulted, so every request arrives exactly once.
+//!
+//! After the faulty steps, the cluster is left to settle with a reliable network (see
+//! [`Checker::settle_rounds`](crate::model::Checker::settle_rounds)), and the eventually
+//! properties must hold by the end of it. Always properties are checked after every step.
+//!
+//! When a schedule breaks a property, it's shrunk to a minimal one that still breaks it: faults
+//! are removed one at a time, and delays shortened, for as long as the property keeps failing.
+//!
+//! ```ignore
+//! let cluster = Cluster::<CounterNode, _, _, _>::new(3, 0, |_| ())?;
+//! let failure = FaultInjector::new(cluster, |_| ())
+//!     .request("n1", json!({"type": "add", "delta": 2}))
+//!     .tick(InjectedPayload::Gossip)
+//!     .eventually("counter converges", |world| world.states().all(|s| s["value"] == 2))
+//!     .check()?;
+//! assert!(failure.is_none(), "{}", failure.unwrap());
+//! ```
+use crate::{
+    model::{Property, World},
+    sim::{Cluster, Envelope},
+    Message, Node, NodeID,
+};
+use anyhow::Context;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+
+/// Something going wrong at one step of a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub step: usize,
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The message due for delivery is lost
+    Drop,
+    /// The message due for delivery goes behind this many others
+    Delay(usize),
+    /// The message due for delivery arrives, and a copy of it is sent again
+    Duplicate,
+    /// The node restarts with fresh state
+    Crash(NodeID),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: ", self.step)?;
+        match &self.kind {
+            FaultKind::Drop => write!(f, "drop"),
+            FaultKind::Delay(by) => write!(f, "delay by {by}"),
+            FaultKind::Duplicate => write!(f, "duplicate"),
+            FaultKind::Crash(node) => write!(f, "crash {node}"),
+        }
+    }
+}
+
+/// A property broken by a fault schedule
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub property: &'static str,
+    /// Which run failed, counting from zero
+    pub run: usize,
+    /// How many faults the failing schedule had before shrinking
+    pub original: usize,
+    /// The shrunk schedule, which [`FaultInjector::replay`] breaks the property with
+    pub schedule: Vec<Fault>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" violated in run {}, shrunk from {} faults to {}:",
+            self.property,
+            self.run,
+            self.original,
+            self.schedule.len()
+        )?;
+        for fault in &self.schedule {
+            write!(f, "\n  {fault}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How likely each kind of fault is at any one step
+#[derive(Debug, Clone, Copy)]
+struct Rates {
+    drop: f64,
+    delay: f64,
+    duplicate: f64,
+    crash: f64,
+}
+
+pub struct FaultInjector<N, S, P, I> {
+    initial: World<N, S, P, I>,
+    state: Box<dyn Fn(&NodeID) -> S>,
+    ticks: Vec<I>,
+    always: Vec<Property<N, S, P, I>>,
+    eventually: Vec<Property<N, S, P, I>>,
+    rates: Rates,
+    max_delay: usize,
+    steps: usize,
+    tick_every: usize,
+    runs: usize,
+    seed: u64,
+    settle_rounds: usize,
+    next_client: u64,
+}
+
+impl<N, S, P, I> FaultInjector<N, S, P, I>
+where
+    N: Node<S, P, I> + Clone,
+    P: DeserializeOwned + Send + 'static,
+    I: Clone + Send + 'static,
+{
+    /// Crashed nodes restart with the state `state` gives for them
+    pub fn new(cluster: Cluster<N, S, P, I>, state: impl Fn(&NodeID) -> S + 'static) -> Self {
+        Self {
+            initial: World {
+                cluster,
+                in_flight: Vec::new(),
+                to_clients: Vec::new(),
+            },
+            state: Box::new(state),
+            ticks: Vec::new(),
+            always: Vec::new(),
+            eventually: Vec::new(),
+            rates: Rates {
+                drop: 0.05,
+                delay: 0.05,
+                duplicate: 0.05,
+                crash: 0.0,
+            },
+            max_delay: 8,
+            steps: 200,
+            tick_every: 10,
+            runs: 100,
+            seed: 0,
+            settle_rounds: 8,
+            next_client: 1,
+        }
+    }
+
+    /// Puts a request from a new client to `node` in flight
+    pub fn request(mut self, node: &str, payload: Value) -> Self {
+        let client = format!("c{}", self.next_client);
+        self.next_client += 1;
+        let message: Envelope = Message::new(client, node).with_id(&mut 0).payload(payload);
+        self.initial.in_flight.push(message);
+        self
+    }
+
+    /// An injected event that fires on every node every few steps
+    pub fn tick(mut self, payload: I) -> Self {
+        self.ticks.push(payload);
+        self
+    }
+
+    pub fn always(
+        mut self,
+        name: &'static str,
+        property: impl Fn(&World<N, S, P, I>) -> bool + 'static,
+    ) -> Self {
+        self.always.push((name, Box::new(property)));
+        self
+    }
+
+    pub fn eventually(
+        mut self,
+        name: &'static str,
+        property: impl Fn(&World<N, S, P, I>) -> bool + 'static,
+    ) -> Self {
+        self.eventually.push((name, Box::new(property)));
+        self
+    }
+
+    /// The chance of a message being dropped at any step (0.05 by default)
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.rates.drop = rate;
+        self
+    }
+
+    /// The chance of a message being delayed at any step (0.05 by default)
+    pub fn delay_rate(mut self, rate: f64) -> Self {
+        self.rates.delay = rate;
+        self
+    }
+
+    /// The chance of a message being duplicated at any step (0.05 by default)
+    pub fn duplicate_rate(mut self, rate: f64) -> Self {
+        self.rates.duplicate = rate;
+        self
+    }
+
+    /// The chance of a node crashing at any step (0 by default)
+    pub fn crash_rate(mut self, rate: f64) -> Self {
+        self.rates.crash = rate;
+        self
+    }
+
+    /// How many messages a delayed one may go behind (8 by default)
+    pub fn max_delay(mut self, by: usize) -> Self {
+        self.max_delay = by.max(1);
+        self
+    }
+
+    /// How many steps each run takes before settling (200 by default)
+    pub fn steps(mut self, steps: usize) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// How many steps apart ticks fire (10 by default)
+    pub fn tick_every(mut self, steps: usize) -> Self {
+        self.tick_every = steps.max(1);
+        self
+    }
+
+    /// How many random schedules to try (100 by default)
+    pub fn runs(mut self, runs: usize) -> Self {
+        self.runs = runs;
+        self
+    }
+
+    /// Seeds the schedules, so failures can be reproduced (0 by default)
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// How many rounds of ticks and reliable delivery eventually properties get to come true
+    /// (8 by default)
+    pub fn settle_rounds(mut self, rounds: usize) -> Self {
+        self.settle_rounds = rounds;
+        self
+    }
+
+    /// Tries random schedules, stopping at the first that breaks a property and shrinking it
+    ///
+    /// Fails if a node's step fails, which is reported along with the schedule that caused it.
+    pub fn check(&self) -> anyhow::Result<Option<Failure>> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        for run in 0..self.runs {
+            let schedule = self.generate(&mut rng);
+            let Some(property) = self.replay(&schedule)? else {
+                continue;
+            };
+            let original = schedule.len();
+            return Ok(Some(Failure {
+                property,
+                run,
+                original,
+                schedule: self.shrink(schedule, property)?,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Runs the cluster under a fault schedule, returning the first property it breaks
+    pub fn replay(&self, schedule: &[Fault]) -> anyhow::Result<Option<&'static str>> {
+        self.run(schedule).with_context(|| {
+            let faults: Vec<_> = schedule.iter().map(ToString::to_string).collect();
+            format!("under faults:\n  {}", faults.join("\n  "))
+        })
+    }
+
+    fn run(&self, schedule: &[Fault]) -> anyhow::Result<Option<&'static str>> {
+        let mut world = self.initial.clone();
+        let mut faults = schedule.iter().peekable();
+        for step in 0..self.steps {
+            if step % self.tick_every == 0 {
+                let nodes = world.cluster.ids().to_vec();
+                for node in &nodes {
+                    for tick in &self.ticks {
+                        let sent = world.cluster.inject(node, tick.clone())?;
+                        world.route(sent);
+                    }
+                }
+            }
+            let mut deliver = true;
+            while let Some(fault) = faults.next_if(|fault| fault.step == step) {
+                deliver &= self.apply(&mut world, &fault.kind)?;
+            }
+            if deliver && !world.in_flight.is_empty() {
+                let message = world.in_flight.remove(0);
+                let sent = world.cluster.deliver(message)?;
+                world.route(sent);
+            }
+            if let Some((name, _)) = self.always.iter().find(|(_, holds)| !holds(&world)) {
+                return Ok(Some(name));
+            }
+        }
+        world.settle(&self.ticks, self.settle_rounds, &self.eventually)?;
+        let broken = self
+            .always
+            .iter()
+            .chain(&self.eventually)
+            .find(|(_, holds)| !holds(&world));
+        Ok(broken.map(|(name, _)| *name))
+    }
+
+    /// Applies a fault, returning whether the step should still deliver a message
+    fn apply(&self, world: &mut World<N, S, P, I>, fault: &FaultKind) -> anyhow::Result<bool> {
+        if let FaultKind::Crash(node) = fault {
+            world.cluster.restart(node, (self.state)(node))?;
+            return Ok(true);
+        }
+        // Only messages between nodes are faulted
+        let Some(message) = world.in_flight.first() else {
+            return Ok(true);
+        };
+        if !world.cluster.contains(&message.src) {
+            return Ok(true);
+        }
+        match fault {
+            FaultKind::Drop => {
+                world.in_flight.remove(0);
+                Ok(false)
+            }
+            FaultKind::Delay(by) => {
+                let message = world.in_flight.remove(0);
+                let at = (*by).min(world.in_flight.len());
+                world.in_flight.insert(at, message);
+                Ok(false)
+            }
+            FaultKind::Duplicate => {
+                world.in_flight.push(message.clone());
+                Ok(true)
+            }
+            FaultKind::Crash(_) => unreachable!("crashes are applied above"),
+        }
+    }
+
+    fn generate(&self, rng: &mut StdRng) -> Vec<Fault> {
+        let Rates {
+            drop,
+            delay,
+            duplicate,
+            crash,
+        } = self.rates;
+        let nodes = self.initial.cluster.ids();
+        let mut schedule = Vec::new();
+        for step in 0..self.steps {
+            let roll: f64 = rng.gen();
+            let kind = if roll < drop {
+                FaultKind::Drop
+            } else if roll < drop + delay {
+                FaultKind::Delay(rng.gen_range(1..=self.max_delay))
+            } else if roll < drop + delay + duplicate {
+                FaultKind::Duplicate
+            } else if roll < drop + delay + duplicate + crash {
+                FaultKind::Crash(nodes[rng.gen_range(0..nodes.len())].clone())
+            } else {
+                continue;
+            };
+            schedule.push(Fault { step, kind });
+        }
+        schedule
+    }
+
+    /// Removes faults and shortens delays for as long as `property` keeps breaking
+    fn shrink(
+        &self,
+        mut schedule: Vec<Fault>,
+        property: &'static str,
+    ) -> anyhow::Result<Vec<Fault>> {
+        loop {
+            let mut shrunk = false;
+            let mut i = 0;
+            while i < schedule.len() {
+                let mut candidate = schedule.clone();
+                candidate.remove(i);
+                if self.replay(&candidate)? == Some(property) {
+                    schedule = candidate;
+                    shrunk = true;
+                } else {
+                    i += 1;
+                }
+            }
+            for i in 0..schedule.len() {
+                let FaultKind::Delay(by) = schedule[i].kind else {
+                    continue;
+                };
+                if by > 1 {
+                    let mut candidate = schedule.clone();
+                    candidate[i].kind = FaultKind::Delay(by / 2);
+                    if self.replay(&candidate)? == Some(property) {
+                        schedule = candidate;
+                        shrunk = true;
+                    }
+                }
+            }
+            if !shrunk {
+                return Ok(schedule);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Forwarding client requests to the node that should handle them
+//!
+//! Maelstrom only lets a node answer messages addressed to it, so a request that belongs to
+//! another node is sent on as a fresh RPC between the two nodes. When the owner's reply comes
+//! back, it's relayed to the original client as though it had been answered directly:
+//!
+//! ```ignore
+//! Event::Message(message) if forwarder.relay(&message, output)? => {}
+//! Event::Message(message) if !owned(&message) => {
+//!     forwarder.forward(message, owner, &mut self.id, output)?
+//! }
+//! ```
+use crate::{Message, MessageID, NodeID};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Where a forwarded request originally came from
+#[derive(Debug, Clone)]
+struct Origin {
+    client: NodeID,
+    id: Option<MessageID>,
+    forwarded: Instant,
+}
+
+#[derive(Debug)]
+pub struct Forwarder {
+    node: NodeID,
+    /// Requests awaiting a reply from their owner, by the ID they were forwarded under
+    pending: HashMap<MessageID, Origin>,
+}
+
+impl Forwarder {
+    pub fn new(node: impl Into<NodeID>) -> Self {
+        Self {
+            node: node.into(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Sends `request` on to `owner`, taking its message ID from `id`
+    pub fn forward<P>(
+        &mut self,
+        request: Message<P>,
+        owner: impl Into<NodeID>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        let message = Message::new(self.node.clone(), owner)
+            .with_id(id)
+            .payload(request.body.payload);
+        let forwarded = message.body.id.expect("forwarded requests have an ID");
+        self.pending.insert(
+            forwarded,
+            Origin {
+                client: request.src,
+                id: request.body.id,
+                forwarded: Instant::now(),
+            },
+        );
+        message.send(output)
+    }
+
+    /// Passes a reply to a forwarded request back to the client that made it
+    ///
+    /// Returns `false` (sending nothing) if the message isn't such a reply.
+    pub fn relay<P>(&mut self, reply: &Message<P>, output: &mut impl Write) -> anyhow::Result<bool>
+    where
+        P: Serialize,
+    {
+        let Some(relayed) = self.relayed(reply) else {
+            return Ok(false);
+        };
+        relayed.send(output)?;
+        Ok(true)
+    }
+
+    /// Like [`Forwarder::relay`], but hands back the reply for the client rather than sending it
+    pub fn relayed<'a, P>(&mut self, reply: &'a Message<P>) -> Option<Message<&'a P>> {
+        let origin = self.pending.remove(&reply.body.in_reply_to?)?;
+        let relayed = Message::new(self.node.clone(), origin.client);
+        Some(
+            match origin.id {
+                Some(id) => relayed.in_reply_to(id),
+                None => relayed,
+            }
+            .payload(&reply.body.payload),
+        )
+    }
+
+    /// Forgets requests that have gone unanswered for longer than `timeout`, returning how many
+    ///
+    /// The owner may have crashed or the reply been lost; either way the client has given up and
+    /// will retry, so nothing is relayed for them.
+    pub fn expire(&mut self, timeout: Duration) -> usize {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, origin| origin.forwarded.elapsed() < timeout);
+        before - self.pending.len()
+    }
+
+    /// How many forwarded requests are still waiting on a reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
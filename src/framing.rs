@@ -0,0 +1,200 @@
+//! Splitting a node's input into JSON documents
+//!
+//! Maelstrom writes one message per line, but nothing stops other harnesses (or a human at a
+//! terminal) from pretty-printing messages across several lines or running them together with
+//! no newline in between. [`Documents`] finds where each top-level document ends by tracking
+//! brackets and strings, so every message reaches the node whole and on a single line, however
+//! it was laid out.
+//!
+//! Garbage doesn't stop the stream: a document is cut short as soon as it can no longer be valid
+//! JSON and handed on as is, so whoever parses it can report the offending input and move on to
+//! the next one. When a document spanning several lines goes wrong, or the input ends in the
+//! middle of one, only its first line is given up on and the rest is scanned again, so a stray
+//! bracket can't swallow the messages after it. Documents over a size limit are skipped without
+//! being held in memory and reported as [`InvalidData`](io::ErrorKind::InvalidData) errors.
+use serde::de::IgnoredAny;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead},
+};
+
+/// Longest document read by default, in bytes
+pub const DEFAULT_MAX_LEN: usize = 16 << 20;
+
+/// An iterator over the JSON documents in a reader, each as a string on one line
+pub struct Documents<R> {
+    reader: R,
+    max_len: usize,
+    /// Input already read that still has to be scanned, after a broken document was cut short
+    carry: VecDeque<u8>,
+}
+
+impl<R: BufRead> Documents<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            max_len: DEFAULT_MAX_LEN,
+            carry: VecDeque::new(),
+        }
+    }
+
+    /// Skips documents longer than `max_len` bytes
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len.max(1);
+        self
+    }
+
+    fn end(&mut self, mut scan: Scan) -> io::Result<String> {
+        if scan.skipped > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "skipped a document of {} bytes, over the limit of {}",
+                    scan.doc.len() + scan.skipped,
+                    self.max_len
+                ),
+            ));
+        }
+        if !scan.complete {
+            // Everything after the first line gets another chance as the start of a document
+            let first = scan.doc.iter().position(|&b| b == b'\n');
+            if let Some(first) = first.filter(|&first| first + 1 < scan.doc.len()) {
+                for &b in scan.doc[first + 1..].iter().rev() {
+                    self.carry.push_front(b);
+                }
+                scan.doc.truncate(first);
+            }
+        }
+        Ok(finish(scan.doc))
+    }
+}
+
+impl<R: BufRead> Iterator for Documents<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut scan = Scan::new(self.max_len);
+        while let Some(b) = self.carry.pop_front() {
+            if scan.push(b) {
+                return Some(self.end(scan));
+            }
+        }
+        loop {
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            if buf.is_empty() {
+                // Whatever is left at the end of the input, complete or not
+                if scan.doc.is_empty() && scan.skipped == 0 {
+                    return None;
+                }
+                return Some(self.end(scan));
+            }
+            let mut used = 0;
+            let mut ended = false;
+            for &b in buf {
+                used += 1;
+                if scan.push(b) {
+                    ended = true;
+                    break;
+                }
+            }
+            self.reader.consume(used);
+            if ended {
+                return Some(self.end(scan));
+            }
+        }
+    }
+}
+
+/// Where a document being read has got to
+struct Scan {
+    doc: Vec<u8>,
+    max_len: usize,
+    /// Bytes past the limit that weren't kept
+    skipped: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Whether the document ended by closing, rather than by going wrong
+    complete: bool,
+    /// How long the document was when last checked for being broken
+    checked: usize,
+}
+
+impl Scan {
+    fn new(max_len: usize) -> Self {
+        Self {
+            doc: Vec::new(),
+            max_len,
+            skipped: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            complete: false,
+            checked: 0,
+        }
+    }
+
+    /// Adds the next byte, returning whether the document has ended
+    fn push(&mut self, b: u8) -> bool {
+        if self.doc.is_empty() && self.skipped == 0 && b.is_ascii_whitespace() {
+            return false;
+        }
+        if self.skipped > 0 || self.doc.len() >= self.max_len {
+            // Too long to be worth reading; skip to the end of the line
+            self.skipped += 1;
+            return b == b'\n';
+        }
+        self.doc.push(b);
+        if self.in_string {
+            match b {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = false,
+                // Strings can't span lines, so this document is broken
+                b'\n' => return true,
+                _ => {}
+            }
+            return false;
+        }
+        match b {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' => {
+                self.depth = self.depth.saturating_sub(1);
+                self.complete = self.depth == 0;
+                return self.complete;
+            }
+            // Not inside an object or array, so this was a bare value or garbage
+            b'\n' if self.depth == 0 => return true,
+            // Checking only each time the document doubles keeps long documents linear
+            b'\n' if self.doc.len() >= 2 * self.checked => {
+                self.checked = self.doc.len();
+                return is_broken(&self.doc);
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Whether a partial document has already gone wrong, rather than just not being finished yet
+fn is_broken(doc: &[u8]) -> bool {
+    serde_json::from_slice::<IgnoredAny>(doc).is_err_and(|e| !e.is_eof())
+}
+
+/// Puts a document on a single line, leaving anything that doesn't parse as it was
+fn finish(doc: Vec<u8>) -> String {
+    let text = String::from_utf8_lossy(&doc);
+    let text = text.trim_end();
+    if !text.contains('\n') {
+        return text.to_owned();
+    }
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value.to_string(),
+        Err(_) => text.to_owned(),
+    }
+}
@@ -0,0 +1,268 @@
+//! Consistent global snapshots (Chandy-Lamport)
+//!
+//! Any node can start a snapshot. It records its own state and sends a marker to every peer.
+//! A node receiving its first marker for a snapshot records its state and sends markers of its
+//! own. From that point until a marker arrives on a channel, every message received on that
+//! channel was in flight when the snapshot was cut, so it's recorded as part of the channel's
+//! state. Once markers have arrived from every peer, the node sends its share to the initiator.
+//! The initiator assembles a [`GlobalSnapshot`] once every node has reported.
+//!
+//! The algorithm assumes channels deliver in order. Channel state is therefore only exact when
+//! messages between each pair of nodes are stepped in the order they were sent.
+//!
+//! The node passes every other message it receives to [`GlobalSnapshots::observe`] so channel
+//! state can be recorded. Like other library payloads, [`SnapshotPayload`] is embedded in the
+//! node's payload through an untagged enum.
+use crate::{Init, Message, NodeID};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+/// Identifies a snapshot across the cluster
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SnapshotID {
+    pub initiator: NodeID,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotPayload {
+    SnapshotMarker {
+        snapshot: SnapshotID,
+    },
+    /// A node's share of a snapshot, sent to its initiator
+    SnapshotReport {
+        snapshot: SnapshotID,
+        #[serde(flatten)]
+        local: NodeSnapshot,
+    },
+}
+
+/// One node's part of a global snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeSnapshot {
+    pub state: Value,
+    /// Messages that were in flight towards this node when the snapshot was cut, by sender
+    pub in_flight: HashMap<NodeID, Vec<Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalSnapshot {
+    pub id: SnapshotID,
+    pub nodes: HashMap<NodeID, NodeSnapshot>,
+}
+
+#[derive(Debug)]
+struct Recording {
+    snapshot: NodeSnapshot,
+    /// Peers whose marker hasn't arrived yet
+    open: HashSet<NodeID>,
+}
+
+#[derive(Debug)]
+pub struct GlobalSnapshots {
+    node: NodeID,
+    peers: Vec<NodeID>,
+    next_seq: u64,
+    recording: HashMap<SnapshotID, Recording>,
+    /// Snapshots this node has already sent its share of
+    reported: HashSet<SnapshotID>,
+    /// Reports gathered for snapshots this node started
+    collecting: HashMap<SnapshotID, HashMap<NodeID, NodeSnapshot>>,
+}
+
+impl GlobalSnapshots {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|&id| *id != init.node_id)
+                .cloned()
+                .collect(),
+            next_seq: 0,
+            recording: HashMap::new(),
+            reported: HashSet::new(),
+            collecting: HashMap::new(),
+        }
+    }
+
+    /// Starts a snapshot with this node's current `state`, which completes through
+    /// [`GlobalSnapshots::handle`]
+    ///
+    /// Returns the finished snapshot straight away if this node has no peers.
+    pub fn initiate<P>(
+        &mut self,
+        state: Value,
+        output: &mut impl Write,
+        wrap: impl Fn(SnapshotPayload) -> P,
+    ) -> anyhow::Result<(SnapshotID, Option<GlobalSnapshot>)>
+    where
+        P: Serialize,
+    {
+        let id = SnapshotID {
+            initiator: self.node.clone(),
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.collecting.insert(id.clone(), HashMap::new());
+        let finished = self.record(&id, None, state, output, &wrap)?;
+        Ok((id, finished))
+    }
+
+    /// Records `payload` as in flight for every snapshot still waiting on a marker from `src`
+    ///
+    /// Call this for every message received other than snapshot messages.
+    pub fn observe(&mut self, src: &NodeID, payload: &impl Serialize) {
+        let mut recording = self
+            .recording
+            .values_mut()
+            .filter(|recording| recording.open.contains(src))
+            .peekable();
+        if recording.peek().is_none() {
+            return;
+        }
+        let payload = serde_json::to_value(payload).unwrap_or(Value::Null);
+        for recording in recording {
+            recording
+                .snapshot
+                .in_flight
+                .entry(src.clone())
+                .or_default()
+                .push(payload.clone());
+        }
+    }
+
+    /// Processes a snapshot message from `src`, returning the snapshot it finished, if any
+    ///
+    /// `state` is only called if this node has yet to record its state for the snapshot.
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: SnapshotPayload,
+        state: impl FnOnce() -> Value,
+        output: &mut impl Write,
+        wrap: impl Fn(SnapshotPayload) -> P,
+    ) -> anyhow::Result<Option<GlobalSnapshot>>
+    where
+        P: Serialize,
+    {
+        match payload {
+            SnapshotPayload::SnapshotMarker { snapshot } => {
+                if let Some(recording) = self.recording.get_mut(&snapshot) {
+                    recording.open.remove(src);
+                    return self.finish(&snapshot, output, &wrap);
+                }
+                if self.reported.contains(&snapshot) {
+                    return Ok(None);
+                }
+                self.record(&snapshot, Some(src), state(), output, &wrap)
+            }
+            SnapshotPayload::SnapshotReport { snapshot, local } => {
+                Ok(self.collect(snapshot, src.clone(), local))
+            }
+        }
+    }
+
+    /// Snapshots this node is still recording channels for
+    pub fn in_progress(&self) -> usize {
+        self.recording.len()
+    }
+
+    /// Records this node's state, and starts recording every channel but the one the first
+    /// marker came in on
+    fn record<P>(
+        &mut self,
+        snapshot: &SnapshotID,
+        marker_from: Option<&NodeID>,
+        state: Value,
+        output: &mut impl Write,
+        wrap: impl Fn(SnapshotPayload) -> P,
+    ) -> anyhow::Result<Option<GlobalSnapshot>>
+    where
+        P: Serialize,
+    {
+        for peer in &self.peers {
+            Message::new(self.node.clone(), peer.clone())
+                .payload(wrap(SnapshotPayload::SnapshotMarker {
+                    snapshot: snapshot.clone(),
+                }))
+                .send(output)?;
+        }
+        self.recording.insert(
+            snapshot.clone(),
+            Recording {
+                snapshot: NodeSnapshot {
+                    state,
+                    in_flight: HashMap::new(),
+                },
+                open: self
+                    .peers
+                    .iter()
+                    .filter(|&peer| Some(peer) != marker_from)
+                    .cloned()
+                    .collect(),
+            },
+        );
+        self.finish(snapshot, output, wrap)
+    }
+
+    /// Reports this node's share once markers have arrived from every peer
+    fn finish<P>(
+        &mut self,
+        snapshot: &SnapshotID,
+        output: &mut impl Write,
+        wrap: impl Fn(SnapshotPayload) -> P,
+    ) -> anyhow::Result<Option<GlobalSnapshot>>
+    where
+        P: Serialize,
+    {
+        if self
+            .recording
+            .get(snapshot)
+            .is_none_or(|recording| !recording.open.is_empty())
+        {
+            return Ok(None);
+        }
+        let local = self
+            .recording
+            .remove(snapshot)
+            .expect("snapshot is recording")
+            .snapshot;
+        self.reported.insert(snapshot.clone());
+        if snapshot.initiator == self.node {
+            return Ok(self.collect(snapshot.clone(), self.node.clone(), local));
+        }
+        Message::new(self.node.clone(), snapshot.initiator.clone())
+            .payload(wrap(SnapshotPayload::SnapshotReport {
+                snapshot: snapshot.clone(),
+                local,
+            }))
+            .send(output)?;
+        Ok(None)
+    }
+
+    fn collect(
+        &mut self,
+        snapshot: SnapshotID,
+        node: NodeID,
+        local: NodeSnapshot,
+    ) -> Option<GlobalSnapshot> {
+        let reports = self.collecting.get_mut(&snapshot)?;
+        reports.insert(node, local);
+        if reports.len() <= self.peers.len() {
+            return None;
+        }
+        let nodes = self.collecting.remove(&snapshot)?;
+        Some(GlobalSnapshot {
+            id: snapshot,
+            nodes,
+        })
+    }
+}
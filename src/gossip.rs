@@ -0,0 +1,396 @@
+//! Epidemic dissemination of a grow-only set of values
+//!
+//! Each gossip round either pushes values to neighbors or pulls them, depending on the
+//! [`GossipMode`]:
+//!
+//! - **Push** sends each neighbor everything it isn't known to have yet, plus a small random
+//!   sample of what it already has (in case earlier rounds were lost).
+//! - **Pull** sends each neighbor a fixed-size digest of the local set, and the neighbor answers
+//!   with the values from every digest bucket that doesn't match its own. Once the cluster has
+//!   mostly converged, rounds cost a digest rather than whole sets.
+//! - **Push-pull** pushes to a few randomly sampled neighbors and exchanges digests with a few
+//!   others. An exchange digest is answered with both the values the sender is missing and the
+//!   receiver's own digest, so a single round trip brings both sides up to date.
+//!
+//! The set of values is anything implementing [`GossipSet`]: a plain `HashSet`, an
+//! [`IntervalSet`] for dense integers, which also keeps gossip payloads small, or a
+//! [`ValueSet`](crate::value::ValueSet) for arbitrary JSON.
+//!
+//! Like other library payloads, [`GossipPayload`] is embedded in a node's payload through an
+//! untagged enum.
+use crate::{encoding::Compressed, interval_set::IntervalSet, Init, Message, NodeID};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::Write,
+};
+
+/// How many buckets pull digests split the value space into
+const BUCKETS: usize = 64;
+
+/// How many neighbors push-pull rounds push to and exchange digests with by default
+const FANOUT: usize = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipMode {
+    #[default]
+    Push,
+    Pull,
+    /// Each round pushes to `push` random neighbors and exchanges digests with `pull` others
+    PushPull {
+        #[serde(default = "fanout")]
+        push: usize,
+        #[serde(default = "fanout")]
+        pull: usize,
+    },
+}
+
+fn fanout() -> usize {
+    FANOUT
+}
+
+/// A set of values that can be gossiped
+pub trait GossipSet: Default {
+    type Value: Clone + Hash;
+
+    /// Adds a value, returning whether it was new
+    fn insert(&mut self, value: Self::Value) -> bool;
+
+    fn contains(&self, value: &Self::Value) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn values(&self) -> impl Iterator<Item = Self::Value> + '_;
+
+    /// Adds every value in `other`, returning the ones that were new
+    ///
+    /// Sets that hold values in bulk, like an [`IntervalSet`]'s ranges, merge them without
+    /// going through them one at a time.
+    fn merge(&mut self, other: &Self) -> Self {
+        let mut new = Self::default();
+        for value in other.values() {
+            if self.insert(value.clone()) {
+                new.insert(value);
+            }
+        }
+        new
+    }
+}
+
+impl<T> GossipSet for HashSet<T>
+where
+    T: Clone + Eq + Hash,
+{
+    type Value = T;
+
+    fn insert(&mut self, value: T) -> bool {
+        HashSet::insert(self, value)
+    }
+
+    fn contains(&self, value: &T) -> bool {
+        HashSet::contains(self, value)
+    }
+
+    fn len(&self) -> usize {
+        HashSet::len(self)
+    }
+
+    fn values(&self) -> impl Iterator<Item = T> + '_ {
+        self.iter().cloned()
+    }
+}
+
+impl GossipSet for IntervalSet {
+    type Value = u64;
+
+    fn insert(&mut self, value: u64) -> bool {
+        IntervalSet::insert(self, value)
+    }
+
+    fn contains(&self, value: &u64) -> bool {
+        IntervalSet::contains(self, *value)
+    }
+
+    fn len(&self) -> usize {
+        IntervalSet::len(self)
+    }
+
+    fn values(&self) -> impl Iterator<Item = u64> + '_ {
+        self.iter()
+    }
+
+    fn merge(&mut self, other: &Self) -> Self {
+        let new = self.missing_from(other);
+        self.union(&new);
+        new
+    }
+}
+
+/// Gossips the inner set compressed
+impl<S: GossipSet> GossipSet for Compressed<S> {
+    type Value = S::Value;
+
+    fn insert(&mut self, value: S::Value) -> bool {
+        self.0.insert(value)
+    }
+
+    fn contains(&self, value: &S::Value) -> bool {
+        self.0.contains(value)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn values(&self) -> impl Iterator<Item = S::Value> + '_ {
+        self.0.values()
+    }
+
+    fn merge(&mut self, other: &Self) -> Self {
+        Compressed(self.0.merge(&other.0))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum GossipPayload<S> {
+    /// Values the receiver may not have yet
+    Gossip { seen: S },
+    /// A summary of the sender's values, asking for whatever it's missing; with `exchange`, the
+    /// receiver's digest is asked for in return
+    GossipDigest {
+        digest: Vec<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        exchange: bool,
+    },
+}
+
+#[derive(Clone)]
+pub struct Gossip<S> {
+    node: NodeID,
+    mode: GossipMode,
+    values: S,
+    /// What each peer is known to have, going by what it has sent us
+    known: HashMap<NodeID, S>,
+    rng: StdRng,
+}
+
+impl<S> Gossip<S>
+where
+    S: GossipSet + Serialize,
+{
+    pub fn new(init: &Init, mode: GossipMode, rng: StdRng) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            mode,
+            values: S::default(),
+            known: init
+                .node_ids
+                .iter()
+                .map(|id| (id.clone(), S::default()))
+                .collect(),
+            rng,
+        }
+    }
+
+    pub fn mode(&self) -> GossipMode {
+        self.mode
+    }
+
+    /// Adds a value to be disseminated, returning whether it was new
+    pub fn insert(&mut self, value: S::Value) -> bool {
+        self.values.insert(value)
+    }
+
+    /// Adds every value in `values` to be disseminated, returning the ones that were new
+    pub fn merge(&mut self, values: &S) -> S {
+        self.values.merge(values)
+    }
+
+    /// Every value received so far
+    pub fn values(&self) -> &S {
+        &self.values
+    }
+
+    /// Runs a gossip round with `neighbors`
+    ///
+    /// Meant to be driven by a periodic injected event.
+    pub fn tick<P>(
+        &mut self,
+        neighbors: &[NodeID],
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        match self.mode {
+            GossipMode::Push => self.push(neighbors, output, &wrap),
+            GossipMode::Pull => self.pull(neighbors, false, output, &wrap),
+            GossipMode::PushPull { push, pull } => {
+                // Sampled together so a round never pushes to and exchanges with the same peer
+                let sampled = neighbors
+                    .choose_multiple(&mut self.rng, push + pull)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let (pushed, pulled) = sampled.split_at(push.min(sampled.len()));
+                self.push(pushed, output, &wrap)?;
+                self.pull(pulled, true, output, &wrap)
+            }
+        }
+    }
+
+    /// Sends each of `neighbors` whatever it isn't known to have
+    fn push<P>(
+        &mut self,
+        neighbors: &[NodeID],
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        for neighbor in neighbors {
+            let seen = self.unknown_to(neighbor);
+            self.send(
+                neighbor.clone(),
+                wrap(GossipPayload::Gossip { seen }),
+                output,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sends each of `neighbors` a digest of this node's values
+    fn pull<P>(
+        &self,
+        neighbors: &[NodeID],
+        exchange: bool,
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        if neighbors.is_empty() {
+            return Ok(());
+        }
+        let digest = digest(&self.values);
+        for neighbor in neighbors {
+            self.send(
+                neighbor.clone(),
+                wrap(GossipPayload::GossipDigest {
+                    digest: digest.clone(),
+                    exchange,
+                }),
+                output,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Processes a gossip message from `src`, returning the values that were new to this node
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: GossipPayload<S>,
+        output: &mut impl Write,
+        wrap: impl Fn(GossipPayload<S>) -> P,
+    ) -> anyhow::Result<S>
+    where
+        P: Serialize,
+    {
+        match payload {
+            GossipPayload::Gossip { seen } => {
+                let new = self.values.merge(&seen);
+                self.known.entry(src.clone()).or_default().merge(&seen);
+                Ok(new)
+            }
+            GossipPayload::GossipDigest {
+                digest: theirs,
+                exchange,
+            } => {
+                let ours = digest(&self.values);
+                let stale = ours
+                    .iter()
+                    .zip(theirs.iter().chain(std::iter::repeat(&0)))
+                    .map(|(ours, theirs)| ours != theirs)
+                    .collect::<Vec<_>>();
+                if stale.iter().any(|&stale| stale) {
+                    let mut seen = S::default();
+                    for value in self.values.values() {
+                        if stale[bucket(&value).0] {
+                            seen.insert(value);
+                        }
+                    }
+                    if !seen.is_empty() {
+                        self.send(src.clone(), wrap(GossipPayload::Gossip { seen }), output)?;
+                    }
+                    if exchange {
+                        self.send(
+                            src.clone(),
+                            wrap(GossipPayload::GossipDigest {
+                                digest: ours,
+                                exchange: false,
+                            }),
+                            output,
+                        )?;
+                    }
+                }
+                Ok(S::default())
+            }
+        }
+    }
+
+    /// What to push to `neighbor`: everything it's missing, plus up to 10% of what it has
+    fn unknown_to(&mut self, neighbor: &NodeID) -> S {
+        let known = self.known.entry(neighbor.clone()).or_default();
+        // Everything a peer sends us is merged into our own values, so it knows a subset of them
+        let already_known = known.len() as u32;
+        let additional_cap = already_known / 10;
+        let mut notify_of = S::default();
+        for value in self.values.values() {
+            if !known.contains(&value) || self.rng.gen_ratio(additional_cap, already_known) {
+                notify_of.insert(value);
+            }
+        }
+        notify_of
+    }
+
+    fn send<P>(&self, dst: NodeID, payload: P, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        Message::new(self.node.clone(), dst)
+            .payload(payload)
+            .send(output)
+    }
+}
+
+/// The bucket a value falls in, along with its hash
+fn bucket(value: &impl Hash) -> (usize, u64) {
+    // Keys are fixed, so every node hashes values identically
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    let hash = hasher.finish();
+    ((hash % BUCKETS as u64) as usize, hash)
+}
+
+/// Per-bucket sums of value hashes, which match exactly when two sets agree on the bucket
+/// (barring collisions)
+fn digest(values: &impl GossipSet) -> Vec<u64> {
+    let mut digest = vec![0u64; BUCKETS];
+    for value in values.values() {
+        let (bucket, hash) = bucket(&value);
+        digest[bucket] = digest[bucket].wrapping_add(hash);
+    }
+    digest
+}
@@ -0,0 +1,215 @@
+//! Globally unique IDs in a choice of formats
+//!
+//! Every format is made unique the same way: each ID carries the generating node's index among
+//! the cluster's nodes, along with a millisecond timestamp and a sequence number that counts IDs
+//! handed out within that millisecond. No two nodes share an index and no node reuses a
+//! (millisecond, sequence) pair, so no coordination is needed. Timestamps never go backwards,
+//! even if the clock does, and a node that runs out of sequence numbers borrows the next
+//! millisecond early, so IDs from one node are strictly increasing, and IDs from different
+//! nodes sort by roughly when they were made.
+//!
+//! | Format     | Shape                                             | Nodes | Per node per ms |
+//! |------------|---------------------------------------------------|-------|-----------------|
+//! | `prefixed` | `n1-42`, the node ID and a counter                | any   | unlimited       |
+//! | `numeric`  | a `u64`: 42 bits of time, 10 of node, 12 of count | 1024  | 4096            |
+//! | `ulid`     | 26 characters of Crockford base32                 | 65536 | 65536           |
+//! | `uuid`     | a version 7 UUID                                  | 65536 | 4096            |
+//!
+//! `prefixed` IDs aren't time-ordered, and `numeric` timestamps count from 2020 rather than 1970
+//! so they last until the 2150s.
+use crate::{Init, NodeID};
+use anyhow::{bail, Context};
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Start of `numeric` timestamps: 2020-01-01T00:00:00Z, in milliseconds since the Unix epoch
+const NUMERIC_EPOCH: u64 = 1_577_836_800_000;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// The node ID and a counter, as in `n1-42`
+    #[default]
+    Prefixed,
+    /// A time-ordered 64-bit integer
+    Numeric,
+    /// A [ULID](https://github.com/ulid/spec)
+    Ulid,
+    /// A version 7 UUID, as specified by RFC 9562
+    Uuid,
+}
+
+impl Format {
+    /// How many bits the node index and the sequence number get
+    fn layout(self) -> (u32, u32) {
+        match self {
+            Format::Prefixed => (0, 64),
+            Format::Numeric => (10, 12),
+            Format::Ulid => (16, 16),
+            Format::Uuid => (16, 12),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "prefixed" => Ok(Format::Prefixed),
+            "numeric" | "u64" => Ok(Format::Numeric),
+            "ulid" => Ok(Format::Ulid),
+            "uuid" | "uuidv7" => Ok(Format::Uuid),
+            other => {
+                bail!("unknown ID format {other:?}; expected prefixed, numeric, ulid, or uuid")
+            }
+        }
+    }
+}
+
+/// A generated ID, which is a number or a string depending on the format
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum Id {
+    Number(u64),
+    Text(String),
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Id::Number(n) => write!(f, "{n}"),
+            Id::Text(s) => f.write_str(s),
+        }
+    }
+}
+
+pub struct IdGen {
+    format: Format,
+    node: NodeID,
+    index: u64,
+    /// Timestamp of the last ID, in milliseconds since the Unix epoch
+    millis: u64,
+    /// IDs handed out so far within `millis`
+    sequence: u64,
+    rng: StdRng,
+}
+
+impl IdGen {
+    /// Generates IDs for the node `init` was sent to, filling the bits that aren't needed for
+    /// uniqueness from `rng`
+    ///
+    /// Fails if the cluster has more nodes than the format has room for.
+    pub fn new(format: Format, init: &Init, rng: StdRng) -> anyhow::Result<Self> {
+        let mut nodes: Vec<&str> = init.node_ids.iter().map(|id| id.as_str()).collect();
+        if !nodes.contains(&init.node_id.as_str()) {
+            nodes.push(&init.node_id);
+        }
+        nodes.sort_unstable();
+        nodes.dedup();
+        let index = nodes
+            .iter()
+            .position(|&id| id == init.node_id.as_str())
+            .context("node is among the nodes")? as u64;
+        let (node_bits, _) = format.layout();
+        if format != Format::Prefixed && nodes.len() as u64 > 1 << node_bits {
+            bail!(
+                "{format:?} IDs have room for {} nodes, but the cluster has {}",
+                1u64 << node_bits,
+                nodes.len()
+            );
+        }
+        Ok(Self {
+            format,
+            node: init.node_id.clone(),
+            index,
+            millis: 0,
+            sequence: 0,
+            rng,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn next_id(&mut self) -> Id {
+        let (millis, sequence) = self.tick();
+        match self.format {
+            Format::Prefixed => Id::Text(format!("{}-{}", self.node, sequence + 1)),
+            Format::Numeric => {
+                let millis = millis.saturating_sub(NUMERIC_EPOCH) & ((1 << 42) - 1);
+                Id::Number(millis << 22 | self.index << 12 | sequence)
+            }
+            Format::Ulid => {
+                let random: u64 = self.rng.gen::<u64>() & ((1 << 48) - 1);
+                let id = (millis as u128 & ((1 << 48) - 1)) << 80
+                    | (self.index as u128) << 64
+                    | (sequence as u128) << 48
+                    | random as u128;
+                Id::Text(ulid(id))
+            }
+            Format::Uuid => {
+                let random: u64 = self.rng.gen::<u64>() & ((1 << 46) - 1);
+                let id = (millis as u128 & ((1 << 48) - 1)) << 80
+                    | 0x7 << 76
+                    | (sequence as u128) << 64
+                    | 0b10 << 62
+                    | (self.index as u128) << 46
+                    | random as u128;
+                Id::Text(uuid(id))
+            }
+        }
+    }
+
+    /// The timestamp and sequence number for the next ID
+    fn tick(&mut self) -> (u64, u64) {
+        if self.format == Format::Prefixed {
+            // Only the counter matters, and it never resets
+            let sequence = self.sequence;
+            self.sequence += 1;
+            return (0, sequence);
+        }
+        let (_, sequence_bits) = self.format.layout();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis() as u64);
+        if now > self.millis {
+            self.millis = now;
+            self.sequence = 0;
+        } else if self.sequence >> sequence_bits != 0 {
+            // This millisecond is used up, so start on the next one early
+            self.millis += 1;
+            self.sequence = 0;
+        }
+        let sequence = self.sequence;
+        self.sequence += 1;
+        (self.millis, sequence)
+    }
+}
+
+/// Crockford base32, most significant bits first
+fn ulid(id: u128) -> String {
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD[(id >> (5 * i) & 0x1f) as usize] as char)
+        .collect()
+}
+
+fn uuid(id: u128) -> String {
+    let hex = format!("{id:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
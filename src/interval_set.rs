@@ -0,0 +1,170 @@
+//! A set of integers stored as sorted, disjoint ranges
+//!
+//! Broadcast-style workloads mostly deal in dense runs of integers, which an interval set holds
+//! (and serializes) in space proportional to the number of gaps rather than the number of values.
+//! On the wire it's a JSON array whose elements are either single values or inclusive
+//! `[start, end]` pairs, e.g. `[1, [3, 7], 9]`.
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct IntervalSet {
+    /// Inclusive ranges, keyed by start; never overlapping or adjacent
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value, returning whether it was new
+    pub fn insert(&mut self, value: u64) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.insert_range(value..=value);
+        true
+    }
+
+    /// Adds every value in `range`
+    pub fn insert_range(&mut self, range: RangeInclusive<u64>) {
+        let (mut start, mut end) = range.into_inner();
+        if start > end {
+            return;
+        }
+        // Absorb a range that starts before ours but overlaps or touches it
+        if let Some((&s, &e)) = self.ranges.range(..start).next_back() {
+            if e.saturating_add(1) >= start {
+                start = s;
+                end = end.max(e);
+            }
+        }
+        // ... and every range starting inside ours or right after it
+        let absorbed = self
+            .ranges
+            .range(start..=end.saturating_add(1))
+            .map(|(&s, _)| s)
+            .collect::<Vec<_>>();
+        for s in absorbed {
+            let e = self.ranges.remove(&s).expect("range was just found");
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, &end)| end >= value)
+    }
+
+    /// Adds every value from `other`
+    pub fn union(&mut self, other: &IntervalSet) {
+        for range in other.ranges() {
+            self.insert_range(range);
+        }
+    }
+
+    /// The values in `other` that aren't in this set, worked out range by range
+    pub fn missing_from(&self, other: &IntervalSet) -> IntervalSet {
+        let mut missing = IntervalSet::new();
+        for range in other.ranges() {
+            let (start, end) = range.into_inner();
+            // The first value of the range not yet accounted for
+            let mut next = Some(start);
+            // Every range of ours overlapping this one, including one starting before it
+            let first = self
+                .ranges
+                .range(..=start)
+                .next_back()
+                .map_or(start, |(&s, _)| s);
+            for (&s, &e) in self.ranges.range(first..=end) {
+                let Some(from) = next else {
+                    break;
+                };
+                if e < from {
+                    continue;
+                }
+                if s > from {
+                    missing.insert_range(from..=s - 1);
+                }
+                next = e.checked_add(1).filter(|&n| n <= end);
+            }
+            if let Some(from) = next {
+                missing.insert_range(from..=end);
+            }
+        }
+        missing
+    }
+
+    /// How many values the set holds, or `usize::MAX` if that's more than it can count
+    pub fn len(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|(&start, &end)| {
+                usize::try_from(end - start).map_or(usize::MAX, |n| n.saturating_add(1))
+            })
+            .fold(0, usize::saturating_add)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The set's maximal runs of consecutive values, in ascending order
+    pub fn ranges(&self) -> impl Iterator<Item = RangeInclusive<u64>> + '_ {
+        self.ranges.iter().map(|(&start, &end)| start..=end)
+    }
+
+    /// Every value in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges().flatten()
+    }
+}
+
+impl FromIterator<u64> for IntervalSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<u64> for IntervalSet {
+    fn extend<I: IntoIterator<Item = u64>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Run {
+    One(u64),
+    Range(u64, u64),
+}
+
+impl Serialize for IntervalSet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges().map(|range| match range.into_inner() {
+            (start, end) if start == end => Run::One(start),
+            (start, end) => Run::Range(start, end),
+        }))
+    }
+}
+
+impl<'de> Deserialize<'de> for IntervalSet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut set = Self::new();
+        for run in Vec::<Run>::deserialize(deserializer)? {
+            match run {
+                Run::One(value) => set.insert_range(value..=value),
+                Run::Range(start, end) => set.insert_range(start..=end),
+            }
+        }
+        Ok(set)
+    }
+}
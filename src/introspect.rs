@@ -0,0 +1,128 @@
+//! A built-in `introspect` RPC that every node answers
+//!
+//! Requests are answered by the thread reading input as soon as they arrive, without going
+//! through the node's event queue. That way a node can be queried mid-run whatever its workload,
+//! and even while it's stuck or backed up:
+//!
+//! ```text
+//! {"src": "c1", "dest": "n1", "body": {"type": "introspect", "msg_id": 1}}
+//! ```
+use crate::{runtime::QueueStats, Init, Message, NodeID, Output, Runtime};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum IntrospectPayload {
+    Introspect,
+    IntrospectOk {
+        node_id: NodeID,
+        uptime_ms: u64,
+        /// Messages read from input, including introspection requests
+        received: u64,
+        /// Messages handed to the writer, including introspection replies
+        sent: u64,
+        queue: QueueStats,
+        peers: BTreeMap<NodeID, PeerStats>,
+    },
+}
+
+/// What this node has heard from one of the other nodes
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub received: u64,
+    /// How long ago the last message came in; `None` if nothing has yet
+    pub last_heard_ms: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    received: u64,
+    last: Option<Instant>,
+}
+
+/// Keeps the input thread's tallies, and answers introspection requests from them
+pub(crate) struct Introspector {
+    node: NodeID,
+    started: Instant,
+    received: u64,
+    peers: HashMap<NodeID, Peer>,
+    output: Output,
+}
+
+impl Introspector {
+    pub(crate) fn new(init: &Init, output: Output) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            started: Instant::now(),
+            received: 0,
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|&id| *id != init.node_id)
+                .map(|id| (id.clone(), Peer::default()))
+                .collect(),
+            output,
+        }
+    }
+
+    /// Counts a received message
+    pub(crate) fn observe(&mut self, src: &NodeID) {
+        self.received += 1;
+        if let Some(peer) = self.peers.get_mut(src) {
+            peer.received += 1;
+            peer.last = Some(Instant::now());
+        }
+    }
+
+    /// Answers `line` if it's an introspection request, returning whether it was
+    pub(crate) fn answer<P, I>(
+        &mut self,
+        line: &str,
+        runtime: &Runtime<P, I>,
+    ) -> anyhow::Result<bool> {
+        if !line.contains("introspect") {
+            return Ok(false);
+        }
+        let Ok(request) = serde_json::from_str::<Message<IntrospectPayload>>(line) else {
+            return Ok(false);
+        };
+        if !matches!(request.body.payload, IntrospectPayload::Introspect) {
+            return Ok(false);
+        }
+        self.received += 1;
+        let now = Instant::now();
+        let peers = self
+            .peers
+            .iter()
+            .map(|(id, peer)| {
+                let stats = PeerStats {
+                    received: peer.received,
+                    last_heard_ms: peer.last.map(|last| (now - last).as_millis() as u64),
+                };
+                (id.clone(), stats)
+            })
+            .collect();
+        let payload = IntrospectPayload::IntrospectOk {
+            node_id: self.node.clone(),
+            uptime_ms: (now - self.started).as_millis() as u64,
+            received: self.received,
+            // Counting the reply about to go out
+            sent: self.output.sent() + 1,
+            queue: runtime.queue_stats(),
+            peers,
+        };
+        let reply = Message::new(self.node.clone(), request.src);
+        match request.body.id {
+            Some(id) => reply.in_reply_to(id),
+            None => reply,
+        }
+        .payload(payload)
+        .send(&mut self.output)?;
+        Ok(true)
+    }
+}
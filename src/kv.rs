@@ -0,0 +1,516 @@
+//! A client for Maelstrom's key-value services
+//!
+//! `lin-kv`, `seq-kv` and `lww-kv` all speak the same protocol and differ only in their
+//! consistency guarantees. Calls go through an [`Rpc`], so failures such as a missing key (code
+//! 20) or a failed compare-and-set (code 22) come back as typed [`Error`](crate::Error)s.
+//!
+//! Most uses of a key boil down to reading it, computing a new value, and compare-and-setting it,
+//! starting over whenever another writer got in first. [`Kv::update`] runs that loop itself, so
+//! a counter is one call, with replies fed through [`Kv::progress`]:
+//!
+//! ```ignore
+//! self.kv.update("counter", move |n: Option<i64>| n.unwrap_or(0) + delta, request, ...)?;
+//! ...
+//! Wire::Kv(reply) => match self.kv.progress(body, &mut self.id, output, Wire::Kv)? {
+//!     Some((request, Ok(value))) => ...,
+//!     Some((request, Err(e))) => ...,
+//!     None => {}
+//! }
+//! ```
+use crate::{
+    rpc::{Rpc, ServiceReply},
+    Body, Error, ErrorCode, MessageID, NodeID,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fmt,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Doublings of the backoff stop here
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+/// The linearizable key-value service
+pub const LIN_KV: &str = "lin-kv";
+/// The sequentially consistent key-value service
+pub const SEQ_KV: &str = "seq-kv";
+/// The last-writer-wins key-value service
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvPayload<K, V> {
+    Read {
+        key: K,
+    },
+    ReadOk {
+        value: V,
+    },
+    Write {
+        key: K,
+        value: V,
+    },
+    WriteOk,
+    Cas {
+        key: K,
+        /// `None` (sent as `null`) only matches a missing key, so with `create_if_not_exists`
+        /// the compare-and-set creates the key and fails if anything already exists
+        from: Option<V>,
+        to: V,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+/// What the service made of a call: its `*_ok` reply, or the error it failed with
+pub type KvResult<K, V> = Result<KvPayload<K, V>, Error>;
+
+/// Why an [update](Kv::update) didn't go through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateError {
+    /// Another writer changed the key in between every read and compare-and-set
+    Contended { attempts: u32 },
+    /// The update was still going when its [timeout](Kv::timeout) ran out
+    TimedOut { attempts: u32 },
+    /// The service failed a call for some other reason
+    Service(Error),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Contended { attempts } => {
+                write!(f, "gave up after {attempts} conflicting attempts")
+            }
+            Self::TimedOut { attempts } => write!(f, "timed out after {attempts} attempts"),
+            Self::Service(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// For passing a failed update on to a client; no attempt took effect when contended, but one
+/// may have when timed out
+impl From<UpdateError> for Error {
+    fn from(error: UpdateError) -> Self {
+        match error {
+            UpdateError::Contended { .. } => {
+                Error::new(ErrorCode::PreconditionFailed, error.to_string())
+            }
+            UpdateError::TimedOut { .. } => Error::new(ErrorCode::Timeout, error.to_string()),
+            UpdateError::Service(error) => error,
+        }
+    }
+}
+
+/// What a call to the service was made for
+#[derive(Debug)]
+pub enum Call<C> {
+    /// A single read, write or compare-and-set
+    Plain(C),
+    /// A step of an update
+    Update(Update<C>),
+}
+
+/// A read-modify-compare-and-set loop in progress
+pub struct Update<C> {
+    context: C,
+    key: Value,
+    apply: Box<dyn FnMut(Option<Value>) -> anyhow::Result<Value>>,
+    attempts: u32,
+    /// The value being compare-and-set, once the key has been read
+    writing: Option<Value>,
+    /// When [`Kv::tick`] gives up on it
+    deadline: Instant,
+}
+
+impl<C: fmt::Debug> fmt::Debug for Update<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Update")
+            .field("context", &self.context)
+            .field("key", &self.key)
+            .field("attempts", &self.attempts)
+            .field("writing", &self.writing)
+            .field("deadline", &self.deadline)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Talks to one key-value service, tagging each call with a caller-chosen context `C`
+#[derive(Debug)]
+pub struct Kv<C> {
+    service: NodeID,
+    rpc: Rpc<Call<C>>,
+    max_attempts: u32,
+    backoff: Duration,
+    timeout: Duration,
+    /// Updates waiting out their backoff before trying again
+    backing_off: Vec<(Instant, Update<C>)>,
+}
+
+impl<C> Kv<C> {
+    /// A client for `service` (one of [`LIN_KV`], [`SEQ_KV`] or [`LWW_KV`]) sending as `node`
+    pub fn new(node: impl Into<NodeID>, service: impl Into<NodeID>) -> Self {
+        Self {
+            service: service.into(),
+            rpc: Rpc::new(node),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: Duration::ZERO,
+            timeout: DEFAULT_TIMEOUT,
+            backing_off: Vec::new(),
+        }
+    }
+
+    /// How many times an update reads and compare-and-sets before giving up (10 by default)
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How long an update waits before trying again after its first conflict, doubling with
+    /// every further one
+    ///
+    /// Updates retry straight away by default. With a backoff, retries are only sent from
+    /// [`Kv::tick`].
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How long an update may take, all attempts included, before [`Kv::tick`] gives up on it
+    /// (5 seconds by default)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn read<K, V, P>(
+        &mut self,
+        key: K,
+        context: C,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<MessageID>
+    where
+        P: Serialize,
+    {
+        let payload = wrap(KvPayload::Read { key });
+        self.rpc.call(
+            self.service.clone(),
+            payload,
+            Call::Plain(context),
+            id,
+            output,
+        )
+    }
+
+    pub fn write<K, V, P>(
+        &mut self,
+        key: K,
+        value: V,
+        context: C,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<MessageID>
+    where
+        P: Serialize,
+    {
+        let payload = wrap(KvPayload::Write { key, value });
+        self.rpc.call(
+            self.service.clone(),
+            payload,
+            Call::Plain(context),
+            id,
+            output,
+        )
+    }
+
+    /// Replaces the value at `key` with `to` if it's currently `from`, creating it when missing
+    /// if `create_if_not_exists` is set
+    ///
+    /// A `from` of `None` only matches a missing key.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cas<K, V, P>(
+        &mut self,
+        key: K,
+        from: Option<V>,
+        to: V,
+        create_if_not_exists: bool,
+        context: C,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<MessageID>
+    where
+        P: Serialize,
+    {
+        let payload = wrap(KvPayload::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        });
+        self.rpc.call(
+            self.service.clone(),
+            payload,
+            Call::Plain(context),
+            id,
+            output,
+        )
+    }
+
+    /// Sets `key` to `f` of its current value (`None` if it doesn't exist yet), by reading it
+    /// and compare-and-setting the result, and starting over with a fresh read whenever another
+    /// writer changed it in between
+    ///
+    /// `f` may run once per attempt. Feed replies through [`Kv::progress`] to move the update
+    /// along and learn how it ended, and call [`Kv::tick`] periodically to learn of updates that
+    /// timed out.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update<K, V, P>(
+        &mut self,
+        key: K,
+        mut f: impl FnMut(Option<V>) -> V + 'static,
+        context: C,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+        P: Serialize,
+    {
+        let apply = Box::new(move |current: Option<Value>| {
+            let current = current
+                .map(serde_json::from_value)
+                .transpose()
+                .context("value read for update has the wrong type")?;
+            serde_json::to_value(f(current)).context("updated value could not be serialized")
+        });
+        let update = Update {
+            context,
+            key: serde_json::to_value(key).context("key could not be serialized")?,
+            apply,
+            attempts: 0,
+            writing: None,
+            deadline: Instant::now() + self.timeout,
+        };
+        self.read_for(update, id, output, &wrap)
+    }
+
+    /// Retries updates whose backoff is over, and gives up on those past their
+    /// [timeout](Kv::timeout), returning their contexts; meant to be driven by a periodic
+    /// injected event
+    ///
+    /// A late reply to a timed out update is ignored.
+    pub fn tick<K, V, P>(
+        &mut self,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<Vec<(C, UpdateError)>>
+    where
+        K: DeserializeOwned,
+        P: Serialize,
+    {
+        let now = Instant::now();
+        let timed_out = |update: Update<C>| {
+            let attempts = update.attempts;
+            (update.context, UpdateError::TimedOut { attempts })
+        };
+        let mut expired: Vec<_> = self
+            .rpc
+            .cancel_where(|call| matches!(call, Call::Update(update) if update.deadline <= now))
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Update(update) => Some(timed_out(update)),
+                Call::Plain(_) => None,
+            })
+            .collect();
+        let (due, waiting) = std::mem::take(&mut self.backing_off)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(at, update)| *at <= now || update.deadline <= now);
+        self.backing_off = waiting;
+        for (_, update) in due {
+            if update.deadline <= now {
+                expired.push(timed_out(update));
+            } else {
+                self.read_for(update, id, output, &wrap)?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Matches a reply from the service to its call; see [`Rpc::complete`]
+    ///
+    /// Replies to an update's calls are left for [`Kv::progress`].
+    pub fn complete<K, V>(
+        &mut self,
+        reply: Body<ServiceReply<KvPayload<K, V>>>,
+    ) -> Option<(C, KvResult<K, V>)> {
+        if !matches!(self.rpc.context(reply.in_reply_to?)?, Call::Plain(_)) {
+            return None;
+        }
+        match self.rpc.complete(reply)? {
+            (Call::Plain(context), result) => Some((context, result)),
+            (Call::Update(_), _) => unreachable!("only plain calls are completed"),
+        }
+    }
+
+    /// Moves the update a reply belongs to along, returning its context and outcome once it's
+    /// done: the value written, or why it couldn't be
+    ///
+    /// Returns `None` for replies to anything other than an update's calls, which are left for
+    /// [`Kv::complete`], and while the update is still going.
+    #[allow(clippy::type_complexity)]
+    pub fn progress<K, V, P>(
+        &mut self,
+        reply: Body<ServiceReply<KvPayload<K, V>>>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<Option<(C, Result<V, UpdateError>)>>
+    where
+        K: DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+        P: Serialize,
+    {
+        let Some(call) = reply.in_reply_to else {
+            return Ok(None);
+        };
+        if !matches!(self.rpc.context(call), Some(Call::Update(_))) {
+            return Ok(None);
+        }
+        let Some((Call::Update(mut update), result)) = self.rpc.complete(reply) else {
+            return Ok(None);
+        };
+        match (result, update.writing.take()) {
+            (Ok(KvPayload::ReadOk { value }), None) => {
+                let current = serde_json::to_value(value).context("read value")?;
+                self.write_for(update, Some(current), id, output, &wrap)?;
+                Ok(None)
+            }
+            (
+                Err(Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }),
+                None,
+            ) => {
+                self.write_for(update, None, id, output, &wrap)?;
+                Ok(None)
+            }
+            (Ok(KvPayload::CasOk), Some(written)) => {
+                let written = serde_json::from_value(written).context("written value")?;
+                Ok(Some((update.context, Ok(written))))
+            }
+            // Another writer got in between our read and compare-and-set
+            (
+                Err(Error {
+                    code: ErrorCode::PreconditionFailed | ErrorCode::KeyDoesNotExist,
+                    ..
+                }),
+                Some(_),
+            ) => {
+                if update.attempts >= self.max_attempts {
+                    let attempts = update.attempts;
+                    return Ok(Some((
+                        update.context,
+                        Err(UpdateError::Contended { attempts }),
+                    )));
+                }
+                if self.backoff.is_zero() {
+                    self.read_for(update, id, output, &wrap)?;
+                } else {
+                    let doublings = (update.attempts - 1).min(MAX_BACKOFF_DOUBLINGS);
+                    let at = Instant::now() + self.backoff * (1 << doublings);
+                    self.backing_off.push((at, update));
+                }
+                Ok(None)
+            }
+            (Err(error), _) => Ok(Some((update.context, Err(UpdateError::Service(error))))),
+            (Ok(_), _) => anyhow::bail!("unexpected reply to an update from {}", self.service),
+        }
+    }
+
+    /// Starts another attempt at an update by reading its key
+    fn read_for<K, V, P>(
+        &mut self,
+        mut update: Update<C>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: &impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        K: DeserializeOwned,
+        P: Serialize,
+    {
+        update.attempts += 1;
+        let key = serde_json::from_value(update.key.clone()).context("update key")?;
+        let payload = wrap(KvPayload::Read { key });
+        self.rpc.call(
+            self.service.clone(),
+            payload,
+            Call::Update(update),
+            id,
+            output,
+        )?;
+        Ok(())
+    }
+
+    /// Compare-and-sets an update's key from `current` to whatever the update makes of it
+    fn write_for<K, V, P>(
+        &mut self,
+        mut update: Update<C>,
+        current: Option<Value>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: &impl Fn(KvPayload<K, V>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+        P: Serialize,
+    {
+        let to = (update.apply)(current.clone())?;
+        // A missing key is only created if nobody else has in the meantime; otherwise the
+        // compare-and-set fails and the update starts over from what they wrote
+        let create_if_not_exists = current.is_none();
+        let from = current
+            .map(serde_json::from_value)
+            .transpose()
+            .context("value read for update")?;
+        let payload = wrap(KvPayload::Cas {
+            key: serde_json::from_value(update.key.clone()).context("update key")?,
+            from,
+            to: serde_json::from_value(to.clone()).context("updated value")?,
+            create_if_not_exists,
+        });
+        update.writing = Some(to);
+        self.rpc.call(
+            self.service.clone(),
+            payload,
+            Call::Update(update),
+            id,
+            output,
+        )?;
+        Ok(())
+    }
+
+    pub fn rpc(&self) -> &Rpc<Call<C>> {
+        &self.rpc
+    }
+}
@@ -0,0 +1,221 @@
+//! Measuring round-trip times between nodes and building a low-latency overlay from them
+//!
+//! Every round, each node pings all of its peers, folds the pongs into a moving average per
+//! peer, and tells everyone its current row of measurements. Once a node has heard a row from
+//! every node, [`LatencyMap::overlay`] turns the (symmetrized) matrix into a spanning tree that
+//! keeps the worst-case propagation delay low: the tree hangs off the node whose farthest peer is
+//! nearest, and every other node joins wherever reaching it from the root is quickest, with each
+//! node's fan-out capped so no one node carries all the traffic. Every node computes the same
+//! tree from the same rows, so trees agree once the rows have spread.
+//!
+//! Like other library payloads, [`LatencyPayload`] is embedded in a node's payload through an
+//! untagged enum.
+use crate::{Body, Init, Message, MessageID, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Weight of the newest sample in the moving average
+const SMOOTHING: f64 = 0.25;
+
+/// Pings unanswered for this long are forgotten
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyPayload {
+    Ping,
+    Pong,
+    /// The sender's smoothed round-trip times to its peers, in microseconds
+    LatencyReport {
+        version: u64,
+        rtts: BTreeMap<NodeID, u64>,
+    },
+}
+
+#[derive(Debug)]
+pub struct LatencyMap {
+    node: NodeID,
+    nodes: Vec<NodeID>,
+    pending: HashMap<MessageID, (NodeID, Instant)>,
+    /// Our own smoothed round-trip times, in microseconds
+    own: BTreeMap<NodeID, f64>,
+    version: u64,
+    /// The latest row reported by every node, ours included
+    rows: BTreeMap<NodeID, (u64, BTreeMap<NodeID, u64>)>,
+}
+
+impl LatencyMap {
+    pub fn new(init: &Init) -> Self {
+        let mut nodes = init.node_ids.clone();
+        nodes.sort();
+        Self {
+            node: init.node_id.clone(),
+            nodes,
+            pending: HashMap::new(),
+            own: BTreeMap::new(),
+            version: 0,
+            rows: BTreeMap::new(),
+        }
+    }
+
+    /// Pings every peer and reports our latest measurements to them
+    ///
+    /// Meant to be driven by a periodic injected event.
+    pub fn tick<P>(
+        &mut self,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(LatencyPayload) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        self.pending
+            .retain(|_, (_, sent)| sent.elapsed() < PING_TIMEOUT);
+        self.version += 1;
+        let rtts: BTreeMap<_, _> = self
+            .own
+            .iter()
+            .map(|(peer, &rtt)| (peer.clone(), rtt.round() as u64))
+            .collect();
+        self.rows
+            .insert(self.node.clone(), (self.version, rtts.clone()));
+        for peer in self.nodes.iter().filter(|&peer| *peer != self.node) {
+            let ping = Message::new(self.node.clone(), peer.clone())
+                .with_id(id)
+                .payload(wrap(LatencyPayload::Ping));
+            self.pending.insert(
+                ping.body.id.expect("pings have an ID"),
+                (peer.clone(), Instant::now()),
+            );
+            ping.send(output)?;
+            Message::new(self.node.clone(), peer.clone())
+                .payload(wrap(LatencyPayload::LatencyReport {
+                    version: self.version,
+                    rtts: rtts.clone(),
+                }))
+                .send(output)?;
+        }
+        Ok(())
+    }
+
+    /// Answers pings, times pongs, and records other nodes' reports
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        body: Body<LatencyPayload>,
+        output: &mut impl Write,
+        wrap: impl Fn(LatencyPayload) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        match body.payload {
+            LatencyPayload::Ping => {
+                let pong = Message::new(self.node.clone(), src.clone());
+                let pong = match body.id {
+                    Some(id) => pong.in_reply_to(id),
+                    None => pong,
+                };
+                pong.payload(wrap(LatencyPayload::Pong)).send(output)?;
+            }
+            LatencyPayload::Pong => {
+                let Some((peer, sent)) =
+                    body.in_reply_to.and_then(|ping| self.pending.remove(&ping))
+                else {
+                    return Ok(());
+                };
+                let sample = sent.elapsed().as_secs_f64() * 1e6;
+                let rtt = self.own.entry(peer).or_insert(sample);
+                *rtt += SMOOTHING * (sample - *rtt);
+            }
+            LatencyPayload::LatencyReport { version, rtts } => {
+                let row = self.rows.entry(src.clone()).or_insert((0, BTreeMap::new()));
+                if version > row.0 {
+                    *row = (version, rtts);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Our smoothed round-trip time to `peer`
+    pub fn rtt(&self, peer: &str) -> Option<Duration> {
+        let rtt = self.own.get(peer)?;
+        Some(Duration::from_micros(rtt.round() as u64))
+    }
+
+    /// A spanning tree over every node in which nobody has more than `max_children` children,
+    /// as each node's neighbors; `None` until every pair of nodes has been measured
+    pub fn overlay(&self, max_children: usize) -> Option<BTreeMap<NodeID, Vec<NodeID>>> {
+        let n = self.nodes.len();
+        let max_children = max_children.max(1);
+        let measured = |a: usize, b: usize| {
+            let (_, row) = self.rows.get(&self.nodes[a])?;
+            row.get(&self.nodes[b]).map(|&rtt| rtt as f64)
+        };
+        // Symmetrized: the average of what each side measured
+        let weight = (0..n)
+            .map(|i| {
+                (0..n)
+                    .map(|j| match (measured(i, j), measured(j, i)) {
+                        _ if i == j => Some(0.0),
+                        (Some(a), Some(b)) => Some((a + b) / 2.0),
+                        (Some(w), None) | (None, Some(w)) => Some(w),
+                        (None, None) => None,
+                    })
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // Shortest paths between every pair, to find the most central node
+        let mut dist = weight.clone();
+        for k in 0..n {
+            for i in 0..n {
+                for j in 0..n {
+                    if dist[i][k] + dist[k][j] < dist[i][j] {
+                        dist[i][j] = dist[i][k] + dist[k][j];
+                    }
+                }
+            }
+        }
+        let eccentricity = |i: usize| dist[i].iter().copied().fold(0.0, f64::max);
+        let root = (0..n).min_by(|&a, &b| eccentricity(a).total_cmp(&eccentricity(b)))?;
+
+        // Grow the tree from the root, always attaching whichever node can be reached soonest
+        let mut reached = vec![None; n];
+        let mut children = vec![0; n];
+        reached[root] = Some(0.0);
+        let mut neighbors: BTreeMap<NodeID, Vec<NodeID>> = self
+            .nodes
+            .iter()
+            .map(|node| (node.clone(), Vec::new()))
+            .collect();
+        for _ in 1..n {
+            let (parent, child, at) = (0..n)
+                .filter_map(|p| {
+                    reached[p]
+                        .filter(|_| children[p] < max_children)
+                        .map(|at| (p, at))
+                })
+                .flat_map(|(p, at)| {
+                    (0..n)
+                        .filter(|&c| reached[c].is_none())
+                        .map(move |c| (p, c, at))
+                })
+                .map(|(p, c, at)| (p, c, at + weight[p][c]))
+                .min_by(|a, b| a.2.total_cmp(&b.2))?;
+            reached[child] = Some(at);
+            children[parent] += 1;
+            let (parent, child) = (&self.nodes[parent], &self.nodes[child]);
+            neighbors.get_mut(parent)?.push(child.clone());
+            neighbors.get_mut(child)?.push(parent.clone());
+        }
+        Some(neighbors)
+    }
+}
@@ -1,46 +1,184 @@
+pub mod broadcast;
+pub mod cbor;
+pub mod coalesce;
+pub mod concurrent;
+pub mod encoding;
+pub mod failure_detector;
+pub mod faults;
+pub mod forward;
+pub mod framing;
+pub mod global_snapshot;
+pub mod gossip;
+pub mod idgen;
+pub mod interval_set;
+pub mod introspect;
+pub mod kv;
+pub mod latency;
+pub mod merkle;
+pub mod model;
+pub mod node_id;
+pub mod options;
+pub mod output;
+pub mod rate_limit;
+pub mod replication;
+pub mod rpc;
+pub mod runtime;
+pub mod session;
+pub mod sharding;
+pub mod shared;
+pub mod signals;
+pub mod sim;
+pub mod snapshot;
+pub mod term;
+pub mod timer;
+pub mod tob;
+pub mod tpc;
+pub mod tracing;
+pub mod transcript;
+pub mod value;
+pub mod wal;
+pub mod watchdog;
+pub mod workloads;
+
+// Lets code generated by `#[workload]` name this crate from inside it
+extern crate self as rasengan;
+
+pub use concurrent::{concurrent_main_loop, concurrent_run_with_io, ConcurrentNode};
+pub use node_id::NodeID;
+pub use options::{ErrorPolicy, Options};
+pub use output::Output;
+pub use rasengan_derive::workload;
+pub use runtime::{Jitter, QueueStats, Runtime};
+pub use shared::Shared;
+pub use timer::TimerHandle;
+
 use anyhow::Context;
+use failure_detector::Liveness;
+use framing::Documents;
+use introspect::Introspector;
+use runtime::{Queued, Stamp};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use signals::Signals;
+use snapshot::SnapshotStore;
 use std::{
     collections::{HashMap, HashSet},
-    io::{BufRead, StdoutLock, Write},
+    io::{BufRead, BufReader, Write},
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
+use tracing::{Span, Tracer};
+use value::JsonValue;
+use watchdog::Watchdog;
 
 #[derive(Debug, Clone)]
 pub enum Event<Payload, InjectedPayload = ()> {
     Message(Message<Payload>),
     Injected(InjectedPayload),
+    /// The runtime's heartbeats suspect that a peer has failed
+    PeerDown(NodeID),
+    /// A peer that was suspected has been heard from again
+    PeerUp(NodeID),
     Shutdown,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<Payload> {
-    pub src: String,
+    pub src: NodeID,
     #[serde(rename = "dest")]
-    pub dst: String,
+    pub dst: NodeID,
     pub body: Body<Payload>,
 }
 
+impl Message<()> {
+    /// Starts building a new message (as opposed to a reply) from `src` to `dst`
+    ///
+    /// ```ignore
+    /// Message::new(self.node.clone(), neighbor.clone())
+    ///     .with_id(&mut self.id)
+    ///     .payload(Payload::Gossip { seen })
+    ///     .send(output)?;
+    /// ```
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(src: impl Into<NodeID>, dst: impl Into<NodeID>) -> MessageBuilder {
+        MessageBuilder {
+            src: src.into(),
+            dst: dst.into(),
+            id: None,
+            in_reply_to: None,
+        }
+    }
+}
+
 impl<Payload> Message<Payload> {
+    /// Like [`Message::into_reply`], taking the reply's ID from a shared allocator
+    pub fn into_reply_from(self, ids: &MsgIdAllocator) -> Self {
+        Self {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                id: Some(ids.next()),
+                in_reply_to: self.body.id,
+                payload: self.body.payload,
+            },
+        }
+    }
+
     /// Converts a message into a reply to the given message
     pub fn into_reply(self, id: Option<&mut MessageID>) -> Self {
         Self {
             src: self.dst,
             dst: self.src,
             body: Body {
-                id: id.map(|id| {
-                    *id += 1;
-                    *id
-                }),
+                id: id.map(next_id),
                 in_reply_to: self.body.id,
                 payload: self.body.payload,
             },
         }
     }
 
+    /// Swaps the payload for one of another type, keeping the addressing and IDs
+    ///
+    /// Handy for replies that borrow from the node instead of owning a copy of its state.
+    pub fn with_payload<Other>(self, payload: Other) -> Message<Other> {
+        Message {
+            src: self.src,
+            dst: self.dst,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload,
+            },
+        }
+    }
+
+    /// Takes the payload out, leaving the addressing and IDs to go with another one later
+    pub fn split_payload(self) -> (Message<()>, Payload) {
+        let Body {
+            id,
+            in_reply_to,
+            payload,
+        } = self.body;
+        let rest = Message {
+            src: self.src,
+            dst: self.dst,
+            body: Body {
+                id,
+                in_reply_to,
+                payload: (),
+            },
+        };
+        (rest, payload)
+    }
+
     /// Send a message to the given output stream
-    pub fn send(&self, output: &mut impl Write) -> anyhow::Result<()>
+    pub fn send<W>(&self, output: &mut W) -> anyhow::Result<()>
     where
         Payload: Serialize,
+        W: Write + ?Sized,
     {
         serde_json::to_writer(&mut *output, self)?;
         writeln!(output)?;
@@ -48,6 +186,71 @@ impl<Payload> Message<Payload> {
     }
 }
 
+/// Builds a message that doesn't reply to anything; see [`Message::new`]
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    src: NodeID,
+    dst: NodeID,
+    id: Option<MessageID>,
+    in_reply_to: Option<MessageID>,
+}
+
+impl MessageBuilder {
+    /// Allocates the message an ID from the given counter, so the recipient can reply to it
+    pub fn with_id(mut self, id: &mut MessageID) -> Self {
+        self.id = Some(next_id(id));
+        self
+    }
+
+    /// Like [`MessageBuilder::with_id`], taking the ID from a shared allocator
+    pub fn with_id_from(mut self, ids: &MsgIdAllocator) -> Self {
+        self.id = Some(ids.next());
+        self
+    }
+
+    pub fn in_reply_to(mut self, id: MessageID) -> Self {
+        self.in_reply_to = Some(id);
+        self
+    }
+
+    pub fn payload<Payload>(self, payload: Payload) -> Message<Payload> {
+        Message {
+            src: self.src,
+            dst: self.dst,
+            body: Body {
+                id: self.id,
+                in_reply_to: self.in_reply_to,
+                payload,
+            },
+        }
+    }
+}
+
+/// Allocates the next message ID from a node's counter
+fn next_id(id: &mut MessageID) -> MessageID {
+    *id += 1;
+    *id
+}
+
+/// A message ID counter that any thread can allocate from
+///
+/// Clones share the counter. Every [`Runtime`] handle carries the node's allocator (see
+/// [`Runtime::ids`]), so background threads can send requests without going through the state
+/// `step` has `&mut` access to. IDs are unique per allocator only, so a node should use either
+/// this or its own counter for the messages it originates, not both.
+#[derive(Debug, Clone, Default)]
+pub struct MsgIdAllocator(Arc<AtomicUsize>);
+
+impl MsgIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next(&self) -> MessageID {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Body<Payload> {
     #[serde(rename = "msg_id")]
@@ -57,14 +260,27 @@ pub struct Body<Payload> {
     pub payload: Payload,
 }
 
-pub type NodeID = String;
+/// Implemented for payload enums by the [`workload`] attribute
+///
+/// Lets code that's generic over payloads, such as a router or a session cache, tell replies
+/// apart from requests, and a node match on its requests alone.
+pub trait Workload: Sized {
+    /// The generated enum of requests alone
+    type Request;
+
+    /// Whether this is one of the generated `*_ok` replies rather than a request
+    fn is_reply(&self) -> bool;
+
+    /// The request this is, or the reply back if it's a generated reply
+    fn into_request(self) -> Result<Self::Request, Self>;
+}
 pub type MessageID = usize;
 
 pub trait Node<State, Payload, InjectedPayload = ()> {
     fn from_init(
         state: State,
         init: Init,
-        inject: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        runtime: Runtime<Payload, InjectedPayload>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized;
@@ -72,8 +288,31 @@ pub trait Node<State, Payload, InjectedPayload = ()> {
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> anyhow::Result<()>;
+
+    /// Captures the node's state so the runtime can persist it; `None` opts out of snapshots
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Reinstates state captured by [`Node::snapshot`], called right after `from_init` when a
+    /// persisted snapshot exists
+    fn restore(&mut self, snapshot: serde_json::Value) -> anyhow::Result<()> {
+        let _ = snapshot;
+        Ok(())
+    }
+
+    /// Called once the latest [`Node::snapshot`] has been durably saved, so whatever else the
+    /// node keeps to recover the same state, such as a write-ahead log, can be compacted
+    fn snapshotted(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The state written to stderr when a debug dump is requested; defaults to the snapshot
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        self.snapshot()
+    }
 }
 
 pub fn main_loop<State, NodeType, Payload, InjectedPayload>(init_state: State) -> anyhow::Result<()>
@@ -82,25 +321,359 @@ where
     NodeType: Node<State, Payload, InjectedPayload>,
     InjectedPayload: Send + 'static,
 {
-    let (tx, rx) = std::sync::mpsc::channel();
+    let options = Options::from_env()?.with_args(std::env::args().skip(1))?;
+    main_loop_with::<_, NodeType, _, _>(init_state, options)
+}
+
+pub fn main_loop_with<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: Node<State, Payload, InjectedPayload>,
+    InjectedPayload: Send + 'static,
+{
+    serve::<_, NodeType, _, _>(
+        init_state,
+        options,
+        BufReader::new(std::io::stdin()),
+        std::io::stdout(),
+        true,
+    )
+}
 
-    let stdin = std::io::stdin().lock();
-    let mut stdin = stdin.lines();
-    let mut stdout = std::io::stdout().lock();
+/// Runs a node over arbitrary input and output instead of stdin and stdout
+///
+/// Returns once `reader` is exhausted and the node has handled [`Event::Shutdown`], which makes
+/// it possible to drive a node from tests or over other transports such as sockets or pipes.
+pub fn run_with_io<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write + Send + 'static,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: Node<State, Payload, InjectedPayload>,
+    InjectedPayload: Send + 'static,
+{
+    serve::<_, NodeType, _, _>(init_state, options, reader, writer, false)
+}
 
-    let init_msg: Message<InitPayload> = serde_json::from_str(
-        &stdin
-            .next()
-            .expect("no init message received")
-            .context("failed to read init message from stdin")?,
+/// Runs a node, shutting it down on SIGTERM and SIGINT if `signals` is set
+fn serve<State, NodeType, Payload, InjectedPayload>(
+    init_state: State,
+    options: Options,
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write + Send + 'static,
+    signals: bool,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    NodeType: Node<State, Payload, InjectedPayload>,
+    InjectedPayload: Send + 'static,
+{
+    serve_with(
+        init_state,
+        options,
+        reader,
+        writer,
+        signals,
+        |state, init, runtime, snapshot, supervisor, _output| {
+            let mut node: NodeType =
+                Node::from_init(state, init, runtime).context("node initialization failed")?;
+            if let Some(snapshot) = snapshot {
+                node.restore(snapshot)
+                    .context("node could not be restored from snapshot")?;
+            }
+            Ok(Sequential {
+                node,
+                supervisor,
+                _state: PhantomData,
+            })
+        },
     )
-    .context("init message could not be deserialized")?;
-    let InitPayload::Init(init) = init_msg.body.payload else {
-        panic!("first message should be init");
-    };
-    let mut node: NodeType =
-        Node::from_init(init_state, init, tx.clone()).context("node initialization failed")?;
+}
+
+/// How [`serve_with`] gets events stepped; it takes care of everything else
+pub(crate) trait Stepper<Payload, InjectedPayload> {
+    /// Steps `input`, or hands it to whatever will; `false` means no more events can be taken
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+        output: &mut Output,
+    ) -> anyhow::Result<bool>;
+
+    /// Waits for everything handed to [`Stepper::step`] to be stepped, then steps the shutdown,
+    /// if there is one
+    fn finish(&mut self, shutdown: Option<Stamp>, output: &mut Output) -> anyhow::Result<()>;
+
+    /// Captures the node's state with no step in progress
+    fn snapshot(&mut self) -> Option<serde_json::Value>;
 
+    fn snapshotted(&mut self) -> anyhow::Result<()>;
+
+    fn debug_state(&self) -> Option<serde_json::Value>;
+}
+
+/// Runs steps under the error policy, the watchdog, and the tracer, from any thread
+pub(crate) struct Supervisor {
+    on_error: ErrorPolicy,
+    watchdog: Option<Watchdog>,
+    tracer: Option<Mutex<Tracer>>,
+}
+
+impl Supervisor {
+    pub(crate) fn step<Payload, InjectedPayload>(
+        &self,
+        input: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+        output: &mut Output,
+        step: impl FnOnce(Event<Payload, InjectedPayload>, &mut Output) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let watch = self
+            .watchdog
+            .as_ref()
+            .map(|watchdog| watchdog.watch(&input, &stamp));
+        let span = self
+            .tracer
+            .is_some()
+            .then(|| Span::start(&input, stamp, output));
+        supervise(input, output, self.on_error, step).context("Node step function failed")?;
+        drop(watch);
+        if let (Some(span), Some(tracer)) = (span, &self.tracer) {
+            span.finish(output, &mut lock(tracer));
+        }
+        Ok(())
+    }
+
+    fn dump(&self, node_id: &str) {
+        if let Some(tracer) = &self.tracer {
+            lock(tracer).dump(node_id);
+        }
+    }
+}
+
+fn lock(tracer: &Mutex<Tracer>) -> MutexGuard<'_, Tracer> {
+    // A step that panicked mid-record leaves at worst one event half-counted
+    tracer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Steps a [`Node`] on the thread taking events off the queue
+struct Sequential<NodeType, State> {
+    node: NodeType,
+    supervisor: Arc<Supervisor>,
+    _state: PhantomData<fn() -> State>,
+}
+
+impl<State, NodeType, Payload, InjectedPayload> Stepper<Payload, InjectedPayload>
+    for Sequential<NodeType, State>
+where
+    NodeType: Node<State, Payload, InjectedPayload>,
+{
+    fn step(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+        output: &mut Output,
+    ) -> anyhow::Result<bool> {
+        let node = &mut self.node;
+        self.supervisor
+            .step(input, stamp, output, |input, output| {
+                node.step(input, output)
+            })?;
+        Ok(true)
+    }
+
+    fn finish(&mut self, shutdown: Option<Stamp>, output: &mut Output) -> anyhow::Result<()> {
+        match shutdown {
+            Some(stamp) => self.step(Event::Shutdown, stamp, output).map(drop),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&mut self) -> Option<serde_json::Value> {
+        self.node.snapshot()
+    }
+
+    fn snapshotted(&mut self) -> anyhow::Result<()> {
+        self.node.snapshotted()
+    }
+
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        self.node.debug_state()
+    }
+}
+
+/// Runs whatever `start` builds over `reader` and `writer`, shutting it down on SIGTERM and
+/// SIGINT if `signals` is set
+///
+/// `start` gets the node's init, its runtime, and the snapshot to restore, if one was persisted.
+pub(crate) fn serve_with<State, Payload, InjectedPayload, S>(
+    init_state: State,
+    options: Options,
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write + Send + 'static,
+    signals: bool,
+    start: impl FnOnce(
+        State,
+        Init,
+        Runtime<Payload, InjectedPayload>,
+        Option<serde_json::Value>,
+        Arc<Supervisor>,
+        &Output,
+    ) -> anyhow::Result<S>,
+) -> anyhow::Result<()>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    InjectedPayload: Send + 'static,
+    S: Stepper<Payload, InjectedPayload>,
+{
+    let mut documents = Documents::new(reader).max_len(options.max_message_bytes);
+    let mut output = Output::spawn_with(writer, options.rate_limit);
+
+    let (init_msg, init) = read_init(&mut documents, options.log_input)?;
+    let node_id = init.node_id.clone();
+    let liveness = Liveness::new(&init, options.heartbeat_interval, options.suspicion);
+    let (runtime, rx) = Runtime::new(options.queue_capacity, &init, options.seed(), liveness);
+    let runtime = runtime.with_jitter(options.tick_jitter);
+    let tx = runtime.clone();
+    let signals = signals
+        .then(|| Signals::forward(runtime.clone()))
+        .transpose()?;
+    let introspector = Introspector::new(&init, output.handle());
+
+    let mut snapshots = options
+        .snapshot_dir
+        .as_ref()
+        .map(|dir| SnapshotStore::new(dir, &node_id, options.snapshot_interval))
+        .transpose()?;
+    let snapshot = snapshots
+        .as_ref()
+        .map(SnapshotStore::load)
+        .transpose()?
+        .flatten();
+    let supervisor = Arc::new(Supervisor {
+        on_error: options.on_error,
+        watchdog: options
+            .watchdog
+            .map(|limit| Watchdog::spawn(limit, options.watchdog_reply, output.handle())),
+        tracer: options.trace.then(|| Mutex::new(Tracer::new())),
+    });
+    let mut stepper = start(
+        init_state,
+        init,
+        runtime,
+        snapshot,
+        Arc::clone(&supervisor),
+        &output,
+    )?;
+
+    send_init_ok(init_msg, &mut output)?;
+
+    if let Some(interval) = options.debug_dump_interval {
+        tx.dump_every(interval);
+    }
+    tx.heartbeat(output.handle());
+    let jh = spawn_input(documents, tx, introspector, output.handle(), &options);
+
+    let mut shutdown = None;
+    for queued in rx {
+        let (input, stamp) = match queued {
+            Queued::Event(input, stamp) => (input, stamp),
+            Queued::DebugDump => {
+                dump_debug_state(&node_id, stepper.debug_state());
+                supervisor.dump(&node_id);
+                continue;
+            }
+        };
+        if let Event::Shutdown = input {
+            // Nothing else is coming in
+            shutdown = Some(stamp);
+            break;
+        }
+        if !stepper.step(input, stamp, &mut output)? {
+            // Whatever failed is surfaced by finishing
+            break;
+        }
+        if let Some(store) = snapshots.as_mut().filter(|store| store.due()) {
+            if let Some(snapshot) = stepper.snapshot() {
+                store.save(&snapshot).context("failed to save snapshot")?;
+                stepper.snapshotted()?;
+            }
+        }
+    }
+    stepper.finish(shutdown, &mut output)?;
+
+    if let Some(store) = &mut snapshots {
+        if let Some(snapshot) = stepper.snapshot() {
+            store
+                .save(&snapshot)
+                .context("failed to save final snapshot")?;
+            stepper.snapshotted()?;
+        }
+    }
+
+    supervisor.dump(&node_id);
+    // The watchdog's thread holds an output handle, which would keep the writer open
+    drop(stepper);
+    drop(supervisor);
+
+    if signals.is_some_and(|signals| signals.received()) {
+        // The input thread may be blocked reading input that never closes, and it holds an
+        // output handle
+        return output.close_now();
+    }
+    output.close()?;
+    jh.join()
+        .expect("input thread panicked")
+        .context("input thread err'd")?;
+
+    Ok(())
+}
+
+/// Reads the init message Maelstrom sends before anything else
+pub(crate) fn read_init(
+    input: &mut impl Iterator<Item = std::io::Result<String>>,
+    log_input: bool,
+) -> anyhow::Result<(Message<InitPayload>, Init)> {
+    loop {
+        let Some(line) = input.next() else {
+            anyhow::bail!("input ended before an init message arrived");
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                eprintln!("rasengan: ignoring input: {e}");
+                continue;
+            }
+            Err(e) => return Err(e).context("failed to read init message"),
+        };
+        if log_input {
+            record_input(&line);
+        }
+        match serde_json::from_str::<Message<InitPayload>>(&line) {
+            Ok(init_msg) => {
+                let InitPayload::Init(init) = &init_msg.body.payload else {
+                    eprintln!("rasengan: ignoring input that came before init: {line}");
+                    continue;
+                };
+                let init = init.clone();
+                return Ok((init_msg, init));
+            }
+            Err(e) => report_bad_input(&line, &e),
+        }
+    }
+}
+
+pub(crate) fn send_init_ok(
+    init_msg: Message<InitPayload>,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
     let reply = Message {
         src: init_msg.dst,
         dst: init_msg.src,
@@ -110,34 +683,183 @@ where
             payload: InitPayload::InitOk,
         },
     };
-    serde_json::to_writer(&mut stdout, &reply).context("serialize response to init")?;
-    stdout.write_all(b"\n").context("write trailing newline")?;
-
-    drop(stdin);
-    let jh = std::thread::spawn(move || {
-        let stdin = std::io::stdin().lock();
-        for line in stdin.lines() {
-            let line = line.context("Maelstrom input from STDIN could not be read")?;
-            let input: Message<Payload> = serde_json::from_str(&line)
-                .context("Maelstrom input from STDIN could not be deserialized")?;
-            if let Err(_) = tx.send(Event::Message(input)) {
-                return Ok::<_, anyhow::Error>(());
+    serde_json::to_writer(&mut *output, &reply).context("serialize response to init")?;
+    output.write_all(b"\n").context("write trailing newline")?;
+    output.flush().context("flush response to init")
+}
+
+/// Runs a step, surviving panics (and, depending on `policy`, errors) inside it
+///
+/// A panic is logged to stderr and answered with a `crash` error to the message that triggered
+/// it, after which the runtime carries on with the next event. The node may be left in whatever
+/// state the handler got to before panicking. Errors returned by the step are handled the same
+/// way under [`ErrorPolicy::Reply`], using the code of an [`Error`] if the handler returned one.
+pub(crate) fn supervise<Payload, InjectedPayload, W>(
+    input: Event<Payload, InjectedPayload>,
+    output: &mut W,
+    policy: ErrorPolicy,
+    step: impl FnOnce(Event<Payload, InjectedPayload>, &mut W) -> anyhow::Result<()>,
+) -> anyhow::Result<()>
+where
+    W: Write + ?Sized,
+{
+    let origin = match &input {
+        Event::Message(message) => {
+            Some((message.src.clone(), message.dst.clone(), message.body.id))
+        }
+        Event::Injected(_) | Event::PeerDown(_) | Event::PeerUp(_) | Event::Shutdown => None,
+    };
+    let (code, text) =
+        match std::panic::catch_unwind(AssertUnwindSafe(|| step(input, &mut *output))) {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) if policy == ErrorPolicy::Abort => return Err(e),
+            Ok(Err(e)) => match e.downcast_ref::<Error>() {
+                Some(error) => (error.code, error.text.clone()),
+                None => (ErrorCode::Crash, format!("handler failed: {e:#}")),
+            },
+            Err(panic) => {
+                let reason = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic payload");
+                (ErrorCode::Crash, format!("handler panicked: {reason}"))
             }
+        };
+    match origin {
+        Some((src, dst, Some(id))) => {
+            eprintln!("rasengan: step failed handling message {id} from {src}: {text}");
+            Message::new(dst, src)
+                .in_reply_to(id)
+                .payload(ErrorPayload::Error { code, text })
+                .send(output)
+        }
+        Some((src, _, None)) => {
+            eprintln!("rasengan: step failed handling a message from {src}: {text}");
+            Ok(())
+        }
+        None => {
+            eprintln!("rasengan: step failed handling a non-message event: {text}");
+            Ok(())
         }
-        let _ = tx.send(Event::Shutdown);
-        Ok(())
-    });
-
-    for input in rx {
-        node.step(input, &mut stdout)
-            .context("Node step function failed")?;
     }
+}
 
-    jh.join()
-        .expect("stdin thread panicked")
-        .context("stdin thread err'd")?;
+/// Prefix of the stderr lines recording each received message, for the `replay` tool to find
+pub const INPUT_RECORD_PREFIX: &str = "rasengan recv ";
 
-    Ok(())
+/// Logs a received line to stderr along with when it arrived (in microseconds since the epoch)
+fn record_input(line: &str) {
+    let micros = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |t| t.as_micros());
+    eprintln!("{INPUT_RECORD_PREFIX}{micros} {line}");
+}
+
+/// Logs input that couldn't be deserialized to stderr, which is all that's done with it
+fn report_bad_input(line: &str, e: &serde_json::Error) {
+    eprintln!("rasengan: ignoring input that could not be deserialized ({e}): {line}");
+}
+
+/// Answers a request whose body couldn't be deserialized with a `malformed-request` error, so
+/// the client isn't left waiting; input that isn't even a message goes unanswered
+fn reject_malformed(
+    line: &str,
+    e: &serde_json::Error,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let Ok(message) = serde_json::from_str::<Message<serde_json::Value>>(line) else {
+        return Ok(());
+    };
+    let (Some(id), None) = (message.body.id, message.body.in_reply_to) else {
+        return Ok(());
+    };
+    Message::new(message.dst, message.src)
+        .in_reply_to(id)
+        .payload(ErrorPayload::Error {
+            code: ErrorCode::MalformedRequest,
+            text: format!("malformed request: {e}"),
+        })
+        .send(output)
+}
+
+/// Whether a line is a request for a debug dump rather than input for the node
+///
+/// Both a bare `{"type": "debug_dump"}` and a message whose body has that type are recognized.
+fn is_debug_dump(line: &str) -> bool {
+    line.contains("debug_dump")
+        && serde_json::from_str::<serde_json::Value>(line).is_ok_and(|value| {
+            value["type"] == "debug_dump" || value["body"]["type"] == "debug_dump"
+        })
+}
+
+/// Writes a node's debug state to stderr as a single line
+pub(crate) fn dump_debug_state(node_id: &str, state: Option<serde_json::Value>) {
+    match state {
+        Some(state) => eprintln!("rasengan: debug state of {node_id}: {state}"),
+        None => eprintln!("rasengan: {node_id} exposes no debug state"),
+    }
+}
+
+/// Feeds every message after init into the event queue, except for debug dump and
+/// [introspection](introspect) requests and the runtime's own heartbeats
+pub(crate) fn spawn_input<Payload, InjectedPayload>(
+    lines: impl Iterator<Item = std::io::Result<String>> + Send + 'static,
+    tx: Runtime<Payload, InjectedPayload>,
+    mut introspector: Introspector,
+    mut output: Output,
+    options: &Options,
+) -> std::thread::JoinHandle<anyhow::Result<()>>
+where
+    Payload: DeserializeOwned + Send + 'static,
+    InjectedPayload: Send + 'static,
+{
+    let log_input = options.log_input;
+    // Both trace and watchdog warnings name events by their message type
+    let trace = options.trace || options.watchdog.is_some();
+    std::thread::spawn(move || {
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    eprintln!("rasengan: ignoring input: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e).context("Maelstrom input could not be read"),
+            };
+            if log_input {
+                record_input(&line);
+            }
+            if is_debug_dump(&line) {
+                tx.request_debug_dump();
+                continue;
+            }
+            if introspector.answer(&line, &tx)? {
+                continue;
+            }
+            if let Some(src) = tx.heartbeat_from(&line) {
+                introspector.observe(&src);
+                tx.observe_peer(&src, true);
+                continue;
+            }
+            let input: Message<Payload> = match serde_json::from_str(&line) {
+                Ok(input) => input,
+                Err(e) => {
+                    report_bad_input(&line, &e);
+                    reject_malformed(&line, &e, &mut output)?;
+                    continue;
+                }
+            };
+            introspector.observe(&input.src);
+            tx.observe_peer(&input.src, false);
+            let kind = trace.then(|| tracing::message_type(&line)).flatten();
+            if tx.send_as(Event::Message(input), kind).is_err() {
+                return Ok::<_, anyhow::Error>(());
+            }
+        }
+        let _ = tx.send(Event::Shutdown);
+        Ok(())
+    })
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -146,6 +868,40 @@ pub struct Init {
     pub node_id: NodeID,
     /// The IDs of all other nodes in the network
     pub node_ids: Vec<NodeID>,
+    /// Any additional fields the init message carried, such as per-run tuning
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Init {
+    /// Deserializes the init message's additional fields into a node's own config type
+    ///
+    /// Fields the config doesn't know about are ignored unless it denies them, and missing ones
+    /// can be filled in with `#[serde(default)]`.
+    pub fn config<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        serde_json::from_value(serde_json::Value::Object(self.extra.clone()))
+            .context("invalid node configuration in init message")
+    }
+
+    /// This node's neighbors in a tree over every node where each has up to `branching`
+    /// children, for use until (or instead of) an explicit topology
+    ///
+    /// Every node derives the same tree from the same node IDs, so neighbors are mutual.
+    pub fn default_neighbors(&self, branching: usize) -> Vec<NodeID> {
+        let branching = branching.max(1);
+        let mut nodes = self.node_ids.clone();
+        nodes.sort();
+        let Some(i) = nodes.iter().position(|node| *node == self.node_id) else {
+            return Vec::new();
+        };
+        let parent = i.checked_sub(1).map(|p| p / branching);
+        let children = (branching * i + 1)..(branching * i + branching + 1).min(nodes.len());
+        parent
+            .into_iter()
+            .chain(children)
+            .map(|j| nodes[j].clone())
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -168,12 +924,124 @@ pub enum BroadcastPayload {
     },
     TopologyOk,
     Broadcast {
-        message: MessageID,
+        message: JsonValue,
     },
     BroadcastOk,
     /// Requests all messages present on a node
     Read,
     ReadOk {
-        messages: HashSet<MessageID>,
+        messages: HashSet<JsonValue>,
     },
 }
+
+/// Maelstrom's standard error codes
+///
+/// Definite errors guarantee the request had no effect; indefinite ones (`Timeout`, `Crash`, and
+/// unrecognized codes) leave that open.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(u32),
+}
+
+impl ErrorCode {
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, Self::Timeout | Self::Crash | Self::Other(_))
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedRequest,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+/// A failed request, carrying the code to report back to the requester
+///
+/// Handlers can return this (through `anyhow`) to control the error reply sent under
+/// [`ErrorPolicy::Reply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} ({}): {}",
+            self.code,
+            u32::from(self.code),
+            self.text
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ErrorPayload> for Error {
+    fn from(ErrorPayload::Error { code, text }: ErrorPayload) -> Self {
+        Self { code, text }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPayload {
+    /// Sent in reply to a request that couldn't be processed
+    Error { code: ErrorCode, text: String },
+}
@@ -0,0 +1,270 @@
+//! Merkle-tree anti-entropy for keyed state
+//!
+//! Keys are spread over `2^depth` leaf buckets by hash, and each leaf's hash summarizes the
+//! entries in it. Internal tree nodes hash their two children, up to a single root. Syncing with
+//! a peer starts by comparing roots. Each side then answers mismatching nodes with their
+//! children's hashes, descending until the differing leaves are found, and only the entries in
+//! those leaves are exchanged. A sync between two peers that already agree costs one message.
+//!
+//! Every level of the tree is kept up to date as entries change, so answering a peer costs no
+//! more than looking up the hashes it asked about. Entries are hashed by a canonical encoding
+//! (their JSON form with object keys sorted, as CBOR) so that nodes agree on the hash of equal
+//! values whatever order their maps happen to iterate in. Sets still serialize in iteration
+//! order, so values should hold ordered collections such as `BTreeSet` instead.
+//!
+//! Diverging values are combined with [`Versioned::merge`], so both sides converge on the same
+//! state. Like other library payloads, [`MerklePayload`] is embedded in a node's payload through
+//! an untagged enum.
+use crate::{cbor, replication::Versioned, Message, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io::Write,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum MerklePayload<K, V> {
+    /// Hashes of some tree nodes at `level` (the root being level 0), by index within the level
+    MerkleHashes { level: u32, hashes: Vec<(u64, u64)> },
+    /// The sender's entries in the given leaves; `reply` asks for the receiver's in return
+    MerkleEntries {
+        leaves: Vec<u64>,
+        entries: Vec<(K, V)>,
+        reply: bool,
+    },
+}
+
+pub struct MerkleStore<K, V> {
+    node: NodeID,
+    depth: u32,
+    entries: HashMap<K, V>,
+    /// Every tree node's hash, by level from the root down; the leaves are wrapping sums of their
+    /// entries' hashes, so they can be updated in place
+    tree: Vec<Vec<u64>>,
+    /// The keys in each leaf
+    buckets: Vec<HashSet<K>>,
+}
+
+impl<K, V> MerkleStore<K, V>
+where
+    K: Clone + Eq + Hash + Serialize,
+    V: Versioned + Serialize,
+{
+    /// Creates an empty store whose tree has `2^depth` leaves
+    pub fn new(node: impl Into<NodeID>, depth: u32) -> Self {
+        let depth = depth.min(20);
+        let mut tree: Vec<Vec<u64>> = vec![vec![0; 1 << depth]];
+        for _ in 0..depth {
+            let below = tree.last().expect("leaves were added");
+            let level = below
+                .chunks(2)
+                .map(|pair| hash(&(pair[0], pair[1])))
+                .collect();
+            tree.push(level);
+        }
+        tree.reverse();
+        Self {
+            node: node.into(),
+            depth,
+            entries: HashMap::new(),
+            tree,
+            buckets: (0..1 << depth).map(|_| HashSet::new()).collect(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Merges `value` into the entry for `key`, returning whether the stored value changed
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        let leaf = self.leaf(&key);
+        let (merged, old) = match self.entries.remove(&key) {
+            Some(current) => {
+                let merged = current.clone().merge(value);
+                if merged == current {
+                    self.entries.insert(key, current);
+                    return false;
+                }
+                (merged, entry_hash(&key, &current))
+            }
+            None => {
+                self.buckets[leaf].insert(key.clone());
+                (value, 0)
+            }
+        };
+        let sum = &mut self.tree[self.depth as usize][leaf];
+        *sum = sum
+            .wrapping_sub(old)
+            .wrapping_add(entry_hash(&key, &merged));
+        self.entries.insert(key, merged);
+        self.rehash(leaf);
+        true
+    }
+
+    /// The hash summarizing the whole store
+    pub fn root(&self) -> u64 {
+        self.tree[0][0]
+    }
+
+    /// Starts a sync with `peer`
+    ///
+    /// Meant to be driven by a periodic injected event, typically against a random peer.
+    pub fn sync<P>(
+        &self,
+        peer: &NodeID,
+        output: &mut impl Write,
+        wrap: impl Fn(MerklePayload<K, V>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        self.send(
+            peer,
+            wrap(MerklePayload::MerkleHashes {
+                level: 0,
+                hashes: vec![(0, self.root())],
+            }),
+            output,
+        )
+    }
+
+    /// Processes a sync message from `src`, returning the keys whose values changed
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: MerklePayload<K, V>,
+        output: &mut impl Write,
+        wrap: impl Fn(MerklePayload<K, V>) -> P,
+    ) -> anyhow::Result<Vec<K>>
+    where
+        P: Serialize,
+    {
+        match payload {
+            MerklePayload::MerkleHashes { level, hashes } => {
+                let level = level.min(self.depth);
+                let ours = &self.tree[level as usize];
+                let differing = hashes
+                    .into_iter()
+                    .filter(|&(index, hash)| {
+                        ours.get(index as usize).is_some_and(|&ours| ours != hash)
+                    })
+                    .map(|(index, _)| index)
+                    .collect::<Vec<_>>();
+                if differing.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let reply = if level == self.depth {
+                    MerklePayload::MerkleEntries {
+                        entries: self.entries_in(&differing),
+                        leaves: differing,
+                        reply: true,
+                    }
+                } else {
+                    let children = &self.tree[level as usize + 1];
+                    MerklePayload::MerkleHashes {
+                        level: level + 1,
+                        hashes: differing
+                            .iter()
+                            .flat_map(|&index| [index * 2, index * 2 + 1])
+                            .map(|index| (index, children[index as usize]))
+                            .collect(),
+                    }
+                };
+                self.send(src, wrap(reply), output)?;
+                Ok(Vec::new())
+            }
+            MerklePayload::MerkleEntries {
+                leaves,
+                entries,
+                reply,
+            } => {
+                // Gather ours before merging, so the peer gets what it's actually missing
+                if reply {
+                    let ours = self.entries_in(&leaves);
+                    self.send(
+                        src,
+                        wrap(MerklePayload::MerkleEntries {
+                            leaves,
+                            entries: ours,
+                            reply: false,
+                        }),
+                        output,
+                    )?;
+                }
+                Ok(entries
+                    .into_iter()
+                    .filter_map(|(key, value)| self.insert(key.clone(), value).then_some(key))
+                    .collect())
+            }
+        }
+    }
+
+    /// Recomputes the hashes on the path from `leaf` up to the root
+    fn rehash(&mut self, leaf: usize) {
+        let mut index = leaf;
+        for level in (0..self.depth as usize).rev() {
+            index /= 2;
+            let below = &self.tree[level + 1];
+            self.tree[level][index] = hash(&(below[index * 2], below[index * 2 + 1]));
+        }
+    }
+
+    fn leaf(&self, key: &K) -> usize {
+        if self.depth == 0 {
+            return 0;
+        }
+        (hash(&canonical(key)) >> (64 - self.depth)) as usize
+    }
+
+    fn entries_in(&self, leaves: &[u64]) -> Vec<(K, V)> {
+        leaves
+            .iter()
+            .filter_map(|&leaf| self.buckets.get(leaf as usize))
+            .flatten()
+            .filter_map(|key| Some((key.clone(), self.entries.get(key)?.clone())))
+            .collect()
+    }
+
+    fn send<P>(&self, dst: &NodeID, payload: P, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        Message::new(self.node.clone(), dst.clone())
+            .payload(payload)
+            .send(output)
+    }
+}
+
+/// Hashes an entry by its canonical form, so values needn't implement `Hash`
+fn entry_hash(key: &impl Serialize, value: &impl Serialize) -> u64 {
+    hash(&canonical(&(key, value)))
+}
+
+/// The same bytes on every node for equal values, whatever order their maps are in
+fn canonical(value: &impl Serialize) -> Vec<u8> {
+    // JSON objects keep their keys sorted
+    serde_json::to_value(value).map_or_else(|_| Vec::new(), |value| cbor::encode(&value))
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    // Keys are fixed, so every node hashes identically
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
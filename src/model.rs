@@ -0,0 +1,420 @@
+//! Explicit-state model checking of node implementations
+//!
+//! A [`Checker`] starts from a freshly initialized [`Cluster`] with some client requests in
+//! flight and explores, breadth first, every order in which in-flight messages can be delivered
+//! and periodic ticks can fire. Along the way it checks two kinds of properties:
+//!
+//! - **Always** properties must hold in every reachable state (no duplicate IDs handed out, no
+//!   acknowledged write lost, ...).
+//! - **Eventually** properties must hold once the system settles: from every state where nothing
+//!   is in flight, the checker keeps firing every tick and delivering everything in order for a
+//!   bounded number of rounds, and the property has to hold by the end (all broadcasts reach
+//!   every node, the counter equals the sum of the adds, ...).
+//!
+//! A violation comes with the shortest sequence of [`Action`]s leading to it.
+//!
+//! ```ignore
+//! let cluster = Cluster::<CounterNode, _, _, _>::new(2, 0, |_| ())?;
+//! let report = Checker::new(cluster)
+//!     .request("n1", json!({"type": "add", "delta": 2}))
+//!     .request("n2", json!({"type": "add", "delta": 3}))
+//!     .tick(InjectedPayload::Gossip)
+//!     .eventually("counter converges", |world| world.states().all(|s| s["value"] == 5))
+//!     .check()?;
+//! assert!(report.violation.is_none(), "{report}");
+//! ```
+//!
+//! States are told apart by every node's [`Node::debug_state`] plus the messages in flight and
+//! those delivered to clients; nodes whose debug state is `None` make every state look new, so
+//! only the depth and state limits bound the search.
+use crate::{
+    sim::{Cluster, Envelope},
+    Message, Node, NodeID,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+/// A property checked against a [`World`]
+pub(crate) type Property<N, S, P, I> = (&'static str, Box<dyn Fn(&World<N, S, P, I>) -> bool>);
+
+/// One state of the system being checked
+pub struct World<N, S, P, I> {
+    pub cluster: Cluster<N, S, P, I>,
+    /// Messages sent but not yet delivered
+    pub in_flight: Vec<Envelope>,
+    /// Everything nodes sent to clients, in the order it was sent
+    pub to_clients: Vec<Envelope>,
+}
+
+impl<N: Clone, S, P, I> Clone for World<N, S, P, I> {
+    fn clone(&self) -> Self {
+        Self {
+            cluster: self.cluster.clone(),
+            in_flight: self.in_flight.clone(),
+            to_clients: self.to_clients.clone(),
+        }
+    }
+}
+
+impl<N, S, P, I> World<N, S, P, I>
+where
+    N: Node<S, P, I>,
+    P: DeserializeOwned + Send + 'static,
+    I: Send + 'static,
+{
+    /// Every node's debug state, with `null` for nodes that don't expose one
+    pub fn states(&self) -> impl Iterator<Item = Value> + '_ {
+        self.cluster
+            .nodes()
+            .iter()
+            .map(|node| node.debug_state().unwrap_or(Value::Null))
+    }
+
+    /// Replies sent to clients with the given `type`
+    pub fn replies<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Envelope> + 'a {
+        self.to_clients
+            .iter()
+            .filter(move |message| message.body.payload["type"] == kind)
+    }
+
+    /// Sends whatever a step produced on its way, to the network or to the client log
+    pub(crate) fn route(&mut self, sent: Vec<Envelope>) {
+        for message in sent {
+            if self.cluster.contains(&message.dst) {
+                self.in_flight.push(message);
+            } else {
+                self.to_clients.push(message);
+            }
+        }
+    }
+
+    /// Runs the system forward fairly, every tick firing on every node and then everything in
+    /// flight arriving in order, until the properties hold or `rounds` run out
+    pub(crate) fn settle(
+        &mut self,
+        ticks: &[I],
+        rounds: usize,
+        properties: &[Property<N, S, P, I>],
+    ) -> anyhow::Result<()>
+    where
+        I: Clone,
+    {
+        for _ in 0..rounds {
+            if properties.iter().all(|(_, holds)| holds(self)) {
+                break;
+            }
+            let nodes = self.cluster.ids().to_vec();
+            for node in &nodes {
+                for tick in ticks {
+                    let sent = self.cluster.inject(node, tick.clone())?;
+                    self.route(sent);
+                }
+            }
+            while !self.in_flight.is_empty() {
+                let message = self.in_flight.remove(0);
+                let sent = self.cluster.deliver(message)?;
+                self.route(sent);
+            }
+        }
+        Ok(())
+    }
+
+    fn fingerprint(&self) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        for node in self.cluster.nodes() {
+            node.debug_state()?.to_string().hash(&mut hasher);
+        }
+        let mut in_flight: Vec<_> = self
+            .in_flight
+            .iter()
+            .map(|message| serde_json::to_string(message).expect("messages serialize"))
+            .collect();
+        in_flight.sort();
+        in_flight.hash(&mut hasher);
+        for message in &self.to_clients {
+            serde_json::to_string(message)
+                .expect("messages serialize")
+                .hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+}
+
+/// A single transition between states
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// A message in flight arrived
+    Deliver(Envelope),
+    /// A node's tick fired
+    Tick { node: NodeID, tick: usize },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Deliver(message) => write!(
+                f,
+                "deliver {} -> {}: {}",
+                message.src, message.dst, message.body.payload
+            ),
+            Self::Tick { node, tick } => write!(f, "tick #{tick} fires on {node}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub property: &'static str,
+    /// How the violating state was reached
+    pub trace: Vec<Action>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Distinct states visited
+    pub states: usize,
+    /// Whether the depth or state limit cut the search short
+    pub truncated: bool,
+    pub violation: Option<Violation>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "explored {} states", self.states)?;
+        if self.truncated {
+            write!(f, " (search truncated)")?;
+        }
+        match &self.violation {
+            None => write!(f, ", no violations"),
+            Some(violation) => {
+                write!(f, "; \"{}\" violated after:", violation.property)?;
+                for (i, action) in violation.trace.iter().enumerate() {
+                    write!(f, "\n  {}. {action}", i + 1)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct Checker<N, S, P, I> {
+    initial: World<N, S, P, I>,
+    ticks: Vec<I>,
+    always: Vec<Property<N, S, P, I>>,
+    eventually: Vec<Property<N, S, P, I>>,
+    max_depth: usize,
+    max_states: usize,
+    settle_rounds: usize,
+    next_client: u64,
+}
+
+impl<N, S, P, I> Checker<N, S, P, I>
+where
+    N: Node<S, P, I> + Clone,
+    P: DeserializeOwned + Send + 'static,
+    I: Clone + Send + 'static,
+{
+    pub fn new(cluster: Cluster<N, S, P, I>) -> Self {
+        Self {
+            initial: World {
+                cluster,
+                in_flight: Vec::new(),
+                to_clients: Vec::new(),
+            },
+            ticks: Vec::new(),
+            always: Vec::new(),
+            eventually: Vec::new(),
+            max_depth: 12,
+            max_states: 100_000,
+            settle_rounds: 8,
+            next_client: 1,
+        }
+    }
+
+    /// Puts a request from a new client to `node` in flight
+    pub fn request(mut self, node: &str, payload: Value) -> Self {
+        let client = format!("c{}", self.next_client);
+        self.next_client += 1;
+        let message: Envelope = Message::new(client, node).with_id(&mut 0).payload(payload);
+        self.initial.in_flight.push(message);
+        self
+    }
+
+    /// An injected event that may fire on any node at any point
+    pub fn tick(mut self, payload: I) -> Self {
+        self.ticks.push(payload);
+        self
+    }
+
+    pub fn always(
+        mut self,
+        name: &'static str,
+        property: impl Fn(&World<N, S, P, I>) -> bool + 'static,
+    ) -> Self {
+        self.always.push((name, Box::new(property)));
+        self
+    }
+
+    pub fn eventually(
+        mut self,
+        name: &'static str,
+        property: impl Fn(&World<N, S, P, I>) -> bool + 'static,
+    ) -> Self {
+        self.eventually.push((name, Box::new(property)));
+        self
+    }
+
+    /// How many actions deep to explore (12 by default)
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// How many distinct states to visit before giving up (100,000 by default)
+    pub fn max_states(mut self, states: usize) -> Self {
+        self.max_states = states;
+        self
+    }
+
+    /// How many rounds of ticks and in-order delivery eventually properties get to come true
+    /// (8 by default)
+    pub fn settle_rounds(mut self, rounds: usize) -> Self {
+        self.settle_rounds = rounds;
+        self
+    }
+
+    /// Explores the state space, stopping at the first violation
+    ///
+    /// Fails if a node's step fails, which is reported along with the trace that caused it.
+    pub fn check(&self) -> anyhow::Result<Report> {
+        // Traces are rebuilt by walking parent links rather than copied into every state
+        let mut links: Vec<(usize, Option<Action>)> = vec![(0, None)];
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([(self.initial.clone(), 0, 0)]);
+        let mut truncated = false;
+        if let Some(fingerprint) = self.initial.fingerprint() {
+            visited.insert(fingerprint);
+        }
+
+        while let Some((world, depth, link)) = queue.pop_front() {
+            if let Some(property) = self.violated(&world)? {
+                return Ok(Report {
+                    states: links.len(),
+                    truncated,
+                    violation: Some(Violation {
+                        property,
+                        trace: trace(&links, link),
+                    }),
+                });
+            }
+            if depth == self.max_depth {
+                truncated |= !world.in_flight.is_empty() || !self.ticks.is_empty();
+                continue;
+            }
+            for action in self.actions(&world) {
+                let next = self.apply(&world, &action).map_err(|e| {
+                    let mut steps = trace(&links, link);
+                    steps.push(action.clone());
+                    let steps: Vec<_> = steps.iter().map(ToString::to_string).collect();
+                    e.context(format!("after:\n  {}", steps.join("\n  ")))
+                })?;
+                if let Some(fingerprint) = next.fingerprint() {
+                    if !visited.insert(fingerprint) {
+                        continue;
+                    }
+                }
+                if links.len() >= self.max_states {
+                    truncated = true;
+                    break;
+                }
+                links.push((link, Some(action)));
+                queue.push_back((next, depth + 1, links.len() - 1));
+            }
+        }
+        Ok(Report {
+            states: links.len(),
+            truncated,
+            violation: None,
+        })
+    }
+
+    /// The first property a state violates
+    fn violated(&self, world: &World<N, S, P, I>) -> anyhow::Result<Option<&'static str>> {
+        if let Some((name, _)) = self.always.iter().find(|(_, holds)| !holds(world)) {
+            return Ok(Some(name));
+        }
+        if self.eventually.is_empty() || !world.in_flight.is_empty() {
+            return Ok(None);
+        }
+        let mut settled = world.clone();
+        settled.settle(&self.ticks, self.settle_rounds, &self.eventually)?;
+        Ok(self
+            .eventually
+            .iter()
+            .find(|(_, holds)| !holds(&settled))
+            .map(|(name, _)| *name))
+    }
+
+    fn actions(&self, world: &World<N, S, P, I>) -> Vec<Action> {
+        let mut actions: Vec<_> = world
+            .in_flight
+            .iter()
+            .cloned()
+            .map(Action::Deliver)
+            .collect();
+        for node in world.cluster.ids() {
+            for tick in 0..self.ticks.len() {
+                actions.push(Action::Tick {
+                    node: node.clone(),
+                    tick,
+                });
+            }
+        }
+        actions
+    }
+
+    fn apply(
+        &self,
+        world: &World<N, S, P, I>,
+        action: &Action,
+    ) -> anyhow::Result<World<N, S, P, I>> {
+        let mut next = world.clone();
+        let sent = match action {
+            Action::Deliver(message) => {
+                let i = next
+                    .in_flight
+                    .iter()
+                    .position(|m| same(m, message))
+                    .expect("delivered messages are in flight");
+                let message = next.in_flight.remove(i);
+                next.cluster.deliver(message)?
+            }
+            Action::Tick { node, tick } => next.cluster.inject(node, self.ticks[*tick].clone())?,
+        };
+        next.route(sent);
+        Ok(next)
+    }
+}
+
+fn same(a: &Envelope, b: &Envelope) -> bool {
+    a.src == b.src
+        && a.dst == b.dst
+        && a.body.id == b.body.id
+        && a.body.in_reply_to == b.body.in_reply_to
+        && a.body.payload == b.body.payload
+}
+
+fn trace(links: &[(usize, Option<Action>)], mut link: usize) -> Vec<Action> {
+    let mut actions = Vec::new();
+    while let (parent, Some(action)) = &links[link] {
+        actions.push(action.clone());
+        link = *parent;
+    }
+    actions.reverse();
+    actions
+}
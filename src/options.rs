@@ -0,0 +1,189 @@
+//! Runtime settings, read from `RASENGAN_*` environment variables
+//!
+//! Maelstrom launches node binaries without arguments, so the environment is the one channel
+//! available for per-run tuning.
+use crate::{failure_detector::Suspicion, framing, rate_limit::RateLimit, runtime::Jitter};
+use anyhow::Context;
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Directory to persist node snapshots in (`RASENGAN_SNAPSHOT_DIR`); disabled when unset
+    pub snapshot_dir: Option<PathBuf>,
+    /// How often to write a snapshot (`RASENGAN_SNAPSHOT_INTERVAL_MS`)
+    pub snapshot_interval: Duration,
+    /// Seed for every RNG handed out by the runtime (`RASENGAN_SEED` or `--seed`); picked at
+    /// random and logged when unset
+    pub seed: Option<u64>,
+    /// How many events may wait to be stepped before input is back-pressured
+    /// (`RASENGAN_QUEUE_CAPACITY`)
+    pub queue_capacity: usize,
+    /// Worker threads used by [`concurrent_main_loop`](crate::concurrent_main_loop)
+    /// (`RASENGAN_WORKERS`); defaults to the available parallelism
+    pub workers: usize,
+    /// What to do when a step returns an error (`RASENGAN_ON_ERROR=abort|reply`)
+    pub on_error: ErrorPolicy,
+    /// Record every received message on stderr for the `replay` tool (`RASENGAN_LOG_INPUT`)
+    pub log_input: bool,
+    /// Caps on outgoing messages per second (`RASENGAN_RATE_LIMIT` overall and
+    /// `RASENGAN_RATE_LIMIT_PER_DEST` towards each destination); unlimited when unset
+    pub rate_limit: RateLimit,
+    /// How often to write the node's debug state to stderr (`RASENGAN_DEBUG_DUMP_INTERVAL_MS`);
+    /// dumps only happen on request when unset
+    pub debug_dump_interval: Option<Duration>,
+    /// How often the runtime heartbeats every peer to track which are alive
+    /// (`RASENGAN_HEARTBEAT_INTERVAL_MS`); peers are never suspected when unset
+    pub heartbeat_interval: Option<Duration>,
+    /// Suspect a peer after this much silence (`RASENGAN_PEER_TIMEOUT_MS`) instead of by
+    /// phi-accrual with `RASENGAN_PHI_THRESHOLD` (8 by default)
+    pub suspicion: Suspicion,
+    /// How much periodic ticks vary, as a fraction of their interval (`RASENGAN_TICK_JITTER`),
+    /// and whether each starts at a random phase (`RASENGAN_TICK_PHASE`); neither by default
+    pub tick_jitter: Jitter,
+    /// Keep latency histograms per payload type and write them to stderr at shutdown and with
+    /// every debug dump (`RASENGAN_TRACE`)
+    pub trace: bool,
+    /// Warn on stderr about any step still running after this long (`RASENGAN_WATCHDOG_MS`);
+    /// steps aren't watched when unset
+    pub watchdog: Option<Duration>,
+    /// Have the watchdog answer a request stuck past its limit with a `temporarily-unavailable`
+    /// error (`RASENGAN_WATCHDOG_REPLY`)
+    pub watchdog_reply: bool,
+    /// Longest input message read, in bytes (`RASENGAN_MAX_MESSAGE_BYTES`); longer ones are
+    /// skipped and reported on stderr
+    pub max_message_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stop the node, as any unexpected failure would
+    #[default]
+    Abort,
+    /// Log the error, answer the offending message with an error reply, and keep going
+    Reply,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            snapshot_dir: None,
+            snapshot_interval: Duration::from_secs(1),
+            seed: None,
+            queue_capacity: 1024,
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            on_error: ErrorPolicy::default(),
+            log_input: false,
+            rate_limit: RateLimit::default(),
+            debug_dump_interval: None,
+            heartbeat_interval: None,
+            suspicion: Suspicion::PhiAccrual { threshold: 8.0 },
+            tick_jitter: Jitter::default(),
+            trace: false,
+            watchdog: None,
+            watchdog_reply: false,
+            max_message_bytes: framing::DEFAULT_MAX_LEN,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            snapshot_dir: env("RASENGAN_SNAPSHOT_DIR")?,
+            snapshot_interval: env("RASENGAN_SNAPSHOT_INTERVAL_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.snapshot_interval),
+            seed: env("RASENGAN_SEED")?,
+            queue_capacity: env("RASENGAN_QUEUE_CAPACITY")?.unwrap_or(defaults.queue_capacity),
+            workers: env("RASENGAN_WORKERS")?.unwrap_or(defaults.workers),
+            on_error: match env::<String>("RASENGAN_ON_ERROR")?.as_deref() {
+                None => defaults.on_error,
+                Some("abort") => ErrorPolicy::Abort,
+                Some("reply") => ErrorPolicy::Reply,
+                Some(other) => anyhow::bail!("RASENGAN_ON_ERROR has an invalid value: {other:?}"),
+            },
+            log_input: flag("RASENGAN_LOG_INPUT")?.unwrap_or(defaults.log_input),
+            rate_limit: RateLimit {
+                global: env("RASENGAN_RATE_LIMIT")?,
+                per_destination: env("RASENGAN_RATE_LIMIT_PER_DEST")?,
+            },
+            debug_dump_interval: env("RASENGAN_DEBUG_DUMP_INTERVAL_MS")?.map(Duration::from_millis),
+            heartbeat_interval: env("RASENGAN_HEARTBEAT_INTERVAL_MS")?.map(Duration::from_millis),
+            suspicion: match env("RASENGAN_PEER_TIMEOUT_MS")? {
+                Some(timeout) => Suspicion::Timeout(Duration::from_millis(timeout)),
+                None => match env("RASENGAN_PHI_THRESHOLD")? {
+                    Some(threshold) => Suspicion::PhiAccrual { threshold },
+                    None => defaults.suspicion,
+                },
+            },
+            tick_jitter: Jitter {
+                spread: env("RASENGAN_TICK_JITTER")?.unwrap_or(defaults.tick_jitter.spread),
+                phase: flag("RASENGAN_TICK_PHASE")?.unwrap_or(defaults.tick_jitter.phase),
+            },
+            trace: flag("RASENGAN_TRACE")?.unwrap_or(defaults.trace),
+            watchdog: env("RASENGAN_WATCHDOG_MS")?.map(Duration::from_millis),
+            watchdog_reply: flag("RASENGAN_WATCHDOG_REPLY")?.unwrap_or(defaults.watchdog_reply),
+            max_message_bytes: env("RASENGAN_MAX_MESSAGE_BYTES")?
+                .unwrap_or(defaults.max_message_bytes),
+        })
+    }
+
+    /// Resolves the RNG seed, picking (and logging) a random one when none was configured
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| {
+            let seed = rand::random();
+            eprintln!("rasengan: running with seed {seed} (set RASENGAN_SEED to replay)");
+            seed
+        })
+    }
+
+    /// Applies overrides given on the command line (`--seed <n>` or `--seed=<n>`)
+    pub fn with_args(mut self, args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let seed = match arg.strip_prefix("--seed") {
+                Some("") => args.next().context("--seed requires a value")?,
+                Some(value) if value.starts_with('=') => value[1..].to_string(),
+                _ => continue,
+            };
+            self.seed = Some(
+                seed.parse()
+                    .with_context(|| format!("--seed has an invalid value: {seed:?}"))?,
+            );
+        }
+        Ok(self)
+    }
+}
+
+/// Reads and parses an environment variable, treating unset or empty as `None`
+pub fn env<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .with_context(|| format!("{name} has an invalid value: {value:?}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("{name} could not be read")),
+    }
+}
+
+/// Reads a boolean environment variable, accepting `1`/`0`, `true`/`false`, `yes`/`no`, and
+/// `on`/`off`
+pub fn flag(name: &str) -> anyhow::Result<Option<bool>> {
+    match env::<String>(name)?
+        .as_deref()
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        None => Ok(None),
+        Some("1" | "true" | "yes" | "on") => Ok(Some(true)),
+        Some("0" | "false" | "no" | "off") => Ok(Some(false)),
+        Some(other) => anyhow::bail!("{name} has an invalid value: {other:?}"),
+    }
+}
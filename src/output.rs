@@ -0,0 +1,161 @@
+//! Where a node's outgoing messages go
+//!
+//! Writing a message only serializes it into memory: every complete line is handed off to a
+//! dedicated writer thread that owns the real output, so a slow stdout (or a huge reply) doesn't
+//! hold up event processing. Lines are forwarded whole, so messages never interleave even when
+//! several [`Output`]s share one writer.
+use crate::rate_limit::{write_limited, RateLimit};
+use anyhow::Context;
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// The writer handed to [`Node::step`](crate::Node::step)
+pub struct Output {
+    /// Bytes written since the last complete line was sent off
+    buf: Vec<u8>,
+    tx: Sender<Vec<u8>>,
+    /// Lines sent off by this output and every other sharing its writer
+    sent: Arc<AtomicU64>,
+    /// Lines sent off by this output alone
+    lines: u64,
+    /// Only set on the output that started the writer thread
+    writer: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Output {
+    /// Starts a writer thread that takes ownership of `writer`
+    pub fn spawn(writer: impl Write + Send + 'static) -> Self {
+        Self::spawn_with(writer, RateLimit::default())
+    }
+
+    /// Starts a writer thread that takes ownership of `writer` and holds outgoing messages to
+    /// `limit`
+    pub fn spawn_with(mut writer: impl Write + Send + 'static, limit: RateLimit) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let handle = std::thread::spawn(move || {
+            if !limit.is_unlimited() {
+                return write_limited(&mut writer, rx, limit);
+            }
+            'chunks: for chunk in &rx {
+                // Batch up whatever else is queued before paying for a flush
+                for chunk in std::iter::once(chunk).chain(rx.try_iter()) {
+                    if chunk.is_empty() {
+                        break 'chunks;
+                    }
+                    writer.write_all(&chunk)?;
+                }
+                writer.flush()?;
+            }
+            writer.flush()
+        });
+        Self {
+            buf: Vec::new(),
+            tx,
+            sent: Arc::default(),
+            lines: 0,
+            writer: Some(handle),
+        }
+    }
+
+    /// An output whose lines are collected from the returned receiver rather than written out,
+    /// for stepping nodes in-process
+    pub(crate) fn capture() -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel();
+        let output = Self {
+            buf: Vec::new(),
+            tx,
+            sent: Arc::new(AtomicU64::new(0)),
+            lines: 0,
+            writer: None,
+        };
+        (output, rx)
+    }
+
+    /// Another output feeding the same writer thread, e.g. for a worker or background thread
+    pub fn handle(&self) -> Self {
+        Self {
+            buf: Vec::new(),
+            tx: self.tx.clone(),
+            sent: Arc::clone(&self.sent),
+            lines: 0,
+            writer: None,
+        }
+    }
+
+    /// Flushes anything pending and, if this output owns the writer thread, waits for every
+    /// queued line to be written out
+    ///
+    /// The writer thread only finishes once every [`Output::handle`] is gone as well.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        self.send_pending().context("flush pending output")?;
+        let Output { tx, writer, .. } = self;
+        drop(tx);
+        match writer {
+            Some(writer) => writer
+                .join()
+                .expect("writer thread panicked")
+                .context("writing output failed"),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`Output::close`], but without waiting for the other handles to go away; whatever
+    /// they send from now on is dropped
+    pub(crate) fn close_now(mut self) -> anyhow::Result<()> {
+        self.send_pending().context("flush pending output")?;
+        // Never sent otherwise, so the writer thread takes an empty line as the signal to stop
+        let _ = self.tx.send(Vec::new());
+        self.close()
+    }
+
+    /// How many lines have been handed to the writer thread, across every handle
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// How many lines have been handed to the writer thread by this handle
+    pub(crate) fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    fn send_pending(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.buf);
+        let lines = pending.iter().filter(|&&b| b == b'\n').count();
+        self.sent.fetch_add(lines as u64, Ordering::Relaxed);
+        self.lines += lines as u64;
+        self.tx.send(pending).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "writer thread has exited")
+        })
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        match self.buf.iter().rposition(|&b| b == b'\n') {
+            Some(end) if end + 1 == self.buf.len() => self.send_pending()?,
+            Some(end) => {
+                let rest = self.buf.split_off(end + 1);
+                self.send_pending()?;
+                self.buf = rest;
+            }
+            None => {}
+        }
+        Ok(buf.len())
+    }
+
+    /// Sends off everything written so far, complete line or not
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_pending()
+    }
+}
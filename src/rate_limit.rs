@@ -0,0 +1,197 @@
+//! Token-bucket limits on outgoing messages
+//!
+//! Limits apply in the writer thread, after handlers are done with a message: anything over the
+//! limit waits in a per-destination queue, and queues take turns so a flood towards one node
+//! doesn't hold up messages to the others. Each bucket holds up to a second's worth of tokens,
+//! so short bursts pass through untouched.
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// Outgoing message rates, in messages per second; `None` means unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RateLimit {
+    /// Across all destinations (`RASENGAN_RATE_LIMIT`)
+    pub global: Option<f64>,
+    /// Towards each individual destination (`RASENGAN_RATE_LIMIT_PER_DEST`)
+    pub per_destination: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.global.is_none() && self.per_destination.is_none()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    fn ready(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    /// How long until a token becomes available
+    fn wait(&self) -> Duration {
+        if self.ready() {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    dest: String,
+}
+
+/// Writes every line received on `rx`, holding back whatever exceeds `limit`
+///
+/// Returns once `rx` disconnects and everything queued has been written.
+pub(crate) fn write_limited(
+    writer: &mut impl Write,
+    rx: Receiver<Vec<u8>>,
+    limit: RateLimit,
+) -> std::io::Result<()> {
+    let mut global = limit.global.map(|rate| Bucket::new(rate, Instant::now()));
+    let mut buckets: HashMap<String, Bucket> = HashMap::new();
+    let mut queues: HashMap<String, VecDeque<Vec<u8>>> = HashMap::new();
+    // Destinations with queued lines, in the order they get a turn
+    let mut turns: VecDeque<String> = VecDeque::new();
+    let mut open = true;
+    while open || !turns.is_empty() {
+        let received = if turns.is_empty() {
+            rx.recv().ok()
+        } else {
+            let wait = turns
+                .iter()
+                .map(|dest| buckets.get(dest).map_or(Duration::ZERO, Bucket::wait))
+                .min()
+                .unwrap_or_default()
+                .max(global.as_ref().map_or(Duration::ZERO, Bucket::wait));
+            if !open {
+                // Nothing more is coming, so just wait out the limit
+                std::thread::sleep(wait);
+                None
+            } else {
+                match rx.recv_timeout(wait) {
+                    Ok(chunk) => Some(chunk),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        open = false;
+                        None
+                    }
+                }
+            }
+        };
+        match received {
+            Some(chunk) => {
+                for chunk in std::iter::once(chunk).chain(rx.try_iter()) {
+                    if chunk.is_empty() {
+                        // Output is closing without waiting for every handle to be dropped
+                        open = false;
+                        break;
+                    }
+                    for line in lines(chunk) {
+                        let dest = serde_json::from_slice::<Envelope>(&line)
+                            .map(|envelope| envelope.dest)
+                            .unwrap_or_default();
+                        let queue = queues.entry(dest.clone()).or_default();
+                        if queue.is_empty() {
+                            turns.push_back(dest);
+                        }
+                        queue.push_back(line);
+                    }
+                }
+            }
+            None if turns.is_empty() => open = false,
+            None => {}
+        }
+
+        let now = Instant::now();
+        if let Some(global) = &mut global {
+            global.refill(now);
+        }
+        let mut wrote = false;
+        loop {
+            let mut progressed = false;
+            for _ in 0..turns.len() {
+                if global.as_ref().is_some_and(|global| !global.ready()) {
+                    break;
+                }
+                let dest = turns.pop_front().expect("turns were just counted");
+                let bucket = limit.per_destination.map(|rate| {
+                    let bucket = buckets
+                        .entry(dest.clone())
+                        .or_insert_with(|| Bucket::new(rate, now));
+                    bucket.refill(now);
+                    bucket
+                });
+                if bucket.as_ref().is_none_or(|bucket| bucket.ready()) {
+                    let queue = queues
+                        .get_mut(&dest)
+                        .expect("queued destinations have queues");
+                    let line = queue.pop_front().expect("queues in turn aren't empty");
+                    writer.write_all(&line)?;
+                    if let Some(bucket) = bucket {
+                        bucket.tokens -= 1.0;
+                    }
+                    if let Some(global) = &mut global {
+                        global.tokens -= 1.0;
+                    }
+                    progressed = true;
+                    wrote = true;
+                }
+                if queues.get(&dest).is_some_and(|queue| !queue.is_empty()) {
+                    turns.push_back(dest);
+                } else {
+                    queues.remove(&dest);
+                }
+            }
+            if !progressed {
+                break;
+            }
+        }
+        if wrote {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+/// Splits a chunk of output into its lines, keeping their newlines
+fn lines(chunk: Vec<u8>) -> Vec<Vec<u8>> {
+    if chunk.iter().filter(|&&b| b == b'\n').count() <= 1 {
+        return vec![chunk];
+    }
+    chunk
+        .split_inclusive(|&b| b == b'\n')
+        .map(<[u8]>::to_vec)
+        .collect()
+}
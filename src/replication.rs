@@ -0,0 +1,500 @@
+//! Quorum-replicated key-value storage with read repair
+//!
+//! Each key is stored on the `n` nodes its [`HashRing`] position picks. Writes complete once
+//! `w` replicas have acknowledged them and reads once `r` have answered, so choosing
+//! `r + w > n` makes every read overlap the latest write.
+//!
+//! Reads can trade freshness for latency with a [`Consistency`] level: a local read answers from
+//! the node's own copy, a quorum read as above, and a linearizable read from the key's primary
+//! (the first of its replicas), which every write waits for on top of its quorum.
+//!
+//! Replicas can disagree after partitions or lost messages. Values implement [`Versioned`],
+//! which decides how two replicas' values combine (last-writer-wins on a timestamp, a vector
+//! clock merge, ...). Every read combines what the replicas returned, and any replica that
+//! answered with something other than the result is sent the combined value (read repair).
+//!
+//! Replicas that never answer, because they're down or the messages were lost, would leave
+//! their operations waiting forever; call [`Replication::expire`] on a timer to give up on them.
+//!
+//! Like other library payloads, [`ReplicaPayload`] is embedded in a node's payload through an
+//! untagged enum.
+use crate::{sharding::HashRing, Body, Init, Message, MessageID, MsgIdAllocator, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// The rule for combining divergent replicas of a value
+pub trait Versioned: Clone + PartialEq {
+    /// Combines two versions into the one that should be kept
+    fn merge(self, other: Self) -> Self;
+}
+
+/// A value versioned by a timestamp; the later write wins
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Timestamped<T> {
+    pub ts: u64,
+    pub value: T,
+}
+
+impl<T: Clone + PartialEq> Versioned for Timestamped<T> {
+    fn merge(self, other: Self) -> Self {
+        if other.ts > self.ts {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaPayload<K, V> {
+    ReplicaGet {
+        key: K,
+    },
+    ReplicaGetOk {
+        key: K,
+        value: Option<V>,
+    },
+    /// Merges `value` into the replica's copy
+    ReplicaPut {
+        key: K,
+        value: V,
+    },
+    ReplicaPutOk {
+        key: K,
+    },
+}
+
+/// How fresh a read has to be, from fastest to strongest
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    /// Whatever this node has, which may be stale or missing
+    Local,
+    /// Overlaps the latest write whenever `r + w > n`
+    #[default]
+    Quorum,
+    /// Whatever the key's primary has, which includes every completed write as long as the
+    /// primary hasn't lost its copy; reads wait on the primary alone, so one that's down fails
+    /// them
+    #[serde(alias = "leader")]
+    Linearizable,
+}
+
+/// Identifies a read or write started on a [`Replication`]
+pub type OpID = u64;
+
+/// A finished read or write
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome<K, V> {
+    Read {
+        op: OpID,
+        key: K,
+        value: Option<V>,
+    },
+    Written {
+        op: OpID,
+        key: K,
+    },
+    /// Too few replicas answered in time; a write may still have reached some of them
+    TimedOut {
+        op: OpID,
+        key: K,
+    },
+}
+
+#[derive(Debug)]
+enum Pending<K, V> {
+    Read {
+        key: K,
+        /// Answers that haven't been checked for staleness yet
+        answers: Vec<(NodeID, Option<V>)>,
+        answered: usize,
+        /// How many answers complete the read, out of how many replicas were asked
+        needed: usize,
+        asked: usize,
+        /// The combined value, once `r` replicas have answered
+        result: Option<Option<V>>,
+    },
+    Write {
+        key: K,
+        acks: usize,
+        /// Whether the key's primary is among the acks
+        primary: bool,
+        done: bool,
+    },
+}
+
+pub struct Replication<K, V> {
+    node: NodeID,
+    ring: HashRing,
+    n: usize,
+    r: usize,
+    w: usize,
+    store: HashMap<K, V>,
+    next_op: OpID,
+    ids: MsgIdAllocator,
+    /// Operations in progress, along with when they started
+    ops: HashMap<OpID, (Instant, Pending<K, V>)>,
+    /// Which operation each outstanding replica request belongs to
+    requests: HashMap<MessageID, OpID>,
+}
+
+impl<K, V> Replication<K, V>
+where
+    K: Clone + Eq + Hash + Serialize,
+    V: Versioned + Serialize,
+{
+    /// Replicates each key on `n` nodes, with read and write quorums of `r` and `w`
+    ///
+    /// Requests to replicas take their IDs from `ids`, which should be the node's own allocator
+    /// (see [`Runtime::ids`](crate::Runtime::ids)) so they can't clash with its other messages.
+    pub fn new(init: &Init, ids: &MsgIdAllocator, n: usize, r: usize, w: usize) -> Self {
+        let n = n.clamp(1, init.node_ids.len().max(1));
+        Self {
+            node: init.node_id.clone(),
+            ring: HashRing::new(&init.node_ids, 64),
+            n,
+            r: r.clamp(1, n),
+            w: w.clamp(1, n),
+            store: HashMap::new(),
+            next_op: 0,
+            ids: ids.clone(),
+            ops: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// This node's own copy of a key, if it has one
+    pub fn local(&self, key: &K) -> Option<&V> {
+        self.store.get(key)
+    }
+
+    /// Starts a quorum read, which completes through [`Replication::handle`]
+    ///
+    /// Returns the read's outcome straight away if this node's own copy is enough for a quorum.
+    pub fn read<P>(
+        &mut self,
+        key: K,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<(OpID, Option<Outcome<K, V>>)>
+    where
+        P: Serialize,
+    {
+        self.read_at(key, Consistency::Quorum, output, wrap)
+    }
+
+    /// Starts a read at the given consistency level, which completes through
+    /// [`Replication::handle`] unless its outcome is returned straight away
+    pub fn read_at<P>(
+        &mut self,
+        key: K,
+        consistency: Consistency,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<(OpID, Option<Outcome<K, V>>)>
+    where
+        P: Serialize,
+    {
+        let replicas = match consistency {
+            Consistency::Local => {
+                let op = self.next_op;
+                self.next_op += 1;
+                let value = self.store.get(&key).cloned();
+                return Ok((op, Some(Outcome::Read { op, key, value })));
+            }
+            Consistency::Quorum => self.replicas(&key),
+            Consistency::Linearizable => self.replicas(&key).into_iter().take(1).collect(),
+        };
+        let op = self.start(Pending::Read {
+            key: key.clone(),
+            answers: Vec::new(),
+            answered: 0,
+            needed: self.r.min(replicas.len()),
+            asked: replicas.len(),
+            result: None,
+        });
+        let mut outcome = None;
+        for replica in replicas {
+            if replica == self.node {
+                let value = self.store.get(&key).cloned();
+                outcome = self.read_answer(op, replica, value, output, &wrap)?;
+            } else {
+                let payload = wrap(ReplicaPayload::ReplicaGet { key: key.clone() });
+                self.request(op, replica, payload, output)?;
+            }
+        }
+        Ok((op, outcome))
+    }
+
+    /// Starts a quorum write, which completes through [`Replication::handle`] once the key's
+    /// primary is among the acks
+    ///
+    /// Returns the write's outcome straight away if this node's own copy is enough for a quorum.
+    pub fn write<P>(
+        &mut self,
+        key: K,
+        value: V,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<(OpID, Option<Outcome<K, V>>)>
+    where
+        P: Serialize,
+    {
+        let op = self.start(Pending::Write {
+            key: key.clone(),
+            acks: 0,
+            primary: false,
+            done: false,
+        });
+        let mut outcome = None;
+        for replica in self.replicas(&key) {
+            if replica == self.node {
+                self.merge(key.clone(), value.clone());
+                outcome = self.write_ack(op, &replica);
+            } else {
+                let payload = wrap(ReplicaPayload::ReplicaPut {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+                self.request(op, replica, payload, output)?;
+            }
+        }
+        Ok((op, outcome))
+    }
+
+    /// Gives up on operations that have been waiting on replicas for longer than `timeout`,
+    /// returning a [`Outcome::TimedOut`] for each one that hadn't completed yet
+    ///
+    /// Meant to be called periodically.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<Outcome<K, V>> {
+        let expired: Vec<OpID> = self
+            .ops
+            .iter()
+            .filter(|(_, (started, _))| started.elapsed() >= timeout)
+            .map(|(&op, _)| op)
+            .collect();
+        let mut outcomes = Vec::new();
+        for op in expired {
+            let Some((_, pending)) = self.ops.remove(&op) else {
+                continue;
+            };
+            match pending {
+                Pending::Read {
+                    key, result: None, ..
+                }
+                | Pending::Write {
+                    key, done: false, ..
+                } => outcomes.push(Outcome::TimedOut { op, key }),
+                // Already answered, and only waiting on stragglers
+                _ => {}
+            }
+        }
+        self.requests.retain(|_, op| self.ops.contains_key(op));
+        outcomes
+    }
+
+    /// Processes a replication message from `src`, returning the operation it finished, if any
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        body: Body<ReplicaPayload<K, V>>,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<Option<Outcome<K, V>>>
+    where
+        P: Serialize,
+    {
+        let node = self.node.clone();
+        let reply = |payload| {
+            let reply = Message::new(node.clone(), src.clone());
+            match body.id {
+                Some(id) => reply.in_reply_to(id),
+                None => reply,
+            }
+            .payload(wrap(payload))
+        };
+        match body.payload {
+            ReplicaPayload::ReplicaGet { key } => {
+                let value = self.store.get(&key).cloned();
+                reply(ReplicaPayload::ReplicaGetOk { key, value }).send(output)?;
+                Ok(None)
+            }
+            ReplicaPayload::ReplicaPut { key, value } => {
+                self.merge(key.clone(), value);
+                reply(ReplicaPayload::ReplicaPutOk { key }).send(output)?;
+                Ok(None)
+            }
+            ReplicaPayload::ReplicaGetOk { value, .. } => match self.answered(body.in_reply_to) {
+                Some(op) => self.read_answer(op, src.clone(), value, output, &wrap),
+                None => Ok(None),
+            },
+            ReplicaPayload::ReplicaPutOk { .. } => Ok(self
+                .answered(body.in_reply_to)
+                .and_then(|op| self.write_ack(op, src))),
+        }
+    }
+
+    fn read_answer<P>(
+        &mut self,
+        op: OpID,
+        replica: NodeID,
+        value: Option<V>,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<Option<Outcome<K, V>>>
+    where
+        P: Serialize,
+    {
+        let Some((
+            _,
+            Pending::Read {
+                key,
+                answers,
+                answered,
+                needed,
+                asked,
+                result,
+            },
+        )) = self.ops.get_mut(&op)
+        else {
+            return Ok(None);
+        };
+        answers.push((replica, value));
+        *answered += 1;
+        let mut outcome = None;
+        if result.is_none() && *answered >= *needed {
+            let merged = answers
+                .iter()
+                .filter_map(|(_, value)| value.clone())
+                .reduce(V::merge);
+            *result = Some(merged.clone());
+            outcome = Some(Outcome::Read {
+                op,
+                key: key.clone(),
+                value: merged,
+            });
+        }
+        // Once the result is known, every replica that answered with something else (including
+        // ones answering late) is brought up to date
+        let mut repairs = Vec::new();
+        if let Some(Some(merged)) = result {
+            repairs.extend(
+                answers
+                    .drain(..)
+                    .filter(|(_, value)| value.as_ref() != Some(merged))
+                    .map(|(replica, _)| (replica, key.clone(), merged.clone())),
+            );
+        }
+        if *answered == *asked {
+            self.ops.remove(&op);
+        }
+        for (replica, key, value) in repairs {
+            self.repair(&replica, key, value, output, &wrap)?;
+        }
+        Ok(outcome)
+    }
+
+    fn write_ack(&mut self, op: OpID, replica: &NodeID) -> Option<Outcome<K, V>> {
+        let Some((
+            _,
+            Pending::Write {
+                key,
+                acks,
+                primary,
+                done,
+            },
+        )) = self.ops.get_mut(&op)
+        else {
+            return None;
+        };
+        *acks += 1;
+        *primary |= self.ring.replicas(&*key, 1).first().copied() == Some(replica);
+        let mut outcome = None;
+        if !*done && *acks >= self.w && *primary {
+            *done = true;
+            outcome = Some(Outcome::Written {
+                op,
+                key: key.clone(),
+            });
+        }
+        if *acks == self.n {
+            self.ops.remove(&op);
+        }
+        outcome
+    }
+
+    /// Writes the winning value back to a replica that answered a read with something older
+    fn repair<P>(
+        &mut self,
+        replica: &NodeID,
+        key: K,
+        value: V,
+        output: &mut impl Write,
+        wrap: impl Fn(ReplicaPayload<K, V>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        if *replica == self.node {
+            self.merge(key, value);
+            return Ok(());
+        }
+        // Fire and forget: the acknowledgement won't match any outstanding request
+        Message::new(self.node.clone(), replica.clone())
+            .payload(wrap(ReplicaPayload::ReplicaPut { key, value }))
+            .send(output)
+    }
+
+    fn merge(&mut self, key: K, value: V) {
+        let merged = match self.store.remove(&key) {
+            Some(current) => current.merge(value),
+            None => value,
+        };
+        self.store.insert(key, merged);
+    }
+
+    fn replicas(&self, key: &K) -> Vec<NodeID> {
+        self.ring
+            .replicas(key, self.n)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    fn start(&mut self, pending: Pending<K, V>) -> OpID {
+        let op = self.next_op;
+        self.next_op += 1;
+        self.ops.insert(op, (Instant::now(), pending));
+        op
+    }
+
+    fn request<P>(
+        &mut self,
+        op: OpID,
+        replica: NodeID,
+        payload: P,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        let message = Message::new(self.node.clone(), replica)
+            .with_id_from(&self.ids)
+            .payload(payload);
+        self.requests
+            .insert(message.body.id.expect("requests have an ID"), op);
+        message.send(output)
+    }
+
+    fn answered(&mut self, in_reply_to: Option<MessageID>) -> Option<OpID> {
+        self.requests.remove(&in_reply_to?)
+    }
+}
@@ -0,0 +1,133 @@
+//! Calling other nodes and Maelstrom's services, with typed error replies
+//!
+//! A reply may be an `error` body rather than the expected `*_ok`. [`ServiceReply`] decodes
+//! either, and [`Rpc`] matches replies to the calls that caused them, handing each caller the
+//! context it started the call with along with a `Result`:
+//!
+//! ```ignore
+//! Wire::Service(reply) => {
+//!     let body = Body { id: input.body.id, in_reply_to: input.body.in_reply_to, payload: reply };
+//!     let Some((client, result)) = self.rpc.complete(body) else {
+//!         return Ok(());
+//!     };
+//!     match result {
+//!         Ok(KvPayload::ReadOk { value }) => ...,
+//!         Err(Error { code: ErrorCode::KeyDoesNotExist, .. }) => ...,
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! Like other library payloads, a [`ServiceReply`] is embedded in a node's payload through an
+//! untagged enum.
+use crate::{Body, Error, ErrorPayload, Message, MessageID, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write};
+
+/// A reply that's either the expected payload or an `error` body
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ServiceReply<T> {
+    // Tried first, so a payload type lenient enough to accept anything can't swallow errors
+    Err(ErrorPayload),
+    Ok(T),
+}
+
+impl<T> ServiceReply<T> {
+    pub fn into_result(self) -> Result<T, Error> {
+        match self {
+            Self::Ok(payload) => Ok(payload),
+            Self::Err(error) => Err(error.into()),
+        }
+    }
+}
+
+impl<T> From<ServiceReply<T>> for Result<T, Error> {
+    fn from(reply: ServiceReply<T>) -> Self {
+        reply.into_result()
+    }
+}
+
+/// Outstanding calls, each remembering a caller-chosen context `C` until it's answered
+#[derive(Debug)]
+pub struct Rpc<C> {
+    node: NodeID,
+    pending: HashMap<MessageID, C>,
+}
+
+impl<C> Rpc<C> {
+    pub fn new(node: impl Into<NodeID>) -> Self {
+        Self {
+            node: node.into(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Sends `payload` to `dst` as a request, taking its message ID from `id`
+    ///
+    /// Returns the ID the call went out under.
+    pub fn call<P>(
+        &mut self,
+        dst: impl Into<NodeID>,
+        payload: P,
+        context: C,
+        id: &mut MessageID,
+        output: &mut impl Write,
+    ) -> anyhow::Result<MessageID>
+    where
+        P: Serialize,
+    {
+        let message = Message::new(self.node.clone(), dst)
+            .with_id(id)
+            .payload(payload);
+        let call = message.body.id.expect("calls have an ID");
+        message.send(output)?;
+        self.pending.insert(call, context);
+        Ok(call)
+    }
+
+    /// Matches a reply to its call, returning the call's context and outcome
+    ///
+    /// Returns `None` if the reply doesn't answer an outstanding call.
+    pub fn complete<T>(&mut self, reply: Body<ServiceReply<T>>) -> Option<(C, Result<T, Error>)> {
+        let context = self.pending.remove(&reply.in_reply_to?)?;
+        Some((context, reply.payload.into_result()))
+    }
+
+    /// The context an outstanding call was started with
+    pub fn context(&self, call: MessageID) -> Option<&C> {
+        self.pending.get(&call)
+    }
+
+    /// Whether a message answers one of the outstanding calls
+    pub fn is_reply<P>(&self, message: &Message<P>) -> bool {
+        message
+            .body
+            .in_reply_to
+            .is_some_and(|id| self.pending.contains_key(&id))
+    }
+
+    /// Gives up on a call, returning its context; a late reply to it is then ignored
+    pub fn cancel(&mut self, call: MessageID) -> Option<C> {
+        self.pending.remove(&call)
+    }
+
+    /// Gives up on every call whose context `cancel` picks, returning their contexts
+    pub fn cancel_where(&mut self, mut cancel: impl FnMut(&C) -> bool) -> Vec<C> {
+        let calls: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, context)| cancel(context))
+            .map(|(&call, _)| call)
+            .collect();
+        calls
+            .into_iter()
+            .filter_map(|call| self.pending.remove(&call))
+            .collect()
+    }
+
+    /// How many calls are still waiting on a reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
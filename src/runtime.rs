@@ -0,0 +1,677 @@
+//! The handle nodes use to interact with the runtime driving them
+//!
+//! Events wait in one of three lanes by [`Priority`], so a burst of client requests can't hold
+//! up the replies and ticks that keep work already in flight moving. Lanes are served most
+//! urgent first, except that any lane passed over [`STARVATION_LIMIT`] times in a row gets the
+//! next turn. Requested [debug dumps](Runtime::request_debug_dump) go ahead of everything, and
+//! [`Event::Shutdown`] waits for every lane to drain.
+use crate::{
+    failure_detector::{Liveness, MembershipChange, PeerStatus},
+    shared,
+    timer::{TimerHandle, Timers},
+    Event, Init, MsgIdAllocator, NodeID, Output,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{SendError, TrySendError},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How many events in a row may jump ahead of a waiting lower-priority one
+pub const STARVATION_LIMIT: u32 = 16;
+
+/// Which lane of the event queue an event waits in, most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Replies to messages this node sent, which usually unblock work already in flight
+    Reply,
+    /// Events injected by the node itself, such as periodic ticks, and peer liveness changes
+    Injected,
+    /// New messages from clients or other nodes, and the shutdown that follows the last of them
+    Request,
+}
+
+impl Priority {
+    pub fn of<Payload, InjectedPayload>(event: &Event<Payload, InjectedPayload>) -> Self {
+        match event {
+            Event::Message(message) if message.body.in_reply_to.is_some() => Self::Reply,
+            Event::Injected(_) | Event::PeerDown(_) | Event::PeerUp(_) => Self::Injected,
+            Event::Message(_) | Event::Shutdown => Self::Request,
+        }
+    }
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// How periodic ticks are spread out so nodes don't all fire at once
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Jitter {
+    /// Each interval is lengthened or shortened by up to this fraction of itself, between 0 and 1
+    pub spread: f64,
+    /// Whether the first tick comes after a random fraction of the interval rather than all of it
+    pub phase: bool,
+}
+
+impl Jitter {
+    /// The first wait, from a tick being set up to it firing
+    fn first(&self, interval: Duration, rng: &mut StdRng) -> Duration {
+        if self.phase {
+            interval.mul_f64(rng.gen::<f64>())
+        } else {
+            self.next(interval, rng)
+        }
+    }
+
+    /// The wait between one tick and the next
+    fn next(&self, interval: Duration, rng: &mut StdRng) -> Duration {
+        let spread = self.spread.clamp(0.0, 1.0);
+        if spread.is_nan() || spread == 0.0 {
+            return interval;
+        }
+        interval.mul_f64(1.0 + rng.gen_range(-spread..=spread))
+    }
+}
+
+/// Handed to [`Node::from_init`](crate::Node::from_init); cheap to clone into background threads
+pub struct Runtime<Payload, InjectedPayload = ()> {
+    lanes: Arc<Lanes<Event<Payload, InjectedPayload>>>,
+    queue: Arc<QueueMetrics>,
+    liveness: Arc<Liveness>,
+    timers: Arc<Timers<InjectedPayload>>,
+    ids: MsgIdAllocator,
+    node_id: NodeID,
+    seed: u64,
+    jitter: Jitter,
+    /// How many periodic injections have been set up, which tells their RNG streams apart
+    ticks: Arc<AtomicU64>,
+}
+
+impl<Payload, InjectedPayload> Clone for Runtime<Payload, InjectedPayload> {
+    fn clone(&self) -> Self {
+        self.lanes.lock().senders += 1;
+        Self {
+            lanes: Arc::clone(&self.lanes),
+            queue: Arc::clone(&self.queue),
+            liveness: Arc::clone(&self.liveness),
+            timers: Arc::clone(&self.timers),
+            ids: self.ids.clone(),
+            node_id: self.node_id.clone(),
+            seed: self.seed,
+            jitter: self.jitter,
+            ticks: Arc::clone(&self.ticks),
+        }
+    }
+}
+
+impl<Payload, InjectedPayload> Drop for Runtime<Payload, InjectedPayload> {
+    fn drop(&mut self) {
+        let mut state = self.lanes.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.lanes.ready.notify_all();
+        }
+    }
+}
+
+impl<Payload, InjectedPayload> Runtime<Payload, InjectedPayload> {
+    /// Creates the runtime handle along with the receiving end of its event queue, each of whose
+    /// lanes holds up to `capacity` events
+    pub(crate) fn new(
+        capacity: usize,
+        init: &Init,
+        seed: u64,
+        liveness: Liveness,
+    ) -> (Self, EventQueue<Payload, InjectedPayload>) {
+        let capacity = capacity.max(1);
+        let lanes = Arc::new(Lanes::new(capacity));
+        let queue = Arc::new(QueueMetrics {
+            capacity,
+            ..Default::default()
+        });
+        let runtime = Self {
+            lanes: Arc::clone(&lanes),
+            queue: Arc::clone(&queue),
+            liveness: Arc::new(liveness),
+            timers: Arc::new(Timers::new()),
+            ids: MsgIdAllocator::new(),
+            node_id: init.node_id.clone(),
+            seed,
+            jitter: Jitter::default(),
+            ticks: Arc::default(),
+        };
+        (runtime, EventQueue { lanes, queue })
+    }
+
+    /// Sets the jitter [`Runtime::every`] applies to ticks
+    pub(crate) fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Queues an event for the node's step function, blocking while the queue is full
+    ///
+    /// Fails once the runtime has shut down. Don't call this from within `step`: with the queue
+    /// full it would wait on the very loop that drains it; use [`Runtime::try_send`] instead.
+    pub fn send(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+    ) -> Result<(), SendError<Event<Payload, InjectedPayload>>> {
+        self.send_as(event, None)
+    }
+
+    /// Like [`Runtime::send`], tracing the event under the type of message it carries
+    pub(crate) fn send_as(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+        kind: Option<Box<str>>,
+    ) -> Result<(), SendError<Event<Payload, InjectedPayload>>> {
+        shared::assert_unheld("queueing an event");
+        let stamp = Stamp::now(kind);
+        self.queue.enqueued();
+        let result = match self.lanes.try_push(event, stamp) {
+            Ok(()) => Ok(()),
+            Err((TrySendError::Full(event), stamp)) => {
+                self.queue.blocked_sends.fetch_add(1, Ordering::Relaxed);
+                self.lanes.push(event, stamp)
+            }
+            Err((TrySendError::Disconnected(event), _)) => Err(SendError(event)),
+        };
+        if result.is_err() {
+            self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Queues an event only if there's room for it right away
+    ///
+    /// Meant for events that are safe to drop under load, such as periodic ticks.
+    pub fn try_send(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+    ) -> Result<(), TrySendError<Event<Payload, InjectedPayload>>> {
+        self.queue.enqueued();
+        let result = self
+            .lanes
+            .try_push(event, Stamp::now(None))
+            .map_err(|(e, _)| e);
+        if result.is_err() {
+            self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Err(TrySendError::Full(_)) = result {
+            self.queue.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Queues an injected event, blocking while the queue is full; fails once the runtime has
+    /// shut down
+    pub fn inject(
+        &self,
+        payload: InjectedPayload,
+    ) -> Result<(), SendError<Event<Payload, InjectedPayload>>> {
+        self.send(Event::Injected(payload))
+    }
+
+    /// Queues an injected event unless the queue is full
+    pub fn try_inject(
+        &self,
+        payload: InjectedPayload,
+    ) -> Result<(), TrySendError<Event<Payload, InjectedPayload>>> {
+        self.try_send(Event::Injected(payload))
+    }
+
+    /// Spawns a thread that injects `payload()` every `interval` until the runtime shuts down
+    ///
+    /// Ticks are skipped rather than queued up while the node is backed up. They're spread out
+    /// by the jitter configured through [`Options`](crate::Options), none by default.
+    pub fn every(
+        &self,
+        interval: Duration,
+        payload: impl Fn() -> InjectedPayload + Send + 'static,
+    ) -> JoinHandle<()>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        self.every_with(interval, self.jitter, payload)
+    }
+
+    /// Like [`Runtime::every`], with the given jitter instead of the configured one
+    pub fn every_with(
+        &self,
+        interval: Duration,
+        jitter: Jitter,
+        payload: impl Fn() -> InjectedPayload + Send + 'static,
+    ) -> JoinHandle<()>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let runtime = self.clone();
+        let tick = self.ticks.fetch_add(1, Ordering::Relaxed);
+        let mut rng = self.rng(&format!("every/{tick}/{}", interval.as_nanos()));
+        std::thread::spawn(move || {
+            let mut wait = jitter.first(interval, &mut rng);
+            loop {
+                std::thread::sleep(wait);
+                if let Err(TrySendError::Disconnected(_)) = runtime.try_inject(payload()) {
+                    break;
+                }
+                wait = jitter.next(interval, &mut rng);
+            }
+        })
+    }
+
+    /// Injects `payload` once `after` has passed, unless the timer is cancelled first
+    pub fn schedule(&self, after: Duration, payload: InjectedPayload) -> TimerHandle
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let handle = self.timers.schedule(Instant::now() + after, payload);
+        let mut state = self.timers.lock();
+        if !state.serving {
+            state.serving = true;
+            let runtime = self.clone();
+            std::thread::spawn(move || loop {
+                let payload = runtime.timers.next_due();
+                // Unlike ticks, a timer firing late beats it never firing
+                if runtime.inject(payload).is_err() {
+                    break;
+                }
+            });
+        }
+        handle
+    }
+
+    /// Disarms a timer, returning its payload if it hadn't fired yet
+    ///
+    /// A timer that fired just before may still be waiting in the event queue, so handlers
+    /// should tolerate the occasional stale one.
+    pub fn cancel(&self, handle: TimerHandle) -> Option<InjectedPayload> {
+        self.timers.cancel(handle)
+    }
+
+    /// Pushes a pending timer's deadline back (or forward) to `after` from now, returning whether
+    /// it was still pending
+    pub fn reschedule(&self, handle: TimerHandle, after: Duration) -> bool {
+        self.timers.reschedule(handle, Instant::now() + after)
+    }
+
+    /// How many timers are waiting to fire
+    pub fn pending_timers(&self) -> usize {
+        self.timers.pending()
+    }
+
+    /// Asks the event loop to write the node's [debug state](crate::Node::debug_state) to stderr
+    /// before stepping its next event
+    ///
+    /// Requests made before an earlier one was served are folded into it. Returns `false` once
+    /// the runtime has shut down.
+    pub fn request_debug_dump(&self) -> bool {
+        let mut state = self.lanes.lock();
+        if !state.receiving {
+            return false;
+        }
+        state.dump_requested = true;
+        self.lanes.ready.notify_one();
+        true
+    }
+
+    /// Spawns a thread that requests a debug dump every `interval` until the runtime shuts down
+    pub(crate) fn dump_every(&self, interval: Duration) -> JoinHandle<()>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let runtime = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if !runtime.request_debug_dump() {
+                break;
+            }
+        })
+    }
+
+    /// Peers not currently suspected of having failed, in order
+    ///
+    /// Only the runtime's own heartbeats (`RASENGAN_HEARTBEAT_INTERVAL_MS`) ever mark a peer as
+    /// suspected; without them, every peer is alive.
+    pub fn alive_peers(&self) -> Vec<NodeID> {
+        let mut peers: Vec<_> = self.liveness.detector().alive_peers().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    pub fn is_alive(&self, peer: &str) -> bool {
+        self.liveness.detector().status(peer) == Some(PeerStatus::Up)
+    }
+
+    /// Records that a message from `src` arrived, letting the node know if that brought it back up
+    pub(crate) fn observe_peer(&self, src: &str, heartbeat: bool) {
+        let mut detector = self.liveness.detector();
+        let change = if heartbeat {
+            detector.heartbeat(src)
+        } else {
+            detector.observe(src)
+        };
+        drop(detector);
+        if let Some(MembershipChange::Up(peer)) = change {
+            let _ = self.send(Event::PeerUp(peer));
+        }
+    }
+
+    /// The sender of a line that's a runtime heartbeat rather than input for the node
+    pub(crate) fn heartbeat_from(&self, line: &str) -> Option<NodeID> {
+        self.liveness.heartbeat_from(line)
+    }
+
+    /// Spawns a thread that heartbeats every peer and reports the ones that went quiet, if
+    /// heartbeats are enabled
+    pub(crate) fn heartbeat(&self, mut output: Output) -> Option<JoinHandle<()>>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let interval = self.liveness.heartbeat()?;
+        let runtime = self.clone();
+        Some(std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            // Holding on to the output past shutdown would keep it from closing
+            if !runtime.lanes.lock().receiving {
+                break;
+            }
+            let changes = runtime
+                .liveness
+                .detector()
+                .tick(&mut output, |heartbeat| heartbeat);
+            let Ok(changes) = changes else {
+                break;
+            };
+            for change in changes {
+                let event = match change {
+                    MembershipChange::Down(peer) => Event::PeerDown(peer),
+                    MembershipChange::Up(peer) => Event::PeerUp(peer),
+                };
+                if runtime.send(event).is_err() {
+                    return;
+                }
+            }
+        }))
+    }
+
+    /// The node's shared message ID allocator, usable from any thread
+    pub fn ids(&self) -> &MsgIdAllocator {
+        &self.ids
+    }
+
+    /// Current state of the event queue
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// The seed this run was started with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns a deterministic RNG for the named purpose
+    ///
+    /// Streams are derived from the run's seed, the node's ID, and `stream`, so every node (and
+    /// every consumer within a node) gets an independent sequence that's identical across runs
+    /// started with the same seed.
+    pub fn rng(&self, stream: &str) -> StdRng {
+        StdRng::seed_from_u64(derive_seed(self.seed, &[&self.node_id, stream]))
+    }
+}
+
+/// Mixes `parts` into `seed` with FNV-1a followed by a SplitMix64 finalizer
+///
+/// Unlike `DefaultHasher`, this is stable across Rust releases, so recorded seeds stay replayable.
+fn derive_seed(seed: u64, parts: &[&str]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for part in parts {
+        for &b in part.as_bytes().iter().chain(&[0xff]) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// The receiving end of the runtime's event queue
+///
+/// Yields events until every [`Runtime`] handle is gone and the lanes have drained.
+pub(crate) struct EventQueue<Payload, InjectedPayload> {
+    lanes: Arc<Lanes<Event<Payload, InjectedPayload>>>,
+    queue: Arc<QueueMetrics>,
+}
+
+/// When an event was queued, for [tracing](crate::tracing)
+pub(crate) struct Stamp {
+    pub(crate) enqueued: Instant,
+    /// The type of message the event carries, when known
+    pub(crate) kind: Option<Box<str>>,
+}
+
+impl Stamp {
+    pub(crate) fn now(kind: Option<Box<str>>) -> Self {
+        Self {
+            enqueued: Instant::now(),
+            kind,
+        }
+    }
+}
+
+/// What the event loop pulls off the queue
+pub(crate) enum Queued<Payload, InjectedPayload> {
+    Event(Event<Payload, InjectedPayload>, Stamp),
+    /// Write the node's debug state to stderr
+    DebugDump,
+}
+
+impl<Payload, InjectedPayload> Iterator for EventQueue<Payload, InjectedPayload> {
+    type Item = Queued<Payload, InjectedPayload>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let queued = self.lanes.pop()?;
+        if let Queued::Event(..) = queued {
+            self.queue.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        Some(queued)
+    }
+}
+
+impl<Payload, InjectedPayload> Drop for EventQueue<Payload, InjectedPayload> {
+    fn drop(&mut self) {
+        self.lanes.lock().receiving = false;
+        self.lanes.room.notify_all();
+    }
+}
+
+/// A bounded queue per [`Priority`], shared by the runtime handles and the event loop
+struct Lanes<T> {
+    state: Mutex<LaneState<T>>,
+    capacity: usize,
+    /// Signalled when an event arrives or the last handle goes away
+    ready: Condvar,
+    /// Signalled when room frees up or the event loop goes away
+    room: Condvar,
+}
+
+struct LaneState<T> {
+    lanes: [VecDeque<(T, Stamp)>; 3],
+    senders: usize,
+    receiving: bool,
+    /// Events served from other lanes since each lane, while waiting, was last served
+    passed_over: [u32; 3],
+    dump_requested: bool,
+}
+
+impl<T> Lanes<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(LaneState {
+                lanes: Default::default(),
+                senders: 1,
+                receiving: true,
+                passed_over: [0; 3],
+                dump_requested: false,
+            }),
+            capacity,
+            ready: Condvar::new(),
+            room: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LaneState<T>> {
+        // Nothing panics while holding the lock, so the state is always consistent
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<Payload, InjectedPayload> Lanes<Event<Payload, InjectedPayload>> {
+    #[allow(clippy::type_complexity)]
+    fn try_push(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+    ) -> Result<(), (TrySendError<Event<Payload, InjectedPayload>>, Stamp)> {
+        let lane = Priority::of(&event).lane();
+        let mut state = self.lock();
+        if !state.receiving {
+            return Err((TrySendError::Disconnected(event), stamp));
+        }
+        if state.lanes[lane].len() >= self.capacity {
+            return Err((TrySendError::Full(event), stamp));
+        }
+        state.lanes[lane].push_back((event, stamp));
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn push(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+    ) -> Result<(), SendError<Event<Payload, InjectedPayload>>> {
+        let lane = Priority::of(&event).lane();
+        let mut state = self.lock();
+        loop {
+            if !state.receiving {
+                return Err(SendError(event));
+            }
+            if state.lanes[lane].len() < self.capacity {
+                break;
+            }
+            state = self
+                .room
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.lanes[lane].push_back((event, stamp));
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<Queued<Payload, InjectedPayload>> {
+        let mut state = self.lock();
+        loop {
+            if state.dump_requested {
+                state.dump_requested = false;
+                return Some(Queued::DebugDump);
+            }
+            let waiting: Vec<usize> = (0..state.lanes.len())
+                .filter(|&lane| !state.lanes[lane].is_empty())
+                .collect();
+            if let Some(&first) = waiting.first() {
+                // The longest-starved lane at the limit goes next, ties going to the less urgent
+                let starved = waiting
+                    .iter()
+                    .copied()
+                    .filter(|&lane| state.passed_over[lane] >= STARVATION_LIMIT)
+                    .max_by_key(|&lane| (state.passed_over[lane], lane));
+                let mut lane = starved.unwrap_or(first);
+                if let Some((Event::Shutdown, _)) = state.lanes[lane].front() {
+                    // Nothing may be stepped after the shutdown, so everything else goes first
+                    lane = waiting
+                        .iter()
+                        .copied()
+                        .find(|&other| other != lane)
+                        .unwrap_or(lane);
+                }
+                for other in 0..state.lanes.len() {
+                    state.passed_over[other] = if other != lane && waiting.contains(&other) {
+                        state.passed_over[other] + 1
+                    } else {
+                        0
+                    };
+                }
+                let event = state.lanes[lane].pop_front();
+                self.room.notify_all();
+                return event.map(|(event, stamp)| Queued::Event(event, stamp));
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self
+                .ready
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueMetrics {
+    capacity: usize,
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    blocked_sends: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl QueueMetrics {
+    fn enqueued(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.capacity,
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            blocked_sends: self.blocked_sends.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time view of the event queue
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Maximum number of events each priority lane holds before senders block
+    pub capacity: usize,
+    /// Events currently waiting to be stepped
+    pub depth: usize,
+    /// The deepest the queue has been
+    pub high_water: usize,
+    /// Sends that had to wait for room in the queue
+    pub blocked_sends: u64,
+    /// Events discarded by `try_send`/`try_inject` because the queue was full
+    pub dropped: u64,
+}
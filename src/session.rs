@@ -0,0 +1,188 @@
+//! Per-client sessions, so that a client retrying a request gets its original reply back
+//!
+//! Maelstrom clients retry requests that time out, and a node that simply handles the retry
+//! again applies its effects twice: a counter adds the delta again, a log appends the message
+//! again. [`Sessions`] remembers, per client, which requests are being handled and the replies to
+//! the latest ones. Check every incoming message with [`Sessions::admit`] before handling it,
+//! send replies to clients through [`Sessions::reply`] so they're remembered, and
+//! [abandon](Sessions::abandon) requests whose handling failed so a retry isn't dropped:
+//!
+//! ```ignore
+//! match self.sessions.admit(&input) {
+//!     Admission::New => {}
+//!     Admission::Answered(reply) => return reply.send(output),
+//!     Admission::Duplicate => return Ok(()),
+//! }
+//! let (client, id) = (input.src.clone(), input.body.id);
+//! let result = self.handle(input, output);
+//! if result.is_err() {
+//!     self.sessions.abandon(&client, id);
+//! }
+//! result
+//! ```
+//!
+//! Memory is bounded: each client keeps only its latest replies, and only the most recently
+//! active clients are tracked at all. Error replies aren't remembered, so a request that failed
+//! is handled afresh when retried.
+use crate::{Body, Init, Message, MessageID, NodeID};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    io::Write,
+};
+
+const DEFAULT_MAX_CLIENTS: usize = 1024;
+const DEFAULT_MAX_REPLIES: usize = 64;
+
+/// What to do with an incoming message
+#[derive(Debug)]
+pub enum Admission {
+    /// Handle it; it's not a request this node has seen before
+    New,
+    /// A retry of a request that was already answered; send this instead of handling it
+    Answered(Message<Value>),
+    /// A retry of a request that's still being handled, or was answered too long ago for the
+    /// reply to be remembered; drop it
+    Duplicate,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    /// Requests admitted but not yet answered
+    in_flight: BTreeSet<MessageID>,
+    /// Replies to the latest answered requests, oldest first
+    replies: VecDeque<(MessageID, Body<Value>)>,
+    /// The newest request whose reply has been forgotten
+    forgotten: Option<MessageID>,
+    last_active: u64,
+}
+
+#[derive(Debug)]
+pub struct Sessions {
+    node: NodeID,
+    nodes: HashSet<NodeID>,
+    clients: HashMap<NodeID, Session>,
+    max_clients: usize,
+    max_replies: usize,
+    clock: u64,
+    replayed: u64,
+}
+
+impl Sessions {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            nodes: init.node_ids.iter().cloned().collect(),
+            clients: HashMap::new(),
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_replies: DEFAULT_MAX_REPLIES,
+            clock: 0,
+            replayed: 0,
+        }
+    }
+
+    /// How many clients to track before forgetting the least recently active one
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients.max(1);
+        self
+    }
+
+    /// How many replies to remember per client
+    pub fn max_replies(mut self, max_replies: usize) -> Self {
+        self.max_replies = max_replies.max(1);
+        self
+    }
+
+    /// How many retries have been answered from remembered replies
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /// Decides whether `message` should be handled; messages from other nodes, replies, and
+    /// requests without an ID always are
+    pub fn admit<P>(&mut self, message: &Message<P>) -> Admission {
+        let Some(id) = message.body.id else {
+            return Admission::New;
+        };
+        if message.body.in_reply_to.is_some() || self.nodes.contains(&message.src) {
+            return Admission::New;
+        }
+        self.clock += 1;
+        if !self.clients.contains_key(&message.src) && self.clients.len() >= self.max_clients {
+            self.evict();
+        }
+        let session = self.clients.entry(message.src.clone()).or_default();
+        session.last_active = self.clock;
+
+        if let Some((_, body)) = session.replies.iter().find(|(request, _)| *request == id) {
+            self.replayed += 1;
+            return Admission::Answered(Message {
+                src: self.node.clone(),
+                dst: message.src.clone(),
+                body: body.clone(),
+            });
+        }
+        if session.in_flight.contains(&id) || session.forgotten.is_some_and(|old| id <= old) {
+            return Admission::Duplicate;
+        }
+        session.in_flight.insert(id);
+        // Requests that never get answered mustn't pile up either
+        while session.in_flight.len() > self.max_replies {
+            session.in_flight.pop_first();
+        }
+        Admission::New
+    }
+
+    /// Sends `reply`, remembering it if it answers a request admitted earlier
+    pub fn reply<P>(&mut self, reply: &Message<P>, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        reply.send(output)?;
+        let Some(request) = reply.body.in_reply_to else {
+            return Ok(());
+        };
+        let Some(session) = self.clients.get_mut(&reply.dst) else {
+            return Ok(());
+        };
+        if !session.in_flight.remove(&request) {
+            return Ok(());
+        }
+        let payload = serde_json::to_value(&reply.body.payload)?;
+        if payload.get("type").and_then(Value::as_str) == Some("error") {
+            return Ok(());
+        }
+        let body = Body {
+            id: reply.body.id,
+            in_reply_to: Some(request),
+            payload,
+        };
+        session.replies.push_back((request, body));
+        while session.replies.len() > self.max_replies {
+            if let Some((old, _)) = session.replies.pop_front() {
+                session.forgotten = session.forgotten.max(Some(old));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops treating a request as being handled, e.g. because handling it failed, so that a
+    /// retry is handled afresh
+    pub fn abandon(&mut self, client: &str, request: Option<MessageID>) {
+        if let (Some(session), Some(request)) = (self.clients.get_mut(client), request) {
+            session.in_flight.remove(&request);
+        }
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .clients
+            .iter()
+            .min_by_key(|(_, session)| session.last_active)
+            .map(|(client, _)| client.clone());
+        if let Some(client) = oldest {
+            self.clients.remove(&client);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Partitioning keys across nodes with consistent hashing
+//!
+//! Every node places a number of virtual points on a hash ring, and a key belongs to the first
+//! node found walking clockwise from the key's own hash. Nodes build identical rings from the
+//! init message, so they all agree on who owns what without coordinating.
+//!
+//! [`Shards`] pairs a ring with a [`Forwarder`], so a node can handle the keys it owns and hand
+//! everything else to the owner:
+//!
+//! ```ignore
+//! if shards.relay(&message, output)? {
+//!     return Ok(());
+//! }
+//! let Some(message) = shards.route(&key, message, &mut self.id, output)? else {
+//!     return Ok(());
+//! };
+//! // This node owns `key`
+//! ```
+use crate::{forward::Forwarder, Init, Message, MessageID, NodeID};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    io::Write,
+    time::Duration,
+};
+
+/// How many points each node gets on the ring by default
+const VIRTUAL_NODES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    points: BTreeMap<u64, NodeID>,
+    nodes: usize,
+}
+
+impl HashRing {
+    /// Builds a ring giving each node `virtual_nodes` points
+    pub fn new(node_ids: &[NodeID], virtual_nodes: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for node in node_ids {
+            for i in 0..virtual_nodes.max(1) {
+                points.insert(hash(&(node, i)), node.clone());
+            }
+        }
+        Self {
+            points,
+            nodes: node_ids.len(),
+        }
+    }
+
+    /// The node responsible for `key`
+    pub fn owner(&self, key: &(impl Hash + ?Sized)) -> &NodeID {
+        self.walk(key).next().expect("hash ring has no nodes")
+    }
+
+    /// The `n` distinct nodes responsible for `key`, starting with its owner
+    ///
+    /// Returns every node if there are fewer than `n`.
+    pub fn replicas(&self, key: &(impl Hash + ?Sized), n: usize) -> Vec<&NodeID> {
+        let mut replicas: Vec<&NodeID> = Vec::with_capacity(n.min(self.nodes));
+        for node in self.walk(key) {
+            if replicas.len() == n.min(self.nodes) {
+                break;
+            }
+            if !replicas.contains(&node) {
+                replicas.push(node);
+            }
+        }
+        replicas
+    }
+
+    /// Every point clockwise from the key's position, wrapping around once
+    fn walk(&self, key: &(impl Hash + ?Sized)) -> impl Iterator<Item = &NodeID> {
+        let start = hash(key);
+        self.points
+            .range(start..)
+            .chain(self.points.range(..start))
+            .map(|(_, node)| node)
+    }
+}
+
+/// A hash ring along with the forwarding needed to act on it
+#[derive(Debug)]
+pub struct Shards {
+    node: NodeID,
+    ring: HashRing,
+    forwarder: Forwarder,
+}
+
+impl Shards {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            node: init.node_id.clone(),
+            ring: HashRing::new(&init.node_ids, VIRTUAL_NODES),
+            forwarder: Forwarder::new(init.node_id.clone()),
+        }
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    pub fn owner(&self, key: &(impl Hash + ?Sized)) -> &NodeID {
+        self.ring.owner(key)
+    }
+
+    pub fn is_local(&self, key: &(impl Hash + ?Sized)) -> bool {
+        *self.ring.owner(key) == self.node
+    }
+
+    /// Hands `request` back if this node owns `key`, and otherwise forwards it to the owner
+    pub fn route<P>(
+        &mut self,
+        key: &(impl Hash + ?Sized),
+        request: Message<P>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<Message<P>>>
+    where
+        P: Serialize,
+    {
+        let owner = self.ring.owner(key);
+        if *owner == self.node {
+            return Ok(Some(request));
+        }
+        let owner = owner.clone();
+        self.forwarder.forward(request, owner, id, output)?;
+        Ok(None)
+    }
+
+    /// Relays the owner's reply to a routed request back to its client; see [`Forwarder::relay`]
+    pub fn relay<P>(&mut self, reply: &Message<P>, output: &mut impl Write) -> anyhow::Result<bool>
+    where
+        P: Serialize,
+    {
+        self.forwarder.relay(reply, output)
+    }
+
+    /// Hands back the reply to relay to a routed request's client; see [`Forwarder::relayed`]
+    pub fn relayed<'a, P>(&mut self, reply: &'a Message<P>) -> Option<Message<&'a P>> {
+        self.forwarder.relayed(reply)
+    }
+
+    /// Forgets routed requests the owner hasn't answered within `timeout`; see
+    /// [`Forwarder::expire`]
+    pub fn expire(&mut self, timeout: Duration) -> usize {
+        self.forwarder.expire(timeout)
+    }
+}
+
+fn hash(value: &(impl Hash + ?Sized)) -> u64 {
+    // Keys are fixed, so every node places things identically
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
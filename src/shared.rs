@@ -0,0 +1,168 @@
+//! State shared between a node and the background threads it spawns
+//!
+//! Background threads otherwise only talk to the node through injected events, which is fine for
+//! telling it something but no use for asking: a thread deciding whom to retry can't see the
+//! node's pending acks. A [`Shared`] value is visible to both sides. It's only ever borrowed for
+//! the length of a closure, so a lock can't be held across a blocking call by accident:
+//!
+//! ```ignore
+//! let pending = Shared::new(HashMap::new());
+//! let watcher = pending.clone();
+//! std::thread::spawn(move || loop {
+//!     std::thread::sleep(Duration::from_millis(100));
+//!     let overdue = watcher.read(|pending| pending.keys().copied().collect::<Vec<_>>());
+//!     if runtime.inject(Injected::Retry(overdue)).is_err() {
+//!         return;
+//!     }
+//! });
+//! ...
+//! Payload::BroadcastOk => self.pending.write(|pending| pending.remove(&in_reply_to)),
+//! ```
+//!
+//! The two ways left to deadlock are refused with a panic instead: touching a shared value again
+//! from within a closure that already borrows it, and queueing an event for the node (with
+//! [`Runtime::send`](crate::Runtime::send) and the like) from within any closure, which could
+//! wait on a `step` that's itself waiting for the lock.
+use std::{
+    cell::RefCell,
+    fmt,
+    sync::{Arc, RwLock, TryLockError},
+};
+
+thread_local! {
+    /// Addresses of the shared values this thread is borrowing
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A value readable and writable from the node and any number of threads
+pub struct Shared<T> {
+    inner: Arc<RwLock<T>>,
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(value)),
+        }
+    }
+
+    /// Runs `f` with the value borrowed, alongside any other readers
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let _held = Held::enter(self.address());
+        let guard = self
+            .inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&guard)
+    }
+
+    /// Runs `f` with the value borrowed mutably, waiting for every other borrow to finish
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _held = Held::enter(self.address());
+        let mut guard = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut guard)
+    }
+
+    /// Like [`Shared::read`], but gives up rather than wait for a writer
+    pub fn try_read<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let _held = Held::enter(self.address());
+        let guard = match self.inner.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(f(&guard))
+    }
+
+    /// Like [`Shared::write`], but gives up rather than wait for other borrows
+    pub fn try_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let _held = Held::enter(self.address());
+        let mut guard = match self.inner.try_write() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(f(&mut guard))
+    }
+
+    /// A copy of the value as it is now
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        self.read(T::clone)
+    }
+
+    /// Replaces the value, returning the old one
+    pub fn replace(&self, value: T) -> T {
+        self.write(|current| std::mem::replace(current, value))
+    }
+
+    fn address(&self) -> usize {
+        Arc::as_ptr(&self.inner) as *const () as usize
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Going around the borrow check, since formatting can't block
+        match self.inner.try_read() {
+            Ok(value) => write!(f, "Shared({:?})", &*value),
+            Err(_) => f.write_str("Shared(<locked>)"),
+        }
+    }
+}
+
+/// Marks a shared value as borrowed by this thread until dropped
+struct Held(usize);
+
+impl Held {
+    fn enter(address: usize) -> Self {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            assert!(
+                !held.contains(&address),
+                "shared value borrowed again while already borrowed, which would deadlock"
+            );
+            held.push(address);
+        });
+        Self(address)
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(at) = held.iter().rposition(|&address| address == self.0) {
+                held.remove(at);
+            }
+        });
+    }
+}
+
+/// Panics if this thread is borrowing a shared value, before it blocks on the node
+pub(crate) fn assert_unheld(action: &str) {
+    let holding = HELD.with(|held| !held.borrow().is_empty());
+    assert!(
+        !holding,
+        "{action} while borrowing a shared value could deadlock with the node's step"
+    );
+}
@@ -0,0 +1,154 @@
+//! Turning SIGTERM and SIGINT into [`Event::Shutdown`]
+//!
+//! Killed outright, a node can die halfway through writing a message or a snapshot. Instead,
+//! the main loops queue a shutdown when either signal arrives, so the node steps it like any
+//! other shutdown, saves its final snapshot, and flushes its output before exiting. A second
+//! signal kills the node the usual way, in case it's stuck.
+use crate::Runtime;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+pub(crate) struct Signals {
+    received: Arc<AtomicBool>,
+}
+
+impl Signals {
+    /// Installs the handlers, queueing a shutdown through `runtime` on the first signal
+    pub(crate) fn forward<Payload, InjectedPayload>(
+        runtime: Runtime<Payload, InjectedPayload>,
+    ) -> anyhow::Result<Self>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let received = Arc::new(AtomicBool::new(false));
+        imp::forward(runtime, Arc::clone(&received))?;
+        Ok(Self { received })
+    }
+
+    /// Whether the node is shutting down because of a signal, in which case its input may never
+    /// close
+    pub(crate) fn received(&self) -> bool {
+        self.received.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+fn shut_down<Payload, InjectedPayload>(
+    name: &str,
+    runtime: &Runtime<Payload, InjectedPayload>,
+    received: &AtomicBool,
+) {
+    eprintln!("rasengan: received {name}, shutting down");
+    received.store(true, Ordering::SeqCst);
+    let _ = runtime.send(crate::Event::Shutdown);
+}
+
+#[cfg(unix)]
+mod imp {
+    use crate::Runtime;
+    use anyhow::bail;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    };
+
+    /// Write end of the pipe that hands signals from the handler to the forwarding thread
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        // Only async-signal-safe calls in here
+        unsafe {
+            if SIGNALED.swap(true, Ordering::SeqCst) {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+                return;
+            }
+            let byte = signal as u8;
+            libc::write(
+                PIPE.load(Ordering::SeqCst),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+            );
+        }
+    }
+
+    pub(super) fn forward<Payload, InjectedPayload>(
+        runtime: Runtime<Payload, InjectedPayload>,
+        received: Arc<AtomicBool>,
+    ) -> anyhow::Result<()>
+    where
+        Payload: Send + 'static,
+        InjectedPayload: Send + 'static,
+    {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            bail!(
+                "could not create signal pipe: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let [read, write] = fds;
+        if PIPE
+            .compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            unsafe {
+                libc::close(read);
+                libc::close(write);
+            }
+            bail!("signal handlers are already installed");
+        }
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                    bail!(
+                        "could not install signal handler: {}",
+                        std::io::Error::last_os_error()
+                    );
+                }
+            }
+        }
+        std::thread::spawn(move || loop {
+            let mut byte = 0u8;
+            let n = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            match n {
+                1 => {
+                    let name = match byte as libc::c_int {
+                        libc::SIGTERM => "SIGTERM",
+                        libc::SIGINT => "SIGINT",
+                        _ => "a signal",
+                    };
+                    super::shut_down(name, &runtime, &received);
+                    return;
+                }
+                -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+                    continue
+                }
+                _ => return,
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use crate::Runtime;
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    /// Signals aren't forwarded on this platform, so the node stops however the OS stops it
+    pub(super) fn forward<Payload, InjectedPayload>(
+        _runtime: Runtime<Payload, InjectedPayload>,
+        _received: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! A whole cluster of nodes running inside one process
+//!
+//! A [`Cluster`] initializes every node the way the runtime would and then steps them only when
+//! told to: each [`deliver`](Cluster::deliver) or [`inject`](Cluster::inject) runs a single
+//! step and hands back the messages it sent, leaving it to the caller to decide which of them
+//! arrive, when, and in what order. Nothing runs in the background: ticks a node sets up with
+//! [`Runtime::every`] or [`Runtime::schedule`] never fire on their own, so whatever drives the
+//! cluster injects them explicitly. Given the same seed, a cluster behaves identically every time,
+//! which is what [model checking](crate::model) and fault injection build on.
+//!
+//! Messages travel as `Message<serde_json::Value>`, so they can be stored, compared, and
+//! fingerprinted without knowing the node's payload type.
+use crate::{
+    failure_detector::{Liveness, Suspicion},
+    Event, Init, Message, Node, NodeID, Output, Runtime,
+};
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::marker::PhantomData;
+
+/// A message as it travels between simulated nodes
+pub type Envelope = Message<Value>;
+
+pub struct Cluster<N, S, P, I = ()> {
+    ids: Vec<NodeID>,
+    nodes: Vec<N>,
+    seed: u64,
+    config: serde_json::Map<String, Value>,
+    _types: PhantomData<fn(S, P, I)>,
+}
+
+impl<N, S, P, I> Clone for Cluster<N, S, P, I>
+where
+    N: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            ids: self.ids.clone(),
+            nodes: self.nodes.clone(),
+            seed: self.seed,
+            config: self.config.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<N, S, P, I> Cluster<N, S, P, I>
+where
+    N: Node<S, P, I>,
+    P: DeserializeOwned + Send + 'static,
+    I: Send + 'static,
+{
+    /// Initializes nodes `n1` through `n{count}`, each with its own state from `state`
+    pub fn new(count: usize, seed: u64, state: impl FnMut(&NodeID) -> S) -> anyhow::Result<Self> {
+        Self::with_config(count, seed, serde_json::Map::new(), state)
+    }
+
+    /// Like [`Cluster::new`], with `config` passed as extra fields on every init message
+    pub fn with_config(
+        count: usize,
+        seed: u64,
+        config: serde_json::Map<String, Value>,
+        mut state: impl FnMut(&NodeID) -> S,
+    ) -> anyhow::Result<Self> {
+        let ids: Vec<NodeID> = (1..=count).map(|i| NodeID::from(format!("n{i}"))).collect();
+        let nodes = ids
+            .iter()
+            .map(|id| init_node(id, &ids, seed, &config, state(id)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            ids,
+            nodes,
+            seed,
+            config,
+            _types: PhantomData,
+        })
+    }
+
+    pub fn ids(&self) -> &[NodeID] {
+        &self.ids
+    }
+
+    pub fn nodes(&self) -> &[N] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: &str) -> Option<&N> {
+        self.index(id).map(|i| &self.nodes[i])
+    }
+
+    /// Whether `id` is one of the cluster's nodes rather than a client
+    pub fn contains(&self, id: &str) -> bool {
+        self.index(id).is_some()
+    }
+
+    /// Steps the message's destination with it, returning what that node sent
+    pub fn deliver(&mut self, message: Envelope) -> anyhow::Result<Vec<Envelope>> {
+        let i = self
+            .index(&message.dst)
+            .with_context(|| format!("{} isn't a node in the cluster", message.dst))?;
+        let message = Message {
+            src: message.src,
+            dst: message.dst,
+            body: crate::Body {
+                id: message.body.id,
+                in_reply_to: message.body.in_reply_to,
+                payload: serde_json::from_value(message.body.payload)
+                    .context("message payload doesn't fit the node's payload type")?,
+            },
+        };
+        self.step(i, Event::Message(message))
+    }
+
+    /// Steps a node with an injected event, returning what it sent
+    pub fn inject(&mut self, node: &str, payload: I) -> anyhow::Result<Vec<Envelope>> {
+        let i = self
+            .index(node)
+            .with_context(|| format!("{node} isn't a node in the cluster"))?;
+        self.step(i, Event::Injected(payload))
+    }
+
+    /// Replaces a node with a freshly initialized one, as if it had crashed and restarted
+    ///
+    /// Whatever the node persisted on its own (such as a write-ahead log) is up to its state and
+    /// init to bring back.
+    pub fn restart(&mut self, node: &str, state: S) -> anyhow::Result<()> {
+        let i = self
+            .index(node)
+            .with_context(|| format!("{node} isn't a node in the cluster"))?;
+        self.nodes[i] = init_node(&self.ids[i], &self.ids, self.seed, &self.config, state)?;
+        Ok(())
+    }
+
+    fn index(&self, id: &str) -> Option<usize> {
+        self.ids.iter().position(|node| node == id)
+    }
+
+    fn step(&mut self, i: usize, event: Event<P, I>) -> anyhow::Result<Vec<Envelope>> {
+        let (mut output, sent) = Output::capture();
+        self.nodes[i]
+            .step(event, &mut output)
+            .with_context(|| format!("{} failed to step", self.ids[i]))?;
+        std::io::Write::flush(&mut output)?;
+        drop(output);
+        let mut messages = Vec::new();
+        for chunk in sent {
+            for line in chunk.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+                messages.push(serde_json::from_slice(line).with_context(|| {
+                    format!("{} sent something other than a message", self.ids[i])
+                })?);
+            }
+        }
+        Ok(messages)
+    }
+}
+
+fn init_node<N, S, P, I>(
+    id: &NodeID,
+    ids: &[NodeID],
+    seed: u64,
+    config: &serde_json::Map<String, Value>,
+    state: S,
+) -> anyhow::Result<N>
+where
+    N: Node<S, P, I>,
+    P: DeserializeOwned + Send + 'static,
+    I: Send + 'static,
+{
+    let init = Init {
+        node_id: id.clone(),
+        node_ids: ids.to_vec(),
+        extra: config.clone(),
+    };
+    // Nobody heartbeats, so every peer stays alive
+    let liveness = Liveness::new(&init, None, Suspicion::Timeout(Default::default()));
+    // The queue is dropped right away, so background ticks stop at their first attempt
+    let (runtime, _queue) = Runtime::new(1, &init, seed, liveness);
+    N::from_init(state, init, runtime).with_context(|| format!("{id} failed to initialize"))
+}
@@ -0,0 +1,68 @@
+//! Periodic on-disk snapshots of node state
+//!
+//! When enabled, the runtime hands the most recent snapshot to [`Node::restore`](crate::Node::restore)
+//! at startup and periodically persists whatever [`Node::snapshot`](crate::Node::snapshot) returns.
+//! Snapshots are written to a temporary file and atomically renamed into place, so a crash never
+//! leaves a half-written snapshot behind. Each save is followed by
+//! [`Node::snapshotted`](crate::Node::snapshotted), for the node to compact any log it keeps.
+//! [Concurrent nodes](crate::ConcurrentNode) are snapshotted the same way, between steps.
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+pub struct SnapshotStore {
+    path: PathBuf,
+    interval: Duration,
+    last: Instant,
+}
+
+impl SnapshotStore {
+    /// Creates a store for the given node's snapshots inside `dir`
+    pub fn new(dir: impl AsRef<Path>, node_id: &str, interval: Duration) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create snapshot directory {}", dir.display()))?;
+        Ok(Self {
+            path: dir.join(format!("{node_id}.snapshot.json")),
+            interval,
+            last: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the latest snapshot, if one has been written
+    pub fn load(&self) -> anyhow::Result<Option<serde_json::Value>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("failed to read snapshot"),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .context("snapshot could not be deserialized")
+    }
+
+    /// Whether the snapshot interval has elapsed since the last save
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= self.interval
+    }
+
+    /// Durably replaces the stored snapshot
+    pub fn save(&mut self, snapshot: &serde_json::Value) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = File::create(&tmp).context("failed to create snapshot file")?;
+        serde_json::to_writer(&mut file, snapshot).context("serialize snapshot")?;
+        file.flush()?;
+        file.sync_all().context("failed to fsync snapshot")?;
+        std::fs::rename(&tmp, &self.path).context("failed to move snapshot into place")?;
+        self.last = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Terms (or epochs) for fencing off nodes that don't know they've been superseded
+//!
+//! A leader that's been partitioned away keeps believing it leads until it hears otherwise, and
+//! anything it writes in the meantime conflicts with the new leader's writes. Stamping every
+//! message between nodes with the sender's term lets the receiver tell: messages from an earlier
+//! term are refused with an error reply, and a message from a later term means this node has been
+//! superseded and should step down. Terms only ever grow, even across restarts when kept in a
+//! directory:
+//!
+//! ```ignore
+//! Message::new(self.node.clone(), peer.clone())
+//!     .payload(Wire::Replica(self.terms.stamp(Replica::Append { entry })))
+//!     .send(output)?;
+//! ...
+//! Wire::Replica(stamped) => {
+//!     let body = Body { payload: stamped, ..input.body };
+//!     match self.terms.admit(Message { src: input.src, dst: input.dst, body }, output)? {
+//!         Verdict::Current(message) => ...,
+//!         Verdict::Newer(message) => { self.step_down(); ... }
+//!         Verdict::Stale => {}
+//!     }
+//! }
+//! ```
+use crate::{wal::Wal, Body, ErrorCode, ErrorPayload, Message};
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::Path};
+
+pub type Term = u64;
+
+/// A payload along with the term its sender was in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Stamped<P> {
+    pub term: Term,
+    #[serde(flatten)]
+    pub payload: P,
+}
+
+/// What to make of a stamped message
+#[derive(Debug)]
+pub enum Verdict<P> {
+    /// Sent in this node's current term
+    Current(Message<P>),
+    /// Sent in a later term, which this node has now moved to; a leader should step down
+    Newer(Message<P>),
+    /// Sent in an earlier term and refused
+    Stale,
+}
+
+/// This node's current term, kept on disk if given a directory
+pub struct Terms {
+    current: Term,
+    wal: Option<Wal<Term>>,
+}
+
+impl Terms {
+    /// Starts at term 0 and forgets the term on restart
+    pub fn new() -> Self {
+        Self {
+            current: 0,
+            wal: None,
+        }
+    }
+
+    /// Picks up where the node left off before a restart, keeping the term in `dir`
+    pub fn persistent(dir: impl AsRef<Path>, node_id: &str) -> anyhow::Result<Self> {
+        let (wal, terms) = Wal::for_node(dir.as_ref().join("terms"), node_id)?;
+        Ok(Self {
+            current: terms.into_iter().max().unwrap_or(0),
+            wal: Some(wal),
+        })
+    }
+
+    pub fn current(&self) -> Term {
+        self.current
+    }
+
+    /// Moves on to the next term, e.g. when standing for election, and returns it
+    pub fn advance(&mut self) -> anyhow::Result<Term> {
+        self.set(self.current + 1)?;
+        Ok(self.current)
+    }
+
+    /// Moves to `term` if it's later than the current one, returning whether it was
+    pub fn observe(&mut self, term: Term) -> anyhow::Result<bool> {
+        if term <= self.current {
+            return Ok(false);
+        }
+        self.set(term)?;
+        Ok(true)
+    }
+
+    /// Stamps an outgoing payload with the current term
+    pub fn stamp<P>(&self, payload: P) -> Stamped<P> {
+        Stamped {
+            term: self.current,
+            payload,
+        }
+    }
+
+    /// Checks an incoming message's term, moving to it if it's later and refusing the message
+    /// if it's earlier
+    ///
+    /// Stale requests are answered with an `abort` error naming the current term; stale replies
+    /// are dropped.
+    pub fn admit<P>(
+        &mut self,
+        message: Message<Stamped<P>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Verdict<P>> {
+        let Message { src, dst, body } = message;
+        let term = body.payload.term;
+        let message = Message {
+            src,
+            dst,
+            body: Body {
+                id: body.id,
+                in_reply_to: body.in_reply_to,
+                payload: body.payload.payload,
+            },
+        };
+        if term < self.current {
+            if let (Some(id), None) = (message.body.id, message.body.in_reply_to) {
+                Message::new(message.dst, message.src)
+                    .in_reply_to(id)
+                    .payload(ErrorPayload::Error {
+                        code: ErrorCode::Abort,
+                        text: format!("stale term {term}; the current term is {}", self.current),
+                    })
+                    .send(output)?;
+            }
+            return Ok(Verdict::Stale);
+        }
+        if self.observe(term)? {
+            return Ok(Verdict::Newer(message));
+        }
+        Ok(Verdict::Current(message))
+    }
+
+    fn set(&mut self, term: Term) -> anyhow::Result<()> {
+        // On disk before anything goes out stamped with it
+        if let Some(wal) = &mut self.wal {
+            wal.append(&term)?;
+        }
+        self.current = term;
+        Ok(())
+    }
+}
+
+impl Default for Terms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,114 @@
+//! One-shot timers that fire injected events
+//!
+//! [`Runtime::schedule`](crate::Runtime::schedule) arms a timer and hands back a
+//! [`TimerHandle`] that can later [cancel](crate::Runtime::cancel) or
+//! [reset](crate::Runtime::reschedule) it, e.g. to retransmit unless an ack shows up first or to
+//! start an election unless the leader is heard from. Pending timers are kept ordered by
+//! deadline and served by a single thread, started the first time one is scheduled.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Condvar, Mutex, MutexGuard},
+    time::Instant,
+};
+
+/// Identifies a scheduled timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerHandle(u64);
+
+pub(crate) struct Timers<T> {
+    state: Mutex<TimerState<T>>,
+    /// Signalled when the earliest deadline may have changed
+    changed: Condvar,
+}
+
+pub(crate) struct TimerState<T> {
+    next: u64,
+    /// Pending timers by deadline, ties broken by scheduling order
+    queue: BTreeMap<(Instant, u64), T>,
+    deadlines: HashMap<u64, Instant>,
+    /// Whether the thread serving the timers has been started
+    pub(crate) serving: bool,
+}
+
+impl<T> Timers<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(TimerState {
+                next: 0,
+                queue: BTreeMap::new(),
+                deadlines: HashMap::new(),
+                serving: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, TimerState<T>> {
+        // Nothing panics while holding the lock, so the state is always consistent
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn schedule(&self, deadline: Instant, payload: T) -> TimerHandle {
+        let mut state = self.lock();
+        let id = state.next;
+        state.next += 1;
+        state.queue.insert((deadline, id), payload);
+        state.deadlines.insert(id, deadline);
+        self.changed.notify_one();
+        TimerHandle(id)
+    }
+
+    pub(crate) fn cancel(&self, handle: TimerHandle) -> Option<T> {
+        let mut state = self.lock();
+        let deadline = state.deadlines.remove(&handle.0)?;
+        state.queue.remove(&(deadline, handle.0))
+    }
+
+    pub(crate) fn reschedule(&self, handle: TimerHandle, deadline: Instant) -> bool {
+        let mut state = self.lock();
+        let Some(previous) = state.deadlines.insert(handle.0, deadline) else {
+            return false;
+        };
+        let payload = state
+            .queue
+            .remove(&(previous, handle.0))
+            .expect("every pending timer is queued");
+        state.queue.insert((deadline, handle.0), payload);
+        self.changed.notify_one();
+        true
+    }
+
+    /// Blocks until the earliest timer is due, then takes it
+    pub(crate) fn next_due(&self) -> T {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            state = match state.queue.first_key_value() {
+                Some((&(deadline, id), _)) if deadline <= now => {
+                    state.deadlines.remove(&id);
+                    return state
+                        .queue
+                        .remove(&(deadline, id))
+                        .expect("the first timer was just seen");
+                }
+                Some((&(deadline, _), _)) => {
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+
+    /// How many timers are waiting to fire
+    pub(crate) fn pending(&self) -> usize {
+        self.lock().queue.len()
+    }
+}
@@ -0,0 +1,368 @@
+//! Sequencer-based total-order broadcast
+//!
+//! The node with the lowest ID acts as the sequencer: every other node forwards the values it
+//! wants to broadcast to it, and the sequencer stamps each one with the next position in the
+//! global order before fanning it out. Each node delivers values strictly in sequence order,
+//! buffering anything that arrives early.
+//!
+//! Nodes that aren't the sequencer call [`TotalOrder::sync`] periodically, which also tells the
+//! sequencer how far they've got, so it only keeps what some node may still ask for again.
+//!
+//! [`TobPayload`] is meant to be embedded in a node's own payload through an untagged enum:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! #[serde(untagged)]
+//! enum Payload {
+//!     Tob(TobPayload<usize>),
+//!     Client(ClientPayload),
+//! }
+//! ```
+use crate::{Init, Message, NodeID};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Submission IDs a node can hand out per millisecond of wall-clock time before an incarnation
+/// that restarts a millisecond later could reuse them
+const IDS_PER_MILLI: u64 = 1 << 20;
+
+/// The submissions the sequencer has assigned a position to, from one node
+#[derive(Default)]
+struct Sequenced {
+    /// Every submission below this ID has been delivered back to the node
+    below: u64,
+    ids: HashSet<u64>,
+}
+
+impl Sequenced {
+    /// Records a submission, returning whether it was new
+    fn insert(&mut self, id: u64) -> bool {
+        id >= self.below && self.ids.insert(id)
+    }
+
+    fn forget_below(&mut self, below: u64) {
+        if below > self.below {
+            self.below = below;
+            self.ids.retain(|&id| id >= below);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TobPayload<T> {
+    /// Asks the sequencer to assign a position to a value
+    TobSubmit {
+        id: u64,
+        value: T,
+        /// The node that submitted the value, when it's been passed along by another
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        origin: Option<NodeID>,
+    },
+    /// A value along with its position in the global order
+    TobDeliver {
+        seq: u64,
+        origin: NodeID,
+        id: u64,
+        value: T,
+    },
+    /// Asks the sequencer to resend everything from position `from` onwards
+    TobSync {
+        from: u64,
+        /// Every value the sender submitted with a lower ID has been delivered back to it
+        #[serde(default)]
+        submitted: u64,
+    },
+}
+
+pub struct TotalOrder<T> {
+    node: NodeID,
+    sequencer: NodeID,
+    peers: Vec<NodeID>,
+    /// The values sequenced that some node may still ask for (only populated on the sequencer)
+    log: VecDeque<(NodeID, u64, T)>,
+    /// The position of the first value in `log`
+    logged_from: u64,
+    /// How far each peer has delivered, going by its latest sync
+    synced: HashMap<NodeID, u64>,
+    /// Submissions the sequencer has already assigned a position to, by the node submitting them
+    sequenced: HashMap<NodeID, Sequenced>,
+    /// Values submitted by this node that haven't been delivered back to it yet
+    outstanding: BTreeMap<u64, T>,
+    /// Starts from the wall clock, so a restarted node never reuses an ID the sequencer has seen
+    next_id: u64,
+    /// Position of the next value to deliver locally
+    next: u64,
+    /// Values received ahead of their turn
+    pending: BTreeMap<u64, (NodeID, u64, T)>,
+}
+
+impl<T> TotalOrder<T>
+where
+    T: Clone + Serialize,
+{
+    pub fn new(init: &Init) -> Self {
+        let sequencer = init
+            .node_ids
+            .iter()
+            .min()
+            .cloned()
+            .unwrap_or_else(|| init.node_id.clone());
+        Self {
+            node: init.node_id.clone(),
+            sequencer,
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|&id| *id != init.node_id)
+                .cloned()
+                .collect(),
+            log: VecDeque::new(),
+            logged_from: 0,
+            synced: HashMap::new(),
+            sequenced: HashMap::new(),
+            outstanding: BTreeMap::new(),
+            next_id: first_id(),
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_sequencer(&self) -> bool {
+        self.node == self.sequencer
+    }
+
+    pub fn sequencer(&self) -> &NodeID {
+        &self.sequencer
+    }
+
+    /// The number of values delivered locally so far
+    pub fn delivered(&self) -> u64 {
+        self.next
+    }
+
+    /// Submits a value for total-order broadcast, returning any values that became deliverable
+    pub fn broadcast<P>(
+        &mut self,
+        value: T,
+        output: &mut impl Write,
+        wrap: impl Fn(TobPayload<T>) -> P,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        P: Serialize,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.is_sequencer() {
+            let origin = self.node.clone();
+            return self.sequence(origin, id, value, output, wrap);
+        }
+        self.outstanding.insert(id, value.clone());
+        self.send(
+            self.sequencer.clone(),
+            wrap(TobPayload::TobSubmit {
+                id,
+                value,
+                origin: None,
+            }),
+            output,
+        )?;
+        Ok(Vec::new())
+    }
+
+    /// Processes a total-order message from `src`, returning the values that are now deliverable
+    /// in their global order
+    pub fn handle<P>(
+        &mut self,
+        src: &NodeID,
+        payload: TobPayload<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TobPayload<T>) -> P,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        P: Serialize,
+    {
+        match payload {
+            TobPayload::TobSubmit { id, value, origin } => {
+                let origin = origin.unwrap_or_else(|| src.clone());
+                if !self.is_sequencer() {
+                    // Someone believes we're the sequencer; pass it along to the real one, on
+                    // behalf of whoever submitted it so that it's their submission acknowledged
+                    self.send(
+                        self.sequencer.clone(),
+                        wrap(TobPayload::TobSubmit {
+                            id,
+                            value,
+                            origin: Some(origin),
+                        }),
+                        output,
+                    )?;
+                    return Ok(Vec::new());
+                }
+                self.sequence(origin, id, value, output, wrap)
+            }
+            TobPayload::TobDeliver {
+                seq,
+                origin,
+                id,
+                value,
+            } => {
+                // Only the sequencer decides positions
+                if *src == self.sequencer && seq >= self.next {
+                    self.pending.insert(seq, (origin, id, value));
+                }
+                Ok(self.drain())
+            }
+            TobPayload::TobSync { from, submitted } => {
+                if !self.is_sequencer() || !self.peers.contains(src) {
+                    return Ok(Vec::new());
+                }
+                self.sequenced
+                    .entry(src.clone())
+                    .or_default()
+                    .forget_below(submitted);
+                let synced = self.synced.entry(src.clone()).or_default();
+                *synced = (*synced).max(from);
+                let skip =
+                    usize::try_from(from.saturating_sub(self.logged_from)).unwrap_or(usize::MAX);
+                for (seq, (origin, id, value)) in (self.logged_from..).zip(&self.log).skip(skip) {
+                    self.send(
+                        src.clone(),
+                        wrap(TobPayload::TobDeliver {
+                            seq,
+                            origin: origin.clone(),
+                            id: *id,
+                            value: value.clone(),
+                        }),
+                        output,
+                    )?;
+                }
+                self.trim();
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Recovers from lost messages by resubmitting undelivered values and asking the sequencer
+    /// for anything missed. Meant to be called periodically on non-sequencer nodes.
+    pub fn sync<P>(
+        &mut self,
+        output: &mut impl Write,
+        wrap: impl Fn(TobPayload<T>) -> P,
+    ) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        if self.is_sequencer() {
+            return Ok(());
+        }
+        for (&id, value) in &self.outstanding {
+            self.send(
+                self.sequencer.clone(),
+                wrap(TobPayload::TobSubmit {
+                    id,
+                    value: value.clone(),
+                    origin: None,
+                }),
+                output,
+            )?;
+        }
+        let submitted = self
+            .outstanding
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.next_id);
+        self.send(
+            self.sequencer.clone(),
+            wrap(TobPayload::TobSync {
+                from: self.next,
+                submitted,
+            }),
+            output,
+        )
+    }
+
+    fn sequence<P>(
+        &mut self,
+        origin: NodeID,
+        id: u64,
+        value: T,
+        output: &mut impl Write,
+        wrap: impl Fn(TobPayload<T>) -> P,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        P: Serialize,
+    {
+        // The sequencer's own submissions never come around twice
+        if origin != self.node && !self.sequenced.entry(origin.clone()).or_default().insert(id) {
+            // A retransmitted submission that already has a position
+            return Ok(Vec::new());
+        }
+        let seq = self.logged_from + self.log.len() as u64;
+        self.log.push_back((origin.clone(), id, value.clone()));
+        for peer in &self.peers {
+            self.send(
+                peer.clone(),
+                wrap(TobPayload::TobDeliver {
+                    seq,
+                    origin: origin.clone(),
+                    id,
+                    value: value.clone(),
+                }),
+                output,
+            )?;
+        }
+        self.trim();
+        self.pending.insert(seq, (origin, id, value));
+        Ok(self.drain())
+    }
+
+    /// Drops the logged values every peer has delivered
+    fn trim(&mut self) {
+        let delivered = self
+            .peers
+            .iter()
+            .map(|peer| self.synced.get(peer).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(u64::MAX);
+        while self.logged_from < delivered && self.log.pop_front().is_some() {
+            self.logged_from += 1;
+        }
+    }
+
+    /// Pops every buffered value whose turn has come
+    fn drain(&mut self) -> Vec<T> {
+        let mut delivered = Vec::new();
+        while let Some((origin, id, value)) = self.pending.remove(&self.next) {
+            if origin == self.node {
+                self.outstanding.remove(&id);
+            }
+            delivered.push(value);
+            self.next += 1;
+        }
+        delivered
+    }
+
+    fn send<P>(&self, dst: NodeID, payload: P, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        Message::new(self.node.clone(), dst)
+            .payload(payload)
+            .send(output)
+    }
+}
+
+/// The first submission ID for this incarnation, above any an earlier one would have used
+fn first_id() -> u64 {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    millis.saturating_mul(IDS_PER_MILLI)
+}
@@ -0,0 +1,557 @@
+//! Two-phase commit for transactions spanning several nodes
+//!
+//! The node starting a transaction coordinates it. It asks every participant to prepare its part
+//! of the work, and participants either lock it in and vote yes, or refuse with an `error` reply.
+//! Only a unanimous yes commits, and the decision is then sent to every participant. A refusal,
+//! or votes that don't all arrive within the timeout, aborts the transaction instead.
+//!
+//! Recovery is presumed-abort: coordinators only remember commit decisions, and only until every
+//! participant has acknowledged them. A participant left prepared for too long asks the
+//! coordinator for the outcome, and a coordinator with no record of the transaction answers
+//! that it aborted. That's only safe if the coordinator can't forget a commit, so a coordinator
+//! that may be restarted should be made [persistent](TwoPhaseCommit::persistent): commit
+//! decisions are then on disk before any participant hears of them, and are resent after a
+//! restart. Transaction numbers are reserved on disk in batches too, so a restarted coordinator
+//! never reuses one.
+//!
+//! The application's side of the protocol is a [`Resource`]. A participant that is also the
+//! coordinator prepares and commits its own part directly rather than messaging itself.
+//!
+//! Votes travel over [`Rpc`], so the node embeds `ServiceReply<TpcPayload<T>>` in its payload
+//! through an untagged enum. That lets refusals arrive as ordinary `error` bodies.
+use crate::{
+    rpc::{Rpc, ServiceReply},
+    wal::Wal,
+    Body, Error, ErrorPayload, Message, MessageID, NodeID,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How many transaction numbers are reserved on disk at a time
+const SEQ_BATCH: u64 = 1024;
+
+/// Identifies a transaction across the cluster
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TxnID {
+    pub coordinator: NodeID,
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TpcPayload<T> {
+    /// Asks a participant to lock in its part of a transaction and vote on it
+    TpcPrepare {
+        txn: TxnID,
+        ops: T,
+    },
+    /// A yes vote; participants vote no with an error reply
+    TpcPrepareOk {
+        txn: TxnID,
+    },
+    TpcCommit {
+        txn: TxnID,
+    },
+    TpcCommitOk {
+        txn: TxnID,
+    },
+    /// Never acknowledged: a participant that misses it finds out by asking
+    TpcAbort {
+        txn: TxnID,
+    },
+    /// Asks the coordinator how an in-doubt transaction ended
+    TpcStatus {
+        txn: TxnID,
+    },
+}
+
+/// What a persistent coordinator keeps on disk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// Transaction numbers below `upto` may have been handed out
+    Reserved { upto: u64 },
+    /// Committed, but not yet acknowledged by these participants
+    Committed {
+        txn: TxnID,
+        participants: Vec<NodeID>,
+    },
+    /// Every participant acknowledged the commit
+    Acked { txn: TxnID },
+}
+
+/// A participant's share of the work
+pub trait Resource<T> {
+    /// Validates `ops` and holds whatever it needs so that a later commit can't fail; an error
+    /// votes to abort
+    fn prepare(&mut self, txn: &TxnID, ops: T) -> Result<(), Error>;
+
+    /// Applies a prepared transaction
+    fn commit(&mut self, txn: &TxnID);
+
+    /// Releases a prepared transaction without applying it
+    fn abort(&mut self, txn: &TxnID);
+}
+
+/// How a transaction this node coordinated ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub txn: TxnID,
+    pub committed: bool,
+}
+
+#[derive(Debug)]
+struct Voting {
+    /// Participants yet to vote
+    waiting: HashSet<NodeID>,
+    prepared: Vec<NodeID>,
+    calls: Vec<MessageID>,
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct Committing {
+    unacked: HashSet<NodeID>,
+    sent: Instant,
+}
+
+#[derive(Debug)]
+struct InDoubt {
+    coordinator: NodeID,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub struct TwoPhaseCommit {
+    node: NodeID,
+    timeout: Duration,
+    next_seq: u64,
+    /// Transaction numbers below this are reserved on disk
+    reserved: u64,
+    wal: Option<Wal<Record>>,
+    rpc: Rpc<TxnID>,
+    voting: HashMap<TxnID, Voting>,
+    committing: HashMap<TxnID, Committing>,
+    /// Transactions prepared here whose outcome isn't known yet
+    in_doubt: HashMap<TxnID, InDoubt>,
+}
+
+impl TwoPhaseCommit {
+    /// Aborts transactions whose votes take longer than `timeout`, which is also how long
+    /// commits go unacknowledged and participants stay in doubt before anything is resent
+    ///
+    /// Forgets every decision on restart; see [`TwoPhaseCommit::persistent`].
+    pub fn new(node: impl Into<NodeID>, timeout: Duration) -> Self {
+        let node = node.into();
+        Self {
+            rpc: Rpc::new(node.clone()),
+            node,
+            timeout,
+            next_seq: 0,
+            reserved: u64::MAX,
+            wal: None,
+            voting: HashMap::new(),
+            committing: HashMap::new(),
+            in_doubt: HashMap::new(),
+        }
+    }
+
+    /// Like [`TwoPhaseCommit::new`], but keeps commit decisions in `dir` and picks up where the
+    /// node left off before a restart
+    ///
+    /// Commits that weren't acknowledged before the restart are resent on the next tick.
+    /// Transactions that were still voting are forgotten, which aborts them.
+    pub fn persistent(
+        dir: impl AsRef<Path>,
+        node_id: &str,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let (wal, records) = Wal::for_node(dir.as_ref().join("tpc"), node_id)?;
+        let mut tpc = Self::new(node_id, timeout);
+        let mut reserved = 0;
+        let mut committed: HashMap<TxnID, Vec<NodeID>> = HashMap::new();
+        for record in records {
+            match record {
+                Record::Reserved { upto } => reserved = reserved.max(upto),
+                Record::Committed { txn, participants } => {
+                    committed.insert(txn, participants);
+                }
+                Record::Acked { txn } => {
+                    committed.remove(&txn);
+                }
+            }
+        }
+        // Numbers in the last reservation may have been used, so the next batch starts after it
+        tpc.next_seq = reserved;
+        tpc.reserved = reserved;
+        // Due for a resend straight away
+        let sent = Instant::now()
+            .checked_sub(timeout)
+            .unwrap_or_else(Instant::now);
+        tpc.committing = committed
+            .into_iter()
+            .map(|(txn, participants)| {
+                let unacked = participants.into_iter().collect();
+                (txn, Committing { unacked, sent })
+            })
+            .collect();
+        tpc.wal = Some(wal);
+        Ok(tpc)
+    }
+
+    /// Starts a transaction made of each participant's `ops`, which completes through
+    /// [`TwoPhaseCommit::handle`] or [`TwoPhaseCommit::tick`]
+    ///
+    /// Returns the decision straight away if this node is the only participant, or refuses its
+    /// own part.
+    pub fn begin<T, P>(
+        &mut self,
+        parts: impl IntoIterator<Item = (NodeID, T)>,
+        resource: &mut impl Resource<T>,
+        id: &mut MessageID,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<(TxnID, Option<Decision>)>
+    where
+        P: Serialize,
+    {
+        if self.next_seq >= self.reserved {
+            self.reserved = self.next_seq + SEQ_BATCH;
+            self.log(&Record::Reserved {
+                upto: self.reserved,
+            })?;
+        }
+        let txn = TxnID {
+            coordinator: self.node.clone(),
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        let mut voting = Voting {
+            waiting: HashSet::new(),
+            prepared: Vec::new(),
+            calls: Vec::new(),
+            started: Instant::now(),
+        };
+        let mut refused = false;
+        for (participant, ops) in parts {
+            if participant == self.node {
+                match resource.prepare(&txn, ops) {
+                    Ok(()) => voting.prepared.push(participant),
+                    Err(_) => refused = true,
+                }
+            } else {
+                let payload = wrap(TpcPayload::TpcPrepare {
+                    txn: txn.clone(),
+                    ops,
+                });
+                let call = self
+                    .rpc
+                    .call(participant.clone(), payload, txn.clone(), id, output)?;
+                voting.calls.push(call);
+                voting.waiting.insert(participant);
+            }
+        }
+        self.voting.insert(txn.clone(), voting);
+        let decision = if refused {
+            Some(self.abort(&txn, resource, output, &wrap)?)
+        } else {
+            self.tally(&txn, resource, output, &wrap)?
+        };
+        Ok((txn, decision))
+    }
+
+    /// Processes a protocol message from `src`, returning the decision it led to, if any
+    pub fn handle<T, P>(
+        &mut self,
+        src: &NodeID,
+        body: Body<ServiceReply<TpcPayload<T>>>,
+        resource: &mut impl Resource<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<Option<Decision>>
+    where
+        P: Serialize,
+    {
+        let payload = match body.payload {
+            ServiceReply::Ok(TpcPayload::TpcPrepareOk { .. }) | ServiceReply::Err(_) => {
+                return self.vote(src, body, resource, output, wrap);
+            }
+            ServiceReply::Ok(payload) => payload,
+        };
+        match payload {
+            TpcPayload::TpcPrepare { txn, ops } => {
+                let reply = Message::new(self.node.clone(), src.clone());
+                let reply = match body.id {
+                    Some(id) => reply.in_reply_to(id),
+                    None => reply,
+                };
+                // A retried prepare gets the same answer as the first one
+                if self.in_doubt.contains_key(&txn) {
+                    return reply
+                        .payload(wrap(TpcPayload::TpcPrepareOk { txn }))
+                        .send(output)
+                        .map(|()| None);
+                }
+                match resource.prepare(&txn, ops) {
+                    Ok(()) => {
+                        self.in_doubt.insert(
+                            txn.clone(),
+                            InDoubt {
+                                coordinator: src.clone(),
+                                since: Instant::now(),
+                            },
+                        );
+                        reply
+                            .payload(wrap(TpcPayload::TpcPrepareOk { txn }))
+                            .send(output)?;
+                    }
+                    Err(Error { code, text }) => {
+                        reply
+                            .payload(ErrorPayload::Error { code, text })
+                            .send(output)?;
+                    }
+                }
+            }
+            TpcPayload::TpcCommit { txn } => {
+                if self.in_doubt.remove(&txn).is_some() {
+                    resource.commit(&txn);
+                }
+                self.send(src, wrap(TpcPayload::TpcCommitOk { txn }), output)?;
+            }
+            TpcPayload::TpcCommitOk { txn } => {
+                if let Some(committing) = self.committing.get_mut(&txn) {
+                    committing.unacked.remove(src);
+                    if committing.unacked.is_empty() {
+                        self.committing.remove(&txn);
+                        self.acked(txn)?;
+                    }
+                }
+            }
+            TpcPayload::TpcAbort { txn } => {
+                if self.in_doubt.remove(&txn).is_some() {
+                    resource.abort(&txn);
+                }
+            }
+            TpcPayload::TpcStatus { txn } => {
+                if self.committing.contains_key(&txn) {
+                    self.send(src, wrap(TpcPayload::TpcCommit { txn }), output)?;
+                } else if !self.voting.contains_key(&txn) {
+                    // No record means it never committed
+                    self.send(src, wrap(TpcPayload::TpcAbort { txn }), output)?;
+                }
+            }
+            TpcPayload::TpcPrepareOk { .. } => unreachable!("votes are handled above"),
+        }
+        Ok(None)
+    }
+
+    /// Aborts transactions stuck voting, resends unacknowledged commits, and asks about
+    /// transactions left in doubt, returning the aborts
+    ///
+    /// Meant to be driven by a periodic injected event.
+    pub fn tick<T, P>(
+        &mut self,
+        resource: &mut impl Resource<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<Vec<Decision>>
+    where
+        P: Serialize,
+    {
+        let now = Instant::now();
+        let expired = self
+            .voting
+            .iter()
+            .filter(|(_, voting)| now - voting.started > self.timeout)
+            .map(|(txn, _)| txn.clone())
+            .collect::<Vec<_>>();
+        let mut decisions = Vec::with_capacity(expired.len());
+        for txn in expired {
+            decisions.push(self.abort(&txn, resource, output, &wrap)?);
+        }
+        for (txn, committing) in &mut self.committing {
+            if now - committing.sent > self.timeout {
+                committing.sent = now;
+                for participant in &committing.unacked {
+                    Message::new(self.node.clone(), participant.clone())
+                        .payload(wrap(TpcPayload::TpcCommit { txn: txn.clone() }))
+                        .send(output)?;
+                }
+            }
+        }
+        for (txn, in_doubt) in &mut self.in_doubt {
+            if now - in_doubt.since > self.timeout {
+                in_doubt.since = now;
+                Message::new(self.node.clone(), in_doubt.coordinator.clone())
+                    .payload(wrap(TpcPayload::TpcStatus { txn: txn.clone() }))
+                    .send(output)?;
+            }
+        }
+        Ok(decisions)
+    }
+
+    /// Transactions this node is coordinating that haven't been decided yet
+    pub fn voting(&self) -> usize {
+        self.voting.len()
+    }
+
+    /// Transactions prepared here that are waiting on their coordinator's decision
+    pub fn in_doubt(&self) -> usize {
+        self.in_doubt.len()
+    }
+
+    fn vote<T, P>(
+        &mut self,
+        src: &NodeID,
+        body: Body<ServiceReply<TpcPayload<T>>>,
+        resource: &mut impl Resource<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<Option<Decision>>
+    where
+        P: Serialize,
+    {
+        let Some((txn, result)) = self.rpc.complete(body) else {
+            return Ok(None);
+        };
+        let Some(voting) = self.voting.get_mut(&txn) else {
+            return Ok(None);
+        };
+        voting.waiting.remove(src);
+        match result {
+            Ok(_) => {
+                voting.prepared.push(src.clone());
+                self.tally(&txn, resource, output, &wrap)
+            }
+            Err(_) => self.abort(&txn, resource, output, &wrap).map(Some),
+        }
+    }
+
+    /// Commits the transaction once every participant has voted yes
+    fn tally<T, P>(
+        &mut self,
+        txn: &TxnID,
+        resource: &mut impl Resource<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<Option<Decision>>
+    where
+        P: Serialize,
+    {
+        if self
+            .voting
+            .get(txn)
+            .is_none_or(|voting| !voting.waiting.is_empty())
+        {
+            return Ok(None);
+        }
+        let voting = self.voting.remove(txn).expect("transaction is voting");
+        let (local, remote): (Vec<_>, Vec<_>) = voting
+            .prepared
+            .into_iter()
+            .partition(|participant| *participant == self.node);
+        let unacked: HashSet<NodeID> = remote.into_iter().collect();
+        if !unacked.is_empty() {
+            // On disk before any participant can act on it
+            self.log(&Record::Committed {
+                txn: txn.clone(),
+                participants: unacked.iter().cloned().collect(),
+            })?;
+        }
+        if !local.is_empty() {
+            resource.commit(txn);
+        }
+        for participant in &unacked {
+            self.send(
+                participant,
+                wrap(TpcPayload::TpcCommit { txn: txn.clone() }),
+                output,
+            )?;
+        }
+        if !unacked.is_empty() {
+            self.committing.insert(
+                txn.clone(),
+                Committing {
+                    unacked,
+                    sent: Instant::now(),
+                },
+            );
+        }
+        Ok(Some(Decision {
+            txn: txn.clone(),
+            committed: true,
+        }))
+    }
+
+    /// Aborts a transaction that's still voting, releasing whichever participants may have
+    /// prepared
+    fn abort<T, P>(
+        &mut self,
+        txn: &TxnID,
+        resource: &mut impl Resource<T>,
+        output: &mut impl Write,
+        wrap: impl Fn(TpcPayload<T>) -> P,
+    ) -> anyhow::Result<Decision>
+    where
+        P: Serialize,
+    {
+        let voting = self.voting.remove(txn).expect("transaction is voting");
+        for call in voting.calls {
+            self.rpc.cancel(call);
+        }
+        // Participants that haven't voted may have prepared all the same
+        for participant in voting.prepared.into_iter().chain(voting.waiting) {
+            if participant == self.node {
+                resource.abort(txn);
+            } else {
+                self.send(
+                    &participant,
+                    wrap(TpcPayload::TpcAbort { txn: txn.clone() }),
+                    output,
+                )?;
+            }
+        }
+        Ok(Decision {
+            txn: txn.clone(),
+            committed: false,
+        })
+    }
+
+    fn log(&mut self, record: &Record) -> anyhow::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.append(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that a commit needs no more resending, starting the log afresh once no commit
+    /// does
+    fn acked(&mut self, txn: TxnID) -> anyhow::Result<()> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        if !self.committing.is_empty() {
+            return wal.append(&Record::Acked { txn });
+        }
+        wal.truncate()?;
+        wal.append(&Record::Reserved {
+            upto: self.reserved,
+        })
+    }
+
+    fn send<P>(&self, dst: &NodeID, payload: P, output: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        Message::new(self.node.clone(), dst.clone())
+            .payload(payload)
+            .send(output)
+    }
+}
@@ -0,0 +1,228 @@
+//! Where the time goes, per kind of event
+//!
+//! With tracing on (`RASENGAN_TRACE`), the runtime notes when each event is queued and, for every
+//! payload type, keeps histograms of how long events waited in the queue, how long `step` took to
+//! handle them, and how long after arriving a message was answered, counting only replies
+//! written by the step that handled it. The histograms are written to stderr at shutdown and
+//! alongside every debug dump, which makes it easy to see which handler eats the p99 budget.
+use crate::{runtime::Stamp, Event, Output};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// Sub-buckets per power of two; values are recorded to within 1/8th
+const PRECISION: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << PRECISION;
+
+/// A log-linear histogram of durations, in microseconds
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = bucket(micros);
+        if bucket >= self.counts.len() {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.total = self.total.saturating_add(micros);
+        self.max = self.max.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.total.checked_div(self.count).unwrap_or(0))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// The smallest duration at least a `q` fraction of recordings were within, rounded up to
+    /// its bucket
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(upper_bound(bucket).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+/// Small values get a bucket each; larger ones share a bucket with values that have the same
+/// leading `PRECISION + 1` bits
+fn bucket(micros: u64) -> usize {
+    if micros < 2 * SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros();
+    let shift = exp - PRECISION;
+    (shift as u64 * SUB_BUCKETS + (micros >> shift)) as usize
+}
+
+fn upper_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < 2 * SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let mantissa = bucket % SUB_BUCKETS + SUB_BUCKETS;
+    (mantissa << shift) + (1 << shift) - 1
+}
+
+/// How the events of one type fared
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    /// From being queued to being handed to `step`
+    pub queued: Histogram,
+    /// Spent inside `step`
+    pub handler: Histogram,
+    /// From being queued to `step` writing a reply
+    pub reply: Histogram,
+}
+
+#[derive(Debug, Default)]
+pub struct Tracer {
+    traces: BTreeMap<String, Trace>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one stepped event of type `kind`; `replied` is how long after being queued it was
+    /// answered, if the step wrote anything
+    pub fn record(
+        &mut self,
+        kind: &str,
+        queued: Duration,
+        handler: Duration,
+        replied: Option<Duration>,
+    ) {
+        if !self.traces.contains_key(kind) {
+            self.traces.insert(kind.to_owned(), Trace::default());
+        }
+        let trace = self.traces.get_mut(kind).expect("trace was just added");
+        trace.queued.record(queued);
+        trace.handler.record(handler);
+        if let Some(replied) = replied {
+            trace.reply.record(replied);
+        }
+    }
+
+    pub fn traces(&self) -> &BTreeMap<String, Trace> {
+        &self.traces
+    }
+
+    /// Writes every histogram to stderr, one line each
+    pub fn dump(&self, node_id: &str) {
+        if self.traces.is_empty() {
+            eprintln!("rasengan: {node_id} has traced no events");
+        }
+        for (kind, trace) in &self.traces {
+            for (stage, histogram) in [
+                ("queued", &trace.queued),
+                ("handler", &trace.handler),
+                ("reply", &trace.reply),
+            ] {
+                if histogram.count() == 0 {
+                    continue;
+                }
+                eprintln!(
+                    "rasengan: trace {node_id} {kind} {stage}: n={} mean={:?} p50={:?} p90={:?} \
+                     p99={:?} max={:?}",
+                    histogram.count(),
+                    histogram.mean(),
+                    histogram.quantile(0.5),
+                    histogram.quantile(0.9),
+                    histogram.quantile(0.99),
+                    histogram.max(),
+                );
+            }
+        }
+    }
+}
+
+/// An event being stepped
+pub(crate) struct Span {
+    kind: Box<str>,
+    enqueued: Instant,
+    started: Instant,
+    /// Lines the stepping output had written before the step
+    lines: u64,
+}
+
+impl Span {
+    pub(crate) fn start<Payload, InjectedPayload>(
+        event: &Event<Payload, InjectedPayload>,
+        stamp: Stamp,
+        output: &Output,
+    ) -> Self {
+        Self {
+            kind: stamp.kind.unwrap_or_else(|| event_kind(event).into()),
+            enqueued: stamp.enqueued,
+            started: Instant::now(),
+            lines: output.lines(),
+        }
+    }
+
+    pub(crate) fn finish(self, output: &Output, tracer: &mut Tracer) {
+        let finished = Instant::now();
+        let replied = (output.lines() > self.lines).then(|| finished - self.enqueued);
+        tracer.record(
+            &self.kind,
+            self.started - self.enqueued,
+            finished - self.started,
+            replied,
+        );
+    }
+}
+
+/// The `type` of a raw message, which events are traced under
+pub(crate) fn message_type(line: &str) -> Option<Box<str>> {
+    #[derive(Deserialize)]
+    struct Typed {
+        body: Body,
+    }
+    #[derive(Deserialize)]
+    struct Body {
+        r#type: Box<str>,
+    }
+    serde_json::from_str::<Typed>(line)
+        .ok()
+        .map(|typed| typed.body.r#type)
+}
+
+/// What an event is traced under when it isn't a message whose type was noted
+pub(crate) fn event_kind<Payload, InjectedPayload>(
+    event: &Event<Payload, InjectedPayload>,
+) -> &'static str {
+    match event {
+        Event::Message(_) => "message",
+        Event::Injected(_) => "injected",
+        Event::PeerDown(_) => "peer_down",
+        Event::PeerUp(_) => "peer_up",
+        Event::Shutdown => "shutdown",
+    }
+}
@@ -0,0 +1,190 @@
+//! Golden transcripts: a node binary's input and output from a run, replayed later to catch
+//! protocol regressions
+//!
+//! A transcript is stored as JSON lines, each either `{"in": <message>}` or `{"out": <message>}`.
+//! Recording feeds the inputs to a fresh instance of the binary (with a fixed RNG seed) and
+//! collects everything it sends; verifying records again from the same inputs and compares the
+//! outputs with the golden ones:
+//!
+//! ```ignore
+//! let golden = Transcript::load("tests/transcripts/echo.jsonl")?;
+//! let actual = Transcript::record(env!("CARGO_BIN_EXE_echo"), golden.inputs())?;
+//! golden.verify(&actual)?;
+//! ```
+//!
+//! Outputs are compared modulo [`VOLATILE_FIELDS`], and only in the order they were sent to each
+//! destination, since timers and concurrent handlers may interleave destinations differently from
+//! run to run. A new scenario only needs its inputs written down: record it once and save the
+//! result as the golden transcript.
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// The seed every recording runs with, so randomized nodes behave the same each time
+pub const TRANSCRIPT_SEED: u64 = 0;
+
+/// Body fields that legitimately differ between runs and are left out of comparisons
+pub const VOLATILE_FIELDS: &[&str] = &["msg_id", "uptime_ms", "last_heard_ms"];
+
+/// One line of a transcript
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Entry {
+    In(Value),
+    Out(Value),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transcript {
+    pub entries: Vec<Entry>,
+}
+
+impl Transcript {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read transcript {}", path.display()))?;
+        let entries = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("malformed entry {} in {}", i + 1, path.display()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut contents = Vec::new();
+        for entry in &self.entries {
+            serde_json::to_writer(&mut contents, entry)?;
+            contents.push(b'\n');
+        }
+        std::fs::write(path, contents)
+            .with_context(|| format!("failed to write transcript {}", path.display()))
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::In(message) => Some(message),
+            Entry::Out(_) => None,
+        })
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Out(message) => Some(message),
+            Entry::In(_) => None,
+        })
+    }
+
+    /// Runs `bin` over `inputs` until it exits, recording the inputs followed by its outputs
+    ///
+    /// Closing the node's stdin after the last input shuts it down, so the outputs are complete
+    /// once it exits.
+    pub fn record<'a>(
+        bin: impl AsRef<Path>,
+        inputs: impl IntoIterator<Item = &'a Value>,
+    ) -> anyhow::Result<Self> {
+        let bin = bin.as_ref();
+        let inputs: Vec<Value> = inputs.into_iter().cloned().collect();
+        let mut child = Command::new(bin)
+            .env("RASENGAN_SEED", TRANSCRIPT_SEED.to_string())
+            .env_remove("RASENGAN_LOG_INPUT")
+            .env_remove("RASENGAN_SNAPSHOT_DIR")
+            .env_remove("RASENGAN_DEBUG_DUMP_INTERVAL_MS")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to start {}", bin.display()))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let lines = inputs
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        let feeder = std::thread::spawn(move || -> std::io::Result<()> {
+            for line in lines {
+                writeln!(stdin, "{line}")?;
+            }
+            Ok(())
+        });
+
+        let mut entries: Vec<Entry> = inputs.into_iter().map(Entry::In).collect();
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        for line in stdout.lines() {
+            let line = line.context("failed to read node output")?;
+            let message = serde_json::from_str(&line)
+                .with_context(|| format!("node wrote something other than JSON: {line}"))?;
+            entries.push(Entry::Out(message));
+        }
+        feeder
+            .join()
+            .expect("feeder thread panicked")
+            .context("failed to feed node input")?;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("{} exited with {status}", bin.display());
+        }
+        Ok(Self { entries })
+    }
+
+    /// Checks that `actual` sent the same messages as this transcript, per destination and modulo
+    /// [`VOLATILE_FIELDS`]
+    pub fn verify(&self, actual: &Transcript) -> anyhow::Result<()> {
+        let expected = by_destination(self.outputs());
+        let actual = by_destination(actual.outputs());
+        for dst in expected.keys().chain(actual.keys()) {
+            let expected = expected.get(dst).map_or(&[][..], Vec::as_slice);
+            let actual = actual.get(dst).map_or(&[][..], Vec::as_slice);
+            if let Some(i) =
+                (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))
+            {
+                let show = |message: Option<&Value>| {
+                    message.map_or("nothing".to_string(), Value::to_string)
+                };
+                bail!(
+                    "output #{} to {dst} differs\nexpected: {}\n  actual: {}",
+                    i + 1,
+                    show(expected.get(i)),
+                    show(actual.get(i)),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Strips [`VOLATILE_FIELDS`] from a message's body
+pub fn normalize(message: &Value) -> Value {
+    let mut message = message.clone();
+    if let Some(body) = message.get_mut("body").and_then(Value::as_object_mut) {
+        for field in VOLATILE_FIELDS {
+            body.remove(*field);
+        }
+    }
+    message
+}
+
+fn by_destination<'a>(messages: impl Iterator<Item = &'a Value>) -> BTreeMap<String, Vec<Value>> {
+    let mut grouped: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for message in messages {
+        let dst = message
+            .get("dest")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        grouped.entry(dst).or_default().push(normalize(message));
+    }
+    grouped
+}
@@ -0,0 +1,231 @@
+//! Arbitrary JSON values as set members
+//!
+//! Maelstrom doesn't restrict broadcast values to integers, but `serde_json::Value` can't be
+//! hashed, so it can't go in a [`GossipSet`]. [`JsonValue`] wraps it with a structural hash that
+//! is identical on every node. [`ValueSet`] keeps the common case of non-negative integers in an
+//! [`IntervalSet`] and everything else alongside. On the wire it's
+//! `{"ints": [1, [3, 7]], "others": ["a", {"b": 2}]}`, with empty halves left out.
+use crate::{gossip::GossipSet, interval_set::IntervalSet};
+use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct JsonValue(pub Value);
+
+impl JsonValue {
+    pub fn into_inner(self) -> Value {
+        self.0
+    }
+}
+
+impl Deref for JsonValue {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl From<Value> for JsonValue {
+    fn from(value: Value) -> Self {
+        Self(value)
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self {
+        Self(Value::from(value))
+    }
+}
+
+impl From<JsonValue> for Value {
+    fn from(value: JsonValue) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Hash for JsonValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_value(&self.0, state);
+    }
+}
+
+/// The bits of `f`, with the zeros (which compare equal) and the NaNs made one each
+fn float_bits(f: f64) -> u64 {
+    if f == 0.0 {
+        0.0f64.to_bits()
+    } else if f.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        f.to_bits()
+    }
+}
+
+/// Hashes a value by its structure, agreeing with `Value`'s equality
+fn hash_value<H: Hasher>(value: &Value, state: &mut H) {
+    match value {
+        Value::Null => state.write_u8(0),
+        Value::Bool(b) => {
+            state.write_u8(1);
+            b.hash(state);
+        }
+        Value::Number(n) => {
+            // Numbers only equal others stored the same way, so hash that representation
+            if let Some(n) = n.as_u64() {
+                state.write_u8(2);
+                n.hash(state);
+            } else if let Some(n) = n.as_i64() {
+                state.write_u8(3);
+                n.hash(state);
+            } else {
+                state.write_u8(4);
+                n.as_f64().map(float_bits).hash(state);
+            }
+        }
+        Value::String(s) => {
+            state.write_u8(5);
+            s.hash(state);
+        }
+        Value::Array(values) => {
+            state.write_u8(6);
+            state.write_usize(values.len());
+            for value in values {
+                hash_value(value, state);
+            }
+        }
+        Value::Object(map) => {
+            // Maps are sorted by key, so equal objects iterate identically
+            state.write_u8(7);
+            state.write_usize(map.len());
+            for (key, value) in map {
+                key.hash(state);
+                hash_value(value, state);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueSet {
+    #[serde(default, skip_serializing_if = "IntervalSet::is_empty")]
+    ints: IntervalSet,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    others: HashSet<JsonValue>,
+}
+
+impl ValueSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value, returning whether it was new
+    pub fn insert(&mut self, value: impl Into<JsonValue>) -> bool {
+        let value = value.into();
+        match value.as_u64() {
+            Some(n) => self.ints.insert(n),
+            None => self.others.insert(value),
+        }
+    }
+
+    pub fn contains(&self, value: &JsonValue) -> bool {
+        match value.as_u64() {
+            Some(n) => self.ints.contains(n),
+            None => self.others.contains(value),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ints.len().saturating_add(self.others.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ints.is_empty() && self.others.is_empty()
+    }
+
+    /// Integers first, in ascending order, then everything else in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = JsonValue> + '_ {
+        self.ints
+            .iter()
+            .map(JsonValue::from)
+            .chain(self.others.iter().cloned())
+    }
+
+    /// The members as a flat JSON array, in the order of [`ValueSet::iter`], serialized straight
+    /// from the set without copying it
+    pub fn elements(&self) -> Elements<'_> {
+        Elements(self)
+    }
+}
+
+/// A [`ValueSet`] serialized as a plain list of its members; see [`ValueSet::elements`]
+#[derive(Debug, Clone, Copy)]
+pub struct Elements<'a>(&'a ValueSet);
+
+impl Serialize for Elements<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for n in self.0.ints.iter() {
+            seq.serialize_element(&n)?;
+        }
+        for value in &self.0.others {
+            seq.serialize_element(value)?;
+        }
+        seq.end()
+    }
+}
+
+impl<V: Into<JsonValue>> FromIterator<V> for ValueSet {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<V: Into<JsonValue>> Extend<V> for ValueSet {
+    fn extend<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl GossipSet for ValueSet {
+    type Value = JsonValue;
+
+    fn insert(&mut self, value: JsonValue) -> bool {
+        ValueSet::insert(self, value)
+    }
+
+    fn contains(&self, value: &JsonValue) -> bool {
+        ValueSet::contains(self, value)
+    }
+
+    fn len(&self) -> usize {
+        ValueSet::len(self)
+    }
+
+    fn values(&self) -> impl Iterator<Item = JsonValue> + '_ {
+        self.iter()
+    }
+
+    fn merge(&mut self, other: &Self) -> Self {
+        Self {
+            ints: self.ints.merge(&other.ints),
+            others: self.others.merge(&other.others),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Append-only write-ahead log for surviving Maelstrom's crash nemesis
+//!
+//! Entries are stored as newline-delimited JSON and fsync'd before `append` returns, so anything
+//! a node acknowledged is still there after it's killed and restarted. Nodes open their log in
+//! `from_init` and rebuild their state from the entries it hands back.
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+pub struct Wal<E> {
+    file: File,
+    path: PathBuf,
+    _entry: PhantomData<fn(E) -> E>,
+}
+
+impl<E> fmt::Debug for Wal<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wal").field("path", &self.path).finish()
+    }
+}
+
+impl<E> Wal<E>
+where
+    E: Serialize + DeserializeOwned,
+{
+    /// Opens (creating if needed) the log at `path`, returning it along with every entry already
+    /// recorded in it
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<(Self, Vec<E>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("failed to open write-ahead log {}", path.display()))?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .context("failed to read write-ahead log")?;
+
+        // A crash mid-append can leave a partial trailing line behind; drop it
+        let complete = contents
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        if complete < contents.len() {
+            file.set_len(complete as u64)
+                .context("failed to discard torn write-ahead log entry")?;
+            file.sync_data()?;
+        }
+
+        let entries = contents[..complete]
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_slice(line)
+                    .with_context(|| format!("write-ahead log entry {i} could not be deserialized"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok((
+            Self {
+                file,
+                path,
+                _entry: PhantomData,
+            },
+            entries,
+        ))
+    }
+
+    /// Opens the log belonging to the given node inside `dir`
+    pub fn for_node(dir: impl AsRef<Path>, node_id: &str) -> anyhow::Result<(Self, Vec<E>)> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create log directory {}", dir.display()))?;
+        Self::open(dir.join(format!("{node_id}.wal")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Durably appends a single entry
+    pub fn append(&mut self, entry: &E) -> anyhow::Result<()> {
+        self.append_all(std::iter::once(entry))
+    }
+
+    /// Durably appends several entries with a single fsync
+    pub fn append_all<'a>(&mut self, entries: impl IntoIterator<Item = &'a E>) -> anyhow::Result<()>
+    where
+        E: 'a,
+    {
+        let mut buf = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry).context("serialize write-ahead log entry")?;
+            buf.push(b'\n');
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.file
+            .write_all(&buf)
+            .context("failed to append to write-ahead log")?;
+        self.file
+            .sync_data()
+            .context("failed to fsync write-ahead log")
+    }
+
+    /// Discards every entry, e.g. once they've been captured in a snapshot
+    pub fn truncate(&mut self) -> anyhow::Result<()> {
+        self.file
+            .set_len(0)
+            .context("failed to truncate write-ahead log")?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Noticing steps that take too long
+//!
+//! Events are stepped one at a time, so a single slow handler holds up everything queued behind
+//! it. With a limit set (`RASENGAN_WATCHDOG_MS`), a watchdog thread keeps an eye on the step in
+//! progress and warns on stderr, naming the payload type, once it has run past the limit, and
+//! again when it finally finishes. It can also answer the stuck request with a
+//! `temporarily-unavailable` error straight away (`RASENGAN_WATCHDOG_REPLY`) so the client can
+//! try elsewhere; the step still runs to completion, so that's only safe for handlers whose late
+//! effects don't matter, such as reads.
+//!
+//! One watchdog covers every step in progress, so concurrent workers share a single thread.
+use crate::{
+    runtime::Stamp, tracing::event_kind, ErrorCode, ErrorPayload, Event, Message, MessageID,
+    NodeID, Output,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant},
+};
+
+/// Shortest pause between checks, however tight the limit
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The step being watched
+struct Watched {
+    kind: Box<str>,
+    started: Instant,
+    /// Who to answer if the step is stuck on a request
+    request: Option<(NodeID, NodeID, MessageID)>,
+    overdue: bool,
+}
+
+/// The steps in progress, each under the number it was given when its watch started
+#[derive(Default)]
+struct Steps {
+    watched: HashMap<u64, Watched>,
+    next: u64,
+}
+
+pub(crate) struct Watchdog {
+    steps: Arc<Mutex<Steps>>,
+}
+
+impl Watchdog {
+    /// Starts the watchdog thread, which answers stuck requests through `output` if `reply` is
+    /// set; it stops once the watchdog is dropped
+    pub(crate) fn spawn(limit: Duration, reply: bool, output: Output) -> Self {
+        let steps = Arc::new(Mutex::new(Steps::default()));
+        let watched = Arc::downgrade(&steps);
+        std::thread::spawn(move || watch(watched, limit, reply, output));
+        Self { steps }
+    }
+
+    /// Watches the step about to handle `event` until the returned guard is dropped
+    pub(crate) fn watch<Payload, InjectedPayload>(
+        &self,
+        event: &Event<Payload, InjectedPayload>,
+        stamp: &Stamp,
+    ) -> WatchGuard<'_> {
+        let request = match event {
+            Event::Message(message) if message.body.in_reply_to.is_none() => message
+                .body
+                .id
+                .map(|id| (message.src.clone(), message.dst.clone(), id)),
+            _ => None,
+        };
+        let watched = Watched {
+            kind: stamp
+                .kind
+                .clone()
+                .unwrap_or_else(|| event_kind(event).into()),
+            started: Instant::now(),
+            request,
+            overdue: false,
+        };
+        let mut steps = lock(&self.steps);
+        let step = steps.next;
+        steps.next += 1;
+        steps.watched.insert(step, watched);
+        WatchGuard {
+            watchdog: self,
+            step,
+        }
+    }
+}
+
+/// Ends the watch on a step when dropped
+pub(crate) struct WatchGuard<'a> {
+    watchdog: &'a Watchdog,
+    step: u64,
+}
+
+impl Drop for WatchGuard<'_> {
+    fn drop(&mut self) {
+        let Some(watched) = lock(&self.watchdog.steps).watched.remove(&self.step) else {
+            return;
+        };
+        if watched.overdue {
+            eprintln!(
+                "rasengan: watchdog: step handling {} finished after {:?}",
+                watched.kind,
+                watched.started.elapsed()
+            );
+        }
+    }
+}
+
+fn watch(steps: Weak<Mutex<Steps>>, limit: Duration, reply: bool, mut output: Output) {
+    let interval = (limit / 4).max(MIN_CHECK_INTERVAL);
+    loop {
+        std::thread::sleep(interval);
+        let Some(steps) = steps.upgrade() else {
+            return;
+        };
+        let mut steps = lock(&steps);
+        for watched in steps.watched.values_mut() {
+            check(watched, limit, reply, &mut output);
+        }
+    }
+}
+
+/// Warns about a step once it's over the limit, answering its request if `reply` is set
+fn check(watched: &mut Watched, limit: Duration, reply: bool, output: &mut Output) {
+    let elapsed = watched.started.elapsed();
+    if watched.overdue || elapsed < limit {
+        return;
+    }
+    watched.overdue = true;
+    eprintln!(
+        "rasengan: watchdog: step handling {} has been running for {elapsed:?}, over the \
+         {limit:?} limit",
+        watched.kind
+    );
+    let Some((src, dst, id)) = watched.request.clone().filter(|_| reply) else {
+        return;
+    };
+    let answered = Message::new(dst, src)
+        .in_reply_to(id)
+        .payload(ErrorPayload::Error {
+            code: ErrorCode::TemporarilyUnavailable,
+            text: format!("request has been stuck for {elapsed:?}"),
+        })
+        .send(output);
+    if let Err(e) = answered {
+        eprintln!("rasengan: watchdog: failed to answer stuck request: {e:#}");
+    }
+}
+
+fn lock(steps: &Mutex<Steps>) -> MutexGuard<'_, Steps> {
+    // Nothing panics while holding the lock, so the state is always consistent
+    steps
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
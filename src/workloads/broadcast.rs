@@ -0,0 +1,316 @@
+//! Maelstrom's `broadcast` workload: spreads every message to every node by gossip
+use crate::{
+    broadcast::BroadcastCore,
+    coalesce::{Coalesce, Coalescer},
+    gossip::{Gossip, GossipMode, GossipPayload},
+    latency::{LatencyMap, LatencyPayload},
+    options,
+    value::{Elements, JsonValue, ValueSet},
+    wal::Wal,
+    *,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+/// How many children each node has in the tree used until a topology arrives
+const DEFAULT_BRANCHING: usize = 4;
+
+#[workload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    #[reply]
+    Broadcast { message: Value },
+    #[reply { messages: Vec<Value> }]
+    Read,
+    #[reply]
+    Topology {
+        topology: HashMap<NodeID, Vec<NodeID>>,
+    },
+}
+
+/// The same as [`Payload::ReadOk`] on the wire, but borrowing the node's messages, so a read
+/// costs nothing however many there are
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<'a> {
+    messages: Elements<'a>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Wire {
+    Gossip(GossipPayload<ValueSet>),
+    Latency(LatencyPayload),
+    Client(Payload),
+}
+
+impl Coalesce for Wire {
+    fn coalesce(&mut self, other: Self) -> Option<Self> {
+        match (self, other) {
+            (Wire::Gossip(gossip), Wire::Gossip(other)) => gossip.coalesce(other).map(Wire::Gossip),
+            (_, other) => Some(other),
+        }
+    }
+}
+
+pub enum InjectedPayload {
+    Gossip,
+    /// Measure round-trip times and rebuild the overlay from them
+    Measure,
+}
+
+/// Tuning passed through extra fields on the init message
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    gossip: GossipMode,
+    /// How long gossip to a neighbor may be held back to merge it with more
+    coalesce_ms: u64,
+    /// Replace Maelstrom's topology with a tree built from measured round-trip times, with at
+    /// most this many children per node
+    optimize_topology: Option<usize>,
+    /// How often to measure round-trip times when optimizing the topology
+    measure_ms: Option<u64>,
+}
+
+/// What gets persisted, so a restarted node picks up where it left off
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    Message(JsonValue),
+    /// Messages learned from gossip, as a set so runs of integers stay compact
+    Messages(ValueSet),
+    /// Maelstrom only sends the topology once, before any crash
+    Neighbors(Vec<NodeID>),
+}
+
+/// What snapshots hold; the write-ahead log is started over after each one
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    messages: ValueSet,
+    neighbors: Vec<NodeID>,
+}
+
+pub struct BroadcastNode {
+    node: NodeID,
+    id: usize,
+    core: BroadcastCore<ValueSet>,
+    /// Present when the topology is optimized for latency
+    latency: Option<(LatencyMap, usize)>,
+    /// Gossip goes out through here so several sets for one neighbor travel together
+    coalescer: Coalescer<Wire>,
+    /// Present when persistence is enabled
+    wal: Option<Wal<Entry>>,
+    /// Whether neighbors were replayed from the write-ahead log, which makes them newer than a
+    /// snapshot's
+    logged_neighbors: bool,
+}
+
+impl BroadcastNode {
+    fn persist(&mut self, entries: &[Entry]) -> anyhow::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.append_all(entries),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Node<Option<PathBuf>, Wire, InjectedPayload> for BroadcastNode {
+    fn from_init(
+        wal_dir: Option<PathBuf>,
+        init: Init,
+        runtime: Runtime<Wire, InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        let config: Config = init.config()?;
+
+        // Periodically gossip to other nodes
+        runtime.every(Duration::from_millis(300), || InjectedPayload::Gossip);
+        if config.optimize_topology.is_some() {
+            let interval = Duration::from_millis(config.measure_ms.unwrap_or(1000));
+            runtime.every(interval, || InjectedPayload::Measure);
+        }
+
+        let gossip = Gossip::new(&init, config.gossip, runtime.rng("gossip"));
+        let mut node = Self {
+            // Broadcasts may arrive before the topology does, so start out on a default tree
+            core: BroadcastCore::new(&init, gossip, DEFAULT_BRANCHING),
+            latency: config
+                .optimize_topology
+                .map(|max_children| (LatencyMap::new(&init), max_children)),
+            node: init.node_id,
+            id: 1,
+            coalescer: Coalescer::new(Duration::from_millis(config.coalesce_ms)),
+            wal: None,
+            logged_neighbors: false,
+        };
+        if let Some(dir) = wal_dir {
+            let (wal, entries) = Wal::for_node(dir, &node.node)?;
+            for entry in entries {
+                match entry {
+                    Entry::Message(message) => {
+                        node.core.insert(message);
+                    }
+                    Entry::Messages(messages) => {
+                        node.core.merge(&messages);
+                    }
+                    Entry::Neighbors(neighbors) => {
+                        node.core.set_neighbors(neighbors);
+                        node.logged_neighbors = true;
+                    }
+                }
+            }
+            node.wal = Some(wal);
+        }
+        Ok(node)
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Wire, InjectedPayload>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::Shutdown => return self.coalescer.flush_all(output),
+            Event::PeerDown(_) | Event::PeerUp(_) => {}
+            Event::Injected(InjectedPayload::Measure) => {
+                if let Some((latency, max_children)) = &mut self.latency {
+                    latency.tick(&mut self.id, output, Wire::Latency)?;
+                    if let Some(mut overlay) = latency.overlay(*max_children) {
+                        self.core
+                            .set_neighbors(overlay.remove(&self.node).unwrap_or_default());
+                    }
+                }
+            }
+            Event::Message(Message {
+                src,
+                body:
+                    Body {
+                        id,
+                        in_reply_to,
+                        payload: Wire::Latency(payload),
+                    },
+                ..
+            }) => {
+                if let Some((latency, _)) = &mut self.latency {
+                    let body = Body {
+                        id,
+                        in_reply_to,
+                        payload,
+                    };
+                    latency.handle(&src, body, output, Wire::Latency)?;
+                }
+            }
+            Event::Injected(InjectedPayload::Gossip) => {
+                self.core.tick(&mut self.coalescer, Wire::Gossip)?;
+            }
+            Event::Message(input) => {
+                let (reply, payload) = input.into_reply(Some(&mut self.id)).split_payload();
+                let payload = match payload {
+                    Wire::Gossip(gossip) => {
+                        let new = self.core.handle(
+                            &reply.dst,
+                            gossip,
+                            &mut self.coalescer,
+                            Wire::Gossip,
+                        )?;
+                        if !new.is_empty() {
+                            self.persist(&[Entry::Messages(new)])?;
+                        }
+                        return self.coalescer.flush_due(output);
+                    }
+                    Wire::Latency(_) => unreachable!("latency payloads are handled above"),
+                    Wire::Client(payload) => payload,
+                };
+                let Ok(request) = payload.into_request() else {
+                    return self.coalescer.flush_due(output);
+                };
+                match request {
+                    PayloadRequest::Broadcast { message } => {
+                        let message = JsonValue(message);
+                        // Durable before it's acknowledged
+                        if self.wal.is_some() && !self.core.values().contains(&message) {
+                            self.persist(&[Entry::Message(message.clone())])?;
+                        }
+                        self.core.insert(message);
+                        reply
+                            .with_payload(Wire::Client(Payload::BroadcastOk))
+                            .send(output)?;
+                    }
+                    PayloadRequest::Read => {
+                        let messages = self.core.values().elements();
+                        reply.with_payload(ReadOk { messages }).send(output)?;
+                    }
+                    PayloadRequest::Topology { topology } => {
+                        self.core.apply_topology(topology);
+                        self.persist(&[Entry::Neighbors(self.core.neighbors().to_vec())])?;
+                        reply
+                            .with_payload(Wire::Client(Payload::TopologyOk))
+                            .send(output)?;
+                    }
+                }
+            }
+        };
+        self.coalescer.flush_due(output)
+    }
+
+    fn snapshot(&self) -> Option<serde_json::Value> {
+        let snapshot = Snapshot {
+            messages: self.core.values().clone(),
+            neighbors: self.core.neighbors().to_vec(),
+        };
+        serde_json::to_value(snapshot).ok()
+    }
+
+    /// Adds to what the write-ahead log replayed, which only has what came after the snapshot
+    fn restore(&mut self, snapshot: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: Snapshot =
+            serde_json::from_value(snapshot).context("broadcast snapshot is malformed")?;
+        self.core.merge(&snapshot.messages);
+        if !self.logged_neighbors {
+            self.core.set_neighbors(snapshot.neighbors);
+        }
+        Ok(())
+    }
+
+    /// Starts the write-ahead log over, since the snapshot covers everything in it
+    fn snapshotted(&mut self) -> anyhow::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        Some(serde_json::json!({
+            "mode": self.core.strategy().mode(),
+            "neighbors": self.core.neighbors(),
+            "messages": self.core.values(),
+        }))
+    }
+}
+
+/// Where accepted messages are persisted, from `--wal-dir <dir>` or `RASENGAN_BROADCAST_WAL_DIR`
+///
+/// Persistence is off unless one of them is given; enable it for runs with the crash nemesis.
+fn wal_dir(args: &[String]) -> anyhow::Result<Option<PathBuf>> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--wal-dir") {
+            Some("") => {
+                let dir = args.next().context("--wal-dir requires a directory")?;
+                return Ok(Some(dir.into()));
+            }
+            Some(dir) if dir.starts_with('=') => return Ok(Some(dir[1..].into())),
+            _ => {}
+        }
+    }
+    options::env("RASENGAN_BROADCAST_WAL_DIR")
+}
+
+/// Runs a node, persisting to `--wal-dir` if it's among `args`
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    main_loop::<_, BroadcastNode, _, _>(wal_dir(args)?)
+}
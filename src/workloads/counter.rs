@@ -0,0 +1,359 @@
+//! Maelstrom's `g-counter` workload: a counter kept as each node's gossiped running totals
+//!
+//! Every node only ever adds to its own totals, and a node hearing of larger totals for another
+//! node takes them, so gossip can repeat or reorder updates without counting anything twice.
+//!
+//! Reads are local by default, answering with whatever has been gossiped so far. A read's
+//! `consistency` field (or the `read_consistency` config) can ask for more: a quorum read first
+//! collects the totals a majority of nodes have seen, and a linearizable read those of every
+//! node, which includes every acknowledged add. Unanswered tallies are asked for again until the
+//! read times out.
+use crate::{
+    broadcast::BroadcastCore,
+    gossip::{Gossip, GossipMode, GossipPayload, GossipSet},
+    replication::Consistency,
+    session::{Admission, Sessions},
+    *,
+};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// How often unanswered tallies are asked for again
+const TALLY_RETRY: Duration = Duration::from_millis(500);
+
+/// How long a read waits on tallies before failing with a timeout
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[workload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    #[reply]
+    Add { delta: i64 },
+    #[reply { value: i64 }]
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consistency: Option<Consistency>,
+    },
+    /// Asks a peer for every node's totals, on behalf of a read
+    #[reply { counts: Counts }]
+    Tally,
+}
+
+/// How much one node has added and subtracted in all, as `[added, subtracted]`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Totals(u64, u64);
+
+impl Totals {
+    /// Whether these totals include everything in `other`
+    fn covers(self, other: Totals) -> bool {
+        self.0 >= other.0 && self.1 >= other.1
+    }
+
+    fn value(self) -> i64 {
+        self.0 as i64 - self.1 as i64
+    }
+}
+
+/// Every node's [`Totals`], merged by keeping the larger of each: a PN-counter CRDT
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Counts(HashMap<NodeID, Totals>);
+
+impl Counts {
+    /// The counter's value, going by the totals seen so far
+    pub fn value(&self) -> i64 {
+        self.0.values().map(|totals| totals.value()).sum()
+    }
+}
+
+impl GossipSet for Counts {
+    type Value = (NodeID, Totals);
+
+    fn insert(&mut self, (node, totals): (NodeID, Totals)) -> bool {
+        let current = self.0.entry(node).or_default();
+        if current.covers(totals) {
+            return false;
+        }
+        *current = Totals(current.0.max(totals.0), current.1.max(totals.1));
+        true
+    }
+
+    fn contains(&self, (node, totals): &(NodeID, Totals)) -> bool {
+        self.0
+            .get(node)
+            .is_some_and(|current| current.covers(*totals))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn values(&self) -> impl Iterator<Item = (NodeID, Totals)> + '_ {
+        self.0.iter().map(|(node, &totals)| (node.clone(), totals))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Wire {
+    Gossip(GossipPayload<Counts>),
+    Client(Payload),
+}
+
+pub enum InjectedPayload {
+    Gossip,
+    /// Asks again for tallies that haven't been answered, and times out reads
+    RetryTallies,
+}
+
+/// Tuning passed through extra fields on the init message
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    gossip: GossipMode,
+    /// How consistent reads are unless they say otherwise; local when unset
+    read_consistency: Option<Consistency>,
+}
+
+/// A read waiting on peers' tallies
+struct PendingRead {
+    reply: Message<()>,
+    waiting: usize,
+    deadline: Instant,
+}
+
+/// Counts without any external service: every node gossips the totals it has seen, and reads
+/// sum them up
+pub struct CounterNode {
+    node: NodeID,
+    id: usize,
+    /// Gossips to every peer, leaving out those the runtime suspects are down
+    core: BroadcastCore<Counts>,
+    peers: Vec<NodeID>,
+    read_consistency: Consistency,
+    reads: HashMap<u64, PendingRead>,
+    next_read: u64,
+    /// Which read and peer each outstanding tally request is for
+    tallies: HashMap<MessageID, (u64, NodeID)>,
+    /// Keeps a retried `add` from counting twice
+    sessions: Sessions,
+}
+
+impl Node<(), Wire, InjectedPayload> for CounterNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        runtime: Runtime<Wire, InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        let config: Config = init.config()?;
+
+        runtime.every(Duration::from_millis(200), || InjectedPayload::Gossip);
+        runtime.every(TALLY_RETRY, || InjectedPayload::RetryTallies);
+
+        let gossip = Gossip::new(&init, config.gossip, runtime.rng("gossip"));
+        let mut core = BroadcastCore::new(&init, gossip, 1);
+        let peers: Vec<NodeID> = init
+            .node_ids
+            .iter()
+            .filter(|&id| *id != init.node_id)
+            .cloned()
+            .collect();
+        core.set_neighbors(peers.clone());
+
+        Ok(Self {
+            core,
+            sessions: Sessions::new(&init),
+            node: init.node_id,
+            id: 1,
+            peers,
+            read_consistency: config.read_consistency.unwrap_or(Consistency::Local),
+            reads: HashMap::new(),
+            next_read: 0,
+            tallies: HashMap::new(),
+        })
+    }
+
+    fn step(
+        &mut self,
+        input: Event<Wire, InjectedPayload>,
+        output: &mut Output,
+    ) -> anyhow::Result<()> {
+        let mut request = None;
+        if let Event::Message(message) = &input {
+            match self.sessions.admit(message) {
+                Admission::New => {}
+                Admission::Answered(reply) => return reply.send(output),
+                Admission::Duplicate => return Ok(()),
+            }
+            request = Some((message.src.clone(), message.body.id));
+        }
+        let result = self.handle(input, output);
+        if let (Err(_), Some((client, id))) = (&result, request) {
+            self.sessions.abandon(&client, id);
+        }
+        result
+    }
+}
+
+impl CounterNode {
+    fn handle(
+        &mut self,
+        input: Event<Wire, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::Shutdown => {}
+            // Suspected peers are left out of gossip rounds until they're heard from again
+            Event::PeerDown(peer) => self.core.remove_neighbor(&peer),
+            Event::PeerUp(peer) => self.core.add_neighbor(peer),
+            Event::Injected(InjectedPayload::Gossip) => {
+                self.core.tick(output, Wire::Gossip)?;
+            }
+            Event::Injected(InjectedPayload::RetryTallies) => self.retry_tallies(output)?,
+            Event::Message(input) => {
+                let in_reply_to = input.body.in_reply_to;
+                let (reply, payload) = input.into_reply(Some(&mut self.id)).split_payload();
+                let payload = match payload {
+                    Wire::Gossip(gossip) => {
+                        self.core.handle(&reply.dst, gossip, output, Wire::Gossip)?;
+                        return Ok(());
+                    }
+                    Wire::Client(payload) => payload,
+                };
+                let request = match payload.into_request() {
+                    Ok(request) => request,
+                    // Tallies are the only replies sent to this node
+                    Err(reply) => return self.tallied(in_reply_to, reply, output),
+                };
+                match request {
+                    PayloadRequest::Tally => {
+                        reply
+                            .with_payload(Wire::Client(Payload::TallyOk {
+                                counts: self.core.values().clone(),
+                            }))
+                            .send(output)?;
+                    }
+                    PayloadRequest::Add { delta } => {
+                        let own = self.core.values().0.get(&self.node).copied();
+                        let Totals(added, subtracted) = own.unwrap_or_default();
+                        let totals = if delta >= 0 {
+                            Totals(added + delta.unsigned_abs(), subtracted)
+                        } else {
+                            Totals(added, subtracted + delta.unsigned_abs())
+                        };
+                        self.core.insert((self.node.clone(), totals));
+                        let reply = reply.with_payload(Wire::Client(Payload::AddOk));
+                        self.sessions.reply(&reply, output)?;
+                    }
+                    PayloadRequest::Read { consistency } => {
+                        // This node's own tally counts towards the quorum
+                        let waiting = match consistency.unwrap_or(self.read_consistency) {
+                            Consistency::Local => 0,
+                            Consistency::Quorum => self.peers.len().div_ceil(2),
+                            Consistency::Linearizable => self.peers.len(),
+                        };
+                        if waiting == 0 {
+                            let reply = reply.with_payload(Wire::Client(Payload::ReadOk {
+                                value: self.core.values().value(),
+                            }));
+                            return self.sessions.reply(&reply, output);
+                        }
+                        let read = self.next_read;
+                        self.next_read += 1;
+                        for peer in self.peers.clone() {
+                            self.tally(read, peer, output)?;
+                        }
+                        let deadline = Instant::now() + READ_TIMEOUT;
+                        self.reads.insert(
+                            read,
+                            PendingRead {
+                                reply,
+                                waiting,
+                                deadline,
+                            },
+                        );
+                    }
+                }
+            }
+        };
+        Ok(())
+    }
+
+    /// Takes in a peer's totals, answering the read they were for if it was the last one it
+    /// waited on
+    fn tallied(
+        &mut self,
+        in_reply_to: Option<MessageID>,
+        reply: Payload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Payload::TallyOk { counts } = reply else {
+            return Ok(());
+        };
+        for totals in counts.values() {
+            self.core.insert(totals);
+        }
+        let Some((read, _)) = in_reply_to.and_then(|id| self.tallies.remove(&id)) else {
+            return Ok(());
+        };
+        let Some(pending) = self.reads.get_mut(&read) else {
+            return Ok(());
+        };
+        pending.waiting = pending.waiting.saturating_sub(1);
+        if pending.waiting == 0 {
+            let pending = self.reads.remove(&read).expect("read was just seen");
+            let reply = pending.reply.with_payload(Wire::Client(Payload::ReadOk {
+                value: self.core.values().value(),
+            }));
+            self.sessions.reply(&reply, output)?;
+        }
+        Ok(())
+    }
+
+    /// Asks `peer` for its totals on behalf of `read`
+    fn tally(&mut self, read: u64, peer: NodeID, output: &mut impl Write) -> anyhow::Result<()> {
+        let tally = Message::new(self.node.clone(), peer.clone())
+            .with_id(&mut self.id)
+            .payload(Wire::Client(Payload::Tally));
+        self.tallies
+            .insert(tally.body.id.expect("tallies have an ID"), (read, peer));
+        tally.send(output)
+    }
+
+    /// Fails reads that have waited too long, and asks again for the rest's missing tallies
+    ///
+    /// A retried tally goes out under a new ID, so only one answer from each peer counts.
+    fn retry_tallies(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .reads
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(&read, _)| read)
+            .collect();
+        for read in expired {
+            let pending = self.reads.remove(&read).expect("read was just seen");
+            let error = pending.reply.with_payload(ErrorPayload::Error {
+                code: ErrorCode::Timeout,
+                text: "timed out waiting on other nodes' tallies".to_string(),
+            });
+            self.sessions.reply(&error, output)?;
+        }
+        let unanswered: Vec<_> = std::mem::take(&mut self.tallies).into_values().collect();
+        for (read, peer) in unanswered {
+            if self.reads.contains_key(&read) {
+                self.tally(read, peer, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run(_args: &[String]) -> anyhow::Result<()> {
+    main_loop::<_, CounterNode, _, _>(())
+}
@@ -0,0 +1,39 @@
+//! Maelstrom's `echo` workload: replies with whatever it's sent
+use crate::*;
+
+#[workload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    #[reply { echo: String }]
+    Echo { echo: String },
+}
+
+pub struct EchoNode {
+    id: usize,
+}
+
+impl Node<(), Payload> for EchoNode {
+    fn from_init(_state: (), _init: Init, _runtime: Runtime<Payload>) -> anyhow::Result<Self> {
+        Ok(Self { id: 1 })
+    }
+
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let Event::Message(input) = input else {
+            panic!("got injected event when there's no event injection");
+        };
+
+        let mut reply = input.into_reply(Some(&mut self.id));
+        match reply.body.payload {
+            Payload::Echo { echo } => {
+                reply.body.payload = Payload::EchoOk { echo };
+                reply.send(output)?;
+            }
+            Payload::EchoOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn run(_args: &[String]) -> anyhow::Result<()> {
+    main_loop::<_, EchoNode, _, _>(())
+}
@@ -0,0 +1,53 @@
+//! Node implementations for Maelstrom's workloads, and a registry to pick one by name at startup
+//!
+//! Each workload has its own binary, and the `rasengan` binary serves any of them:
+//!
+//! ```text
+//! rasengan --workload broadcast --wal-dir /tmp/wal
+//! ```
+//!
+//! Nodes differ in their state, payload, and injected payload types, so the registry erases them
+//! behind each workload's `run`, which drives the node's main loop to completion.
+pub mod broadcast;
+pub mod counter;
+pub mod echo;
+pub mod unique_ids;
+
+/// A workload that can be selected by name
+pub struct Registered {
+    pub name: &'static str,
+    /// Other names it answers to, such as its Maelstrom workload name
+    pub aliases: &'static [&'static str],
+    /// Runs a node until its input closes, given the command-line arguments meant for it
+    pub run: fn(&[String]) -> anyhow::Result<()>,
+}
+
+pub const REGISTRY: &[Registered] = &[
+    Registered {
+        name: "echo",
+        aliases: &[],
+        run: echo::run,
+    },
+    Registered {
+        name: "unique-ids",
+        aliases: &["unique_ids"],
+        run: unique_ids::run,
+    },
+    Registered {
+        name: "broadcast",
+        aliases: &[],
+        run: broadcast::run,
+    },
+    Registered {
+        name: "counter",
+        aliases: &["g-counter"],
+        run: counter::run,
+    },
+];
+
+/// Looks a workload up by name or alias
+pub fn find(name: &str) -> Option<&'static Registered> {
+    REGISTRY
+        .iter()
+        .find(|workload| workload.name == name || workload.aliases.contains(&name))
+}
@@ -0,0 +1,72 @@
+//! Maelstrom's `unique-ids` workload: hands out IDs that are unique across the cluster
+//!
+//! IDs are the node's ID and a counter unless another [format](Format) is picked with
+//! `--id-format` (or `RASENGAN_ID_FORMAT`).
+use crate::{
+    idgen::{Format, Id, IdGen},
+    *,
+};
+use anyhow::Context;
+
+#[workload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    #[reply { id: Id }]
+    Generate,
+}
+
+pub struct UniqueIDNode {
+    id: usize,
+    ids: IdGen,
+}
+
+impl Node<Format, Payload> for UniqueIDNode {
+    fn from_init(format: Format, init: Init, runtime: Runtime<Payload>) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: 1,
+            ids: IdGen::new(format, &init, runtime.rng("ids"))?,
+        })
+    }
+
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let Event::Message(input) = input else {
+            panic!("got injected event when there's no event injection");
+        };
+
+        let mut reply = input.into_reply(Some(&mut self.id));
+        match reply.body.payload {
+            Payload::Generate => {
+                let id = self.ids.next_id();
+                reply.body.payload = Payload::GenerateOk { id };
+                reply.send(output)?;
+            }
+            Payload::GenerateOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn id_format(args: &[String]) -> anyhow::Result<Format> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--id-format") {
+            Some("") => {
+                return args
+                    .next()
+                    .context("--id-format requires a format")?
+                    .parse()
+            }
+            Some(format) if format.starts_with('=') => return format[1..].parse(),
+            _ => {}
+        }
+    }
+    Ok(options::env::<String>("RASENGAN_ID_FORMAT")?
+        .map(|format| format.parse())
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// Runs a node, handing out IDs in the format given by `--id-format` if it's among `args`
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    main_loop::<_, UniqueIDNode, _, _>(id_format(args)?)
+}
@@ -3,3 +3,7 @@
 ~/workspace/maelstrom/pkg/maelstrom test -w broadcast --bin target/debug/broadcast --time-limit 20 --node-count 1 --rate 10
 ~/workspace/maelstrom/pkg/maelstrom test -w broadcast --bin target/debug/broadcast --time-limit 20 --node-count 5 --rate 10
 ~/workspace/maelstrom/pkg/maelstrom test -w broadcast --bin target/debug/broadcast --time-limit 20 --node-count 5 --rate 10 --nemesis partition
+~/workspace/maelstrom/pkg/maelstrom test -w kafka --bin target/debug/kafka --node-count 2 --concurrency 2n --time-limit 20 --rate 1000
+~/workspace/maelstrom/pkg/maelstrom test -w txn-list-append --bin target/debug/txn-list-append --node-count 2 --time-limit 20 --rate 100 --consistency-models serializable
+~/workspace/maelstrom/pkg/maelstrom test -w lin-kv --bin target/debug/lin-kv --node-count 3 --concurrency 2n --time-limit 20 --rate 100
+~/workspace/maelstrom/pkg/maelstrom test -w g-counter --bin target/debug/counter --node-count 3 --rate 100 --time-limit 20 --nemesis partition
@@ -0,0 +1,159 @@
+//! Round trips and hostile input for the CBOR codec
+//!
+//! Random inputs are seeded, so a failure can be reproduced from the seed it reports.
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rasengan::cbor::{decode, encode};
+use serde_json::{json, Value};
+
+const SEEDS: u64 = 500;
+
+fn round_trip(value: Value) -> Vec<u8> {
+    let encoded = encode(&value);
+    let decoded = decode(&encoded).unwrap_or_else(|e| panic!("{value}: {e}"));
+    assert_eq!(decoded, value);
+    encoded
+}
+
+#[test]
+fn integers_use_the_shortest_head() {
+    // The value, and how many bytes its encoding takes
+    let cases = [
+        (0, 1),
+        (23, 1),
+        (24, 2),
+        (255, 2),
+        (256, 3),
+        (65_535, 3),
+        (65_536, 5),
+        (u32::MAX as u64, 5),
+        (u32::MAX as u64 + 1, 9),
+        (u64::MAX, 9),
+    ];
+    for (n, len) in cases {
+        assert_eq!(round_trip(json!(n)).len(), len, "{n}");
+    }
+}
+
+#[test]
+fn negative_integers_round_trip() {
+    let cases = [
+        (-1, 1),
+        (-24, 1),
+        (-25, 2),
+        (-256, 2),
+        (-257, 3),
+        (-65_536, 3),
+        (-65_537, 5),
+        (i64::MIN, 9),
+    ];
+    for (n, len) in cases {
+        assert_eq!(round_trip(json!(n)).len(), len, "{n}");
+    }
+    // Below i64::MIN, which JSON can only hold as a float
+    let decoded = decode(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+    assert_eq!(decoded.as_f64(), Some(-18_446_744_073_709_551_616.0));
+}
+
+#[test]
+fn floats_round_trip() {
+    for f in [
+        0.5,
+        -0.5,
+        1.0,
+        -0.0,
+        1e-300,
+        f64::MIN_POSITIVE,
+        f64::MAX,
+        f64::MIN,
+        std::f64::consts::PI,
+    ] {
+        round_trip(json!(f));
+    }
+    // Single precision is never produced, but is accepted
+    assert_eq!(decode(&[0xfa, 0x3f, 0xc0, 0x00, 0x00]).unwrap(), json!(1.5));
+    // JSON has no infinities
+    assert_eq!(
+        decode(&[0xfa, 0x7f, 0x80, 0x00, 0x00]).unwrap(),
+        Value::Null
+    );
+}
+
+#[test]
+fn nested_values_round_trip() {
+    round_trip(json!(null));
+    round_trip(json!(true));
+    round_trip(json!(""));
+    round_trip(json!("héllo, wörld"));
+    round_trip(json!([]));
+    round_trip(json!({}));
+    round_trip(json!({
+        "a": [1, -2, 3.5, null, true, false, "x"],
+        "b": { "c": { "d": [[], {}, [[{ "e": 256 }]]] } },
+        "": "empty key",
+        "long": "x".repeat(70_000),
+    }));
+    round_trip(Value::Array((0..300).map(Value::from).collect()));
+    let mut nested = json!(0);
+    for _ in 0..200 {
+        nested = json!([nested]);
+    }
+    round_trip(nested);
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    let encoded = encode(&json!({ "a": [1, 300, 70_000, -5, 2.5, "text"], "b": null }));
+    for len in 0..encoded.len() {
+        assert!(decode(&encoded[..len]).is_err(), "prefix of length {len}");
+    }
+    let mut trailing = encoded.clone();
+    trailing.push(0);
+    assert!(decode(&trailing).is_err());
+}
+
+#[test]
+fn unsupported_items_are_an_error() {
+    for input in [
+        &[0x1c][..],         // reserved additional info
+        &[0x5f],             // indefinite byte string
+        &[0x41, 0x00],       // byte string
+        &[0x9f, 0xff],       // indefinite array
+        &[0xc1, 0x00],       // tag
+        &[0xf9, 0x3c, 0x00], // half precision float
+        &[0xe0],             // unassigned simple value
+        &[0x62, 0xc3, 0x28], // text that isn't UTF-8
+        &[0xa1, 0x01, 0x01], // map with an integer key
+    ] {
+        assert!(decode(input).is_err(), "{input:02x?}");
+    }
+}
+
+#[test]
+fn hostile_lengths_are_an_error_without_allocating() {
+    let huge = [0xff; 8];
+    for major in [0x9b, 0xbb, 0x7b] {
+        // An array, map, or text claiming u64::MAX items, with none following
+        let input = [&[major][..], &huge].concat();
+        assert!(decode(&input).is_err(), "{major:#04x}");
+    }
+    // A few items short of the count it claims
+    assert!(decode(&[0x9a, 0xff, 0xff, 0xff, 0xff, 0x01, 0x02]).is_err());
+    // Nested deeper than the decoder goes
+    assert!(decode(&vec![0x81; 100_000]).is_err());
+}
+
+#[test]
+fn random_input_never_panics() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let len = rng.gen_range(0..64);
+        let input: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        let _ = decode(&input);
+
+        // Corrupting a valid encoding should be caught, or decode to something else entirely
+        let mut encoded = encode(&json!({ "k": [seed, -(seed as i64), 0.25, "v"] }));
+        let at = rng.gen_range(0..encoded.len());
+        encoded[at] = rng.gen();
+        let _ = decode(&encoded);
+    }
+}
@@ -0,0 +1,174 @@
+//! Runs a concurrent node over many clients at once
+//!
+//! Each client's requests are numbered, and the node notes the order it stepped them in, so the
+//! test can check that no two requests from one client were ever reordered across workers.
+use rasengan::*;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{BufReader, Cursor, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const CLIENTS: usize = 8;
+const REQUESTS: u64 = 50;
+
+#[workload]
+#[derive(Debug, Clone)]
+enum Payload {
+    #[reply]
+    Add { n: u64 },
+}
+
+struct OrderNode {
+    /// The numbers each client sent, in the order they were stepped
+    seen: Mutex<HashMap<NodeID, Vec<u64>>>,
+    shutdowns: AtomicUsize,
+}
+
+impl ConcurrentNode<(), Payload> for OrderNode {
+    fn from_init(_state: (), _init: Init, _runtime: Runtime<Payload>) -> anyhow::Result<Self> {
+        Ok(Self {
+            seen: Mutex::default(),
+            shutdowns: AtomicUsize::new(0),
+        })
+    }
+
+    fn step(&self, input: Event<Payload>, output: &mut dyn Write) -> anyhow::Result<()> {
+        match input {
+            Event::Message(message) => {
+                let Payload::Add { n } = message.body.payload else {
+                    return Ok(());
+                };
+                // Give other workers a chance to overtake
+                std::thread::sleep(Duration::from_micros(n % 7 * 50));
+                let src = message.src.clone();
+                self.seen.lock().unwrap().entry(src).or_default().push(n);
+                message
+                    .into_reply(None)
+                    .with_payload(Payload::AddOk)
+                    .send(output)
+            }
+            Event::Shutdown => {
+                let shutdowns = self.shutdowns.fetch_add(1, Ordering::SeqCst) + 1;
+                let seen = self.seen.lock().unwrap().clone();
+                Message::new("n1", "c0")
+                    .payload(json!({ "type": "summary", "seen": seen, "shutdowns": shutdowns }))
+                    .send(output)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn snapshot(&self) -> Option<Value> {
+        Some(json!(*self.seen.lock().unwrap()))
+    }
+
+    fn restore(&mut self, snapshot: Value) -> anyhow::Result<()> {
+        self.seen = Mutex::new(serde_json::from_value(snapshot)?);
+        Ok(())
+    }
+}
+
+/// A writer whose contents can still be read after the node has finished with it
+#[derive(Clone, Default)]
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the node over `requests` from each client, returning everything it wrote
+fn run(requests: u64, options: Options) -> Vec<Value> {
+    let mut input = json!({
+        "src": "c0", "dest": "n1",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+    })
+    .to_string();
+    input.push('\n');
+    for n in 0..requests {
+        for client in 0..CLIENTS {
+            let message = json!({
+                "src": format!("c{}", client + 1), "dest": "n1",
+                "body": { "type": "add", "msg_id": n + 1, "n": n }
+            });
+            input.push_str(&message.to_string());
+            input.push('\n');
+        }
+    }
+    let output = SharedWriter::default();
+    let options = Options {
+        workers: 4,
+        ..options
+    };
+    concurrent_run_with_io::<_, OrderNode, _, _>(
+        (),
+        options,
+        BufReader::new(Cursor::new(input)),
+        output.clone(),
+    )
+    .unwrap();
+
+    let output = output.0.lock().unwrap();
+    String::from_utf8_lossy(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn steps_each_clients_requests_in_order_and_shuts_down_once() {
+    let messages = run(REQUESTS, Options::default());
+    let acks = messages
+        .iter()
+        .filter(|message| message["body"]["type"] == "add_ok")
+        .count();
+    assert_eq!(acks, CLIENTS * REQUESTS as usize);
+
+    let summaries: Vec<_> = messages
+        .iter()
+        .filter(|message| message["body"]["type"] == "summary")
+        .collect();
+    assert_eq!(summaries.len(), 1, "shutdown was stepped more than once");
+    let summary = &summaries[0]["body"];
+    assert_eq!(summary["shutdowns"], 1);
+    // Shutdown came after every request had been stepped
+    let expected: Vec<u64> = (0..REQUESTS).collect();
+    for client in 1..=CLIENTS {
+        assert_eq!(summary["seen"][format!("c{client}")], json!(expected));
+    }
+    // And nothing was written after it
+    assert_eq!(messages.last().unwrap()["body"]["type"], "summary");
+}
+
+#[test]
+fn snapshots_between_steps_and_restores() {
+    let dir = std::env::temp_dir().join(format!("rasengan-concurrent-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let options = || Options {
+        snapshot_dir: Some(dir.clone()),
+        snapshot_interval: Duration::ZERO,
+        ..Options::default()
+    };
+    run(REQUESTS, options());
+
+    // A restarted node picks up where the last one left off
+    let messages = run(0, options());
+    let summary = &messages.last().unwrap()["body"];
+    let expected: Vec<u64> = (0..REQUESTS).collect();
+    for client in 1..=CLIENTS {
+        assert_eq!(summary["seen"][format!("c{client}")], json!(expected));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
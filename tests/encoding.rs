@@ -0,0 +1,129 @@
+//! Round trips and hostile input for the compact encodings
+//!
+//! Random inputs are seeded, so a failure can be reproduced from the seed it reports.
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rasengan::encoding::{compress, decompress, decompress_limited, Binary, Compressed};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const SEEDS: u64 = 200;
+
+/// Bytes drawn from a small alphabet so that matches are common, mixed with some random runs
+fn sample(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(0..4096);
+    let alphabet = rng.gen_range(1..=256);
+    (0..len).map(|_| rng.gen_range(0..alphabet) as u8).collect()
+}
+
+#[test]
+fn compress_round_trips() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let input = sample(&mut rng);
+        let packed = compress(&input);
+        let unpacked = decompress(&packed).unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+        assert_eq!(unpacked, input, "seed {seed}");
+    }
+}
+
+#[test]
+fn compress_round_trips_edge_cases() {
+    let cases: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"a".to_vec(),
+        b"abc".to_vec(),
+        b"abcd".to_vec(),
+        vec![0; 100_000],
+        // Literal runs right at and around the longest a token holds
+        (0..=255).cycle().take(128).collect(),
+        (0..=255).cycle().take(129).collect(),
+        // A match whose source is further back than the window reaches
+        [b"abcdefgh".as_slice(), &vec![b'x'; 70_000], b"abcdefgh"].concat(),
+    ];
+    for input in cases {
+        assert_eq!(decompress(&compress(&input)).unwrap(), input);
+    }
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    let input = br#"{"values":[1,2,3,1,2,3,1,2,3],"name":"values values values"}"#;
+    let packed = compress(input);
+    for len in 0..packed.len() {
+        // Every prefix either decodes to a prefix of the input or is refused
+        if let Ok(unpacked) = decompress(&packed[..len]) {
+            assert!(input.starts_with(&unpacked), "prefix of length {len}");
+        }
+    }
+    assert!(decompress(&[5, b'a']).is_err(), "short literal run");
+    assert!(decompress(&[0, b'a', 0x80, 1]).is_err(), "short match");
+}
+
+#[test]
+fn corrupt_input_is_an_error_not_a_panic() {
+    // A match before any output, and one reaching past the start
+    assert!(decompress(&[0x80, 1, 0]).is_err());
+    assert!(decompress(&[0, b'a', 0x80, 2, 0]).is_err());
+    assert!(decompress(&[0, b'a', 0x80, 0, 0]).is_err());
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut packed = compress(&sample(&mut rng));
+        for _ in 0..rng.gen_range(1..8) {
+            if packed.is_empty() {
+                break;
+            }
+            let at = rng.gen_range(0..packed.len());
+            packed[at] = rng.gen();
+        }
+        let _ = decompress(&packed);
+    }
+}
+
+#[test]
+fn decompression_is_bounded() {
+    // Each three-byte token stands for 131 bytes
+    let mut bomb = vec![0, b'a'];
+    for _ in 0..10_000 {
+        bomb.extend_from_slice(&[0xff, 1, 0]);
+    }
+    assert_eq!(decompress(&bomb).unwrap().len(), 1 + 10_000 * 131);
+    let err = decompress_limited(&bomb, 1 << 16).unwrap_err();
+    assert!(err.to_string().contains("more than"), "{err}");
+    // Right at the limit is fine
+    assert_eq!(decompress_limited(&bomb[..5], 132).unwrap().len(), 132);
+    assert!(decompress_limited(&bomb[..5], 131).is_err());
+}
+
+#[test]
+fn wrappers_round_trip() {
+    let state: HashMap<String, Vec<u64>> = (0..50)
+        .map(|i| (format!("key-{i}"), (0..i).collect()))
+        .collect();
+    let encoded = serde_json::to_value(Compressed(state.clone())).unwrap();
+    assert!(encoded.is_string());
+    let decoded: Compressed<HashMap<String, Vec<u64>>> = serde_json::from_value(encoded).unwrap();
+    assert_eq!(decoded.0, state);
+
+    let value = json!({"a": [1, -2, 3.5, null, true], "b": {"c": "d"}});
+    let encoded = serde_json::to_value(Binary(value.clone())).unwrap();
+    let decoded: Binary<Value> = serde_json::from_value(encoded).unwrap();
+    assert_eq!(decoded.0, value);
+}
+
+#[test]
+fn bad_base64_is_an_error() {
+    for encoded in ["A", "AAAAA", "AA*A", "AA A", "é"] {
+        let result: Result<Compressed<Value>, _> = serde_json::from_value(json!(encoded));
+        assert!(result.is_err(), "{encoded:?}");
+    }
+    // Every length of valid base64, with and without padding
+    for len in 0..16 {
+        let value = json!("x".repeat(len));
+        let encoded = serde_json::to_value(Compressed(value.clone())).unwrap();
+        let unpadded = json!(encoded.as_str().unwrap().trim_end_matches('='));
+        for encoded in [encoded, unpadded] {
+            let decoded: Compressed<Value> = serde_json::from_value(encoded).unwrap();
+            assert_eq!(decoded.0, value);
+        }
+    }
+}
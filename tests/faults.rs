@@ -0,0 +1,163 @@
+//! Runs gossip-based broadcast and counter nodes under randomly injected faults
+//!
+//! The nodes are pared-down versions of the `broadcast` and `counter` binaries, built on the same
+//! library gossip.
+use rasengan::{
+    faults::{FaultInjector, FaultKind},
+    gossip::{Gossip, GossipMode, GossipPayload},
+    sim::Cluster,
+    *,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+
+#[workload]
+#[derive(Debug, Clone)]
+enum Payload {
+    #[reply]
+    Broadcast { message: u64 },
+    #[reply]
+    Add { delta: i64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct Increment(NodeID, u64, i64);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Wire {
+    Values(GossipPayload<HashSet<u64>>),
+    Increments(GossipPayload<HashSet<Increment>>),
+    Client(Payload),
+}
+
+#[derive(Clone)]
+struct Tick;
+
+/// Gossips both broadcast values and counter increments to every other node
+#[derive(Clone)]
+struct GossipNode {
+    node: NodeID,
+    seq: u64,
+    peers: Vec<NodeID>,
+    values: Gossip<HashSet<u64>>,
+    increments: Gossip<HashSet<Increment>>,
+}
+
+impl Node<(), Wire, Tick> for GossipNode {
+    fn from_init(_state: (), init: Init, runtime: Runtime<Wire, Tick>) -> anyhow::Result<Self> {
+        Ok(Self {
+            values: Gossip::new(&init, GossipMode::Push, runtime.rng("values")),
+            increments: Gossip::new(&init, GossipMode::Push, runtime.rng("increments")),
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|&id| *id != init.node_id)
+                .cloned()
+                .collect(),
+            node: init.node_id,
+            seq: 0,
+        })
+    }
+
+    fn step(&mut self, input: Event<Wire, Tick>, output: &mut Output) -> anyhow::Result<()> {
+        match input {
+            Event::Injected(Tick) => {
+                self.values.tick(&self.peers, output, Wire::Values)?;
+                self.increments
+                    .tick(&self.peers, output, Wire::Increments)?;
+            }
+            Event::Message(input) => {
+                let mut reply = input.into_reply(None);
+                match reply.body.payload {
+                    Wire::Values(gossip) => {
+                        self.values
+                            .handle(&reply.dst, gossip, output, Wire::Values)?;
+                    }
+                    Wire::Increments(gossip) => {
+                        self.increments
+                            .handle(&reply.dst, gossip, output, Wire::Increments)?;
+                    }
+                    Wire::Client(Payload::Broadcast { message }) => {
+                        self.values.insert(message);
+                        reply.body.payload = Wire::Client(Payload::BroadcastOk);
+                        reply.send(output)?;
+                    }
+                    Wire::Client(Payload::Add { delta }) => {
+                        self.seq += 1;
+                        self.increments
+                            .insert(Increment(self.node.clone(), self.seq, delta));
+                        reply.body.payload = Wire::Client(Payload::AddOk);
+                        reply.send(output)?;
+                    }
+                    Wire::Client(_) => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn debug_state(&self) -> Option<serde_json::Value> {
+        let mut values: Vec<_> = self.values.values().iter().copied().collect();
+        values.sort();
+        let value: i64 = self.increments.values().iter().map(|i| i.2).sum();
+        Some(json!({ "values": values, "value": value }))
+    }
+}
+
+fn injector() -> FaultInjector<GossipNode, (), Wire, Tick> {
+    let cluster = Cluster::new(4, 0, |_| ()).unwrap();
+    FaultInjector::new(cluster, |_| ())
+        .request("n1", json!({"type": "broadcast", "message": 1}))
+        .request("n2", json!({"type": "broadcast", "message": 2}))
+        .request("n3", json!({"type": "add", "delta": 3}))
+        .request("n4", json!({"type": "add", "delta": 4}))
+        .tick(Tick)
+        .eventually("every broadcast reaches every node", |world| {
+            world.states().all(|state| state["values"] == json!([1, 2]))
+        })
+        .eventually("the counter equals the sum of the adds", |world| {
+            world.states().all(|state| state["value"] == 7)
+        })
+        .always("every request is acknowledged at most once", |world| {
+            let mut acked = HashSet::new();
+            world
+                .to_clients
+                .iter()
+                .all(|reply| acked.insert(&reply.dst))
+        })
+}
+
+#[test]
+fn gossip_survives_message_faults() {
+    let failure = injector()
+        .drop_rate(0.2)
+        .delay_rate(0.1)
+        .duplicate_rate(0.1)
+        .check()
+        .unwrap();
+    assert!(failure.is_none(), "{}", failure.unwrap());
+}
+
+#[test]
+fn lost_state_shrinks_to_a_single_crash() {
+    let failure = injector()
+        .drop_rate(0.1)
+        .crash_rate(0.05)
+        .check()
+        .unwrap()
+        .expect("restarting without persisted state loses writes");
+    assert!(failure.original > 1, "{failure}");
+    assert_eq!(failure.schedule.len(), 1, "{failure}");
+    assert!(
+        matches!(failure.schedule[0].kind, FaultKind::Crash(_)),
+        "{failure}"
+    );
+    let injector = injector();
+    assert_eq!(
+        injector.replay(&failure.schedule).unwrap(),
+        Some(failure.property)
+    );
+}
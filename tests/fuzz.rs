@@ -0,0 +1,276 @@
+//! Throws random and hostile input at the stdin parsing path
+//!
+//! Each case is seeded, so a failure can be reproduced by running it again with the seed it
+//! reports. Valid messages are mixed in with the garbage to check that nothing is lost around it.
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rasengan::{framing::Documents, *};
+use serde_json::{json, Value};
+use std::{
+    io::{BufReader, Cursor, Write},
+    sync::{Arc, Mutex},
+};
+
+const SEEDS: u64 = 200;
+
+/// Bytes that make up JSON, weighted towards the ones that trip a parser up
+const ALPHABET: &[u8] = b"{}[]\"\"\\::,,\n\n \t0-1.eE+truefalsnul\x00\xff\xc3\xa9ab";
+
+fn garbage(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+    let len = rng.gen_range(0..max_len);
+    (0..len).map(|_| *ALPHABET.choose(rng).unwrap()).collect()
+}
+
+/// A line of garbage that can't open a document spanning into the lines after it, which could
+/// legitimately wrap the next message up in an array
+fn garbage_line(rng: &mut StdRng) -> Vec<u8> {
+    let mut line: Vec<u8> = garbage(rng, 64)
+        .into_iter()
+        .filter(|b| !b"{[\n".contains(b))
+        .collect();
+    line.push(b'\n');
+    line
+}
+
+#[workload]
+#[derive(Debug, Clone)]
+enum Payload {
+    #[reply { echo: Value }]
+    Echo { echo: Value },
+}
+
+struct EchoNode;
+
+impl Node<(), Payload> for EchoNode {
+    fn from_init(_state: (), _init: Init, _runtime: Runtime<Payload>) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let Event::Message(input) = input else {
+            return Ok(());
+        };
+        let mut reply = input.into_reply(None);
+        if let Payload::Echo { echo } = reply.body.payload {
+            reply.body.payload = Payload::EchoOk { echo };
+            reply.send(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// A writer whose contents can still be read after the node has finished with it
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn init() -> String {
+    json!({
+        "src": "c0", "dest": "n1",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+    })
+    .to_string()
+}
+
+/// Runs the echo node over `input` with `options`, returning every message it wrote
+fn run(input: Vec<u8>, options: Options) -> Vec<Value> {
+    let output = Shared::default();
+    run_with_io::<_, EchoNode, _, _>((), options, BufReader::new(Cursor::new(input)), {
+        output.clone()
+    })
+    .unwrap();
+    let output = output.0.lock().unwrap();
+    String::from_utf8_lossy(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn replies_to(output: &[Value], id: u64) -> Vec<&Value> {
+    output
+        .iter()
+        .filter(|message| message["body"]["in_reply_to"] == id)
+        .collect()
+}
+
+#[test]
+fn documents_survive_random_bytes() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let input = garbage(&mut rng, 512);
+        let max_len = rng.gen_range(1..128);
+        let documents = Documents::new(Cursor::new(&input)).max_len(max_len);
+        // Every document consumes input, so there can't be more of them than bytes
+        assert!(
+            documents.take(input.len() + 1).count() <= input.len(),
+            "seed {seed} produced documents out of nothing"
+        );
+    }
+}
+
+#[test]
+fn documents_keep_messages_around_garbage() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..10 {
+            let message = json!({ "src": "c1", "dest": "n1", "body": { "n": i } });
+            expected.push(message.clone());
+            input.extend(message.to_string().bytes());
+            input.push(b'\n');
+            input.extend(garbage_line(&mut rng));
+        }
+        let parsed: Vec<Value> = Documents::new(Cursor::new(input))
+            .filter_map(Result::ok)
+            .filter_map(|doc| serde_json::from_str(&doc).ok())
+            .filter(|value: &Value| value["body"]["n"].is_number())
+            .collect();
+        assert_eq!(parsed, expected, "seed {seed} lost messages");
+    }
+}
+
+#[test]
+fn stray_brackets_only_cost_their_own_line() {
+    let message = r#"{"src":"c1","dest":"n1","body":{"n":1}}"#;
+    for stray in ["{", "[", "{\"a\":", "[1,", "}", "]"] {
+        // Broken by the message, and left unfinished by the end of the input
+        for input in [
+            format!("{stray}\n{message}\n{{x\n"),
+            format!("{stray}\n{message}"),
+        ] {
+            let documents: Vec<String> = Documents::new(Cursor::new(input))
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert!(
+                documents.iter().any(|doc| doc == message),
+                "{stray:?} swallowed the message: {documents:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn every_request_is_answered_despite_garbage() {
+    for seed in 0..SEEDS / 10 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut input = init().into_bytes();
+        input.push(b'\n');
+        for id in 2..20u64 {
+            let message = if rng.gen_bool(0.3) {
+                // A request the node can't make sense of
+                json!({ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": id } })
+            } else {
+                json!({
+                    "src": "c1", "dest": "n1",
+                    "body": { "type": "echo", "msg_id": id, "echo": id }
+                })
+            };
+            input.extend(message.to_string().bytes());
+            input.push(b'\n');
+            input.extend(garbage_line(&mut rng));
+        }
+        let output = run(input, Options::default());
+        for id in 2..20u64 {
+            let replies = replies_to(&output, id);
+            assert_eq!(
+                replies.len(),
+                1,
+                "seed {seed}: request {id} got {replies:?}"
+            );
+            let body = &replies[0]["body"];
+            match body["type"].as_str() {
+                Some("echo_ok") => assert_eq!(body["echo"], id),
+                Some("error") => assert_eq!(body["code"], 12),
+                _ => panic!("seed {seed}: request {id} got {body}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn weird_but_legal_fields_are_accepted() {
+    let requests = [
+        // Unknown fields, both on the message and in its body
+        json!({
+            "id": 7, "src": "c1", "dest": "n1",
+            "body": { "type": "echo", "msg_id": 2, "echo": "a", "extra": [1, 2] }
+        }),
+        // An explicit null in_reply_to
+        json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "echo", "msg_id": 3, "in_reply_to": null, "echo": null }
+        }),
+        // The extremes of the ID range
+        json!({ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 0, "echo": 0 } }),
+        json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "echo", "msg_id": u64::MAX, "echo": "max" }
+        }),
+        // Node IDs needn't be ASCII
+        json!({ "src": "ç1 ☃", "dest": "n1", "body": { "type": "echo", "msg_id": 4, "echo": "☃" } }),
+    ];
+    let mut input = init();
+    input.push('\n');
+    for request in &requests {
+        input.push_str(&serde_json::to_string_pretty(request).unwrap());
+    }
+    // No msg_id at all, so the answer can't say what it's in reply to
+    input.push_str(r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"quiet"}}"#);
+    let output = run(input.into_bytes(), Options::default());
+
+    for request in &requests {
+        let id = request["body"]["msg_id"].as_u64().unwrap();
+        let replies = replies_to(&output, id);
+        assert_eq!(replies.len(), 1, "request {id} got {replies:?}");
+        assert_eq!(replies[0]["dest"], request["src"]);
+        assert_eq!(replies[0]["body"]["echo"], request["body"]["echo"]);
+    }
+    let quiet: Vec<_> = output
+        .iter()
+        .filter(|message| message["body"]["echo"] == "quiet")
+        .collect();
+    assert_eq!(quiet.len(), 1);
+    assert!(quiet[0]["body"]["in_reply_to"].is_null());
+}
+
+#[test]
+fn oversized_messages_are_skipped() {
+    let mut input = init();
+    input.push('\n');
+    let huge = "x".repeat(4096);
+    input.push_str(&format!(
+        r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":2,"echo":"{huge}"}}}}"#
+    ));
+    input.push('\n');
+    input.push_str(r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"ok"}}"#);
+    let options = Options {
+        max_message_bytes: 1024,
+        ..Options::default()
+    };
+    let output = run(input.into_bytes(), options);
+    assert!(replies_to(&output, 2).is_empty());
+    assert_eq!(replies_to(&output, 3)[0]["body"]["echo"], "ok");
+}
+
+#[test]
+fn missing_init_is_an_error_not_a_panic() {
+    let input =
+        b"garbage\n{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"echo\"}}\n".to_vec();
+    let result = run_with_io::<_, EchoNode, _, _>(
+        (),
+        Options::default(),
+        BufReader::new(Cursor::new(input)),
+        Vec::new(),
+    );
+    assert!(result.is_err());
+}
@@ -0,0 +1,50 @@
+//! Interval sets against a plain set of the same values
+//!
+//! Random inputs are seeded, so a failure can be reproduced from the seed it reports.
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rasengan::{gossip::GossipSet, interval_set::IntervalSet};
+use std::collections::BTreeSet;
+
+const SEEDS: u64 = 200;
+
+/// A few ranges of small values, so sets overlap, touch and nest
+fn sample(rng: &mut StdRng) -> (IntervalSet, BTreeSet<u64>) {
+    let mut set = IntervalSet::new();
+    let mut values = BTreeSet::new();
+    for _ in 0..rng.gen_range(0..8) {
+        let start = rng.gen_range(0..100);
+        let end = start + rng.gen_range(0..10);
+        set.insert_range(start..=end);
+        values.extend(start..=end);
+    }
+    (set, values)
+}
+
+#[test]
+fn merge_returns_exactly_the_new_values() {
+    for seed in 0..SEEDS {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (mut ours, our_values) = sample(&mut rng);
+        let (theirs, their_values) = sample(&mut rng);
+        let new = ours.merge(&theirs);
+        let expected: Vec<_> = their_values.difference(&our_values).copied().collect();
+        assert_eq!(new.iter().collect::<Vec<_>>(), expected, "seed {seed}");
+        let union: Vec<_> = our_values.union(&their_values).copied().collect();
+        assert_eq!(ours.iter().collect::<Vec<_>>(), union, "seed {seed}");
+        assert_eq!(ours.len(), union.len(), "seed {seed}");
+    }
+}
+
+#[test]
+fn huge_ranges_merge_without_being_expanded() {
+    let mut everything = IntervalSet::new();
+    everything.insert_range(0..=u64::MAX);
+    assert_eq!(everything.len(), usize::MAX);
+
+    let mut ours: IntervalSet = [5, 6, 7, u64::MAX - 1].into_iter().collect();
+    let new = ours.merge(&everything);
+    let gaps: Vec<_> = new.ranges().collect();
+    assert_eq!(gaps, [0..=4, 8..=u64::MAX - 2, u64::MAX..=u64::MAX]);
+    assert_eq!(ours, everything);
+    assert!(ours.merge(&everything).is_empty());
+}
@@ -0,0 +1,107 @@
+//! Drives [`Kv::update`] by hand, playing the key-value service's part
+use rasengan::{
+    kv::{Kv, KvPayload, UpdateError, LIN_KV},
+    rpc::ServiceReply,
+    *,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+type Payload = KvPayload<String, u64>;
+
+/// The one request the node sent since the last call, with its message ID
+fn sent(output: &mut Vec<u8>) -> (MessageID, Value) {
+    let text = String::from_utf8(std::mem::take(output)).unwrap();
+    let mut lines = text.lines();
+    let message: Value = serde_json::from_str(lines.next().expect("a request")).unwrap();
+    assert_eq!(lines.next(), None, "only one request");
+    (
+        serde_json::from_value(message["body"]["msg_id"].clone()).unwrap(),
+        message["body"].clone(),
+    )
+}
+
+fn reply(to: MessageID, payload: Value) -> Body<ServiceReply<Payload>> {
+    Body {
+        id: None,
+        in_reply_to: Some(to),
+        payload: serde_json::from_value(payload).unwrap(),
+    }
+}
+
+fn increment(kv: &mut Kv<&'static str>, id: &mut MessageID, output: &mut Vec<u8>) {
+    kv.update(
+        "counter".to_string(),
+        |n: Option<u64>| n.unwrap_or(0) + 1,
+        "client",
+        id,
+        output,
+        |payload: Payload| payload,
+    )
+    .unwrap();
+}
+
+#[test]
+fn a_missing_key_is_only_created_if_still_missing() {
+    let mut kv = Kv::new("n1", LIN_KV);
+    let (mut id, mut output) = (0, Vec::new());
+    increment(&mut kv, &mut id, &mut output);
+
+    let (read, _) = sent(&mut output);
+    let missing = json!({ "type": "error", "code": 20, "text": "not found" });
+    let progress = kv.progress(reply(read, missing), &mut id, &mut output, |p| p);
+    assert!(progress.unwrap().is_none());
+
+    // Nothing can match a null `from`, so a concurrent create fails the compare-and-set
+    let (cas, body) = sent(&mut output);
+    assert_eq!(body["from"], Value::Null);
+    assert_eq!(body["to"], json!(1));
+    assert_eq!(body["create_if_not_exists"], json!(true));
+    let conflict = json!({ "type": "error", "code": 22, "text": "current value is 1" });
+    let progress = kv.progress(reply(cas, conflict), &mut id, &mut output, |p| p);
+    assert!(progress.unwrap().is_none());
+
+    // And the update starts over from what the other writer left
+    let (read, body) = sent(&mut output);
+    assert_eq!(body["type"], json!("read"));
+    let progress = kv.progress(
+        reply(read, json!({ "type": "read_ok", "value": 1 })),
+        &mut id,
+        &mut output,
+        |p| p,
+    );
+    assert!(progress.unwrap().is_none());
+    let (cas, body) = sent(&mut output);
+    assert_eq!((&body["from"], &body["to"]), (&json!(1), &json!(2)));
+    let done = kv.progress(
+        reply(cas, json!({ "type": "cas_ok" })),
+        &mut id,
+        &mut output,
+        |p| p,
+    );
+    assert_eq!(done.unwrap(), Some(("client", Ok(2))));
+}
+
+#[test]
+fn updates_time_out() {
+    let mut kv = Kv::new("n1", LIN_KV).timeout(Duration::ZERO);
+    let (mut id, mut output) = (0, Vec::new());
+    increment(&mut kv, &mut id, &mut output);
+    let (read, _) = sent(&mut output);
+
+    let expired = kv.tick(&mut id, &mut output, |p: Payload| p).unwrap();
+    assert_eq!(expired, [("client", UpdateError::TimedOut { attempts: 1 })]);
+    assert_eq!(kv.rpc().pending(), 0);
+    let error: Error = expired[0].1.clone().into();
+    assert_eq!(error.code, ErrorCode::Timeout);
+
+    // A late reply is ignored
+    let late = kv.progress(
+        reply(read, json!({ "type": "read_ok", "value": 1 })),
+        &mut id,
+        &mut output,
+        |p| p,
+    );
+    assert!(late.unwrap().is_none());
+    assert!(output.is_empty());
+}
@@ -0,0 +1,108 @@
+//! Serves the runtime's priority lanes with all three of them full
+//!
+//! The node fills the lanes before its first step and records the lane of each event it's
+//! given, so the tests can check that neither of the less urgent lanes is starved, and that the
+//! shutdown still comes last.
+use rasengan::{
+    runtime::{Priority, STARVATION_LIMIT},
+    *,
+};
+use serde_json::{json, Value};
+use std::{
+    io::{BufReader, Cursor},
+    sync::{Arc, Mutex},
+};
+
+/// Events queued in each lane up front
+const QUEUED: usize = 100;
+
+type Served = Arc<Mutex<Vec<Priority>>>;
+
+/// Where to record the events served, and whether to queue requests along with the rest
+type Setup = (Served, bool);
+
+struct LaneNode {
+    served: Served,
+}
+
+impl Node<Setup, Value> for LaneNode {
+    fn from_init(
+        (served, requests): Setup,
+        _init: Init,
+        runtime: Runtime<Value>,
+    ) -> anyhow::Result<Self> {
+        for n in 0..QUEUED {
+            let reply = Message::new("n2", "n1")
+                .in_reply_to(n)
+                .payload(json!({ "type": "ping_ok" }));
+            runtime.try_send(Event::Message(reply))?;
+            runtime.try_inject(())?;
+            if !requests {
+                continue;
+            }
+            let request = Message::new("c1", "n1").payload(json!({ "type": "ping" }));
+            runtime.try_send(Event::Message(request))?;
+        }
+        Ok(Self { served })
+    }
+
+    fn step(&mut self, input: Event<Value>, _output: &mut Output) -> anyhow::Result<()> {
+        self.served.lock().unwrap().push(Priority::of(&input));
+        Ok(())
+    }
+}
+
+/// Runs the node over nothing but its init, returning the lanes of the events it stepped
+fn serve(requests: bool) -> Vec<Priority> {
+    let mut input = json!({
+        "src": "c0", "dest": "n1",
+        "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+    })
+    .to_string();
+    input.push('\n');
+    let served = Served::default();
+    let options = Options {
+        queue_capacity: QUEUED,
+        ..Options::default()
+    };
+    run_with_io::<_, LaneNode, _, _>(
+        (served.clone(), requests),
+        options,
+        BufReader::new(Cursor::new(input)),
+        std::io::sink(),
+    )
+    .unwrap();
+    let served = served.lock().unwrap().clone();
+    served
+}
+
+#[test]
+fn every_lane_is_served_while_all_are_saturated() {
+    let served = serve(true);
+    let count = |priority| served.iter().filter(|&&p| p == priority).count();
+    assert_eq!(count(Priority::Reply), QUEUED);
+    assert_eq!(count(Priority::Injected), QUEUED);
+    // Along with the shutdown
+    assert_eq!(count(Priority::Request), QUEUED + 1);
+
+    // Replies are served most, but only so long as none of the others has waited its limit
+    let window = STARVATION_LIMIT as usize + 2;
+    let saturated = &served[..QUEUED];
+    for (at, events) in saturated.windows(window).enumerate() {
+        for lane in [Priority::Reply, Priority::Injected, Priority::Request] {
+            assert!(
+                events.contains(&lane),
+                "{lane:?} wasn't served in the {window} events from {at}: {served:?}"
+            );
+        }
+    }
+    assert_eq!(saturated[0], Priority::Reply);
+}
+
+#[test]
+fn shutdown_waits_for_the_other_lanes() {
+    // The shutdown is all the request lane holds, so it's passed over from the start
+    let served = serve(false);
+    assert_eq!(served.len(), 2 * QUEUED + 1);
+    assert_eq!(served.last(), Some(&Priority::Request));
+}
@@ -0,0 +1,88 @@
+//! Restarts a broadcast node that keeps both a write-ahead log and snapshots
+//!
+//! Each snapshot starts the log over, so a restarted node has to put the two back together.
+use rasengan::{workloads::broadcast::BroadcastNode, *};
+use serde_json::{json, Value};
+use std::{
+    io::{BufReader, Cursor, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a node over `requests`, sent by client `c1` after the init message, returning its replies
+fn run(dir: &Path, requests: &[Value]) -> Vec<Value> {
+    let mut input = String::new();
+    let init = json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] });
+    for (id, body) in std::iter::once(&init).chain(requests).enumerate() {
+        let mut body = body.clone();
+        body["msg_id"] = json!(id + 1);
+        let message = json!({ "src": "c1", "dest": "n1", "body": body });
+        input.push_str(&message.to_string());
+        input.push('\n');
+    }
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let options = Options {
+        snapshot_dir: Some(dir.join("snapshots")),
+        snapshot_interval: Duration::ZERO,
+        ..Options::default()
+    };
+    run_with_io::<_, BroadcastNode, _, _>(
+        Some(dir.join("wal")),
+        options,
+        BufReader::new(Cursor::new(input)),
+        SharedWriter(written.clone()),
+    )
+    .unwrap();
+    let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+    written
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|message| message["dest"] == "c1")
+        .map(|message| message["body"].clone())
+        .collect()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rasengan-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn snapshots_compact_the_log_and_restore_with_it() {
+    let dir = temp_dir("recovery");
+    let topology = json!({ "type": "topology", "topology": { "n1": [], "n2": [] } });
+    let broadcasts: Vec<_> = (0..5)
+        .map(|n| json!({ "type": "broadcast", "message": n }))
+        .chain([topology])
+        .collect();
+    run(&dir, &broadcasts);
+
+    // Everything went into a snapshot, so the log was started over
+    let wal = std::fs::read_dir(dir.join("wal")).unwrap();
+    for entry in wal {
+        assert_eq!(entry.unwrap().metadata().unwrap().len(), 0);
+    }
+
+    let replies = run(&dir, &[json!({ "type": "read" })]);
+    let read = replies
+        .iter()
+        .find(|body| body["type"] == "read_ok")
+        .unwrap();
+    assert_eq!(read["messages"], json!([0, 1, 2, 3, 4]));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
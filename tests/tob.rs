@@ -0,0 +1,125 @@
+//! Runs total-order broadcast between three nodes, passing their messages along by hand
+use rasengan::{tob::*, *};
+use std::collections::{BTreeMap, VecDeque};
+
+type Payload = TobPayload<u64>;
+
+struct Cluster {
+    nodes: BTreeMap<NodeID, TotalOrder<u64>>,
+    delivered: BTreeMap<NodeID, Vec<u64>>,
+    in_flight: VecDeque<Message<Payload>>,
+}
+
+impl Cluster {
+    fn new() -> Self {
+        let node_ids: Vec<NodeID> = ["n1", "n2", "n3"].map(NodeID::from).to_vec();
+        let nodes = node_ids
+            .iter()
+            .map(|id| {
+                let init = Init {
+                    node_id: id.clone(),
+                    node_ids: node_ids.clone(),
+                    extra: Default::default(),
+                };
+                (id.clone(), TotalOrder::new(&init))
+            })
+            .collect();
+        Self {
+            nodes,
+            delivered: BTreeMap::new(),
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Runs `f` against node `id`, queueing whatever it sends and noting what it delivers
+    fn on(
+        &mut self,
+        id: &str,
+        f: impl FnOnce(&mut TotalOrder<u64>, &mut Vec<u8>) -> anyhow::Result<Vec<u64>>,
+    ) {
+        let mut output = Vec::new();
+        let delivered = f(self.nodes.get_mut(id).unwrap(), &mut output).unwrap();
+        self.delivered
+            .entry(id.into())
+            .or_default()
+            .extend(delivered);
+        let output = String::from_utf8(output).unwrap();
+        self.in_flight.extend(
+            output
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap()),
+        );
+    }
+
+    /// Delivers every message in flight, and any they lead to
+    fn settle(&mut self) {
+        while let Some(message) = self.in_flight.pop_front() {
+            self.receive(message);
+        }
+    }
+
+    fn receive(&mut self, message: Message<Payload>) {
+        let Message { src, dst, body } = message;
+        self.on(&dst, |node, output| {
+            node.handle(&src, body.payload, output, |p| p)
+        });
+    }
+
+    fn sync(&mut self) {
+        for id in ["n2", "n3"] {
+            self.on(id, |node, output| {
+                node.sync(output, |p| p).map(|()| Vec::new())
+            });
+        }
+        self.settle();
+    }
+}
+
+#[test]
+fn every_node_delivers_in_the_same_order() {
+    let mut cluster = Cluster::new();
+    for (n, id) in (0..9).zip(["n1", "n2", "n3"].into_iter().cycle()) {
+        cluster.on(id, |node, output| node.broadcast(n, output, |p| p));
+    }
+    cluster.settle();
+
+    let order = &cluster.delivered["n1"];
+    assert_eq!(order.len(), 9);
+    assert_eq!(&cluster.delivered["n2"], order);
+    assert_eq!(&cluster.delivered["n3"], order);
+}
+
+#[test]
+fn only_the_sequencer_assigns_positions() {
+    let mut cluster = Cluster::new();
+    let forged = serde_json::json!({
+        "src": "n3", "dest": "n2",
+        "body": { "type": "tob_deliver", "seq": 0, "origin": "n3", "id": 0, "value": 7 }
+    });
+    cluster.receive(serde_json::from_value(forged).unwrap());
+    assert_eq!(cluster.delivered["n2"], Vec::<u64>::new());
+    assert_eq!(cluster.nodes["n2"].delivered(), 0);
+}
+
+#[test]
+fn the_sequencer_forgets_what_every_node_has_delivered() {
+    let mut cluster = Cluster::new();
+    cluster.on("n2", |node, output| node.broadcast(1, output, |p| p));
+    let submit = cluster.in_flight[0].clone();
+    cluster.settle();
+    cluster.sync();
+
+    // Everyone has position 0, so a request to resend it goes unanswered
+    let resend = serde_json::json!({
+        "src": "n2", "dest": "n1",
+        "body": { "type": "tob_sync", "from": 0 }
+    });
+    cluster.receive(serde_json::from_value(resend).unwrap());
+    assert!(cluster.in_flight.is_empty());
+
+    // And a late copy of n2's submission isn't sequenced a second time
+    cluster.receive(submit);
+    cluster.settle();
+    assert_eq!(cluster.delivered["n1"], [1]);
+    assert_eq!(cluster.delivered["n2"], [1]);
+}
@@ -0,0 +1,46 @@
+//! Replays the golden transcripts under `tests/transcripts` against each binary
+//!
+//! Set `RASENGAN_BLESS=1` to re-record the transcripts from their inputs after an intended
+//! protocol change (or to fill in the outputs of a new scenario).
+use rasengan::transcript::Transcript;
+use std::path::Path;
+
+fn check(bin: &str, name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/transcripts")
+        .join(format!("{name}.jsonl"));
+    let golden = Transcript::load(&path).unwrap();
+    let actual = Transcript::record(bin, golden.inputs()).unwrap();
+    if std::env::var_os("RASENGAN_BLESS").is_some() {
+        actual.save(&path).unwrap();
+        return;
+    }
+    if let Err(e) = golden.verify(&actual) {
+        panic!("{name} no longer matches its transcript: {e:#}");
+    }
+}
+
+#[test]
+fn echo() {
+    check(env!("CARGO_BIN_EXE_echo"), "echo");
+}
+
+#[test]
+fn unique_ids() {
+    check(env!("CARGO_BIN_EXE_unique-ids"), "unique-ids");
+}
+
+#[test]
+fn broadcast() {
+    check(env!("CARGO_BIN_EXE_broadcast"), "broadcast");
+}
+
+#[test]
+fn counter() {
+    check(env!("CARGO_BIN_EXE_counter"), "counter");
+}
+
+#[test]
+fn kafka() {
+    check(env!("CARGO_BIN_EXE_kafka"), "kafka");
+}
@@ -0,0 +1,13 @@
+//! Puts JSON values that compare equal into a hash set
+use rasengan::value::JsonValue;
+use serde_json::json;
+use std::collections::HashSet;
+
+#[test]
+fn equal_floats_hash_alike() {
+    let zeros: HashSet<JsonValue> = [json!(0.0), json!(-0.0)]
+        .into_iter()
+        .map(JsonValue::from)
+        .collect();
+    assert_eq!(zeros.len(), 1);
+}